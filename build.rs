@@ -0,0 +1,13 @@
+fn main() {
+    // Generate the gRPC API, with the vendored protoc, so no system-wide install (or cross one) is needed.
+
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().expect("protoc is not available for host"));
+
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/phd.proto"], &["proto"])
+            .expect("unable to compile proto/phd.proto");
+    }
+}
@@ -0,0 +1,362 @@
+//! # HTTP status API
+//!
+//! The listening socket can also be passed by systemd (socket activation,
+//! with Accept=no), in which case it takes precedence over the configured
+//! address. The API can be announced via mDNS, see mdns.rs. Clients can be
+//! required to authenticate (see auth.rs) and the API can be served over TLS
+//! (see tls.rs). The recent records of a device can be exported, see
+//! export.rs. Records of unknown users held by a device (see unknown_user)
+//! can be listed and assigned to a person. The status only includes the
+//! recent records if the client may also read records. A measurement can be
+//! triggered on devices able to start one (see control.rs).
+
+use axum::{Json, Router};
+use axum::extract::{Path, Request, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum_server::tls_rustls::RustlsConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::net::{self, SocketAddr};
+use std::os::fd::{FromRawFd, RawFd};
+use std::process;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+use crate::auth::{Auth, AuthConfig, AuthPtr, Denied, Scope};
+use crate::control::{ControlPtr, MeasureError};
+use crate::export::{Export, ExportFormat};
+use crate::mdns::{AnnounceConfig, Mdns};
+use crate::persons::PersonsPtr;
+use crate::status::{DeviceStatus, StatusPtr};
+use crate::store::{HeldRecord, HeldState, StorePtr};
+use crate::tls::TlsConfig;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiConfig {
+    listen: Option<SocketAddr>, // Can be left out if socket activation is used.
+    announce: Option<AnnounceConfig>,
+    auth: Option<AuthConfig>,
+    tls: Option<TlsConfig>,
+}
+
+const LISTEN_FDS_START: RawFd = 3; // First fd passed by systemd.
+
+#[derive(Serialize)]
+struct StatusResp {
+    devices: BTreeMap<String, DeviceStatus>,
+}
+
+#[derive(Serialize)]
+struct HeldResp {
+    id: i64,
+    #[serde(flatten)]
+    record: HeldRecord,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AssignReq {
+    person: String,
+}
+
+pub struct Api;
+
+impl Api {
+    pub async fn start(config: ApiConfig, status: StatusPtr, control: ControlPtr, store: StorePtr, persons: PersonsPtr) -> Result<(), String> {
+        let tls = match &config.tls {
+            Some(tls) => Some(tls.load("API")?.get_server_config()?),
+            None => None,
+        };
+
+        let listener = match Self::get_activated_listener()? {
+            Some(listener) => {
+                println!("API: using socket passed by systemd");
+                listener
+            },
+            None => match config.listen {
+                Some(listen) => TcpListener::bind(listen).await.map_err(|e| format!("Unable to listen on {}: {}", listen, e))?,
+                None => return Err(String::from("API has no listen address configured and no socket was passed by systemd")),
+            },
+        };
+
+        if let Some(announce) = &config.announce {
+            Self::announce(announce, &listener, tls.is_some()).await;
+        }
+
+        let auth = match config.auth {
+            Some(auth_config) => Some(AuthPtr::new(Auth::new(auth_config)?)),
+            None => None,
+        };
+
+        let status_routes = Router::new()
+            .route("/status", get(Self::get_status))
+            .route("/metrics", get(Self::get_metrics));
+
+        let records_routes = Router::new()
+            .route("/devices/:id/:fname", get(Self::get_records)); // records.csv, records.json or records.fhir
+
+        let control_routes = Router::new()
+            .route("/devices/:id/measure", post(Self::trigger_measure));
+
+        let held_routes = Router::new()
+            .route("/held", get(Self::get_held))
+            .route("/held/:id/assign", post(Self::assign_held));
+
+        let app = Router::new()
+            .merge(Self::guard(status_routes, &auth, Scope::ReadStatus).with_state((StatusPtr::clone(&status), auth.clone())))
+            .merge(Self::guard(records_routes, &auth, Scope::ReadRecords).with_state(status))
+            .merge(Self::guard(control_routes, &auth, Scope::TriggerFetch).with_state(control))
+            .merge(Self::guard(held_routes, &auth, Scope::AssignRecords).with_state((store, persons)));
+
+        tokio::spawn(async move {
+            let result = match tls {
+                Some(tls) => match listener.into_std() {
+                    Ok(listener) => axum_server::from_tcp_rustls(listener, RustlsConfig::from_config(Arc::new(tls))).serve(app.into_make_service()).await,
+                    Err(e) => Err(e),
+                },
+                None => axum::serve(listener, app).await,
+            };
+
+            if let Err(e) = result {
+                eprintln!("API error: {}", e);
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn announce(config: &AnnounceConfig, listener: &TcpListener, tls: bool) {
+        // Best effort, the API works without it.
+
+        let addr = match listener.local_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                eprintln!("API: unable to get listening address, not announcing: {}", e);
+                return;
+            }
+        };
+
+        if addr.ip().is_loopback() {
+            eprintln!("API: listening on loopback only, not announcing");
+            return;
+        }
+
+        if let Err(e) = Mdns::announce(config, addr.port(), tls).await {
+            eprintln!("API: {}", e);
+        }
+    }
+
+    fn get_activated_listener() -> Result<Option<TcpListener>, String> {
+        // See sd_listen_fds(3): LISTEN_PID must match us, only the first of LISTEN_FDS is used.
+
+        let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+        let fds = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<u32>().ok());
+
+        if pid != Some(process::id()) || fds.unwrap_or(0) == 0 {
+            return Ok(None);
+        }
+
+        // Safety: systemd hands the fd over to us, nothing else in the process owns it.
+        let listener = unsafe { net::TcpListener::from_raw_fd(LISTEN_FDS_START) };
+
+        listener.set_nonblocking(true)
+            .and_then(|_| TcpListener::from_std(listener))
+            .map(Some)
+            .map_err(|e| format!("Unable to use socket passed by systemd: {}", e))
+    }
+
+    fn guard<S: Clone + Send + Sync + 'static>(routes: Router<S>, auth: &Option<AuthPtr>, scope: Scope) -> Router<S> {
+        match auth {
+            Some(auth) => routes.route_layer(middleware::from_fn_with_state((AuthPtr::clone(auth), scope), Self::check_auth)),
+            None => routes,
+        }
+    }
+
+    async fn check_auth(State((auth, scope)): State<(AuthPtr, Scope)>, req: Request, next: Next) -> Response {
+        let header = req.headers().get(AUTHORIZATION).and_then(|header| header.to_str().ok());
+
+        match auth.check(header, scope) {
+            Ok(()) => next.run(req).await,
+            Err(Denied::Unauthenticated) => (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, auth.get_challenge())]).into_response(),
+            Err(Denied::Forbidden) => StatusCode::FORBIDDEN.into_response(),
+        }
+    }
+
+    async fn get_status(State((status, auth)): State<(StatusPtr, Option<AuthPtr>)>, headers: HeaderMap) -> Json<StatusResp> {
+        let header = headers.get(AUTHORIZATION).and_then(|header| header.to_str().ok());
+
+        Json(StatusResp {
+            devices: Self::get_devices(&status, &auth, header),
+        })
+    }
+
+    fn get_devices(status: &StatusPtr, auth: &Option<AuthPtr>, header: Option<&str>) -> BTreeMap<String, DeviceStatus> {
+        // The recent records are health data, left out unless the client could also get them via get_records.
+
+        let mut devices = status.get_devices();
+
+        if auth.as_ref().is_some_and(|auth| auth.check(header, Scope::ReadRecords).is_err()) {
+            for device_status in devices.values_mut() {
+                device_status.recent.clear();
+            }
+        }
+
+        devices
+    }
+
+    async fn get_records(State(status): State<StatusPtr>, Path((id, fname)): Path<(String, String)>) -> Response {
+        let format = match ExportFormat::from_file_name(&fname) {
+            Some(format) => format,
+            None => return StatusCode::NOT_FOUND.into_response(),
+        };
+
+        match status.get_recent(&id) {
+            Some(records) => ([(CONTENT_TYPE, format.get_content_type())], Export::format(format, &id, &records)).into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+
+    async fn trigger_measure(State(control): State<ControlPtr>, Path(id): Path<String>) -> StatusCode {
+        // The device task takes the measurement and uploads it, see control.rs.
+
+        match control.trigger_measure(&id) {
+            Ok(()) => {
+                println!("{}: measurement triggered through API", id);
+                StatusCode::ACCEPTED
+            },
+            Err(MeasureError::NoDevice) => StatusCode::NOT_FOUND,
+            Err(MeasureError::NotSupported) => StatusCode::CONFLICT,
+        }
+    }
+
+    async fn get_held(State((store, _)): State<(StorePtr, PersonsPtr)>) -> Response {
+        match store.get_held(None, HeldState::Pending) {
+            Ok(held) => Json(held.into_iter().map(|(id, record)| HeldResp { id, record }).collect::<Vec<_>>()).into_response(),
+            Err(e) => {
+                eprintln!("API: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            },
+        }
+    }
+
+    async fn assign_held(State((store, persons)): State<(StorePtr, PersonsPtr)>, Path(id): Path<i64>, Json(req): Json<AssignReq>) -> StatusCode {
+        // The device task uploads the record on its next check.
+
+        match store.get_held_record(id) {
+            Ok(Some(_)) => (),
+            Ok(None) => return StatusCode::NOT_FOUND,
+            Err(e) => {
+                eprintln!("API: {}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR;
+            },
+        }
+
+        if !persons.has_person(&req.person) {
+            return StatusCode::BAD_REQUEST;
+        }
+
+        let result = store.update_held(id, |record| {
+            if record.state != HeldState::Pending {
+                return Err(format!("Held record {} is already {}", id, record.state.get_name()));
+            }
+
+            record.state = HeldState::Assigned;
+            record.person = Some(req.person.clone());
+            Ok(())
+        });
+
+        match result {
+            Ok(_) => StatusCode::NO_CONTENT,
+            Err(_) => StatusCode::CONFLICT,
+        }
+    }
+
+    async fn get_metrics(State((status, _)): State<(StatusPtr, Option<AuthPtr>)>) -> String { // Prometheus text format.
+        let devices = status.get_devices();
+        let mut body = String::new();
+
+        body.push_str("# TYPE phd_device_state gauge\n");
+        for (id, device_status) in &devices {
+            body.push_str(&format!("phd_device_state{{device_id=\"{}\",state=\"{}\"}} 1\n", id, device_status.state.get_name()));
+        }
+
+        body.push_str("# TYPE phd_device_state_since_seconds gauge\n");
+        for (id, device_status) in &devices {
+            body.push_str(&format!("phd_device_state_since_seconds{{device_id=\"{}\"}} {}\n", id, device_status.since));
+        }
+
+        body.push_str("# TYPE phd_device_last_adv_seconds gauge\n");
+        for (id, device_status) in &devices {
+            if let Some(last_adv) = device_status.last_adv {
+                body.push_str(&format!("phd_device_last_adv_seconds{{device_id=\"{}\"}} {}\n", id, last_adv));
+            }
+        }
+
+        body.push_str("# TYPE phd_device_paired gauge\n");
+        for (id, device_status) in &devices {
+            if let Some(paired) = device_status.pairing.paired {
+                body.push_str(&format!("phd_device_paired{{device_id=\"{}\"}} {}\n", id, paired as u8));
+            }
+        }
+
+        body.push_str("# TYPE phd_device_records_total counter\n");
+        for (id, device_status) in &devices {
+            body.push_str(&format!("phd_device_records_total{{device_id=\"{}\"}} {}\n", id, device_status.stats.records));
+        }
+
+        body.push_str("# TYPE phd_device_bytes_read_total counter\n");
+        for (id, device_status) in &devices {
+            body.push_str(&format!("phd_device_bytes_read_total{{device_id=\"{}\"}} {}\n", id, device_status.stats.bytes_read));
+        }
+
+        body.push_str("# TYPE phd_device_failures gauge\n");
+        for (id, device_status) in &devices {
+            body.push_str(&format!("phd_device_failures{{device_id=\"{}\"}} {}\n", id, device_status.stats.failures));
+        }
+
+        body
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::Api;
+    use crate::auth::{Auth, AuthConfig, AuthPtr};
+    use crate::status::StatusPtr;
+    use crate::store::RecentRecord;
+
+    #[test]
+    fn status_recent() {
+        let status = StatusPtr::default();
+        status.set_recent("bpm", vec![RecentRecord {
+            id: None,
+            ts: 1_700_000_000_000_000_000,
+            meas: String::from("blood_pressure"),
+            tags: BTreeMap::new(),
+            fields: BTreeMap::from([(String::from("sys"), serde_json::json!(128))]),
+        }]);
+
+        let config: AuthConfig = serde_json::from_value(serde_json::json!({
+            "tokens": [
+                "s3cret",
+                { "secret": "dashboard", "scopes": ["read_status"] },
+            ],
+        })).unwrap();
+        let auth = Some(AuthPtr::new(Auth::new(config).unwrap()));
+
+        let devices = Api::get_devices(&status, &auth, Some("Bearer dashboard"));
+        assert!(devices["bpm"].recent.is_empty());
+        assert!(!serde_json::to_string(&devices).unwrap().contains("128"));
+
+        assert_eq!(Api::get_devices(&status, &auth, Some("Bearer s3cret"))["bpm"].recent.len(), 1);
+        assert_eq!(Api::get_devices(&status, &None, None)["bpm"].recent.len(), 1);
+    }
+}
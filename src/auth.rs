@@ -0,0 +1,175 @@
+//! # API authentication
+//!
+//! Shared by the HTTP and gRPC APIs. Clients send a bearer token
+//! (`Authorization: Bearer <token>`, `authorization` metadata in gRPC) or
+//! use basic auth, whichever is configured. Without an auth section, the API
+//! is open to anyone who can reach it.
+//!
+//! Tokens and users can be limited to scopes, e.g. a dashboard reading the
+//! status doesn't need to trigger fetches. Without scopes, everything is
+//! allowed.
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+const REALM: &str = "phd";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    #[serde(default)]
+    tokens: Vec<CredentialConfig>, // Bearer tokens.
+    #[serde(default)]
+    users: BTreeMap<String, CredentialConfig>, // Basic auth: user name to password.
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CredentialConfig {
+    Plain(String), // All scopes.
+    Scoped(ScopedConfig),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScopedConfig {
+    secret: String, // Token or password.
+    scopes: Vec<Scope>,
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    ReadStatus,
+    ReadRecords,
+    TriggerFetch,
+    AssignRecords, // Assigning held records of unknown users to persons.
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Denied {
+    Unauthenticated, // Missing or invalid credentials.
+    Forbidden, // Valid credentials, scope not granted.
+}
+
+type Hash = [u8; 32];
+
+struct Credential {
+    hash: Hash,
+    scopes: Option<Vec<Scope>>, // None: all scopes.
+}
+
+pub struct Auth {
+    tokens: Vec<Credential>,
+    users: BTreeMap<String, Credential>,
+}
+
+pub type AuthPtr = Arc<Auth>;
+
+impl Auth {
+    pub fn new(config: AuthConfig) -> Result<Self, String> {
+        if config.tokens.is_empty() && config.users.is_empty() {
+            return Err(String::from("API auth has no tokens or users configured"));
+        }
+
+        Ok(Self {
+            tokens: config.tokens.into_iter().map(Credential::new).collect(),
+            users: config.users.into_iter().map(|(user, password)| (user, Credential::new(password))).collect(),
+        })
+    }
+
+    pub fn check(&self, header: Option<&str>, scope: Scope) -> Result<(), Denied> {
+        // Value of the Authorization header, the scheme is case-insensitive.
+
+        let credential = self.get_credential(header).ok_or(Denied::Unauthenticated)?;
+
+        match &credential.scopes {
+            Some(scopes) if !scopes.contains(&scope) => Err(Denied::Forbidden),
+            _ => Ok(()),
+        }
+    }
+
+    fn get_credential(&self, header: Option<&str>) -> Option<&Credential> {
+        let (scheme, credentials) = header?.trim().split_once(' ')?;
+        let credentials = credentials.trim();
+
+        if scheme.eq_ignore_ascii_case("bearer") {
+            let hash = Self::hash(credentials);
+            self.tokens.iter().find(|token| token.hash == hash)
+        } else if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = String::from_utf8(BASE64.decode(credentials).ok()?).ok()?;
+            let (user, password) = decoded.split_once(':')?;
+
+            self.users.get(user).filter(|credential| credential.hash == Self::hash(password))
+        } else {
+            None
+        }
+    }
+
+    pub fn get_challenge(&self) -> String {
+        // WWW-Authenticate value of a 401 response.
+
+        if self.users.is_empty() {
+            format!("Bearer realm=\"{}\"", REALM)
+        } else {
+            format!("Basic realm=\"{}\"", REALM)
+        }
+    }
+
+    fn hash(secret: &str) -> Hash {
+        // Secrets are compared by their hash, so the comparison time doesn't tell how much of a guess was right.
+
+        Sha256::digest(secret.as_bytes()).into()
+    }
+}
+
+impl Credential {
+    fn new(config: CredentialConfig) -> Self {
+        match config {
+            CredentialConfig::Plain(secret) => Self {
+                hash: Auth::hash(&secret),
+                scopes: None,
+            },
+            CredentialConfig::Scoped(config) => Self {
+                hash: Auth::hash(&config.secret),
+                scopes: Some(config.scopes),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{Auth, AuthConfig, CredentialConfig, Denied, Scope, ScopedConfig};
+
+    #[test]
+    fn check() {
+        let auth = Auth::new(AuthConfig {
+            tokens: vec![
+                CredentialConfig::Plain(String::from("s3cret")),
+                CredentialConfig::Scoped(ScopedConfig { secret: String::from("dashboard"), scopes: vec![Scope::ReadStatus] }),
+            ],
+            users: BTreeMap::from([(String::from("grafana"), CredentialConfig::Plain(String::from("pa:ss")))]),
+        }).unwrap();
+
+        assert_eq!(auth.check(Some("Bearer s3cret"), Scope::AssignRecords), Ok(()));
+        assert_eq!(auth.check(Some("bearer  s3cret "), Scope::ReadStatus), Ok(()));
+        assert_eq!(auth.check(Some("Bearer s3cre"), Scope::ReadStatus), Err(Denied::Unauthenticated));
+        assert_eq!(auth.check(Some("Basic Z3JhZmFuYTpwYTpzcw=="), Scope::ReadStatus), Ok(())); // grafana:pa:ss
+        assert_eq!(auth.check(Some("Basic Z3JhZmFuYTpwYQ=="), Scope::ReadStatus), Err(Denied::Unauthenticated)); // grafana:pa
+        assert_eq!(auth.check(Some("Basic czNjcmV0"), Scope::ReadStatus), Err(Denied::Unauthenticated)); // s3cret
+        assert_eq!(auth.check(Some("s3cret"), Scope::ReadStatus), Err(Denied::Unauthenticated));
+        assert_eq!(auth.check(None, Scope::ReadStatus), Err(Denied::Unauthenticated));
+
+        assert_eq!(auth.check(Some("Bearer dashboard"), Scope::ReadStatus), Ok(()));
+        assert_eq!(auth.check(Some("Bearer dashboard"), Scope::TriggerFetch), Err(Denied::Forbidden));
+
+        assert!(Auth::new(AuthConfig { tokens: Vec::new(), users: BTreeMap::new() }).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+//! # BlueZ backend
+//!
+//! Links to units through BlueZ (via D-Bus), advertisements are received by
+//! the shared scanner.
+
+use async_trait::async_trait;
+use bluer::{AdapterEvent, Address, Device, Session};
+use bluer::agent::{Agent, ReqError, ReqResult, RequestPasskey};
+use bluer::gatt::remote::{Characteristic, Service};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::io;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use tokio::time::{self, Duration, Instant};
+use uuid::Uuid;
+
+use crate::btutil::{AdvData, AdvPattern, BTBackend, BTBackendPtr, BTLink, BTLinkPtr, BTRxStream, Error, Result};
+use crate::redact::Redact;
+use crate::scanner::{Scanner, ScannerPtr};
+
+const READY_BACKOFF_MIN: u64 = 1; // [s]
+const READY_BACKOFF_MAX: u64 = 30; // [s]
+
+const BLUETOOTHD_PATHS: &[&str] = &["bluetoothd", "/usr/libexec/bluetooth/bluetoothd", "/usr/lib/bluetooth/bluetoothd"];
+
+pub struct BluezBackend {
+    scanner: ScannerPtr,
+}
+
+impl BluezBackend {
+    pub fn start() -> BTBackendPtr {
+        Arc::new(Self {
+            scanner: Scanner::start(),
+        })
+    }
+
+    pub async fn wait_ready(timeout: Option<Duration>) -> std::result::Result<(), String> {
+        // At boot (e.g. on SBCs) BlueZ or the adapter might not be up yet, retry with backoff instead of failing. Waits forever without timeout.
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut backoff = Duration::from_secs(READY_BACKOFF_MIN);
+
+        loop {
+            let e = match Self::check_ready().await {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+
+            let now = Instant::now();
+            let pause = match deadline {
+                Some(deadline) if now >= deadline => return Err(format!("Bluetooth is not ready: {}", e)),
+                Some(deadline) => backoff.min(deadline - now),
+                None => backoff,
+            };
+
+            println!("waiting for Bluetooth: {}", e);
+            time::sleep(pause).await;
+            backoff = (backoff * 2).min(Duration::from_secs(READY_BACKOFF_MAX));
+        }
+    }
+
+    pub async fn get_adapters() -> Result<Vec<String>> {
+        // Fails if D-Bus or bluetoothd is not reachable.
+
+        let session = Session::new().await?;
+        Ok(session.adapter_names().await?)
+    }
+
+    pub async fn get_default_adapter() -> Result<String> {
+        let session = Session::new().await?;
+        let adapter = session.default_adapter().await?;
+
+        if !adapter.is_powered().await? {
+            return Err(Error::General(format!("Adapter {} is not powered", adapter.name())));
+        }
+
+        Ok(format!("{} {}", adapter.name(), adapter.address().await?))
+    }
+
+    pub async fn check_monitor() -> Result<()> {
+        // Registering the monitor root fails if bluetoothd doesn't offer the API (e.g. without experimental features).
+
+        let session = Session::new().await?;
+        let adapter = session.default_adapter().await?;
+        adapter.monitor().await?;
+
+        Ok(())
+    }
+
+    pub fn get_version() -> Option<String> {
+        // Not exposed over D-Bus, ask the binary (outside of PATH on most distros).
+
+        BLUETOOTHD_PATHS.iter().find_map(|path| {
+            let output = Command::new(path).arg("--version").output().ok().filter(|output| output.status.success())?;
+            Some(String::from(String::from_utf8(output.stdout).ok()?.trim()))
+        })
+    }
+
+    async fn check_ready() -> Result<()> {
+        let session = Session::new().await?;
+        let adapter = session.default_adapter().await?;
+
+        if !adapter.is_powered().await? {
+            return Err("Adapter is not powered".into());
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BTBackend for BluezBackend {
+    async fn get_link(&self, addr: &Address, do_disco: bool) -> Result<BTLinkPtr> {
+        let session = Session::new().await?; // TODO: Have single session only.
+        let adapter = session.default_adapter().await?;
+        let device = adapter.device(*addr)?;
+
+        if do_disco {
+            let mut disco = adapter.discover_devices().await?;
+
+            while let Some(ev) = disco.next().await {
+                if let AdapterEvent::DeviceAdded(ev_addr) = ev {
+                    if ev_addr == *addr {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(Arc::new(BluezLink {
+            session,
+            device,
+            chars: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    async fn wait_for_adv(&self, addr: &Address, patterns: &[AdvPattern]) -> Result<AdvData> {
+        self.scanner.wait_for_adv(*addr, patterns).await
+    }
+
+    fn rearm_adv(&self) {
+        self.scanner.rearm();
+    }
+
+    async fn get_adapter(&self) -> Result<String> {
+        let session = Session::new().await?;
+        let adapter = session.default_adapter().await?;
+
+        Ok(adapter.address().await?.to_string())
+    }
+}
+
+struct BluezLink {
+    session: Session,
+    device: Device,
+    chars: Mutex<HashMap<(Uuid, Uuid), Characteristic>>, // Looked up characteristics, keyed by service and characteristic uuid.
+}
+
+impl BluezLink {
+    async fn get_char(&self, service_uuid: &Uuid, char_uuid: &Uuid) -> Result<Characteristic> {
+        if let Some(char) = self.chars.lock().unwrap().get(&(*service_uuid, *char_uuid)) {
+            return Ok(char.clone());
+        }
+
+        let service = Self::lookup_service(&self.device, service_uuid).await?;
+        let char = Self::lookup_char(&service, char_uuid).await?;
+        self.chars.lock().unwrap().insert((*service_uuid, *char_uuid), char.clone());
+
+        Ok(char)
+    }
+
+    async fn lookup_service(device: &Device, service_uuid: &Uuid) -> Result<Service> {
+        let services: Vec<Service> = device.services().await?;
+
+        for service in services.into_iter() {
+            if service.uuid().await? == *service_uuid {
+                return Ok(service);
+            }
+        }
+
+        Err("Service not found".into())
+    }
+
+    async fn lookup_char(service: &Service, char_uuid: &Uuid) -> Result<Characteristic> {
+        let chars = service.characteristics().await?;
+
+        for char in chars.into_iter() {
+            if char.uuid().await? == *char_uuid {
+                return Ok(char);
+            }
+        }
+
+        Err("Characteristic not found".into())
+    }
+
+    async fn request_passkey(req: RequestPasskey) -> ReqResult<u32> {
+        // Pairing is run interactively (phd -p), read it from the terminal.
+
+        println!("{}", Redact::apply(&format!("{}: enter the PIN shown on the unit:", req.device)));
+
+        let line = tokio::task::spawn_blocking(|| {
+            let mut line = String::new();
+            io::stdin().read_line(&mut line).map(|_| line)
+        }).await;
+
+        match line {
+            Ok(Ok(line)) => line.trim().parse().map_err(|_| ReqError::Rejected),
+            _ => Err(ReqError::Canceled),
+        }
+    }
+}
+
+#[async_trait]
+impl BTLink for BluezLink {
+    async fn is_paired(&self) -> Result<bool> {
+        Ok(self.device.is_paired().await?)
+    }
+
+    async fn pair(&self) -> Result<()> {
+        let agent = Agent { // Accept all requests, ask for the PIN if the unit shows one.
+            request_passkey: Some(Box::new(|req| Box::pin(Self::request_passkey(req)))),
+            ..Default::default()
+        };
+        let _ = self.session.register_agent(agent).await?;
+
+        Ok(self.device.pair().await?)
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        Ok(self.device.is_connected().await?)
+    }
+
+    async fn connect(&self) -> Result<()> {
+        Ok(self.device.connect().await?)
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.chars.lock().unwrap().clear(); // Handles are invalid after reconnecting.
+        Ok(self.device.disconnect().await?)
+    }
+
+    async fn read_char(&self, service_uuid: &Uuid, char_uuid: &Uuid) -> Result<Vec<u8>> {
+        Ok(self.get_char(service_uuid, char_uuid).await?.read().await?)
+    }
+
+    async fn write_char(&self, service_uuid: &Uuid, char_uuid: &Uuid, data: &[u8]) -> Result<()> {
+        Ok(self.get_char(service_uuid, char_uuid).await?.write(data).await?)
+    }
+
+    async fn notify_char(&self, service_uuid: &Uuid, char_uuid: &Uuid) -> Result<BTRxStream> {
+        let rx_stream = self.get_char(service_uuid, char_uuid).await?.notify().await?;
+        Ok(Box::pin(rx_stream))
+    }
+}
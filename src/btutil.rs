@@ -1,22 +1,143 @@
-use bluer::{Adapter, AdapterEvent, Address, Device, Session};
-use bluer::agent::Agent;
-use bluer::gatt::remote::{Characteristic, Service};
-use bluer::monitor::{Monitor, MonitorEvent, Pattern, RssiSamplingPeriod, Type};
-use futures::StreamExt;
+use async_trait::async_trait;
+use bluer::{Address, UuidExt};
+use bluer::monitor::{data_type, Pattern};
+use futures::Stream;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
 use std::result;
+use std::sync::Arc;
+use tokio::time::{self, Duration};
 use uuid::{uuid, Uuid};
 
-const DEVICE_INFO_SERVICE: &Uuid = &uuid!("0000180a-0000-1000-8000-00805f9b34fb");
-const MANUFACTURER_CHAR: &Uuid = &uuid!("00002a29-0000-1000-8000-00805f9b34fb");
-const MODEL_CHAR: &Uuid = &uuid!("00002a24-0000-1000-8000-00805f9b34fb");
-const FIRMWARE_CHAR: &Uuid = &uuid!("00002a26-0000-1000-8000-00805f9b34fb");
+pub const DEVICE_INFO_SERVICE: &Uuid = &uuid!("0000180a-0000-1000-8000-00805f9b34fb");
+pub const MANUFACTURER_CHAR: &Uuid = &uuid!("00002a29-0000-1000-8000-00805f9b34fb");
+pub const MODEL_CHAR: &Uuid = &uuid!("00002a24-0000-1000-8000-00805f9b34fb");
+pub const FIRMWARE_CHAR: &Uuid = &uuid!("00002a26-0000-1000-8000-00805f9b34fb");
+pub const BATTERY_SERVICE: &Uuid = &uuid!("0000180f-0000-1000-8000-00805f9b34fb");
+pub const BATTERY_LEVEL_CHAR: &Uuid = &uuid!("00002a19-0000-1000-8000-00805f9b34fb");
+
+const SERVICE_DATA_16_BIT_UUID: u8 = 0x16; // Advertising data type, not defined by bluer.
+
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum AdvPattern { // Something an advertisement of the unit carries.
+    Manufacturer(ManufacturerPattern),
+    Service(ServicePattern),
+    ServiceData(ServiceDataPattern),
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManufacturerPattern {
+    pub company_id: u16,
+    #[serde(default, deserialize_with = "hex::serde::deserialize")]
+    pub data: Vec<u8>, // Start of the data following the company id.
+    #[serde(default, deserialize_with = "hex::serde::deserialize")]
+    pub mask: Vec<u8>, // Bits of data to compare, all of them where not given.
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServicePattern {
+    pub service: u16, // 16-bit service UUID.
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceDataPattern {
+    pub service_data: u16, // 16-bit service UUID the data is sent for.
+}
+
+#[derive(Clone, Default)]
+pub struct AdvData { // Passed to the waiter, broadcast-only units carry their readings in it.
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+    pub services: HashSet<Uuid>,
+    pub service_data: HashMap<Uuid, Vec<u8>>,
+}
+
+impl AdvPattern {
+    pub fn manufacturer(company_id: u16, data: &[u8], mask: &[u8]) -> Self {
+        Self::Manufacturer(ManufacturerPattern {
+            company_id,
+            data: data.to_vec(),
+            mask: mask.to_vec(),
+        })
+    }
+
+    pub fn service(service: u16) -> Self {
+        Self::Service(ServicePattern {
+            service,
+        })
+    }
+
+    pub fn service_data(service_data: u16) -> Self {
+        Self::ServiceData(ServiceDataPattern {
+            service_data,
+        })
+    }
+
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Self::Manufacturer(pattern) => pattern.mask.len() <= pattern.data.len(),
+            Self::Service(_) | Self::ServiceData(_) => true,
+        }
+    }
+
+    pub fn get_monitor_patterns(&self) -> Vec<Pattern> {
+        // BlueZ only matches exact content, so the monitor gets the company id and
+        // the data up to the first masked byte, the rest is checked by is_match.
+        // Services are matched when listed first, either in a complete or an incomplete list.
+
+        match self {
+            Self::Manufacturer(pattern) => {
+                let exact = pattern.data.iter().enumerate().take_while(|(i, _)| pattern.get_mask(*i) == 0xff).count();
+                let mut content = pattern.company_id.to_le_bytes().to_vec();
+                content.extend_from_slice(&pattern.data[..exact]);
+
+                vec![Pattern {
+                    data_type: data_type::MANUFACTURER_SPECIFIC_DATA,
+                    start_position: 0,
+                    content,
+                }]
+            },
+            Self::Service(pattern) => [data_type::COMPLETE_LIST_16_BIT_SERVICE_CLASS_UUIDS, data_type::INCOMPLETE_LIST_16_BIT_SERVICE_CLASS_UUIDS].into_iter().map(|data_type| Pattern {
+                data_type,
+                start_position: 0,
+                content: pattern.service.to_le_bytes().to_vec(),
+            }).collect(),
+            Self::ServiceData(pattern) => vec![Pattern {
+                data_type: SERVICE_DATA_16_BIT_UUID,
+                start_position: 0,
+                content: pattern.service_data.to_le_bytes().to_vec(),
+            }],
+        }
+    }
+
+    pub fn is_match(&self, adv_data: &AdvData) -> bool {
+        match self {
+            Self::Manufacturer(pattern) => match adv_data.manufacturer_data.get(&pattern.company_id) {
+                Some(data) => data.len() >= pattern.data.len() && pattern.data.iter().zip(data).enumerate().all(|(i, (expected, actual))| (expected ^ actual) & pattern.get_mask(i) == 0),
+                None => false,
+            },
+            Self::Service(pattern) => adv_data.services.contains(&Uuid::from_u16(pattern.service)),
+            Self::ServiceData(pattern) => adv_data.service_data.contains_key(&Uuid::from_u16(pattern.service_data)),
+        }
+    }
+}
+
+impl ManufacturerPattern {
+    fn get_mask(&self, index: usize) -> u8 {
+        self.mask.get(index).copied().unwrap_or(0xff)
+    }
+}
 
 pub struct BTDeviceInfo {
     pub manufacturer: String,
     pub model: String,
-    #[allow(dead_code)] // TODO: Get serial number as well and print it out during pairing?
-    pub firmware: String,
+    pub firmware: String, // TODO: Get serial number as well and print it out during pairing?
 }
 
 pub enum Error {
@@ -48,105 +169,79 @@ impl From<&str> for Error {
 
 pub type Result<T> = result::Result<T, Error>;
 
-pub struct BTUtil;
+#[async_trait]
+pub trait BTLink: Send + Sync { // Connection to a unit, implemented for BlueZ and by the test harness.
+    async fn is_paired(&self) -> Result<bool>;
+    async fn pair(&self) -> Result<()>;
+    async fn is_connected(&self) -> Result<bool>;
+    async fn connect(&self) -> Result<()>;
+    async fn disconnect(&self) -> Result<()>;
+    async fn read_char(&self, service_uuid: &Uuid, char_uuid: &Uuid) -> Result<Vec<u8>>;
+    async fn write_char(&self, service_uuid: &Uuid, char_uuid: &Uuid, data: &[u8]) -> Result<()>;
+    async fn notify_char(&self, service_uuid: &Uuid, char_uuid: &Uuid) -> Result<BTRxStream>;
+}
 
-impl BTUtil {
-    pub async fn get_device(addr: &Address, do_disco: bool) -> Result<(Session, Adapter, Device)> {
-        let session = Session::new().await?; // TODO: Have single session only.
-        let adapter = session.default_adapter().await?;
-        let device = adapter.device(*addr)?;
-
-        if do_disco {
-            let mut disco = adapter.discover_devices().await?;
-
-            while let Some(ev) = disco.next().await {
-                if let AdapterEvent::DeviceAdded(ev_addr) = ev {
-                    if ev_addr == *addr {
-                        break;
-                    }
-                }
-            }
-        }
+pub type BTLinkPtr = Arc<dyn BTLink>;
 
-        Ok((session, adapter, device))
-    }
+pub type BTRxStream = Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>; // Notifications of a characteristic.
 
-    pub async fn pair(session: &Session, device: &Device) -> Result<()> {
-        let agent = Agent { // Accept all requests.
-            ..Default::default()
-        };
-        let _ = session.register_agent(agent).await?;
-        
-        Ok(device.pair().await?)
-    }
-
-    pub async fn wait_for_adv(adapter: &Adapter, device: &Device, pattern: Pattern) -> Result<()> {
-        // Passive listen for advertisements.
-        
-        let mon_mgr = adapter.monitor().await?;
-
-        let mon = Monitor {
-            monitor_type: Type::OrPatterns,
-            rssi_low_threshold: None,
-            rssi_high_threshold: None,
-            rssi_low_timeout: None,
-            rssi_high_timeout: None,
-            rssi_sampling_period: Some(RssiSamplingPeriod::All),
-            patterns: Some(vec![pattern]),
-            ..Default::default()
-        };
-        let mut mon_handle = mon_mgr.register(mon).await?;
+#[async_trait]
+pub trait BTBackend: Send + Sync { // Hands out links and advertisements.
+    async fn get_link(&self, addr: &Address, do_disco: bool) -> Result<BTLinkPtr>;
+    async fn wait_for_adv(&self, addr: &Address, patterns: &[AdvPattern]) -> Result<AdvData>; // Any of the patterns.
+    fn rearm_adv(&self); // Re-register the advertisement monitor.
+    async fn get_adapter(&self) -> Result<String>; // Address of the adapter bonds are made on.
+}
 
-        while let Some(ev) = mon_handle.next().await {
-            if let MonitorEvent::DeviceFound(device_id) = ev {
-                if device_id.device == device.address() {
-                    return Ok(());
-                }
-            }
-        }
+pub type BTBackendPtr = Arc<dyn BTBackend>;
 
-        Err("Failed to receive advertisements".into())
-    }
+pub struct BTUtil;
 
-    pub async fn lookup_service(device: &Device, service_uuid: &Uuid) -> Result<Service> {
-        let services: Vec<Service> = device.services().await?;
+impl BTUtil {
+    pub async fn disconnect(link: &BTLinkPtr) {
+        // Errors are ignored, the device might have dropped the connection already.
 
-        for service in services.into_iter() {
-            if service.uuid().await? == *service_uuid {
-                return Ok(service);
-            }
+        if let Ok(true) = link.is_connected().await {
+            let _ = link.disconnect().await;
         }
-
-        Err("Service not found".into())
     }
 
-    pub async fn lookup_char(service: &Service, char_uuid: &Uuid) -> Result<Characteristic> {
-        let chars = service.characteristics().await?;
+    pub async fn with_deadline<T, F>(link: &BTLinkPtr, timeout: Option<Duration>, fut: F) -> Result<T> where F: Future<Output = Result<T>> {
+        // Cancel a hung exchange and drop the connection, so the next attempt starts from scratch.
+
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return fut.await,
+        };
 
-        for char in chars.into_iter() {
-            if char.uuid().await? == *char_uuid {
-                return Ok(char);
+        match time::timeout(timeout, fut).await {
+            Ok(result) => result,
+            Err(_) => {
+                let _ = link.disconnect().await;
+                Err("Fetch timed out".into())
             }
         }
-
-        Err("Characteristic not found".into())
     }
 
-    pub async fn get_device_info(device: &Device) -> Result<BTDeviceInfo> {
-        let service = Self::lookup_service(device, DEVICE_INFO_SERVICE).await?;
-        let manufacturer_char = Self::lookup_char(&service, MANUFACTURER_CHAR).await?;
-        let model_char = Self::lookup_char(&service, MODEL_CHAR).await?;
-        let firmware_char = Self::lookup_char(&service, FIRMWARE_CHAR).await?;
-
+    pub async fn get_device_info(link: &BTLinkPtr) -> Result<BTDeviceInfo> {
         Ok(BTDeviceInfo {
-            manufacturer: Self::get_string(&manufacturer_char).await?,
-            model: Self::get_string(&model_char).await?,
-            firmware: Self::get_string(&firmware_char).await?,
+            manufacturer: Self::get_string(link, MANUFACTURER_CHAR).await?,
+            model: Self::get_string(link, MODEL_CHAR).await?,
+            firmware: Self::get_string(link, FIRMWARE_CHAR).await?,
         })
     }
 
-    async fn get_string(char: &Characteristic) -> Result<String> {
-        let data = char.read().await?;
+    pub async fn get_battery_level(link: &BTLinkPtr) -> Result<u8> {
+        // Percentage, as in the standard Battery Service.
+
+        match link.read_char(BATTERY_SERVICE, BATTERY_LEVEL_CHAR).await?.first() {
+            Some(level) if *level <= 100 => Ok(*level),
+            _ => Err("Invalid battery level".into()),
+        }
+    }
+
+    async fn get_string(link: &BTLinkPtr, char_uuid: &Uuid) -> Result<String> {
+        let data = link.read_char(DEVICE_INFO_SERVICE, char_uuid).await?;
 
         match String::from_utf8(data) {
             Ok(s) => Ok(s),
@@ -154,3 +249,49 @@ impl BTUtil {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bluer::UuidExt;
+    use std::collections::{HashMap, HashSet};
+    use uuid::Uuid;
+
+    use super::{AdvData, AdvPattern};
+
+    #[test]
+    fn adv_pattern() {
+        let pattern = AdvPattern::manufacturer(0x020e, &[0x01, 0x10, 0x02], &[0xff, 0xf0]);
+        let monitor_patterns = pattern.get_monitor_patterns();
+        assert_eq!(monitor_patterns.len(), 1);
+        assert_eq!(monitor_patterns[0].content, vec![0x0e, 0x02, 0x01]);
+
+        let data = |company_id: u16, data: &[u8]| AdvData {
+            manufacturer_data: HashMap::from([(company_id, data.to_vec())]),
+            ..Default::default()
+        };
+        assert!(pattern.is_match(&data(0x020e, &[0x01, 0x1f, 0x02, 0x00])));
+        assert!(!pattern.is_match(&data(0x020e, &[0x01, 0x20, 0x02])));
+        assert!(!pattern.is_match(&data(0x020e, &[0x01, 0x10, 0x03])));
+        assert!(!pattern.is_match(&data(0x020e, &[0x01, 0x10])));
+        assert!(!pattern.is_match(&data(0x03ff, &[0x01, 0x10, 0x02])));
+
+        let pattern = AdvPattern::service(0x181d);
+        assert_eq!(pattern.get_monitor_patterns().len(), 2);
+        assert!(pattern.is_match(&AdvData {
+            services: HashSet::from([Uuid::from_u16(0x181d)]),
+            ..Default::default()
+        }));
+        assert!(!pattern.is_match(&AdvData::default()));
+
+        let pattern = AdvPattern::service_data(0x181b);
+        assert_eq!(pattern.get_monitor_patterns()[0].content, vec![0x1b, 0x18]);
+        assert!(pattern.is_match(&AdvData {
+            service_data: HashMap::from([(Uuid::from_u16(0x181b), vec![0x02])]),
+            ..Default::default()
+        }));
+        assert!(!pattern.is_match(&AdvData {
+            services: HashSet::from([Uuid::from_u16(0x181b)]),
+            ..Default::default()
+        }));
+    }
+}
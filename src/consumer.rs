@@ -0,0 +1,111 @@
+//! # Record consumers
+//!
+//! For applications embedding phd: records are handed to the registered
+//! consumers after they are written to the DB (at the same point as the
+//! on_records hook), either through a callback or an mpsc channel. Use
+//! Db::discard() to process records in-process only.
+
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::db::{DbRecord, DbRecords};
+
+#[derive(Clone)]
+pub struct RecordBatch { // Records of a device, going to the same measurement.
+    pub id: String,
+    pub meas: String,
+    pub records: DbRecords,
+}
+
+enum Consumer {
+    Callback(Box<dyn Fn(&RecordBatch) + Send + Sync>),
+    Channel(mpsc::Sender<RecordBatch>),
+}
+
+#[derive(Default)]
+pub struct Consumers {
+    consumers: Mutex<Vec<Consumer>>,
+}
+
+pub type ConsumersPtr = Arc<Consumers>;
+
+impl Consumers {
+    pub fn add_callback<F>(&self, callback: F) where F: Fn(&RecordBatch) + Send + Sync + 'static {
+        // Called from the device task, keep it short. Must not register consumers.
+
+        self.consumers.lock().unwrap().push(Consumer::Callback(Box::new(callback)));
+    }
+
+    pub fn subscribe(&self, buffer: usize) -> mpsc::Receiver<RecordBatch> {
+        // Batches are dropped (and logged) while the channel is full, the channel is removed once the receiver is dropped.
+
+        let (tx, rx) = mpsc::channel(buffer);
+        self.consumers.lock().unwrap().push(Consumer::Channel(tx));
+        rx
+    }
+
+    pub fn on_records(&self, id: &str, meas: &str, records: &[DbRecord]) {
+        let mut consumers = self.consumers.lock().unwrap();
+
+        if consumers.is_empty() {
+            return;
+        }
+
+        let batch = RecordBatch {
+            id: String::from(id),
+            meas: String::from(meas),
+            records: records.to_vec(),
+        };
+
+        consumers.retain(|consumer| match consumer {
+            Consumer::Callback(callback) => {
+                callback(&batch);
+                true
+            },
+            Consumer::Channel(tx) => match tx.try_send(batch.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    eprintln!("{}: record consumer is lagging, dropped {} records", id, batch.records.len());
+                    true
+                },
+                Err(TrySendError::Closed(_)) => false,
+            },
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::db::DbRecord;
+    use super::Consumers;
+
+    #[test]
+    fn delivery() {
+        let consumers = Consumers::default();
+        let count = Arc::new(AtomicUsize::new(0));
+
+        {
+            let count = Arc::clone(&count);
+            consumers.add_callback(move |batch| { count.fetch_add(batch.records.len(), Ordering::Relaxed); });
+        }
+
+        let mut rx = consumers.subscribe(1);
+        let records = vec![DbRecord::new(1), DbRecord::new(2)];
+
+        consumers.on_records("dev", "meas", &records);
+        consumers.on_records("dev", "meas", &records); // Channel is full, dropped.
+
+        assert_eq!(count.load(Ordering::Relaxed), 4);
+
+        let batch = rx.try_recv().unwrap();
+        assert_eq!((batch.id.as_str(), batch.meas.as_str(), batch.records.len()), ("dev", "meas", 2));
+        assert!(rx.try_recv().is_err());
+
+        drop(rx);
+        consumers.on_records("dev", "meas", &records);
+        assert_eq!(consumers.consumers.lock().unwrap().len(), 1);
+    }
+}
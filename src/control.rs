@@ -0,0 +1,148 @@
+//! # Device control
+//!
+//! Lets the APIs act on running device tasks: a triggered fetch cuts the
+//! device's sleep short and connects without waiting for the unit to
+//! advertise. A trigger arriving during a fetch is kept for the next one.
+//! A triggered measurement is the same, except the device task starts a
+//! measurement on the unit instead of fetching (streaming devices just
+//! connect, the stream delivers the reading).
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Notify;
+
+#[derive(Default)]
+pub struct Trigger {
+    pending: AtomicBool,
+    measure: AtomicBool, // The pending trigger is for a measurement.
+    measurable: AtomicBool, // Set by the device task if the driver can start a measurement.
+    notify: Notify,
+}
+
+pub type TriggerPtr = Arc<Trigger>;
+
+#[derive(Default)]
+pub struct Control {
+    triggers: Mutex<HashMap<String, TriggerPtr>>,
+}
+
+pub type ControlPtr = Arc<Control>;
+
+#[derive(Debug, PartialEq)]
+pub enum MeasureError {
+    NoDevice,
+    NotSupported,
+}
+
+impl Trigger {
+    pub fn fire(&self) {
+        self.pending.store(true, Ordering::Relaxed);
+        self.notify.notify_waiters();
+    }
+
+    pub async fn wait(&self) {
+        // Returns once a trigger is pending, without taking it.
+
+        loop {
+            let notified = self.notify.notified(); // Registered before checking, so a fire() in between isn't missed.
+
+            if self.pending.load(Ordering::Relaxed) {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+
+    pub fn take(&self) -> bool {
+        self.pending.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn set_measurable(&self, measurable: bool) {
+        self.measurable.store(measurable, Ordering::Relaxed);
+    }
+
+    pub fn take_measure(&self) -> bool {
+        self.measure.swap(false, Ordering::Relaxed)
+    }
+}
+
+impl Control {
+    pub fn register(&self, id: &str) -> TriggerPtr {
+        // Called by the device task when (re)started.
+
+        let trigger = TriggerPtr::default();
+        self.triggers.lock().unwrap().insert(String::from(id), TriggerPtr::clone(&trigger));
+        trigger
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.triggers.lock().unwrap().remove(id);
+    }
+
+    pub fn trigger_fetch(&self, id: &str) -> Result<(), String> {
+        match self.triggers.lock().unwrap().get(id) {
+            Some(trigger) => {
+                trigger.fire();
+                Ok(())
+            },
+            None => Err(format!("No such device: {}", id)),
+        }
+    }
+
+    pub fn trigger_measure(&self, id: &str) -> Result<(), MeasureError> {
+        match self.triggers.lock().unwrap().get(id) {
+            Some(trigger) if trigger.measurable.load(Ordering::Relaxed) => {
+                trigger.measure.store(true, Ordering::Relaxed);
+                trigger.fire();
+                Ok(())
+            },
+            Some(_) => Err(MeasureError::NotSupported),
+            None => Err(MeasureError::NoDevice),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::{self, Duration};
+
+    use super::{Control, MeasureError};
+
+    #[tokio::test]
+    async fn trigger() {
+        let control = Control::default();
+        let trigger = control.register("dev");
+
+        assert!(control.trigger_fetch("other").is_err());
+        assert!(!trigger.take());
+
+        let waiter = tokio::spawn({
+            let trigger = trigger.clone();
+            async move { trigger.wait().await }
+        });
+
+        control.trigger_fetch("dev").unwrap();
+        time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+
+        assert!(trigger.take());
+        assert!(!trigger.take());
+    }
+
+    #[test]
+    fn measure() {
+        let control = Control::default();
+        let trigger = control.register("dev");
+
+        assert_eq!(control.trigger_measure("other"), Err(MeasureError::NoDevice));
+        assert_eq!(control.trigger_measure("dev"), Err(MeasureError::NotSupported));
+        assert!(!trigger.take());
+
+        trigger.set_measurable(true);
+        control.trigger_measure("dev").unwrap();
+        assert!(trigger.take()); // Connects directly, like a triggered fetch.
+        assert!(trigger.take_measure());
+        assert!(!trigger.take_measure());
+    }
+}
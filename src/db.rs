@@ -1,103 +1,778 @@
-use reqwest::Client;
-use serde::Deserialize;
-use std::collections::HashMap;
-use std::sync::Arc;
+use chrono::DateTime;
+use reqwest::{Client, RequestBuilder, Response, StatusCode, Url};
+use reqwest::header::RETRY_AFTER;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+use crate::naming::NamingProfile;
+use crate::timeutil::TimeUtil;
+
+const RATE_LIMIT_PAUSE: u64 = 60; // [s] If the DB doesn't tell how long to wait.
+const MAX_RATE_LIMIT_PAUSE: u64 = 3600; // [s]
+const ENDPOINT_ERROR_PAUSE: u64 = 60; // [s] Bad token, org or bucket: retried until fixed, without hammering the DB.
+
+const TAG_SPECIAL: &[char] = &[',', '=', ' ']; // Escaped in tag keys, tag values and field keys.
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct DbConfig {
     url: String,
     token: String,
-    org: String,
-    bucket: String,
+    #[serde(default)]
+    api: DbApi,
+    org: Option<String>, // v2 only.
+    bucket: Option<String>, // v2 only.
+    database: Option<String>, // v3 only.
+    #[serde(default)]
+    no_sync: bool, // v3 only: acknowledge before the write is persisted.
+    #[serde(default)]
+    precision: DbPrecision,
+    float_digits: Option<usize>, // Float fields are written with at most this many decimals.
+    #[serde(default)]
+    naming: NamingProfile, // Field names as written.
+    #[serde(default)]
+    routes: Vec<DbRouteConfig>,
+    #[serde(default)]
+    exclude: Vec<DbFilter>, // Records not taken by a route and matching any of these are not sent at all.
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DbApi {
+    #[default]
+    V2, // InfluxDB 2 (and v1 with compatibility API): /api/v2/write with org and bucket.
+    V3, // InfluxDB 3: /api/v3/write_lp with database.
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DbPrecision { // Timestamps are truncated to this precision when written.
+    S,
+    Ms,
+    Us,
+    #[default]
+    Ns,
+}
+
+impl DbPrecision {
+    fn get_name(&self, api: DbApi) -> &'static str { // As in precision query parameter.
+        match (api, self) {
+            (DbApi::V2, DbPrecision::S) => "s",
+            (DbApi::V2, DbPrecision::Ms) => "ms",
+            (DbApi::V2, DbPrecision::Us) => "us",
+            (DbApi::V2, DbPrecision::Ns) => "ns",
+            (DbApi::V3, DbPrecision::S) => "second",
+            (DbApi::V3, DbPrecision::Ms) => "millisecond",
+            (DbApi::V3, DbPrecision::Us) => "microsecond",
+            (DbApi::V3, DbPrecision::Ns) => "nanosecond",
+        }
+    }
+
+    fn get_divisor(&self) -> i64 { // Number of ns in one unit.
+        match self {
+            DbPrecision::S => 1_000_000_000,
+            DbPrecision::Ms => 1_000_000,
+            DbPrecision::Us => 1_000,
+            DbPrecision::Ns => 1,
+        }
+    }
 }
 
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DbRouteConfig { // Records having all the tags (and in one of meas, if set) are sent to this target, first match wins.
+    #[serde(default)]
+    tags: HashMap<String, String>,
+    #[serde(default)]
+    meas: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<DbFilter>, // Records matching any of these skip this route.
+    url: Option<String>, // Unset settings are inherited from the default target.
+    token: Option<String>,
+    org: Option<String>,
+    bucket: Option<String>,
+    database: Option<String>,
+    precision: Option<DbPrecision>,
+    naming: Option<NamingProfile>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DbFilter { // Matches records having all the tags and in one of meas (if set).
+    #[serde(default)]
+    tags: HashMap<String, String>,
+    #[serde(default)]
+    meas: Vec<String>,
+}
+
+impl DbFilter {
+    fn is_match(&self, meas: &str, record: &DbRecord) -> bool {
+        (self.meas.is_empty() || self.meas.iter().any(|filter_meas| filter_meas == meas)) &&
+            self.tags.iter().all(|(key, value)| record.tags.get(key) == Some(value))
+    }
+}
+
+struct DbTarget {
+    url: String,
+    token: String,
+    api: DbTargetApi,
+    precision: DbPrecision,
+    naming: NamingProfile,
+}
+
+enum DbTargetApi {
+    V2 { org: String, bucket: String },
+    V3 { database: String, no_sync: bool },
+}
+
+impl DbTarget {
+    fn get_name(&self) -> String {
+        match &self.api {
+            DbTargetApi::V2 { org, bucket } => format!("{} (org {}, bucket {})", self.url, org, bucket),
+            DbTargetApi::V3 { database, .. } => format!("{} (database {})", self.url, database),
+        }
+    }
+}
+
+struct DbRoute {
+    filter: DbFilter,
+    exclude: Vec<DbFilter>,
+    target: DbTarget,
+}
+
+#[derive(Clone)]
 pub struct DbRecord {
     ts: i64, // Timestamp [ns]
+    meas: Option<String>, // Overrides the device's measurement.
     tags: HashMap<String, String>,
     fields: HashMap<String, DbFieldValue>,
+    id: Option<String>, // Idempotency key for the sinks other than the DB, see set_ids().
 }
 
 pub type DbRecords = Vec<DbRecord>;
 
+#[derive(Clone)]
 pub enum DbFieldValue {
     Float(f64),
     Integer(i64),
     Bool(bool),
+    String(String),
+}
+
+pub enum DbError {
+    Temporary(String), // Worth retrying, e.g. network error, 5xx or rate limit.
+    Permanent(String), // The DB refused the records (400, 413, 422), it would refuse them again.
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DbError::Temporary(e) | DbError::Permanent(e) => formatter.write_str(e),
+        }
+    }
+}
+
+impl From<DbError> for String {
+    fn from(e: DbError) -> Self {
+        e.to_string()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DbFieldType {
+    Float,
+    Integer,
+    Bool,
+    String,
+}
+
+impl fmt::Display for DbFieldType {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            DbFieldType::Float => "float",
+            DbFieldType::Integer => "integer",
+            DbFieldType::Bool => "bool",
+            DbFieldType::String => "string",
+        };
+        formatter.write_str(s)
+    }
+}
+
+impl DbFieldValue {
+    pub fn get_type(&self) -> DbFieldType {
+        match self {
+            DbFieldValue::Float(_) => DbFieldType::Float,
+            DbFieldValue::Integer(_) => DbFieldType::Integer,
+            DbFieldValue::Bool(_) => DbFieldType::Bool,
+            DbFieldValue::String(_) => DbFieldType::String,
+        }
+    }
+
+    pub fn cast(self, to: DbFieldType) -> Option<Self> {
+        // Returns None if the value can't be represented.
+
+        match (self, to) {
+            (DbFieldValue::Float(value), DbFieldType::Integer) => if value.is_finite() { Some(DbFieldValue::Integer(value.round() as i64)) } else { None },
+            (DbFieldValue::Integer(value), DbFieldType::Float) => Some(DbFieldValue::Float(value as f64)),
+            (DbFieldValue::Bool(value), DbFieldType::Integer) => Some(DbFieldValue::Integer(value.into())),
+            (DbFieldValue::Bool(value), DbFieldType::Float) => Some(DbFieldValue::Float(if value { 1.0 } else { 0.0 })),
+            (DbFieldValue::Float(value), DbFieldType::String) => Some(DbFieldValue::String(format!("{}", value))),
+            (DbFieldValue::Integer(value), DbFieldType::String) => Some(DbFieldValue::String(format!("{}", value))),
+            (DbFieldValue::Bool(value), DbFieldType::String) => Some(DbFieldValue::String(format!("{}", value))),
+            (DbFieldValue::String(value), DbFieldType::Float) => value.parse().ok().map(DbFieldValue::Float),
+            (DbFieldValue::String(value), DbFieldType::Integer) => value.parse().ok().map(DbFieldValue::Integer),
+            (DbFieldValue::String(value), DbFieldType::Bool) => value.parse().ok().map(DbFieldValue::Bool),
+            (value, to) if value.get_type() == to => Some(value),
+            _ => None,
+        }
+    }
 }
 
 impl DbRecord {
     pub fn new(ts: i64) -> Self {
         Self {
             ts,
+            meas: None,
             tags: HashMap::new(),
-            fields: HashMap::new()
+            fields: HashMap::new(),
+            id: None,
+        }
+    }
+
+    pub fn get_id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    pub fn set_ids(device_id: &str, meas: &str, records: &mut [DbRecord]) {
+        // Derived from the device, measurement, timestamp, tags (as InfluxDB identifies a point) and the record's position among
+        // the ones with the same timestamp and tags, so a record read again (a replayed batch, or the unit's memory fetched
+        // again) gets the same id, downstream consumers can drop the duplicates.
+
+        let mut seqs: HashMap<(i64, String), u32> = HashMap::new();
+
+        for record in records {
+            let mut tags: Vec<_> = record.tags.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+            tags.sort();
+            let tags = tags.join(",");
+
+            let seq = seqs.entry((record.ts, tags.clone())).or_default();
+            let digest = Sha256::digest(format!("{}\n{}\n{}\n{}\n{}", device_id, meas, record.ts, tags, seq));
+
+            record.id = Some(hex::encode(&digest[..16]));
+            *seq += 1;
         }
     }
 
+    pub fn get_ts(&self) -> i64 {
+        self.ts
+    }
+
+    pub fn set_ts(&mut self, ts: i64) {
+        self.ts = ts;
+    }
+
+    pub fn set_meas(&mut self, meas: &str) {
+        self.meas = Some(String::from(meas));
+    }
+
+    pub fn get_meas(&self) -> Option<&str> {
+        self.meas.as_deref()
+    }
+
     pub fn add_tag(&mut self, key: &str, value: &str) {
         self.tags.insert(String::from(key), String::from(value));
     }
 
+    pub fn get_tag(&self, key: &str) -> Option<&str> {
+        self.tags.get(key).map(|value| value.as_str())
+    }
+
+    pub fn get_tags(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.tags.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn get_fields(&self) -> impl Iterator<Item = (&str, &DbFieldValue)> {
+        self.fields.iter().map(|(key, value)| (key.as_str(), value))
+    }
+
     pub fn add_field(&mut self, key: &str, value: DbFieldValue) {
         self.fields.insert(String::from(key), value);
     }
+
+    pub fn get_field(&self, key: &str) -> Option<&DbFieldValue> {
+        self.fields.get(key)
+    }
+
+    pub fn remove_field(&mut self, key: &str) -> Option<DbFieldValue> {
+        self.fields.remove(key)
+    }
+
+    pub fn get_field_types(&self) -> impl Iterator<Item = (&str, DbFieldType)> {
+        self.fields.iter().map(|(key, value)| (key.as_str(), value.get_type()))
+    }
+
+    pub fn clear_fields(&mut self) {
+        self.fields.clear();
+    }
+
+    pub fn has_fields(&self) -> bool {
+        !self.fields.is_empty()
+    }
+
+    pub fn dedup(records: &mut DbRecords) -> usize {
+        // Drop records which are identical to an earlier one, returns the number of dropped records.
+
+        let len = records.len();
+        let mut keys = HashSet::new();
+        records.retain(|record| keys.insert(record.get_key()));
+
+        len - records.len()
+    }
+
+    fn get_key(&self) -> u64 { // Hash of ts, meas, tags and fields.
+        let mut hasher = DefaultHasher::new();
+        self.ts.hash(&mut hasher);
+        self.meas.hash(&mut hasher);
+
+        let mut tags: Vec<_> = self.tags.iter().collect();
+        tags.sort();
+        tags.hash(&mut hasher);
+
+        let mut fields: Vec<_> = self.fields.iter().collect();
+        fields.sort_by_key(|(key, _)| *key);
+
+        for (key, value) in fields {
+            key.hash(&mut hasher);
+
+            match value {
+                DbFieldValue::Float(value) => value.to_bits().hash(&mut hasher),
+                DbFieldValue::Integer(value) => value.hash(&mut hasher),
+                DbFieldValue::Bool(value) => value.hash(&mut hasher),
+                DbFieldValue::String(value) => value.hash(&mut hasher),
+            }
+        }
+
+        hasher.finish()
+    }
 }
 
 pub struct Db {
-    config: DbConfig,
+    target: Option<DbTarget>, // Default target, records are discarded without one.
+    routes: Vec<DbRoute>,
+    exclude: Vec<DbFilter>, // Not sent to the default target.
+    float_digits: Option<usize>,
+    paused_until: Mutex<Option<(Instant, String)>>, // Rate limited (or refused) by the DB, writes fail without a request until then, with the reason.
 }
 
 pub type DbPtr = Arc<Db>;
 
 impl Db {
-    pub fn new(config: DbConfig) -> Self {
+    pub fn new(config: DbConfig) -> Result<Self, String> {
+        for url in iter::once(&config.url).chain(config.routes.iter().filter_map(|route| route.url.as_ref())) {
+            Self::check_url(url)?;
+        }
+
+        let routes = config.routes.iter().map(|route| Ok(DbRoute {
+            filter: DbFilter {
+                tags: route.tags.clone(),
+                meas: route.meas.clone(),
+            },
+            exclude: route.exclude.clone(),
+            target: DbTarget {
+                url: route.url.clone().unwrap_or_else(|| config.url.clone()),
+                token: route.token.clone().unwrap_or_else(|| config.token.clone()),
+                api: Self::get_target_api(&config, route.org.as_ref().or(config.org.as_ref()), route.bucket.as_ref().or(config.bucket.as_ref()), route.database.as_ref().or(config.database.as_ref()))?,
+                precision: route.precision.unwrap_or(config.precision),
+                naming: route.naming.unwrap_or(config.naming),
+            },
+        })).collect::<Result<_, String>>()?;
+
+        Ok(Self {
+            target: Some(DbTarget {
+                url: config.url.clone(),
+                token: config.token.clone(),
+                api: Self::get_target_api(&config, config.org.as_ref(), config.bucket.as_ref(), config.database.as_ref())?,
+                precision: config.precision,
+                naming: config.naming,
+            }),
+            routes,
+            exclude: config.exclude.clone(),
+            float_digits: config.float_digits,
+            paused_until: Mutex::new(None),
+        })
+    }
+
+    pub fn discard() -> Self {
+        // Writes nothing, for embedding with record consumers only (see consumer.rs).
+
         Self {
-            config,
+            target: None,
+            routes: Vec::new(),
+            exclude: Vec::new(),
+            float_digits: None,
+            paused_until: Mutex::new(None),
+        }
+    }
+
+    fn check_url(url: &str) -> Result<(), String> {
+        match Url::parse(url) {
+            Ok(parsed) if parsed.has_host() => Ok(()),
+            _ => Err(format!("Invalid DB url {} (IPv6 addresses need brackets, e.g. http://[::1]:8086)", url)),
+        }
+    }
+
+    fn get_target_api(config: &DbConfig, org: Option<&String>, bucket: Option<&String>, database: Option<&String>) -> Result<DbTargetApi, String> {
+        match config.api {
+            DbApi::V2 => match (org, bucket, database) {
+                (Some(org), Some(bucket), None) => Ok(DbTargetApi::V2 { org: org.clone(), bucket: bucket.clone() }),
+                _ => Err(String::from("DB api v2 needs org and bucket (and no database)")),
+            },
+            DbApi::V3 => match (org, bucket, database) {
+                (None, None, Some(database)) => Ok(DbTargetApi::V3 { database: database.clone(), no_sync: config.no_sync }),
+                _ => Err(String::from("DB api v3 needs database (and no org/bucket)")),
+            },
         }
     }
 
-    pub async fn send(&self, meas: &str, records: &[DbRecord]) -> Result<(), String> {
+    pub async fn send(&self, meas: &str, records: &[DbRecord]) -> Result<(), DbError> {
         assert!(!records.is_empty());
 
-        // Construct body.
+        if let Some(pause) = self.get_pause() {
+            let reason = self.paused_until.lock().unwrap().as_ref().map(|(_, reason)| reason.clone()).unwrap_or_default();
+            return Err(DbError::Temporary(format!("{}, uploads are paused for {}s", reason, pause.as_secs())));
+        }
+
+        // Split records by target, keep the order of targets stable.
+
+        let mut groups: Vec<(&DbTarget, Vec<&DbRecord>)> = Vec::new();
+
+        for record in records {
+            let target = match self.get_target(meas, record) {
+                Some(target) => target,
+                None => continue, // Excluded.
+            };
+
+            match groups.iter_mut().find(|(group_target, _)| ptr::eq(*group_target, target)) {
+                Some((_, group)) => group.push(record),
+                None => groups.push((target, vec![record])),
+            }
+        }
+
+        for (target, group) in groups {
+            self.write(target, meas, &group).await?;
+        }
+
+        Ok(())
+    }
+
+    fn get_target(&self, meas: &str, record: &DbRecord) -> Option<&DbTarget> {
+        let route = self.routes.iter()
+            .find(|route| route.filter.is_match(meas, record) && !route.exclude.iter().any(|filter| filter.is_match(meas, record)));
+
+        match route {
+            Some(route) => Some(&route.target),
+            None if self.exclude.iter().any(|filter| filter.is_match(meas, record)) => None,
+            None => self.target.as_ref(),
+        }
+    }
+
+    pub fn get_pause(&self) -> Option<Duration> {
+        // Time left until writes are allowed again, if the DB asked us to slow down (or refused the token, org or bucket).
+
+        let paused_until = self.paused_until.lock().unwrap().as_ref()?.0;
+        let pause = paused_until.saturating_duration_since(Instant::now());
+
+        if pause.is_zero() { None } else { Some(pause) }
+    }
+
+    async fn write(&self, target: &DbTarget, meas: &str, records: &[&DbRecord]) -> Result<(), DbError> {
+        let body = self.get_body(target, meas, records);
+
+        match Self::get_request(target)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .header("Accept", "application/json")
+            .body(body)
+            .send()
+            .await {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS || response.status() == StatusCode::SERVICE_UNAVAILABLE => {
+                let pause = Self::get_retry_after(&response).unwrap_or(Duration::from_secs(RATE_LIMIT_PAUSE)).min(Duration::from_secs(MAX_RATE_LIMIT_PAUSE));
+                let reason = format!("DB rate limit ({})", response.status());
+                *self.paused_until.lock().unwrap() = Some((Instant::now() + pause, reason.clone()));
+
+                Err(DbError::Temporary(format!("{}, pausing uploads for {}s", reason, pause.as_secs())))
+            },
+            Ok(response) if [StatusCode::UNAUTHORIZED, StatusCode::FORBIDDEN, StatusCode::NOT_FOUND].contains(&response.status()) => {
+                // E.g. expired token, wrong org or missing bucket: not the records' fault, keep them until the DB is fixed.
+
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+                let reason = format!("DB refused the write: {} {}", status, body.trim());
+                *self.paused_until.lock().unwrap() = Some((Instant::now() + Duration::from_secs(ENDPOINT_ERROR_PAUSE), reason.clone()));
+
+                Err(DbError::Temporary(format!("{}, pausing uploads for {}s", reason, ENDPOINT_ERROR_PAUSE)))
+            },
+            Ok(response) if [StatusCode::BAD_REQUEST, StatusCode::PAYLOAD_TOO_LARGE, StatusCode::UNPROCESSABLE_ENTITY].contains(&response.status()) => {
+                // E.g. malformed line protocol or a field type conflict: sending the same batch again won't help.
+
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+
+                Err(DbError::Permanent(format!("DB refused the records: {} {}", status, body.trim())))
+            },
+            Ok(response) => Err(DbError::Temporary(format!("DB error: {}", response.status()))),
+            Err(e) => Err(DbError::Temporary(format!("DB error: {}", e))),
+        }
+    }
+
+    pub async fn check(&self) -> Vec<(String, Result<(), String>)> {
+        // An empty write to each target: checks that it's reachable and the token may write to the bucket/database, without
+        // writing anything. Some DBs refuse the empty body (400), but only after checking the rest.
+
+        let mut targets: Vec<&DbTarget> = Vec::new();
+
+        for target in self.target.iter().chain(self.routes.iter().map(|route| &route.target)) {
+            if !targets.iter().any(|known| known.get_name() == target.get_name() && known.token == target.token) {
+                targets.push(target);
+            }
+        }
+
+        let mut results = Vec::new();
+
+        for target in targets {
+            let result = match Self::get_request(target).body("").send().await {
+                Ok(response) if response.status().is_success() || response.status() == StatusCode::BAD_REQUEST => Ok(()),
+                Ok(response) if response.status() == StatusCode::UNAUTHORIZED || response.status() == StatusCode::FORBIDDEN => Err(format!("DB refused the token: {}", response.status())),
+                Ok(response) => Err(format!("DB error: {}", response.status())),
+                Err(e) => Err(format!("DB error: {}", e)),
+            };
+
+            results.push((target.get_name(), result));
+        }
+
+        results
+    }
+
+    fn get_request(target: &DbTarget) -> RequestBuilder {
+        let client = Client::new(); // Fresh client (and connection) per write, so the host name is resolved again if the DB moved.
+
+        match &target.api {
+            DbTargetApi::V2 { org, bucket } => client.post(format!("{}/api/v2/write", target.url))
+                .query(&[
+                    ("org", org.as_str()),
+                    ("bucket", bucket.as_str()),
+                    ("precision", target.precision.get_name(DbApi::V2)),
+                ])
+                .header("Authorization", format!("Token {}", target.token)),
+            DbTargetApi::V3 { database, no_sync } => client.post(format!("{}/api/v3/write_lp", target.url))
+                .query(&[
+                    ("db", database.as_str()),
+                    ("precision", target.precision.get_name(DbApi::V3)),
+                    ("no_sync", if *no_sync { "true" } else { "false" }),
+                ])
+                .header("Authorization", format!("Bearer {}", target.token)),
+        }
+    }
+
+    fn get_body(&self, target: &DbTarget, meas: &str, records: &[&DbRecord]) -> String {
+        // Line protocol, tag values are free text (e.g. firmware versions, person names) so everything is escaped.
 
-        let body = records.iter().map(|record| { // TODO: escape tags and fields
+        records.iter().map(|record| {
             assert!(!record.fields.is_empty());
 
             format!("{}{} {} {}\n",
-                meas,
-                record.tags.iter().map(|(key, value)| format!(",{}={}", key, value)).collect::<Vec<String>>().join(""),
+                Self::escape(meas, &[',', ' ']),
+                record.tags.iter().map(|(key, value)| format!(",{}={}", Self::escape(key, TAG_SPECIAL), Self::escape(value, TAG_SPECIAL))).collect::<Vec<String>>().join(""),
                 record.fields.iter().map(|(key, value)| format!("{}={}",
-                    key,
+                    Self::escape(target.naming.get_name(key), TAG_SPECIAL),
                     match value {
-                        DbFieldValue::Float(value) => format!("{}", value),
+                        DbFieldValue::Float(value) => Self::format_float(*value, self.float_digits),
                         DbFieldValue::Integer(value) => format!("{}", value),
                         DbFieldValue::Bool(value) => String::from(if *value { "true" } else { "false" }),
+                        DbFieldValue::String(value) => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
                     }
                 )).collect::<Vec<String>>().join(","),
-                record.ts
+                record.ts.div_euclid(target.precision.get_divisor()) // Truncate towards past, also for pre-1970 timestamps.
             )
-        }).collect::<Vec<String>>().join("");
+        }).collect::<Vec<String>>().join("")
+    }
 
-        // Send request.
+    fn escape(s: &str, special: &[char]) -> String {
+        let mut escaped = String::with_capacity(s.len());
 
-        let client = Client::new();
+        for c in s.chars() {
+            if special.contains(&c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
 
-        match client.post(format!("{}/api/v2/write", self.config.url))
-            .query(&[
-                ("org", self.config.org.as_ref()),
-                ("bucket", self.config.bucket.as_ref()),
-                ("precision", "ns"),
-            ])
-            .header("Authorization", format!("Token {}", self.config.token))
-            .header("Content-Type", "text/plain; charset=utf-8")
-            .header("Accept", "application/json")
-            .body(body)
-            .send()
-            .await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(format!("DB error: {}", e)),
+        escaped
+    }
+
+    fn format_float(value: f64, digits: Option<usize>) -> String {
+        // Rust formatting doesn't depend on the locale: always '.' and no grouping, as line protocol needs.
+
+        match digits {
+            Some(digits) => {
+                let s = format!("{:.*}", digits, value);
+
+                if s.contains('.') { String::from(s.trim_end_matches('0').trim_end_matches('.')) } else { s }
+            },
+            None => format!("{}", value), // Shortest representation which reads back as the same value.
+        }
+    }
+
+    fn get_retry_after(response: &Response) -> Option<Duration> {
+        // Either delay in seconds or HTTP date.
+
+        let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+        if let Ok(secs) = value.parse::<u64>() {
+            return Some(Duration::from_secs(secs));
+        }
+
+        let date = DateTime::parse_from_rfc2822(value).ok()?;
+        let secs = date.timestamp() - TimeUtil::get_current_unix();
+
+        Some(Duration::from_secs(secs.max(0) as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{Config, File, FileFormat};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::{Db, DbConfig, DbError, DbFieldValue, DbRecord};
+
+    #[test]
+    fn ids() {
+        let get_ids = |device_id: &str, ts: &[i64]| {
+            let mut records: Vec<DbRecord> = ts.iter().map(|ts| DbRecord::new(*ts)).collect();
+            DbRecord::set_ids(device_id, "meas", &mut records);
+            records.iter().map(|record| String::from(record.get_id().unwrap())).collect::<Vec<String>>()
+        };
+
+        let ids = get_ids("dev", &[1, 2, 2]);
+        assert_eq!(ids[0].len(), 32);
+        assert_ne!(ids[1], ids[2]); // Same timestamp, e.g. two users.
+
+        assert_eq!(get_ids("dev", &[0, 1, 2, 2])[1..], ids); // Read again, along with an older record.
+        assert_ne!(get_ids("other", &[1, 2, 2]), ids);
+    }
+
+    #[test]
+    fn ids_users() {
+        // Two users' records with the same timestamp, uploaded together or in separate batches (e.g. one held first).
+
+        let record = |user: &str| {
+            let mut record = DbRecord::new(1);
+            record.add_tag("user", user);
+            record.add_tag("device_id", "dev");
+            record
+        };
+        let get_ids = |mut records: Vec<DbRecord>| {
+            DbRecord::set_ids("dev", "meas", &mut records);
+            records.iter().map(|record| String::from(record.get_id().unwrap())).collect::<Vec<String>>()
+        };
+
+        let ids = get_ids(vec![record("1"), record("2")]);
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(get_ids(vec![record("2")]), ids[1..]);
+        assert_ne!(get_ids(vec![record("1")]), get_ids(vec![record("2")]));
+    }
+
+    #[test]
+    fn escape() {
+        let config: DbConfig = Config::builder()
+            .add_source(File::from_str("url: http://localhost:8086\ntoken: t\norg: o\nbucket: b", FileFormat::Yaml))
+            .build()
+            .and_then(|config| config.try_deserialize())
+            .unwrap();
+        let db = Db::new(config).unwrap();
+
+        let get_body = |key: &str, value: &str| {
+            let mut record = DbRecord::new(1_000_000_000);
+            record.add_tag(key, value);
+            record.add_field("sys", DbFieldValue::Integer(120));
+            db.get_body(db.target.as_ref().unwrap(), "bp", &[&record])
+        };
+
+        assert_eq!(get_body("fw", "V1.0 b2,x"), "bp,fw=V1.0\\ b2\\,x sys=120 1000000000\n");
+        assert_eq!(get_body("a b", "c=d"), "bp,a\\ b=c\\=d sys=120 1000000000\n");
+    }
+
+    async fn send(status: Option<&str>) -> (Result<(), DbError>, Db) {
+        // Fake DB answering one write with the given status line, None if nothing is listening.
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        if let Some(status) = status {
+            let response = format!("HTTP/1.1 {}\r\nContent-Length: 5\r\nConnection: close\r\n\r\noops\n", status);
+
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+
+                while !String::from_utf8_lossy(&request).contains("\r\n\r\n") || !request.ends_with(b"\n") {
+                    let len = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..len]);
+                }
+
+                stream.write_all(response.as_bytes()).await.unwrap();
+            });
+        } else {
+            drop(listener);
         }
+
+        let config: DbConfig = Config::builder()
+            .add_source(File::from_str(&format!("url: http://{}\ntoken: t\norg: o\nbucket: b", addr), FileFormat::Yaml))
+            .build()
+            .and_then(|config| config.try_deserialize())
+            .unwrap();
+        let db = Db::new(config).unwrap();
+
+        let mut record = DbRecord::new(0);
+        record.add_field("sys", DbFieldValue::Integer(120));
+
+        (db.send("bp", &[record]).await, db)
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        assert!(send(Some("204 No Content")).await.0.is_ok());
+
+        for status in ["400 Bad Request", "413 Payload Too Large", "422 Unprocessable Entity"] {
+            assert!(matches!(send(Some(status)).await.0, Err(DbError::Permanent(e)) if e.contains("oops")), "{}", status);
+        }
+
+        for status in ["408 Request Timeout", "500 Internal Server Error", "502 Bad Gateway"] {
+            assert!(matches!(send(Some(status)).await.0, Err(DbError::Temporary(_))), "{}", status);
+        }
+
+        for status in ["401 Unauthorized", "403 Forbidden", "404 Not Found"] { // Endpoint or credentials, not the records.
+            let (result, db) = send(Some(status)).await;
+            assert!(matches!(result, Err(DbError::Temporary(e)) if e.contains("oops")), "{}", status);
+            assert!(db.get_pause().is_some());
+        }
+
+        let (result, db) = send(Some("429 Too Many Requests")).await;
+        assert!(matches!(result, Err(DbError::Temporary(_))));
+        assert!(db.get_pause().is_some());
+
+        assert!(matches!(send(None).await.0, Err(DbError::Temporary(_)))); // Unreachable.
     }
 }
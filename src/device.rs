@@ -1,10 +1,40 @@
+use chrono::{NaiveDate, NaiveTime};
 use serde::Deserialize;
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
 use tokio::time::{self, Duration};
 
-use crate::db::DbPtr;
-use crate::driver::{self, DriverConfig};
+use crate::bluez::BluezBackend;
+use crate::btutil::BTBackendPtr;
+use crate::consumer::ConsumersPtr;
+use crate::control::{ControlPtr, Trigger, TriggerPtr};
+use crate::db::{DbError, DbFieldValue, DbPtr, DbRecord, DbRecords};
+use crate::driver::{self, Driver, DriverConfig, DriverContext, FetchBufferPtr, FetchMeterPtr, PairProgressPtr, Poll};
+use crate::driver::fingerprint::IdentityCheck;
+use crate::gdt::GdtPtr;
+use crate::hooks::HooksPtr;
+use crate::mqtt::MqttPtr;
+use crate::otel::Otel;
+use crate::persons::{self, PersonsPtr};
+use crate::redact::Redact;
+use crate::secrets::Secrets;
+use crate::status::{DeviceState, PairingStatus, StatusPtr};
+use crate::store::{DeviceStats, HeldRecord, HeldState, RecentRecord, StorePtr};
+use crate::template::Template;
+use crate::telemetry::{TelemetryCycle, TelemetryPtr};
+use crate::transform::{Transform, TransformConfig};
+use crate::trend::{Trend, TrendConfig};
+use crate::timeutil::TimeUtil;
 
 const WAIT: u64 = 3; // [s]
+const POLL_SLEEP: u32 = 300; // [s] Between direct connection attempts, if sleep is unset.
+const STATS_MEAS: &str = "phd_stats";
+const HELD_POLL: u64 = 30; // [s] Between checks for assigned held records.
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -12,88 +42,1372 @@ pub struct DeviceConfig {
     id: String,
     driver_config: DriverConfig,
     sleep: Option<u32>,
-    meas: String,
+    #[serde(default)]
+    poll: Poll,
+    meas: Template, // Placeholders are expanded per record from tags, {driver} is the driver name.
+    #[serde(default)]
+    debug_protocol: bool,
+    fetch_timeout: Option<u32>,
+    adv_wait: Option<AdvWaitConfig>,
+    backoff: Option<BackoffConfig>,
+    #[serde(default)]
+    skip_if_connected: bool,
+    window: Option<WindowConfig>,
+    #[serde(default)]
+    write_stats: bool,
+    #[serde(default)]
+    transforms: Vec<TransformConfig>,
+    backfill: Option<BackfillConfig>,
+    trend: Option<TrendConfig>,
+    clock_unset: Option<ClockUnsetConfig>,
+    recent: Option<RecentConfig>,
+    unknown_user: Option<UnknownUser>, // Records of unknown users are written without person tag if unset.
+    #[serde(default = "DeviceConfig::get_default_hold_expiry")]
+    hold_expiry: u32, // [days] Held records not assigned by then are dropped.
+    #[serde(default)]
+    version_tags: bool,
+    identity_check: Option<IdentityCheck>, // Relaxed for drivers whose fingerprints are assumed, strict otherwise.
+    #[serde(default)]
+    stream_buffer: StreamBufferConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BackoffConfig {
+    failures: u32, // Number of consecutive failed fetches before cooling down.
+    cooldown: u32, // [s]
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BackfillConfig { // On the first sync a cutoff is fixed, older records are ignored from then on.
+    max_age: Option<u32>, // [days]
+    #[serde(default)]
+    after_pairing: bool, // Records taken before pairing (or before the first sync, if pairing time is unknown) are ignored.
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClockUnsetConfig { // Records dated before this were taken while the unit's clock was unset (e.g. after a battery change).
+    #[serde(default = "ClockUnsetConfig::get_default_before", deserialize_with = "crate::timeutil::TimeUtil::parse_date")]
+    before: NaiveDate,
+    #[serde(default)]
+    action: ClockUnsetAction,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ClockUnsetAction {
+    #[default]
+    Drop,
+    Tag, // Add clock_unset=true tag.
+    Redate, // Shift them, so the newest one is at the time of the fetch.
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum UnknownUser { // Records of a device shared by user slot, taken by none of the persons.
+    Guest, // Add person=guest tag.
+    Drop,
+    Hold, // Keep them in the store until assigned to a person.
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RecentConfig { // Last records written are kept for the status API.
+    count: usize,
+    #[serde(default)]
+    persist: bool, // Also keep them in the store, so they survive restarts.
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StreamBufferConfig { // Record batches of a streaming driver kept in memory while uploading, e.g. while the DB is unreachable.
+    #[serde(default = "StreamBufferConfig::get_default_size")]
+    size: usize,
+    high_water: Option<usize>, // Alert at this many batches waiting, 3/4 of size if unset.
+    #[serde(default)]
+    overflow: StreamOverflow,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum StreamOverflow { // When the buffer is full.
+    #[default]
+    Block, // The driver waits (the unit's own buffering applies).
+    DropOldest, // Keep the newest readings.
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AdvWaitConfig {
+    timeout: u32, // [s]
+    #[serde(default)]
+    direct_fallback: bool, // For units which are always connectable.
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WindowConfig { // Only fetch within this time of day (host's local time), e.g. when the vendor app is not used.
+    #[serde(deserialize_with = "crate::timeutil::TimeUtil::parse_time_of_day")]
+    from: NaiveTime,
+    #[serde(deserialize_with = "crate::timeutil::TimeUtil::parse_time_of_day")]
+    to: NaiveTime,
+}
+
+impl ClockUnsetConfig {
+    fn get_default_before() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2010, 1, 1).unwrap()
+    }
+
+    fn apply(&self, id: &str, records: &mut DbRecords) {
+        let before = TimeUtil::get_ts_unix(self.before.and_time(NaiveTime::MIN).and_utc().timestamp());
+        let newest = match records.iter().map(|record| record.get_ts()).filter(|ts| *ts < before).max() {
+            Some(newest) => newest,
+            None => return,
+        };
+
+        println!("{}: {} records were taken with the unit's clock unset", id, records.iter().filter(|record| record.get_ts() < before).count());
+
+        match self.action {
+            ClockUnsetAction::Drop => records.retain(|record| record.get_ts() >= before),
+            ClockUnsetAction::Tag => {
+                for record in records.iter_mut().filter(|record| record.get_ts() < before) {
+                    record.add_tag("clock_unset", "true");
+                }
+            },
+            ClockUnsetAction::Redate => {
+                // Right for units fetched soon after measuring (e.g. advertising after each measurement).
+
+                let shift = TimeUtil::get_ts_unix(TimeUtil::get_current_unix()) - newest;
+
+                for record in records.iter_mut().filter(|record| record.get_ts() < before) {
+                    record.set_ts(record.get_ts() + shift);
+                }
+            },
+        }
+    }
+}
+
+impl StreamBufferConfig {
+    fn get_default_size() -> usize {
+        16
+    }
+
+    fn get_size(&self) -> usize {
+        self.size.max(1)
+    }
+
+    fn get_high_water(&self) -> usize {
+        self.high_water.unwrap_or(self.get_size() * 3 / 4).max(1)
+    }
+}
+
+impl Default for StreamBufferConfig {
+    fn default() -> Self {
+        Self {
+            size: Self::get_default_size(),
+            high_water: None,
+            overflow: StreamOverflow::default(),
+        }
+    }
+}
+
+impl WindowConfig {
+    pub fn get_secs_until(&self) -> u64 {
+        TimeUtil::get_secs_until_window(&self.from, &self.to)
+    }
 }
 
 impl DeviceConfig {
     pub fn get_id(&self) -> &str{
         &self.id
     }
+
+    fn get_default_hold_expiry() -> u32 {
+        30
+    }
+
+    fn get_sleep(&self) -> Option<u32> {
+        match self.poll {
+            Poll::Adv => self.sleep,
+            Poll::Direct => Some(self.sleep.unwrap_or(POLL_SLEEP)), // Nothing else paces the attempts.
+        }
+    }
+
+    fn get_driver_ctx(&self, status: StatusPtr, backend: BTBackendPtr, store: StorePtr) -> DriverContext {
+        let mut ctx = DriverContext::new(&self.id, self.debug_protocol, status, backend, store);
+        ctx.fetch_timeout = self.fetch_timeout.map(|fetch_timeout| Duration::from_secs(fetch_timeout.into()));
+        ctx.adv_timeout = self.adv_wait.as_ref().map(|adv_wait| Duration::from_secs(adv_wait.timeout.into()));
+        ctx.adv_direct_fallback = self.adv_wait.as_ref().is_some_and(|adv_wait| adv_wait.direct_fallback);
+        ctx.poll = self.poll;
+        ctx.skip_if_connected = self.skip_if_connected;
+        ctx.window = self.window;
+        ctx.identity_check = self.identity_check;
+        ctx
+    }
+}
+
+#[derive(Clone)]
+pub struct DeviceEnv { // Shared by all device tasks.
+    pub db: DbPtr,
+    pub status: StatusPtr,
+    pub control: ControlPtr,
+    pub backend: BTBackendPtr,
+    pub store: StorePtr,
+    pub persons: PersonsPtr,
+    pub gdt: Option<GdtPtr>,
+    pub hooks: Option<HooksPtr>,
+    pub consumers: Option<ConsumersPtr>,
+    pub mqtt: Option<MqttPtr>,
+    pub telemetry: Option<TelemetryPtr>,
 }
 
 pub struct Device;
 
 impl Device {
-    pub async fn pair(config: DeviceConfig) -> bool {
+    pub async fn pair(store: StorePtr, hooks: Option<HooksPtr>, config: DeviceConfig, repair: bool) -> bool {
+        // With repair, the existing bond is kept and only the key is rewritten.
+
+        let backend = BluezBackend::start();
+        let ctx = config.get_driver_ctx(StatusPtr::default(), BTBackendPtr::clone(&backend), StorePtr::clone(&store));
+        let pair_progress = PairProgressPtr::clone(&ctx.pair_progress);
+        let driver = driver::create(ctx, config.driver_config);
         let id = config.id;
 
-        println!("{}: pairing", id);
+        println!("{}: pairing, {}", id, driver.get_pair_hint());
 
-        let driver = driver::create(&id, config.driver_config);
+        loop {
+            let result = if repair { driver.repair().await } else { driver.pair().await };
 
-        match driver.pair().await {
-            Ok(_) => {
-                println!("{}: ok", id);
-                true
-            },
+            match result {
+                Ok(_) => {
+                    let adapter = backend.get_adapter().await.ok();
+                    let secret = driver.get_secret().map(Secrets::get_fingerprint);
+
+                    store.update_device(&id, |entry| {
+                        if !repair || entry.paired_at.is_none() { // Bond is the same on re-pairing, keep when it was made.
+                            entry.paired_at = Some(TimeUtil::get_current_unix());
+                            entry.paired_adapter = adapter;
+                        }
+                        entry.paired_secret = secret;
+                    });
+
+                    if let Some(hooks) = &hooks {
+                        hooks.on_pair(&id);
+                    }
+
+                    println!("{}: ok", id);
+                    return true;
+                },
+                Err(e) => {
+                    eprintln!("{}: {}", id, Redact::apply(&e));
+
+                    match pair_progress.take_step() {
+                        Some(step) if step.is_retryable() => {
+                            if !Self::prompt_retry(&id, driver.get_pair_hint()).await {
+                                return false;
+                            }
+                        },
+                        _ => return false,
+                    }
+                }
+            }
+        }
+    }
+
+    async fn check_pairing(status: &StatusPtr, store: &StorePtr, backend: &BTBackendPtr, id: &str, driver: &(dyn Driver + Send + Sync)) {
+        // Warn early instead of failing on the first fetch.
+
+        let entry = store.get_device(id);
+        let adapter = backend.get_adapter().await.ok();
+        let paired = match backend.get_link(driver.get_addr(), false).await {
+            Ok(link) => link.is_paired().await.ok(),
+            Err(_) => None,
+        };
+
+        if paired == Some(false) {
+            match (&entry.paired_adapter, &adapter) {
+                (Some(paired_adapter), Some(adapter)) if paired_adapter != adapter => {
+                    eprintln!("{}: unit was paired on adapter {}, not on this one ({}), pair it again", id, Redact::apply(paired_adapter), Redact::apply(adapter));
+                },
+                _ => eprintln!("{}: unit is not paired on this adapter yet, pair it first", id),
+            }
+        } else if paired.is_none() && entry.paired_at.is_none() { // E.g. BlueZ does not know the unit at all.
+            eprintln!("{}: unit was never paired by phd, pair it first", id);
+        }
+
+        if let (Some(paired_secret), Some(secret)) = (&entry.paired_secret, driver.get_secret()) {
+            if *paired_secret != Secrets::get_fingerprint(secret) {
+                eprintln!("{}: secret was changed since pairing, pair the unit again", id);
+            }
+        }
+
+        status.set_pairing(id, PairingStatus {
+            paired,
+            paired_at: entry.paired_at,
+            adapter: entry.paired_adapter,
+        });
+    }
+
+    async fn prompt_retry(id: &str, hint: &str) -> bool {
+        // Returns false if stdin is closed, e.g. not running interactively.
+
+        println!("{}: the unit might have left pairing mode too early, {} and press Enter to retry (Ctrl+C to abort)", id, hint);
+
+        let result = tokio::task::spawn_blocking(|| io::stdin().read_line(&mut String::new())).await;
+        matches!(result, Ok(Ok(len)) if len > 0)
+    }
+
+    pub async fn measure(db: DbPtr, store: StorePtr, persons: PersonsPtr, gdt: Option<GdtPtr>, hooks: Option<HooksPtr>, config: DeviceConfig) -> bool {
+        let status = StatusPtr::default();
+        let uploader = Uploader::new(db, StatusPtr::clone(&status), StorePtr::clone(&store), persons, gdt, hooks, &config);
+        let driver = driver::create(config.get_driver_ctx(status, BluezBackend::start(), store), config.driver_config);
+        let id = config.id;
+
+        println!("{}: measuring", id);
+
+        let records = match driver.measure().await {
+            Ok(records) => records,
             Err(e) => {
-                eprintln!("{}: {}", id, e);
-                false
+                eprintln!("{}: {}", id, Redact::apply(&e));
+                return false;
             }
+        };
+
+        println!("{}: received {} records", id, records.len());
+
+        for (meas, records, originals) in uploader.prepare_keep(records) {
+            println!("{}: sending {} records to {}", id, records.len(), meas);
+
+            if let Err(e) = uploader.send(&meas, &records).await {
+                eprintln!("{}: {}", id, Redact::apply(&e.to_string()));
+                return false;
+            }
+
+            uploader.notify(&meas, &records, &originals);
         }
+
+        println!("{}: ok", id);
+        true
     }
 
-    pub fn start(db: DbPtr, config: DeviceConfig) {
-        tokio::spawn(Self::run(db, config));
+    pub async fn backfill(db: DbPtr, store: StorePtr, persons: PersonsPtr, config: DeviceConfig, full: bool) -> bool {
+        // One-shot fetch without a deadline, uploaded with dedup (points already in the DB are overwritten with the same
+        // values). Full reads the whole memory and ignores the backfill cutoff. Old records are not exported to GDT, nor
+        // passed to hooks.
+
+        let status = StatusPtr::default();
+        let uploader = Uploader::new(db, StatusPtr::clone(&status), StorePtr::clone(&store), persons, None, None, &config);
+        let mut ctx = config.get_driver_ctx(status, BluezBackend::start(), store);
+        ctx.fetch_timeout = None; // Reading the whole memory can take long.
+        ctx.full_read = full;
+        let driver = driver::create(ctx, config.driver_config);
+        let id = config.id;
+
+        if driver.is_streaming() || driver.is_passive() {
+            eprintln!("{}: records are streamed or broadcast, the unit has no memory to backfill from", id);
+            return false;
+        }
+
+        println!("{}: backfilling{}, waiting for the unit to advertise", id, if full { " (full)" } else { "" });
+
+        let mut records = match driver.get_records().await {
+            Ok(records) => records,
+            Err(e) => {
+                eprintln!("{}: {}", id, Redact::apply(&e));
+                return false;
+            }
+        };
+
+        println!("{}: received {} records", id, records.len());
+
+        uploader.clean(&mut records, !full);
+        let mut sent = 0;
+
+        for (meas, records) in uploader.prepare(records) {
+            println!("{}: sending {} records to {}", id, records.len(), meas);
+
+            if let Err(e) = uploader.send(&meas, &records).await {
+                eprintln!("{}: {}", id, Redact::apply(&e.to_string()));
+                return false;
+            }
+
+            sent += records.len();
+        }
+
+        if let Err(e) = driver.commit().await {
+            eprintln!("{}: {}", id, Redact::apply(&e));
+        }
+
+        println!("{}: ok, {} records sent", id, sent);
+        true
+    }
+
+    pub async fn annotate(db: DbPtr, store: StorePtr, persons: PersonsPtr, config: DeviceConfig, record: DbRecord) -> bool {
+        // Annotations go through the same tagging, transforms and measurement naming as readings.
+
+        let uploader = Uploader::new(db, StatusPtr::default(), store, persons, None, None, &config); // Annotations are not exported to GDT, nor passed to hooks/consumers.
+        let id = config.id;
+
+        for (meas, records) in uploader.prepare(vec![record]) {
+            println!("{}: sending annotation to {}", id, meas);
+
+            if let Err(e) = uploader.send(&meas, &records).await {
+                eprintln!("{}: {}", id, Redact::apply(&e.to_string()));
+                return false;
+            }
+        }
+
+        println!("{}: ok", id);
+        true
+    }
+
+    pub fn start(env: DeviceEnv, config: DeviceConfig, delay: Duration) -> JoinHandle<()> {
+        tokio::spawn(Self::run(env, config, delay))
     }
 
-    async fn run(db: DbPtr, config: DeviceConfig) {
+    async fn run(env: DeviceEnv, config: DeviceConfig, delay: Duration) {
+        let DeviceEnv { db, status, control, backend, store, persons, gdt, hooks, consumers, mqtt, telemetry } = env;
+        let mut ctx = config.get_driver_ctx(StatusPtr::clone(&status), BTBackendPtr::clone(&backend), StorePtr::clone(&store));
+        ctx.trigger = control.register(&config.id);
+        let trigger = TriggerPtr::clone(&ctx.trigger);
+        let meter = FetchMeterPtr::clone(&ctx.meter);
+        let buffer = FetchBufferPtr::clone(&ctx.buffer);
+        let uploader = Uploader::new(DbPtr::clone(&db), StatusPtr::clone(&status), StorePtr::clone(&store), persons, gdt, hooks.clone(), &config).with_consumers(consumers).with_mqtt(mqtt);
+        let hold = matches!(config.unknown_user, Some(UnknownUser::Hold));
+
+        let sleep = config.get_sleep();
+        let driver = driver::create(ctx, config.driver_config);
         let id = config.id;
+        trigger.set_measurable(driver.can_measure());
 
-        println!("{}: starting", id);
+        let entry = store.get_device(&id);
+        status.set_last_adv(&id, entry.last_adv);
+        status.set_stats(&id, entry.stats);
 
-        let driver = driver::create(&id, config.driver_config);
+        if config.recent.is_some_and(|recent| recent.persist) {
+            status.set_recent(&id, entry.recent);
+        }
+
+        status.set_state(&id, DeviceState::Starting);
+
+        time::sleep(delay).await; // Staggered startup.
+
+        if !driver.is_passive() { // Nothing to pair with.
+            Self::check_pairing(&status, &store, &backend, &id, driver.as_ref()).await;
+        }
+
+        uploader.upload_refused().await; // E.g. a cast transform was added since.
+
+        let fetch = async {
+            if driver.is_streaming() {
+                loop {
+                    // Forward records to DB as they arrive, until the stream ends. Batches wait in the queue while uploading.
+
+                    trigger.take_measure(); // The triggered connection streams, which takes the reading.
+
+                    let (tx, mut rx) = mpsc::channel::<DbRecords>(1);
+                    let queue = StreamQueue::new(config.stream_buffer);
+
+                    let receive = async {
+                        let mut alerted = false;
+
+                        while let Some(records) = rx.recv().await {
+                            let depth = queue.push(records).await;
+
+                            if depth < config.stream_buffer.get_high_water() {
+                                alerted = false;
+                            } else if !alerted { // Once per crossing, not for each batch.
+                                let e = format!("upload queue is at {}/{} batches, DB might be unreachable", depth, config.stream_buffer.get_size());
+                                eprintln!("{}: {}", id, e);
+                                Self::run_error_hook(&hooks, &id, &e);
+                                alerted = true;
+                            }
+                        }
+
+                        queue.close();
+                    };
+
+                    let forward = async {
+                        while let Some((records, depth, dropped)) = queue.pop().await {
+                            if dropped > 0 {
+                                eprintln!("{}: upload queue was full, dropped the {} oldest batches", id, dropped);
+                            }
+
+                            let mut cycle = TelemetryCycle {
+                                ok: true,
+                                records: records.len(),
+                                queue_depth: Some(depth),
+                                dropped_batches: Some(dropped),
+                                ..Default::default()
+                            };
+                            let stats = Self::update_stats(&status, &store, &id, &meter, Ok(records.len()));
+                            cycle.retries = uploader.upload(records).await;
+                            status.set_state(&id, DeviceState::Streaming); // Back from uploading, the driver is still connected.
+
+                            if config.write_stats {
+                                Self::write_stats(&db, &id, &stats).await;
+                            }
+
+                            Self::write_telemetry(&telemetry, &id, &cycle).await;
+                        }
+                    };
+
+                    let (result, _, _) = tokio::join!(driver.stream(tx), receive, forward);
+                    if let Err(e) = result {
+                        let stats = Self::update_stats(&status, &store, &id, &meter, Err(&e));
+                        Self::run_error_hook(&hooks, &id, &e);
+                        status.set_state(&id, DeviceState::Error { reason: e });
+
+                        if config.write_stats {
+                            Self::write_stats(&db, &id, &stats).await;
+                        }
+
+                        Self::write_telemetry(&telemetry, &id, &TelemetryCycle::default()).await;
+                    }
 
-        loop {
-            let mut records = match driver.get_records().await {
-                Ok(records) => records,
-                Err(e) => {
-                    eprintln!("{}: {}", id, e);
                     Self::wait().await;
-                    continue;
                 }
-            };
+            } else {
+                loop {
+                    if let Some(window) = &config.window {
+                        let secs = window.get_secs_until();
+
+                        if secs > 0 {
+                            println!("{}: outside of fetch window", id);
+                            status.set_state(&id, DeviceState::Sleeping);
+                            time::sleep(Duration::from_secs(secs)).await;
+                        }
+                    }
 
-            if !records.is_empty() {
-                println!("{}: received {} records, sending to DB", id, records.len());
+                    let measure = trigger.take_measure();
+                    let fetching = AtomicBool::new(true);
+                    let fetch = async {
+                        let result = if measure {
+                            println!("{}: measurement triggered", id);
+                            Otel::device_span("measure", &id, driver.measure()).await
+                        } else {
+                            Otel::device_span("fetch", &id, driver.get_records()).await
+                        };
 
-                for record in &mut records {
-                    record.add_tag("device_id", &id);
-                }
+                        fetching.store(false, Ordering::Relaxed);
+                        buffer.close();
+                        result
+                    };
+                    let upload_partial = async {
+                        // Records read so far (e.g. finished user banks) are uploaded while the fetch goes on, so a
+                        // connection dropped near the end of a long fetch doesn't lose them.
 
-                loop {
-                    // TODO: Put records into a queue and have a background task to submit it to influxdb.
-                    // TODO: Once commited, update unread status on unit.
-                    
-                    match db.send(&config.meas, &records).await {
-                        Ok(_) => break,
+                        let mut sent = 0;
+                        let mut retries = 0;
+
+                        while let Some(partial) = buffer.next().await {
+                            println!("{}: uploading {} records read so far", id, partial.len());
+                            sent += partial.len();
+                            retries += uploader.upload(partial).await;
+
+                            if fetching.load(Ordering::Relaxed) {
+                                status.set_state(&id, DeviceState::Fetching);
+                            }
+                        }
+
+                        (sent, retries)
+                    };
+                    let (result, (sent, partial_retries)) = tokio::join!(fetch, upload_partial);
+                    let mut cycle = TelemetryCycle {
+                        ok: result.is_ok(),
+                        fetch_duration: meter.take_duration(),
+                        records: result.as_ref().map_or(sent, |records| records.len()),
+                        retries: partial_retries,
+                        ..Default::default()
+                    };
+                    let stats = Self::update_stats(&status, &store, &id, &meter, result.as_ref().map(|records| records.len()).map_err(|e| e.as_str()));
+
+                    if config.write_stats {
+                        Self::write_stats(&db, &id, &stats).await;
+                    }
+
+                    let records = match result {
+                        Ok(mut records) => {
+                            records.drain(..sent.min(records.len())); // Uploaded already, these come first.
+                            records
+                        },
                         Err(e) => {
-                            eprintln!("{}: {}", id, e);
-                            Self::wait().await;
+                            uploader.take_held_full(); // Not marked as read anyway.
+
+                            Self::run_error_hook(&hooks, &id, &e);
+                            status.set_state(&id, DeviceState::Error { reason: e });
+                            Self::write_telemetry(&telemetry, &id, &cycle).await;
+
+                            match &config.backoff {
+                                Some(backoff) if stats.failures >= backoff.failures => {
+                                    // Don't batter a half-broken device every time it advertises.
+
+                                    println!("{}: {} consecutive failures, cooling down", id, stats.failures);
+                                    status.set_state(&id, DeviceState::Sleeping);
+                                    Self::sleep(&trigger, Duration::from_secs(backoff.cooldown.into())).await;
+                                },
+                                _ => match sleep {
+                                    Some(sleep) if config.poll == Poll::Direct => { // Unit might just be out of range, try again on schedule.
+                                        status.set_state(&id, DeviceState::Sleeping);
+                                        Self::sleep(&trigger, Duration::from_secs(sleep.into())).await;
+                                    },
+                                    _ => Self::wait().await,
+                                },
+                            }
+
+                            continue;
+                        }
+                    };
+
+                    cycle.retries += uploader.upload(records).await;
+
+                    if uploader.take_held_full() { // Left on the unit, read again next time.
+                        println!("{}: not marking records as read, held records are full", id);
+                    } else if !measure { // A measurement leaves the unit's memory as it was.
+                        if let Err(e) = driver.commit().await { // Not fatal, the records are read again next time.
+                            eprintln!("{}: {}", id, Redact::apply(&e));
                         }
                     }
+
+                    Self::write_telemetry(&telemetry, &id, &cycle).await;
+
+                    if let Some(sleep) = sleep {
+                        status.set_state(&id, DeviceState::Sleeping);
+                        Self::sleep(&trigger, Duration::from_secs(sleep.into())).await;
+                    }
                 }
+            }
+        };
 
-                println!("{}: ok", id);
+        let held = async {
+            if hold { // Alongside the fetches, stopped with them when the device is restarted or removed.
+                Self::run_held(&uploader).await;
             }
+        };
+
+        tokio::join!(fetch, held);
+    }
+
+    async fn run_held(uploader: &Uploader) {
+        // Held records are assigned through the CLI or the API, possibly by another process.
+
+        loop {
+            uploader.upload_held().await;
+            time::sleep(Duration::from_secs(HELD_POLL)).await;
+        }
+    }
+
+    fn run_error_hook(hooks: &Option<HooksPtr>, id: &str, e: &str) {
+        if let Some(hooks) = hooks {
+            hooks.on_error(id, e);
+        }
+    }
+
+    fn update_stats(status: &StatusPtr, store: &StorePtr, id: &str, meter: &FetchMeterPtr, result: Result<usize, &str>) -> DeviceStats {
+        // Account the outcome of a fetch, result is the number of records or the error.
+
+        let bytes_read = meter.take_bytes();
+
+        store.update_device(id, |entry| {
+            let stats = &mut entry.stats;
+            stats.bytes_read += bytes_read;
 
-            if let Some(sleep) = config.sleep {
-                time::sleep(Duration::from_secs(sleep.into())).await;
+            match result {
+                Ok(records) => {
+                    stats.records += records as u64;
+                    stats.failures = 0;
+                },
+                Err(e) => {
+                    stats.failures += 1;
+                    stats.last_error = Some(Redact::apply(e));
+                }
             }
+        });
+
+        let stats = store.get_device(id).stats;
+        status.set_stats(id, stats.clone());
+        stats
+    }
+
+    async fn write_stats(db: &DbPtr, id: &str, stats: &DeviceStats) {
+        // Best effort, stats are not worth retrying for.
+
+        let mut record = DbRecord::new(TimeUtil::get_ts_unix(TimeUtil::get_current_unix()));
+        record.add_tag("device_id", id);
+        record.add_field("records", DbFieldValue::Integer(stats.records as i64));
+        record.add_field("bytes_read", DbFieldValue::Integer(stats.bytes_read as i64));
+        record.add_field("failures", DbFieldValue::Integer(stats.failures.into()));
+
+        if let Some(last_error) = &stats.last_error {
+            record.add_field("last_error", DbFieldValue::String(last_error.clone()));
+        }
+
+        if let Err(e) = db.send(STATS_MEAS, &[record]).await {
+            eprintln!("{}: unable to write stats: {}", id, Redact::apply(&e.to_string()));
+        }
+    }
+
+    async fn write_telemetry(telemetry: &Option<TelemetryPtr>, id: &str, cycle: &TelemetryCycle) {
+        if let Some(telemetry) = telemetry {
+            telemetry.write(id, cycle).await;
         }
     }
 
     async fn wait() {
         time::sleep(Duration::from_secs(WAIT)).await;
     }
+
+    async fn sleep(trigger: &Trigger, duration: Duration) {
+        // Cut short by a triggered fetch, which is taken by the driver's wait_for_adv().
+
+        tokio::select! {
+            _ = time::sleep(duration) => (),
+            _ = trigger.wait() => (),
+        }
+    }
+}
+
+struct StreamQueue { // Record batches of a streaming driver waiting for upload, see StreamBufferConfig.
+    config: StreamBufferConfig,
+    state: Mutex<StreamQueueState>,
+    pushed: Notify,
+    popped: Notify,
+}
+
+#[derive(Default)]
+struct StreamQueueState {
+    batches: VecDeque<DbRecords>,
+    dropped: usize, // Batches dropped since the last pop.
+    closed: bool,
+}
+
+impl StreamQueue {
+    fn new(config: StreamBufferConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(StreamQueueState::default()),
+            pushed: Notify::new(),
+            popped: Notify::new(),
+        }
+    }
+
+    async fn push(&self, records: DbRecords) -> usize {
+        // Returns the number of batches waiting, including this one.
+
+        let size = self.config.get_size();
+
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+
+                if state.batches.len() >= size && matches!(self.config.overflow, StreamOverflow::DropOldest) {
+                    state.batches.pop_front();
+                    state.dropped += 1;
+                }
+
+                if state.batches.len() < size {
+                    state.batches.push_back(records);
+                    let depth = state.batches.len();
+                    drop(state);
+
+                    self.pushed.notify_one();
+                    return depth;
+                }
+            }
+
+            self.popped.notified().await;
+        }
+    }
+
+    async fn pop(&self) -> Option<(DbRecords, usize, usize)> {
+        // Oldest batch, with the number of batches still waiting and the ones dropped since the last pop. None once
+        // closed and empty.
+
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+
+                if let Some(records) = state.batches.pop_front() {
+                    let result = (records, state.batches.len(), mem::take(&mut state.dropped));
+                    drop(state);
+
+                    self.popped.notify_one();
+                    return Some(result);
+                }
+
+                if state.closed {
+                    return None;
+                }
+            }
+
+            self.pushed.notified().await;
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.pushed.notify_one();
+    }
+}
+
+struct Uploader { // Sends records of a device to the DB.
+    db: DbPtr,
+    status: StatusPtr,
+    store: StorePtr,
+    persons: PersonsPtr,
+    gdt: Option<GdtPtr>,
+    hooks: Option<HooksPtr>,
+    consumers: Option<ConsumersPtr>,
+    mqtt: Option<MqttPtr>,
+    id: String,
+    driver_name: &'static str,
+    meas: Template,
+    transforms: Vec<TransformConfig>,
+    backfill: Option<BackfillConfig>,
+    clock_unset: Option<ClockUnsetConfig>,
+    trend: Option<TrendConfig>,
+    recent: Option<RecentConfig>,
+    unknown_user: Option<UnknownUser>,
+    hold_expiry: u32, // [days]
+    version_tags: bool,
+    held_full: AtomicBool, // Records were refused by the full held table (block policy) since last taken.
+}
+
+impl Uploader {
+    fn new(db: DbPtr, status: StatusPtr, store: StorePtr, persons: PersonsPtr, gdt: Option<GdtPtr>, hooks: Option<HooksPtr>, config: &DeviceConfig) -> Self {
+        Self {
+            db,
+            status,
+            store,
+            persons,
+            gdt,
+            hooks,
+            consumers: None,
+            mqtt: None,
+            id: config.id.clone(),
+            driver_name: config.driver_config.get_name(),
+            meas: config.meas.clone(),
+            transforms: config.transforms.clone(),
+            backfill: config.backfill,
+            clock_unset: config.clock_unset,
+            trend: config.trend.clone(),
+            recent: config.recent,
+            unknown_user: config.unknown_user,
+            hold_expiry: config.hold_expiry,
+            version_tags: config.version_tags,
+            held_full: AtomicBool::new(false),
+        }
+    }
+
+    fn with_consumers(mut self, consumers: Option<ConsumersPtr>) -> Self {
+        self.consumers = consumers;
+        self
+    }
+
+    fn with_mqtt(mut self, mqtt: Option<MqttPtr>) -> Self {
+        self.mqtt = mqtt;
+        self
+    }
+
+    async fn upload(&self, mut records: DbRecords) -> u32 {
+        // Returns the number of retries needed.
+
+        let id = &self.id;
+
+        if records.is_empty() {
+            return 0;
+        }
+
+        self.status.set_state(id, DeviceState::Uploading);
+        println!("{}: received {} records, sending to DB", id, records.len());
+
+        self.clean(&mut records, true);
+
+        let mut retries = 0;
+
+        for (meas, records, originals) in self.prepare_keep(records) {
+            if let Err(e) = self.store.check_schema(&meas, &records) {
+                // Retrying is pointless, the DB would reject these records forever.
+
+                self.park(originals, e);
+                continue;
+            }
+
+            if let Some(mqtt) = &self.mqtt { // Right away, the DB write might take retries.
+                mqtt.publish(id, &meas, &records);
+            }
+
+            loop {
+                // At-least-once: a batch might be written again after a failed response, InfluxDB overwrites points with the same series and timestamp.
+                // TODO: Put records into a queue and have a background task to submit it to influxdb.
+
+                match Otel::device_span("db_write", id, self.db.send(&meas, &records)).await {
+                    Ok(_) => {
+                        self.store.add_schema(&meas, &records);
+                        self.notify(&meas, &records, &originals);
+                        self.keep_recent(&meas, &records);
+                        break;
+                    },
+                    Err(DbError::Permanent(e)) => {
+                        // Retrying would block the device's uploads for good.
+
+                        self.park(originals, Redact::apply(&e));
+                        break;
+                    },
+                    Err(DbError::Temporary(e)) => {
+                        self.status.set_state(id, DeviceState::Error { reason: e });
+                        retries += 1;
+
+                        match self.db.get_pause() {
+                            Some(pause) => time::sleep(pause).await, // Rate limited, don't hammer the DB.
+                            None => Device::wait().await,
+                        }
+                    }
+                }
+            }
+        }
+
+        println!("{}: ok", id);
+        retries
+    }
+
+    fn clean(&self, records: &mut DbRecords, use_cutoff: bool) {
+        // Drop duplicates, fix records taken while the clock was unset, drop the ones before the backfill cutoff, then
+        // apply the unknown user policy.
+
+        let id = &self.id;
+
+        let dups = DbRecord::dedup(records); // E.g. ring buffer wrap-around or overlapping reads.
+        if dups > 0 {
+            println!("{}: dropped {} duplicate records", id, dups);
+        }
+
+        if let Some(clock_unset) = &self.clock_unset { // Before the cutoff, which would drop them anyway.
+            clock_unset.apply(id, records);
+        }
+
+        let cutoff = if use_cutoff { self.get_cutoff() } else { None }; // Not fixed by a full backfill.
+
+        if let Some(cutoff) = cutoff {
+            let len = records.len();
+            records.retain(|record| record.get_ts() >= cutoff);
+
+            if records.len() < len {
+                println!("{}: ignored {} records older than backfill cutoff", id, len - records.len());
+            }
+        }
+
+        if let Some(unknown_user) = self.unknown_user {
+            let (unknown, known): (DbRecords, DbRecords) = records.drain(..).partition(|record| self.persons.is_unassigned(id, record));
+            *records = known;
+
+            if unknown.is_empty() {
+                return;
+            }
+
+            match unknown_user {
+                UnknownUser::Guest => records.extend(unknown.into_iter().map(|mut record| {
+                    record.add_tag("person", persons::GUEST);
+                    record
+                })),
+                UnknownUser::Drop => println!("{}: dropped {} records of unknown users", id, unknown.len()),
+                UnknownUser::Hold => {
+                    println!("{}: holding {} records of unknown users until assigned", id, unknown.len());
+                    self.hold(unknown.iter().map(|record| HeldRecord::new(id, record)).collect());
+                },
+            }
+        }
+    }
+
+    async fn upload_held(&self) {
+        // Expire held records left unassigned, then upload the assigned ones. Failed uploads are retried on the next call.
+
+        let id = &self.id;
+
+        let expired = self.store.expire_held(id, TimeUtil::get_current_unix() - i64::from(self.hold_expiry) * 86400);
+        if expired > 0 {
+            println!("{}: dropped {} held records not assigned in time", id, expired);
+        }
+
+        let held = match self.store.get_held(Some(id), HeldState::Assigned) {
+            Ok(held) => held,
+            Err(e) => {
+                eprintln!("{}: {}", id, e);
+                return;
+            },
+        };
+
+        'records: for (held_id, held) in held {
+            let mut record = held.to_record();
+            let person = held.person.as_deref().unwrap_or_default();
+
+            if let Err(e) = self.persons.assign(person, &mut record) { // Person was removed from the configuration since.
+                eprintln!("{}: held record {}: {}, it is pending again", id, held_id, e);
+
+                let result = self.store.update_held(held_id, |held| {
+                    held.state = HeldState::Pending;
+                    held.person = None;
+                    Ok(())
+                });
+
+                if let Err(e) = result {
+                    eprintln!("{}: {}", id, e);
+                }
+
+                continue;
+            }
+
+            println!("{}: sending held record {} of {}", id, held_id, person);
+
+            for (meas, records, originals) in self.prepare_keep(vec![record]) {
+                match self.send(&meas, &records).await {
+                    Ok(_) => (),
+                    Err(DbError::Permanent(e)) => { // Parked, like the records refused by upload().
+                        let e = Redact::apply(&e);
+                        eprintln!("{}: held record {}: {}, parking it until the next start", id, held_id, e);
+
+                        let result = self.store.update_held(held_id, |held| {
+                            held.state = HeldState::Refused;
+                            held.reason = Some(e);
+                            Ok(())
+                        });
+
+                        if let Err(e) = result {
+                            eprintln!("{}: {}", id, e);
+                        }
+
+                        continue 'records;
+                    },
+                    Err(DbError::Temporary(e)) => {
+                        eprintln!("{}: {}", id, Redact::apply(&e));
+                        return;
+                    },
+                }
+
+                self.notify(&meas, &records, &originals);
+                self.keep_recent(&meas, &records);
+            }
+
+            let result = self.store.update_held(held_id, |held| {
+                held.state = HeldState::Uploaded;
+                Ok(())
+            });
+
+            if let Err(e) = result {
+                eprintln!("{}: {}", id, e);
+            }
+        }
+    }
+
+    fn park(&self, records: DbRecords, reason: String) {
+        // Keep records the DB would refuse (or did refuse) in the state, as read from the unit (before tagging and transforms), so a
+        // cast transform added in the meantime applies when they are retried at the next start.
+
+        eprintln!("{}: {}, parking {} records until the next start", self.id, reason, records.len());
+
+        self.hold(records.iter().map(|record| {
+            let mut held = HeldRecord::new(&self.id, record);
+            held.state = HeldState::Refused;
+            held.reason = Some(reason.clone());
+            held
+        }).collect());
+
+        self.status.set_state(&self.id, DeviceState::Error { reason });
+    }
+
+    fn hold(&self, records: Vec<HeldRecord>) {
+        let result = self.store.hold(records);
+
+        if let Some(len) = result.high_water { // Once per crossing, not for each record.
+            let e = format!("held records are at {} rows, records are being parked (DB might be refusing them)", len);
+            eprintln!("{}: {}", self.id, e);
+            Device::run_error_hook(&self.hooks, &self.id, &e);
+        }
+
+        if result.refused > 0 {
+            eprintln!("{}: {} records could not be kept in the state (held records are full)", self.id, result.refused);
+            self.held_full.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn take_held_full(&self) -> bool {
+        self.held_full.swap(false, Ordering::Relaxed)
+    }
+
+    async fn upload_refused(&self) {
+        // Retry records parked by park(), the ones still refused stay parked.
+
+        let id = &self.id;
+
+        let refused = match self.store.get_held(Some(id), HeldState::Refused) {
+            Ok(refused) => refused,
+            Err(e) => {
+                eprintln!("{}: {}", id, e);
+                return;
+            },
+        };
+
+        if refused.is_empty() {
+            return;
+        }
+
+        println!("{}: retrying {} parked records", id, refused.len());
+
+        'records: for (held_id, held) in refused {
+            let mut record = held.to_record();
+
+            if let Some(person) = &held.person { // Assigned by hand before it was refused, see upload_held().
+                if let Err(e) = self.persons.assign(person, &mut record) {
+                    eprintln!("{}: parked record {}: {}", id, held_id, e);
+                    continue;
+                }
+            }
+
+            for (meas, records, originals) in self.prepare_keep(vec![record]) {
+                if let Err(e) = self.send(&meas, &records).await {
+                    eprintln!("{}: parked record {}: {}", id, held_id, Redact::apply(&e.to_string()));
+                    continue 'records;
+                }
+
+                self.notify(&meas, &records, &originals);
+                self.keep_recent(&meas, &records);
+            }
+
+            let result = self.store.update_held(held_id, |held| {
+                held.state = HeldState::Uploaded;
+                Ok(())
+            });
+
+            if let Err(e) = result {
+                eprintln!("{}: {}", id, e);
+            }
+        }
+    }
+
+    async fn send(&self, meas: &str, records: &[DbRecord]) -> Result<(), DbError> {
+        // Single attempt.
+
+        self.store.check_schema(meas, records).map_err(DbError::Permanent)?;
+        self.db.send(meas, records).await?;
+        self.store.add_schema(meas, records);
+
+        Ok(())
+    }
+
+    fn notify(&self, meas: &str, records: &[DbRecord], originals: &[DbRecord]) {
+        // Records written to the DB, originals are the same ones as passed to prepare_keep().
+
+        if let Some(gdt) = &self.gdt { // Values before transforms, in the units reported by the driver.
+            for original in originals {
+                let mut record = original.clone();
+                self.persons.apply(&self.id, &mut record);
+                gdt.export(&self.id, &record);
+            }
+        }
+
+        if let Some(hooks) = &self.hooks {
+            hooks.on_records(&self.id, meas, records);
+        }
+
+        if let Some(consumers) = &self.consumers {
+            consumers.on_records(&self.id, meas, records);
+        }
+    }
+
+    fn keep_recent(&self, meas: &str, records: &[DbRecord]) {
+        let recent = match &self.recent {
+            Some(recent) => recent,
+            None => return,
+        };
+
+        let kept = self.status.add_recent(&self.id, records.iter().map(|record| RecentRecord::new(meas, record)).collect(), recent.count);
+
+        if recent.persist {
+            self.store.update_device(&self.id, |entry| entry.recent = kept);
+        }
+    }
+
+    fn get_cutoff(&self) -> Option<i64> {
+        // Fixed on first call (i.e. first sync) and kept in the store, so a later sync doesn't let older records through.
+
+        let backfill = self.backfill?;
+        let entry = self.store.get_device(&self.id);
+
+        if entry.backfill_cutoff.is_some() {
+            return entry.backfill_cutoff;
+        }
+
+        let now = TimeUtil::get_current_unix();
+        let mut cutoff = i64::MIN;
+
+        if let Some(max_age) = backfill.max_age {
+            cutoff = cutoff.max(now - i64::from(max_age) * 86400);
+        }
+
+        if backfill.after_pairing {
+            cutoff = cutoff.max(entry.paired_at.unwrap_or(now));
+        }
+
+        let cutoff = TimeUtil::get_ts_unix(cutoff.max(0));
+        self.store.update_device(&self.id, |entry| entry.backfill_cutoff = Some(cutoff));
+        Some(cutoff)
+    }
+
+    fn prepare(&self, records: DbRecords) -> Vec<(String, DbRecords)> {
+        self.prepare_keep(records).into_iter().map(|(meas, records, _)| (meas, records)).collect()
+    }
+
+    fn prepare_keep(&self, records: DbRecords) -> Vec<(String, DbRecords, DbRecords)> {
+        // Tag and transform records, then group them by measurement (and add trend). Records left without fields are dropped.
+        // Each group also has its records as they were passed in.
+
+        let mut groups: Vec<(String, DbRecords, DbRecords)> = Vec::new();
+        let firmware = if self.version_tags { self.store.get_device(&self.id).firmware } else { None };
+
+        for original in records {
+            let mut record = original.clone();
+            record.add_tag("device_id", &self.id);
+
+            if self.version_tags { // Identifies points written by a version with a decoding bug.
+                record.add_tag("driver_ver", env!("CARGO_PKG_VERSION"));
+
+                if let Some(firmware) = &firmware {
+                    record.add_tag("fw", firmware);
+                }
+            }
+
+            self.persons.apply(&self.id, &mut record);
+
+            Transform::apply(&self.transforms, &mut record);
+
+            if !record.has_fields() {
+                continue;
+            }
+
+            let record_meas = match record.get_meas() {
+                Some(record_meas) => String::from(record_meas), // Set by driver.
+                None => self.meas.expand(|name| match record.get_tag(name) {
+                    Some(value) => Some(String::from(value)),
+                    None if name == "driver" => Some(String::from(self.driver_name)),
+                    None => None,
+                }),
+            };
+
+            match groups.iter_mut().find(|(group_meas, _, _)| *group_meas == record_meas) {
+                Some((_, group, originals)) => {
+                    group.push(record);
+                    originals.push(original);
+                },
+                None => groups.push((record_meas, vec![record], vec![original])),
+            }
+        }
+
+        if let Some(trend) = &self.trend {
+            for (meas, records, _) in groups.iter_mut() {
+                Trend::apply(trend, &self.store, &self.id, meas, records);
+            }
+        }
+
+        for (meas, records, _) in groups.iter_mut() {
+            DbRecord::set_ids(&self.id, meas, records);
+        }
+
+        groups
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{Config, File, FileFormat};
+    use std::sync::Arc;
+
+    use crate::db::{Db, DbFieldValue, DbRecord};
+    use crate::driver::FetchBuffer;
+    use crate::persons::{PersonConfig, Persons};
+    use crate::status::StatusPtr;
+    use crate::store::{HeldRecord, HeldState, Store};
+    use super::{DeviceConfig, StreamBufferConfig, StreamOverflow, StreamQueue, Uploader};
+
+    #[tokio::test]
+    async fn upload_refused() {
+        // A held record assigned by hand, then refused, keeps its person when retried.
+
+        let config = Config::builder()
+            .add_source(File::from_str("device:
+  id: scale
+  driver_config:
+    driver: Xiaomi_XMTZC05HM
+    addr: 00:11:22:33:44:cc
+  meas: weight
+  recent:
+    count: 10
+persons:
+  - name: alice
+    devices:
+      - device: scale
+        user: alice", FileFormat::Yaml))
+            .build()
+            .unwrap();
+        let device: DeviceConfig = config.get("device").unwrap();
+        let persons: Vec<PersonConfig> = config.get("persons").unwrap();
+
+        let status = StatusPtr::default();
+        let store = Arc::new(Store::open(None).unwrap());
+        let uploader = Uploader::new(Arc::new(Db::discard()), StatusPtr::clone(&status), Arc::clone(&store), Arc::new(Persons::new(persons).unwrap()), None, None, &device);
+
+        let mut record = DbRecord::new(1_000_000_000);
+        record.add_field("weight", DbFieldValue::Float(71.5));
+        let mut held = HeldRecord::new("scale", &record);
+        held.state = HeldState::Refused;
+        held.person = Some(String::from("alice"));
+        store.hold(vec![held]);
+
+        uploader.upload_refused().await;
+
+        let recent = status.get_recent("scale").unwrap();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].tags.get("person").map(String::as_str), Some("alice"));
+        assert_eq!(store.get_held(None, HeldState::Uploaded).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stream_queue() {
+        let queue = StreamQueue::new(StreamBufferConfig {
+            size: 2,
+            high_water: None,
+            overflow: StreamOverflow::DropOldest,
+        });
+        let batch = |ts: i64| vec![DbRecord::new(ts)];
+
+        assert_eq!(queue.push(batch(1)).await, 1);
+        assert_eq!(queue.push(batch(2)).await, 2);
+        assert_eq!(queue.push(batch(3)).await, 2); // Full, doesn't wait.
+        queue.close();
+
+        let (records, depth, dropped) = queue.pop().await.unwrap();
+        assert_eq!((records[0].get_ts(), depth, dropped), (2, 1, 1));
+        let (records, depth, dropped) = queue.pop().await.unwrap();
+        assert_eq!((records[0].get_ts(), depth, dropped), (3, 0, 0));
+        assert!(queue.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn fetch_buffer() {
+        // Batches are taken while the fetch goes on, the buffer is drained before it is reported closed.
+
+        let buffer = FetchBuffer::default();
+
+        buffer.add(&DbRecord::new(1));
+        assert_eq!(buffer.next().await.unwrap().len(), 1);
+
+        buffer.extend(&[DbRecord::new(2), DbRecord::new(3)]);
+        buffer.close();
+        assert_eq!(buffer.next().await.unwrap().iter().map(|record| record.get_ts()).collect::<Vec<_>>(), [2, 3]);
+        assert!(buffer.next().await.is_none());
+
+        buffer.add(&DbRecord::new(4)); // Reused by the next fetch.
+        assert_eq!(buffer.next().await.unwrap()[0].get_ts(), 4);
+    }
 }
@@ -0,0 +1,345 @@
+//! # Beurer BF 700 / BF 720 and Sanitas SBF 70 driver
+//!
+//! The scales speak a proprietary protocol over a single characteristic (as
+//! documented by the openScale project): after an init handshake and setting
+//! the clock, the user slots are listed (each acknowledged), then the saved
+//! measurements of each user are requested. A measurement arrives in two
+//! parts, each acknowledged too. Values are big endian, timestamps are Unix
+//! time. Records are tagged with the user's initials as set on the scale.
+//!
+//! The Sanitas SBF 70 (the same hardware, also sold under other brands) speaks
+//! the same protocol with another start byte.
+
+use async_trait::async_trait;
+use bluer::Address;
+use serde::Deserialize;
+use uuid::{uuid, Uuid};
+
+use crate::btutil::{self, AdvPattern, BTLinkPtr, BTUtil};
+use crate::db::{DbFieldValue, DbRecord, DbRecords};
+use crate::driver::gatt::meas::MeasStream;
+use crate::driver::{Driver, DriverContext, PairStep};
+use crate::otel::Otel;
+use crate::status::DeviceState;
+use crate::timeutil::TimeUtil;
+
+pub const START_BEURER: u8 = 0xf7;
+pub const START_SANITAS: u8 = 0xe7;
+
+const SERVICE_ID: u16 = 0xffe0;
+
+const MAIN_SERVICE: &Uuid = &uuid!("0000ffe0-0000-1000-8000-00805f9b34fb");
+const MAIN_CHAR: &Uuid = &uuid!("0000ffe1-0000-1000-8000-00805f9b34fb");
+
+// Start byte offsets of the non-command messages.
+const START_INIT: u8 = 0x01; // Below the command start byte.
+const START_SET_TIME: u8 = 0x02;
+
+const CMD_INIT: u8 = 0x01;
+const CMD_USER_LIST: u8 = 0x33;
+const CMD_USER_INFO: u8 = 0x34;
+const CMD_GET_SAVED: u8 = 0x41;
+const CMD_SAVED: u8 = 0x42;
+const CMD_SCALE_ACK: u8 = 0xf0;
+const CMD_APP_ACK: u8 = 0xf1;
+
+const STATUS_OK: u8 = 0x00;
+const STATUS_NO_USERS: u8 = 0x01;
+
+const UID_LEN: usize = 8;
+const USER_INFO_LEN: usize = 4 + UID_LEN + 3 + 5; // Header, uid, initials, then birth date, height, sex and activity (not used).
+const PART_LEN: usize = 11; // Half of a measurement.
+const RECORD_LEN: usize = 2 * PART_LEN;
+
+const KCAL: f64 = 4.184; // [kJ]
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    addr: Address, // TODO: unique check
+    #[serde(default)]
+    keep_connected: bool, // Do not disconnect after pairing/fetching.
+}
+
+pub struct DriverImpl {
+    ctx: DriverContext,
+    start: u8, // First byte of commands, differs by brand.
+    config: Config,
+}
+
+struct User {
+    uid: [u8; UID_LEN],
+    initials: String,
+}
+
+impl DriverImpl {
+    pub fn new(ctx: DriverContext, start: u8, config: Config) -> Self {
+        Self {
+            ctx,
+            start,
+            config,
+        }
+    }
+
+    async fn pair(&self) -> btutil::Result<()> {
+        // Pair device.
+
+        self.ctx.set_pair_step(PairStep::Discovering);
+        let link = self.ctx.get_link(&self.config.addr, true).await?;
+
+        if link.is_paired().await? {
+            return Err("Device is already paired".into());
+        }
+
+        let result = self.pair_device(&link).await;
+
+        if !self.config.keep_connected {
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn pair_device(&self, link: &BTLinkPtr) -> btutil::Result<()> {
+        self.ctx.set_pair_step(PairStep::Connecting);
+        link.connect().await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_pair_step(PairStep::Bonding);
+        link.pair().await?;
+        self.ctx.set_pair_step(PairStep::Bonded);
+
+        // Synchronize time.
+
+        self.ctx.set_pair_step(PairStep::SyncingTime);
+
+        let mut stream = MeasStream::new(&self.ctx, link, MAIN_SERVICE, MAIN_CHAR).await?;
+        self.init(link, &mut stream).await
+    }
+
+    async fn get_records(&self) -> btutil::Result<DbRecords> {
+        // Connect to device.
+
+        let link = self.ctx.get_link(&self.config.addr, false).await?;
+
+        if !link.is_paired().await? {
+            return Err("Device is not yet paired".into());
+        }
+
+        self.ctx.set_state(DeviceState::WaitingForAdvertisement);
+        self.ctx.wait_for_adv(&self.config.addr, &[AdvPattern::service(SERVICE_ID)]).await?;
+
+        let result = BTUtil::with_deadline(&link, self.ctx.fetch_timeout, self.fetch(&link)).await;
+
+        if !self.config.keep_connected { // Notification sessions are gone by now, since fetch() has finished.
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn fetch(&self, link: &BTLinkPtr) -> btutil::Result<DbRecords> {
+        self.ctx.check_policy(link).await?;
+        self.ctx.set_state(DeviceState::Connecting);
+        Otel::span("connect", link.connect()).await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_state(DeviceState::Fetching);
+
+        let mut stream = MeasStream::new(&self.ctx, link, MAIN_SERVICE, MAIN_CHAR).await?;
+        self.init(link, &mut stream).await?;
+
+        // List user slots, then fetch the saved measurements of each user.
+
+        let mut records = DbRecords::new();
+
+        for user in self.get_users(link, &mut stream).await? {
+            link.write_char(MAIN_SERVICE, MAIN_CHAR, &[&[self.start, CMD_GET_SAVED][..], &user.uid].concat()).await?;
+
+            let count = match self.recv(&mut stream, CMD_SCALE_ACK, 5).await?[2..] {
+                [CMD_GET_SAVED, count, _] => count, // Number of parts, two per measurement.
+                _ => return Err("Invalid response".into()),
+            };
+
+            let mut data = Vec::new();
+
+            for current in 1..=count {
+                let part = self.recv_item(link, &mut stream, CMD_SAVED, count, current, 4 + PART_LEN).await?;
+                data.extend_from_slice(&part[4..]);
+
+                if data.len() == RECORD_LEN {
+                    if let Some(record) = Self::decode_record(&user.initials, &data)? {
+                        self.ctx.buffer.add(&record);
+                        records.push(record);
+                    }
+
+                    data.clear();
+                }
+            }
+
+            if !data.is_empty() {
+                return Err("Incomplete measurement".into());
+            }
+        }
+
+        Ok(records)
+    }
+
+    async fn init(&self, link: &BTLinkPtr, stream: &mut MeasStream) -> btutil::Result<()> {
+        // Handshake, then set the clock (not answered).
+
+        let init_start = self.start - START_INIT;
+        link.write_char(MAIN_SERVICE, MAIN_CHAR, &[init_start, CMD_INIT]).await?;
+
+        if stream.recv().await.as_deref() != Some(&[init_start, CMD_INIT]) {
+            return Err("Invalid response".into());
+        }
+
+        let current = match u32::try_from(TimeUtil::get_current_unix()) {
+            Ok(current) => current,
+            Err(_) => return Err("Host time is out of range".into()),
+        };
+
+        link.write_char(MAIN_SERVICE, MAIN_CHAR, &[&[self.start + START_SET_TIME][..], &current.to_be_bytes()].concat()).await
+    }
+
+    async fn get_users(&self, link: &BTLinkPtr, stream: &mut MeasStream) -> btutil::Result<Vec<User>> {
+        link.write_char(MAIN_SERVICE, MAIN_CHAR, &[self.start, CMD_USER_LIST]).await?;
+
+        let count = match self.recv(stream, CMD_SCALE_ACK, 6).await?[2..] {
+            [CMD_USER_LIST, STATUS_NO_USERS, _, _] => return Ok(Vec::new()),
+            [CMD_USER_LIST, STATUS_OK, count, _] => count, // Count and maximum number of users.
+            _ => return Err("Invalid response".into()),
+        };
+
+        let mut users = Vec::new();
+
+        for current in 1..=count {
+            let data = self.recv_item(link, stream, CMD_USER_INFO, count, current, USER_INFO_LEN).await?;
+
+            let mut uid = [0; UID_LEN];
+            uid.copy_from_slice(&data[4..4 + UID_LEN]);
+
+            let initials = String::from_utf8_lossy(&data[4 + UID_LEN..4 + UID_LEN + 3]);
+
+            users.push(User {
+                uid,
+                initials: String::from(initials.trim_matches(|c: char| c == '\0' || c.is_whitespace())),
+            });
+        }
+
+        Ok(users)
+    }
+
+    async fn recv(&self, stream: &mut MeasStream, cmd: u8, len: usize) -> btutil::Result<Vec<u8>> {
+        // Next message of the scale, len bytes.
+
+        match stream.recv().await {
+            Some(data) if data.len() == len && data[0] == self.start && data[1] == cmd => Ok(data),
+            Some(_) => Err("Invalid response".into()),
+            None => Err("No response".into()),
+        }
+    }
+
+    async fn recv_item(&self, link: &BTLinkPtr, stream: &mut MeasStream, cmd: u8, count: u8, current: u8, len: usize) -> btutil::Result<Vec<u8>> {
+        // One of the count items of a list (numbered from 1), acknowledged.
+
+        let data = self.recv(stream, cmd, len).await?;
+
+        if data[2] != count || data[3] != current {
+            return Err("Invalid response".into());
+        }
+
+        link.write_char(MAIN_SERVICE, MAIN_CHAR, &[self.start, CMD_APP_ACK, cmd, count, current]).await?;
+
+        Ok(data)
+    }
+
+    pub fn decode_record(user: &str, data: &[u8]) -> btutil::Result<Option<DbRecord>> {
+        // Returns None for measurements taken before the clock was set. Timestamp, weight, impedance, fat, water, muscle,
+        // bone mass, BMR, AMR (not used) and BMI.
+
+        if data.len() != RECORD_LEN {
+            return Err("Invalid measurement".into());
+        }
+
+        let get = |pos: usize| u16::from_be_bytes([data[pos], data[pos + 1]]);
+        let ts = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+
+        if ts == 0 {
+            return Ok(None);
+        }
+
+        let mut record = DbRecord::new(TimeUtil::get_ts_unix(ts.into()));
+
+        if !user.is_empty() {
+            record.add_tag("user", user);
+        }
+
+        record.add_field("weight", DbFieldValue::Float(f64::from(get(4)) / 20.0)); // In 50 g.
+
+        if get(6) != 0 { // Body composition is only measured barefoot.
+            record.add_field("impedance", DbFieldValue::Float(get(6).into()));
+            record.add_field("fat", DbFieldValue::Float(f64::from(get(8)) / 10.0)); // In 0.1%.
+            record.add_field("water", DbFieldValue::Float(f64::from(get(10)) / 10.0));
+            record.add_field("muscle", DbFieldValue::Float(f64::from(get(12)) / 10.0));
+            record.add_field("bone_mass", DbFieldValue::Float(f64::from(get(14)) / 20.0));
+            record.add_field("basal_metabolism", DbFieldValue::Integer((f64::from(get(16)) * KCAL).round() as i64)); // kcal -> kJ
+        }
+
+        record.add_field("bmi", DbFieldValue::Float(f64::from(get(20)) / 10.0));
+
+        Ok(Some(record))
+    }
+}
+
+#[async_trait]
+impl Driver for DriverImpl {
+    async fn pair(&self) -> Result<(), String> {
+        self.pair().await.map_err(|e| format!("{}", e))
+    }
+
+    fn get_addr(&self) -> &Address {
+        &self.config.addr
+    }
+
+    fn get_pair_hint(&self) -> &'static str {
+        "step on the scale to wake it up, then start pairing"
+    }
+
+    async fn get_records(&self) -> Result<DbRecords, String> {
+        self.get_records().await.map_err(|e| format!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::driver::harness::Harness;
+
+    #[tokio::test]
+    async fn conformance_bf700() {
+        Harness::check(
+            "driver: Beurer_BF700\naddr: 00:11:22:33:55:00",
+            include_str!("../../../tests/fixtures/beurer_bf700/pair.txt"),
+            include_str!("../../../tests/fixtures/beurer_bf700/fetch.txt"),
+        ).await;
+    }
+
+    #[tokio::test]
+    async fn conformance_bf720() {
+        Harness::check(
+            "driver: Beurer_BF720\naddr: 00:11:22:33:55:11",
+            include_str!("../../../tests/fixtures/beurer_bf720/pair.txt"),
+            include_str!("../../../tests/fixtures/beurer_bf720/fetch.txt"),
+        ).await;
+    }
+
+    #[tokio::test]
+    async fn conformance_sbf70() {
+        Harness::check(
+            "driver: Sanitas_SBF70\naddr: 00:11:22:33:55:22",
+            include_str!("../../../tests/fixtures/sanitas_sbf70/pair.txt"),
+            include_str!("../../../tests/fixtures/sanitas_sbf70/fetch.txt"),
+        ).await;
+    }
+}
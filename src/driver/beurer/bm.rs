@@ -0,0 +1,264 @@
+//! # Beurer BM 57 / BM 64 driver
+//!
+//! The units keep their memory (per user) behind the Blood Pressure
+//! Measurement characteristic: once it is subscribed to, the unread
+//! measurements are indicated, as described in the UBPM project. Values are
+//! IEEE 11073 SFLOATs, timestamps are in the unit's local time. The unit's
+//! clock is set through the Current Time characteristic at pairing and at
+//! each data retrieval.
+
+use async_trait::async_trait;
+use bluer::Address;
+use serde::Deserialize;
+use tzfile::Tz;
+use uuid::{uuid, Uuid};
+
+use crate::btutil::{self, AdvPattern, BTLinkPtr, BTUtil};
+use crate::db::{DbFieldValue, DbRecord, DbRecords};
+use crate::driver::gatt::meas::{MeasReader, MeasStream};
+use crate::driver::{Driver, DriverContext, PairStep};
+use crate::otel::Otel;
+use crate::status::DeviceState;
+use crate::timeutil::TimeUtil;
+
+const SERVICE_ID: u16 = 0x1810;
+
+const MAIN_SERVICE: &Uuid = &uuid!("00001810-0000-1000-8000-00805f9b34fb");
+const MEAS_CHAR: &Uuid = &uuid!("00002a35-0000-1000-8000-00805f9b34fb");
+const TIME_SERVICE: &Uuid = &uuid!("00001805-0000-1000-8000-00805f9b34fb");
+const TIME_CHAR: &Uuid = &uuid!("00002a2b-0000-1000-8000-00805f9b34fb");
+
+const FLAG_KPA: u8 = 0x01;
+const FLAG_TIMESTAMP: u8 = 0x02;
+const FLAG_PULSE: u8 = 0x04;
+const FLAG_USER: u8 = 0x08;
+const FLAG_STATUS: u8 = 0x10;
+
+const STATUS_MOVEMENT: u16 = 0x0001; // Body movement detected.
+const STATUS_CUFF: u16 = 0x0002; // Cuff too loose.
+const STATUS_IRREGULAR: u16 = 0x0004; // Irregular pulse detected.
+const STATUS_POSITION: u16 = 0x0020; // Improper measurement position.
+
+const TIME_ADJUST_MANUAL: u8 = 0x01;
+const KPA: f64 = 7.50062; // [mmHg]
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    addr: Address, // TODO: unique check
+    #[serde(default = "crate::timeutil::TimeUtil::get_local_tz", deserialize_with = "crate::timeutil::TimeUtil::parse_tz")]
+    tz: Tz, // Host's timezone if unset.
+    #[serde(default)]
+    keep_connected: bool, // Do not disconnect after pairing/fetching.
+}
+
+pub struct DriverImpl {
+    ctx: DriverContext,
+    config: Config,
+}
+
+impl DriverImpl {
+    pub fn new(ctx: DriverContext, config: Config) -> Self {
+        Self {
+            ctx,
+            config,
+        }
+    }
+
+    async fn pair(&self) -> btutil::Result<()> {
+        // Pair device.
+
+        self.ctx.set_pair_step(PairStep::Discovering);
+        let link = self.ctx.get_link(&self.config.addr, true).await?;
+
+        if link.is_paired().await? {
+            return Err("Device is already paired".into());
+        }
+
+        let result = self.pair_device(&link).await;
+
+        if !self.config.keep_connected {
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn pair_device(&self, link: &BTLinkPtr) -> btutil::Result<()> {
+        self.ctx.set_pair_step(PairStep::Connecting);
+        link.connect().await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_pair_step(PairStep::Bonding);
+        link.pair().await?;
+        self.ctx.set_pair_step(PairStep::Bonded);
+
+        // Synchronize time.
+
+        self.ctx.set_pair_step(PairStep::SyncingTime);
+        self.sync_time(link).await
+    }
+
+    async fn get_records(&self) -> btutil::Result<DbRecords> {
+        // Connect to device.
+
+        let link = self.ctx.get_link(&self.config.addr, false).await?;
+
+        if !link.is_paired().await? {
+            return Err("Device is not yet paired".into());
+        }
+
+        self.ctx.set_state(DeviceState::WaitingForAdvertisement);
+        self.ctx.wait_for_adv(&self.config.addr, &[AdvPattern::service(SERVICE_ID)]).await?;
+
+        let result = BTUtil::with_deadline(&link, self.ctx.fetch_timeout, self.fetch(&link)).await;
+
+        if !self.config.keep_connected { // Notification sessions are gone by now, since fetch() has finished.
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn fetch(&self, link: &BTLinkPtr) -> btutil::Result<DbRecords> {
+        self.ctx.check_policy(link).await?;
+        self.ctx.set_state(DeviceState::Connecting);
+        Otel::span("connect", link.connect()).await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_state(DeviceState::Fetching);
+
+        let status = self.ctx.get_status(link).await;
+
+        // Subscribe first, the unit starts indicating its memory once its clock is set.
+
+        let mut stream = MeasStream::new(&self.ctx, link, MAIN_SERVICE, MEAS_CHAR).await?;
+
+        self.sync_time(link).await?;
+
+        let mut records = DbRecords::new();
+
+        while let Some(data) = stream.recv().await {
+            if let Some(record) = Self::decode_record(&self.config.tz, &data)? {
+                self.ctx.buffer.add(&record);
+                records.push(record);
+            }
+        }
+
+        records.extend(status);
+
+        Ok(records)
+    }
+
+    pub fn decode_record(tz: &Tz, data: &[u8]) -> btutil::Result<Option<DbRecord>> {
+        // Returns None for unsuccessful measurements. Flags, systolic, diastolic and mean arterial pressure, then the
+        // optional timestamp, pulse rate, user id and measurement status.
+
+        let mut reader = MeasReader::new(data);
+        let flags = reader.get_u8()?;
+        let sys = reader.get_sfloat()?;
+        let dia = reader.get_sfloat()?;
+        reader.skip(2)?; // Mean arterial pressure.
+        let ts = if flags & FLAG_TIMESTAMP != 0 { reader.get_ts(tz)? } else { None };
+        let bpm = if flags & FLAG_PULSE != 0 { reader.get_sfloat()? } else { None };
+        let user = if flags & FLAG_USER != 0 { Some(reader.get_u8()?) } else { None };
+        let status = if flags & FLAG_STATUS != 0 { Some(reader.get_u16()?) } else { None };
+
+        let (sys, dia) = match (sys, dia) {
+            (Some(sys), Some(dia)) => (sys, dia),
+            _ => return Ok(None),
+        };
+
+        let to_mmhg = |value: f64| (if flags & FLAG_KPA != 0 { value * KPA } else { value }).round() as i64;
+
+        let mut record = MeasReader::new_record(ts, user);
+        record.add_field("sys", DbFieldValue::Integer(to_mmhg(sys)));
+        record.add_field("dia", DbFieldValue::Integer(to_mmhg(dia)));
+
+        if let Some(bpm) = bpm {
+            record.add_field("bpm", DbFieldValue::Integer(bpm.round() as i64));
+        }
+
+        if let Some(status) = status {
+            record.add_field("mov", DbFieldValue::Bool(status & STATUS_MOVEMENT != 0));
+            record.add_field("ihb", DbFieldValue::Bool(status & STATUS_IRREGULAR != 0));
+            record.add_field("position", DbFieldValue::Bool(status & STATUS_POSITION != 0));
+            record.add_field("cuff", DbFieldValue::Bool(status & STATUS_CUFF != 0));
+        }
+
+        Ok(Some(record))
+    }
+
+    async fn sync_time(&self, link: &BTLinkPtr) -> btutil::Result<()> {
+        // Current Time: date time, day of week (unknown), fractions of a second, adjust reason.
+
+        let current = TimeUtil::get_current(&self.config.tz);
+        let year = current.year.to_le_bytes();
+        let data = [year[0], year[1], current.month, current.day, current.hour, current.min, current.sec, 0, 0, TIME_ADJUST_MANUAL];
+
+        link.write_char(TIME_SERVICE, TIME_CHAR, &data).await
+    }
+}
+
+#[async_trait]
+impl Driver for DriverImpl {
+    async fn pair(&self) -> Result<(), String> {
+        self.pair().await.map_err(|e| format!("{}", e))
+    }
+
+    fn get_addr(&self) -> &Address {
+        &self.config.addr
+    }
+
+    fn get_pair_hint(&self) -> &'static str {
+        "start the transfer on the unit (see instruction manual), then enter the PIN shown on the unit if asked"
+    }
+
+    async fn get_records(&self) -> Result<DbRecords, String> {
+        self.get_records().await.map_err(|e| format!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tzfile::Tz;
+
+    use crate::db::DbFieldValue;
+    use crate::driver::harness::Harness;
+    use super::DriverImpl;
+
+    #[test]
+    fn decode_record() {
+        let tz = Tz::named("Europe/Budapest").unwrap();
+        let decode = |data: &str| DriverImpl::decode_record(&tz, &hex::decode(data).unwrap());
+
+        // 120/80 mmHg, 70 bpm, improper position and loose cuff.
+
+        let record = decode("16780050006000e8070501081e0046002200").ok().flatten().unwrap();
+        assert!(matches!(record.get_field("sys"), Some(DbFieldValue::Integer(120))));
+        assert!(matches!(record.get_field("position"), Some(DbFieldValue::Bool(true))));
+        assert!(matches!(record.get_field("cuff"), Some(DbFieldValue::Bool(true))));
+        assert!(matches!(record.get_field("mov"), Some(DbFieldValue::Bool(false))));
+
+        assert!(decode("047800500060004600").ok().flatten().unwrap().get_field("position").is_none()); // No status.
+        assert!(decode("16780050006000e8070501081e00460022").is_err()); // Truncated status.
+    }
+
+    #[tokio::test]
+    async fn conformance_bm57() {
+        Harness::check(
+            "driver: Beurer_BM57\naddr: 00:11:22:33:44:88\ntz: Europe/Budapest",
+            include_str!("../../../tests/fixtures/beurer_bm57/pair.txt"),
+            include_str!("../../../tests/fixtures/beurer_bm57/fetch.txt"),
+        ).await;
+    }
+
+    #[tokio::test]
+    async fn conformance_bm64() {
+        Harness::check(
+            "driver: Beurer_BM64\naddr: 00:11:22:33:44:99\ntz: Europe/Budapest",
+            include_str!("../../../tests/fixtures/beurer_bm64/pair.txt"),
+            include_str!("../../../tests/fixtures/beurer_bm64/fetch.txt"),
+        ).await;
+    }
+}
@@ -0,0 +1,2 @@
+pub mod bf;
+pub mod bm;
@@ -0,0 +1,116 @@
+//! # Device fingerprints
+//!
+//! Maps what a unit tells about itself (device information, advertisement)
+//! to the driver handling it, see fingerprints.toml. Used to verify the unit
+//! before talking to it, and to suggest the right driver for a unit that is
+//! known but configured with another one. The check can be relaxed to the
+//! manufacturer (e.g. for rebranded units) or turned off. Drivers for
+//! standard profiles are generic: any unit is accepted. Drivers whose
+//! fingerprints are assumed (not confirmed on a unit) are checked relaxed
+//! by default, an unknown unit is only warned about.
+
+use serde::Deserialize;
+use std::sync::OnceLock;
+
+use crate::btutil::BTDeviceInfo;
+
+const GENERIC_DRIVERS: &[&str] = &["GATT_Body_Composition", "GATT_Glucose", "GATT_Health_Thermometer", "GATT_Heart_Rate", "GATT_Weight_Scale"];
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentityCheck {
+    Strict, // Manufacturer and model must be known for the driver.
+    Relaxed, // Manufacturer must be known for the driver, an unknown model is only warned about (any unknown unit, if the driver's fingerprints are assumed).
+    Off,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FingerprintFile {
+    device: Vec<Fingerprint>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fingerprint {
+    pub manufacturer: String,
+    pub model: String,
+    #[serde(deserialize_with = "hex::serde::deserialize")]
+    pub pattern: Vec<u8>, // Start of manufacturer specific data in advertisements, company id first.
+    pub driver: String,
+    #[serde(default)]
+    pub assumed: bool, // Manufacturer and model strings are not confirmed by a device information dump.
+}
+
+impl IdentityCheck {
+    pub fn get_default(driver: &str) -> Self {
+        if Fingerprint::is_assumed(driver) { IdentityCheck::Relaxed } else { IdentityCheck::Strict }
+    }
+}
+
+impl Fingerprint {
+    pub fn get_all() -> &'static [Fingerprint] {
+        // Embedded and covered by tests, an invalid table is a bug.
+
+        static LOADED: OnceLock<Vec<Fingerprint>> = OnceLock::new();
+
+        LOADED.get_or_init(|| match toml::from_str::<FingerprintFile>(include_str!("fingerprints.toml")) {
+            Ok(file) => file.device,
+            Err(e) => panic!("Invalid fingerprint table: {}", e),
+        })
+    }
+
+    pub fn find(device_info: &BTDeviceInfo) -> Option<&'static Fingerprint> {
+        Self::get_all().iter().find(|fingerprint| fingerprint.manufacturer == device_info.manufacturer && fingerprint.model == device_info.model)
+    }
+
+    pub fn find_by_adv(data: &[u8]) -> impl Iterator<Item = &'static Fingerprint> + '_ { // Candidates, advertisements are less specific than device information.
+        Self::get_all().iter().filter(move |fingerprint| data.starts_with(&fingerprint.pattern))
+    }
+
+    pub fn check(driver: &str, device_info: &BTDeviceInfo) -> Result<(), String> {
+        match Self::find(device_info) {
+            Some(fingerprint) if fingerprint.driver == driver => Ok(()),
+            Some(fingerprint) => Err(format!("Device is a {} {}, use driver {}", device_info.manufacturer, device_info.model, fingerprint.driver)),
+            None => Err(format!("Unknown device: {} {}", device_info.manufacturer, device_info.model)),
+        }
+    }
+
+    pub fn is_generic(driver: &str) -> bool {
+        GENERIC_DRIVERS.contains(&driver)
+    }
+
+    pub fn is_manufacturer(driver: &str, manufacturer: &str) -> bool {
+        Self::get_all().iter().any(|fingerprint| fingerprint.driver == driver && fingerprint.manufacturer == manufacturer)
+    }
+
+    pub fn is_assumed(driver: &str) -> bool {
+        let mut fingerprints = Self::get_all().iter().filter(|fingerprint| fingerprint.driver == driver).peekable();
+        fingerprints.peek().is_some() && fingerprints.all(|fingerprint| fingerprint.assumed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::Fingerprint;
+
+    #[test]
+    fn table() {
+        let mut seen = HashSet::new();
+
+        for fingerprint in Fingerprint::get_all() {
+            assert!(seen.insert((&fingerprint.manufacturer, &fingerprint.model)), "duplicated: {} {}", fingerprint.manufacturer, fingerprint.model);
+            assert!(fingerprint.pattern.len() >= 2, "no company id: {} {}", fingerprint.manufacturer, fingerprint.model);
+        }
+    }
+
+    #[test]
+    fn assumed() {
+        assert!(Fingerprint::is_assumed("Beurer_BF700"));
+        assert!(Fingerprint::is_assumed("Withings_Body"));
+        assert!(!Fingerprint::is_assumed("Omron_HEM_7361T"));
+        assert!(!Fingerprint::is_assumed("GATT_Weight_Scale")); // Generic, no fingerprints.
+    }
+}
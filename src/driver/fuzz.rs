@@ -0,0 +1,138 @@
+//! # Fuzzing entry points
+//!
+//! Feed arbitrary data (as received over the radio) to the packet and record
+//! parsers, used by the cargo-fuzz targets in fuzz/. Errors are fine, panics
+//! are not.
+
+use std::sync::OnceLock;
+use tzfile::Tz;
+
+use super::beurer::{bf, bm};
+use super::gatt::{body_composition, glucose, health_thermometer, heart_rate, weight_scale};
+use super::omron::btcomm::BTComm;
+use super::omron::{hbf_702t, hem, hn_300t2};
+use super::omron::model::Model;
+use super::withings::{body, thermo};
+use super::withings::wpp::WppPkt;
+use super::xiaomi::xmtzc05hm;
+
+const OMRON_REC_LEN: usize = 0x10;
+
+fn get_tz() -> &'static Tz {
+    // Has DST transitions, so ambiguous and nonexistent local times are covered.
+
+    static TZ: OnceLock<Tz> = OnceLock::new();
+    TZ.get_or_init(|| Tz::named("Europe/Budapest").expect("unable to open timezone"))
+}
+
+pub fn beurer_bf_record(data: &[u8]) {
+    let _ = bf::DriverImpl::decode_record("", data);
+}
+
+pub fn beurer_bm_meas(data: &[u8]) {
+    let _ = bm::DriverImpl::decode_record(get_tz(), data);
+}
+
+pub fn gatt_body_composition_meas(data: &[u8]) {
+    let _ = body_composition::DriverImpl::decode_record(get_tz(), data);
+}
+
+pub fn gatt_glucose_meas(data: &[u8]) {
+    let _ = glucose::DriverImpl::decode_record(get_tz(), data);
+    let _ = glucose::DriverImpl::decode_context(data);
+}
+
+pub fn gatt_health_thermometer_meas(data: &[u8]) {
+    let _ = health_thermometer::DriverImpl::decode_record(get_tz(), data);
+}
+
+pub fn gatt_heart_rate_meas(data: &[u8]) {
+    let _ = heart_rate::DriverImpl::decode_meas(data);
+}
+
+pub fn gatt_weight_scale_meas(data: &[u8]) {
+    let _ = weight_scale::DriverImpl::decode_record(get_tz(), data);
+}
+
+pub fn omron_resp(data: &[u8]) {
+    // Expected address (2 bytes) and length (1 byte) of EEPROM read, followed by the response packet.
+
+    if data.len() < 3 {
+        return;
+    }
+
+    let addr = (data[0] as u16) << 8 | (data[1] as u16);
+    let todo = data[2] as usize;
+
+    if let Ok(resp) = BTComm::decode_resp(&data[3..]) {
+        let _ = BTComm::decode_read_resp(addr, todo, &resp);
+    }
+}
+
+pub fn omron_hem_7361t_record(data: &[u8]) {
+    omron_hem_record("hem_7361t", data);
+}
+
+pub fn omron_hem_7322t_record(data: &[u8]) {
+    omron_hem_record("hem_7322t", data);
+}
+
+pub fn omron_hem_7155t_record(data: &[u8]) {
+    omron_hem_record("hem_7155t", data);
+}
+
+pub fn omron_hem_7143t_record(data: &[u8]) {
+    omron_hem_record("hem_7143t", data);
+}
+
+pub fn omron_hem_6232t_record(data: &[u8]) {
+    omron_hem_record("hem_6232t", data);
+}
+
+pub fn omron_bp7900_record(data: &[u8]) {
+    omron_hem_record("bp7900", data);
+}
+
+pub fn omron_bp7900_ecg_record(data: &[u8]) {
+    let layout = &Model::get("bp7900").ecg.as_ref().expect("bp7900 has no ECG section").record;
+
+    for chunk in data.chunks(layout.len) {
+        let _ = hem::DriverImpl::decode_ecg_record(layout, get_tz(), 0, chunk);
+    }
+}
+
+fn omron_hem_record(key: &str, data: &[u8]) {
+    let model = Model::get(key);
+
+    for chunk in data.chunks(model.record.len) {
+        let _ = hem::DriverImpl::decode_record(model, get_tz(), 0, chunk);
+    }
+}
+
+pub fn omron_hbf_702t_record(data: &[u8]) {
+    for chunk in data.chunks(OMRON_REC_LEN) {
+        let _ = hbf_702t::DriverImpl::decode_record(get_tz(), 0, chunk);
+    }
+}
+
+pub fn omron_hn_300t2_record(data: &[u8]) {
+    for chunk in data.chunks(OMRON_REC_LEN) {
+        let _ = hn_300t2::DriverImpl::decode_record(get_tz(), None, chunk);
+    }
+}
+
+pub fn withings_body_pkt(data: &[u8]) {
+    if let Ok(pkt) = WppPkt::decode(data) {
+        let _ = body::DriverImpl::decode_record(&pkt);
+    }
+}
+
+pub fn withings_thermo_pkt(data: &[u8]) {
+    if let Ok(pkt) = WppPkt::decode(data) {
+        let _ = thermo::DriverImpl::decode_record(&pkt);
+    }
+}
+
+pub fn xiaomi_xmtzc05hm_adv(data: &[u8]) {
+    let _ = xmtzc05hm::DriverImpl::decode_record(get_tz(), data);
+}
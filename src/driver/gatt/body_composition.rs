@@ -0,0 +1,281 @@
+//! # Bluetooth Body Composition Service driver
+//!
+//! For any scale implementing the standard Body Composition Service (0x181B):
+//! stored measurements are indicated on the Body Composition Measurement
+//! characteristic once it is subscribed to (see meas.rs). A measurement not
+//! fitting into one packet is split into two, both flagged. Units without
+//! memory (live) notify their readings during the weigh-in instead, the last
+//! one is the result. The unit's clock is not set, use the vendor app for that
+//! if it drifts.
+
+use async_trait::async_trait;
+use bluer::Address;
+use serde::Deserialize;
+use tzfile::Tz;
+use uuid::{uuid, Uuid};
+
+use crate::btutil::{self, AdvPattern, BTLinkPtr, BTUtil};
+use crate::db::{DbFieldValue, DbRecord, DbRecords};
+use crate::driver::{Driver, DriverContext, PairStep};
+use crate::otel::Otel;
+use crate::status::DeviceState;
+use super::meas::{MeasReader, MeasStream};
+
+const SERVICE_ID: u16 = 0x181b;
+const WEIGHT_SCALE_SERVICE_ID: u16 = 0x181d; // Advertised instead by some units.
+
+const MAIN_SERVICE: &Uuid = &uuid!("0000181b-0000-1000-8000-00805f9b34fb");
+const MEAS_CHAR: &Uuid = &uuid!("00002a9c-0000-1000-8000-00805f9b34fb");
+
+const FLAG_IMPERIAL: u16 = 0x0001;
+const FLAG_TIMESTAMP: u16 = 0x0002;
+const FLAG_USER: u16 = 0x0004;
+const FLAG_BASAL_METABOLISM: u16 = 0x0008;
+const FLAG_MUSCLE: u16 = 0x0010;
+const FLAG_MUSCLE_MASS: u16 = 0x0020;
+const FLAG_FAT_FREE_MASS: u16 = 0x0040;
+const FLAG_SOFT_LEAN_MASS: u16 = 0x0080;
+const FLAG_BODY_WATER: u16 = 0x0100;
+const FLAG_IMPEDANCE: u16 = 0x0200;
+const FLAG_WEIGHT: u16 = 0x0400;
+const FLAG_HEIGHT: u16 = 0x0800;
+const FLAG_MULTI_PACKET: u16 = 0x1000;
+
+const FAT_UNKNOWN: u16 = 0xffff; // Measurement unsuccessful.
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    addr: Address, // TODO: unique check
+    #[serde(default = "crate::timeutil::TimeUtil::get_local_tz", deserialize_with = "crate::timeutil::TimeUtil::parse_tz")]
+    tz: Tz, // Host's timezone if unset.
+    #[serde(default)]
+    keep_connected: bool, // Do not disconnect after pairing/fetching.
+    #[serde(default)]
+    live: bool, // The unit has no memory, it notifies readings during the weigh-in: only the last (settled) one is kept.
+}
+
+pub struct DriverImpl {
+    ctx: DriverContext,
+    config: Config,
+}
+
+impl DriverImpl {
+    pub fn new(ctx: DriverContext, config: Config) -> Self {
+        Self {
+            ctx,
+            config,
+        }
+    }
+
+    async fn pair(&self) -> btutil::Result<()> {
+        // Pair device.
+
+        self.ctx.set_pair_step(PairStep::Discovering);
+        let link = self.ctx.get_link(&self.config.addr, true).await?;
+
+        if link.is_paired().await? {
+            return Err("Device is already paired".into());
+        }
+
+        let result = self.pair_device(&link).await;
+
+        if !self.config.keep_connected {
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn pair_device(&self, link: &BTLinkPtr) -> btutil::Result<()> {
+        self.ctx.set_pair_step(PairStep::Connecting);
+        link.connect().await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_pair_step(PairStep::Bonding);
+        link.pair().await?;
+        self.ctx.set_pair_step(PairStep::Bonded);
+
+        Ok(())
+    }
+
+    async fn get_records(&self) -> btutil::Result<DbRecords> {
+        // Connect to device.
+
+        let link = self.ctx.get_link(&self.config.addr, false).await?;
+
+        if !link.is_paired().await? {
+            return Err("Device is not yet paired".into());
+        }
+
+        self.ctx.set_state(DeviceState::WaitingForAdvertisement);
+        self.ctx.wait_for_adv(&self.config.addr, &[AdvPattern::service(SERVICE_ID), AdvPattern::service(WEIGHT_SCALE_SERVICE_ID)]).await?;
+
+        let result = BTUtil::with_deadline(&link, self.ctx.fetch_timeout, self.fetch(&link)).await;
+
+        if !self.config.keep_connected { // Notification sessions are gone by now, since fetch() has finished.
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn fetch(&self, link: &BTLinkPtr) -> btutil::Result<DbRecords> {
+        self.ctx.check_policy(link).await?;
+        self.ctx.set_state(DeviceState::Connecting);
+        Otel::span("connect", link.connect()).await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_state(DeviceState::Fetching);
+
+        let status = self.ctx.get_status(link).await;
+
+        // Fetch measurements: subscribing makes the unit indicate the stored ones.
+
+        let mut records = DbRecords::new();
+        let mut stream = MeasStream::new(&self.ctx, link, MAIN_SERVICE, MEAS_CHAR).await?;
+
+        let mut first = None; // First packet of a split measurement.
+
+        while let Some(data) = stream.recv().await {
+            let record = Self::decode_record(&self.config.tz, &data)?;
+
+            let record = if Self::is_split(&data) {
+                match first.take() {
+                    Some(first) => Self::merge(first, record),
+                    None => {
+                        first = Some(record);
+                        continue;
+                    }
+                }
+            } else {
+                record
+            };
+
+            if let Some(record) = record {
+                if self.config.live {
+                    records.clear(); // Superseded interim reading, not worth uploading if the weigh-in is cut short.
+                } else {
+                    self.ctx.buffer.add(&record);
+                }
+
+                records.push(record);
+            }
+        }
+
+        if first.is_some() {
+            return Err("Incomplete measurement".into());
+        }
+
+        records.extend(status);
+
+        Ok(records)
+    }
+
+    pub fn decode_record(tz: &Tz, data: &[u8]) -> btutil::Result<Option<DbRecord>> {
+        // Returns None for unsuccessful measurements. Flags, body fat, then the optional values in the order of the flags.
+
+        let mut reader = MeasReader::new(data);
+        let flags = reader.get_u16()?;
+        let imperial = flags & FLAG_IMPERIAL != 0;
+
+        let fat = reader.get_u16()?;
+        let ts = if flags & FLAG_TIMESTAMP != 0 { reader.get_ts(tz)? } else { None };
+        let user = if flags & FLAG_USER != 0 { Some(reader.get_u8()?) } else { None };
+
+        let mut values = Vec::new();
+
+        for (flag, field) in [
+            (FLAG_BASAL_METABOLISM, "basal_metabolism"),
+            (FLAG_MUSCLE, "muscle"),
+            (FLAG_MUSCLE_MASS, "muscle_mass"),
+            (FLAG_FAT_FREE_MASS, "fat_free_mass"),
+            (FLAG_SOFT_LEAN_MASS, "soft_lean_mass"),
+            (FLAG_BODY_WATER, "body_water"),
+            (FLAG_IMPEDANCE, "impedance"),
+            (FLAG_WEIGHT, "weight"),
+            (FLAG_HEIGHT, "height"),
+        ] {
+            if flags & flag != 0 {
+                values.push((flag, field, reader.get_u16()?));
+            }
+        }
+
+        if fat == FAT_UNKNOWN {
+            return Ok(None);
+        }
+
+        let mut record = MeasReader::new_record(ts, user);
+        record.add_field("fat", DbFieldValue::Float((fat as f64) / 10.0)); // Body fat in 0.1%.
+
+        for (flag, field, raw) in values {
+            let value = match flag {
+                FLAG_BASAL_METABOLISM => DbFieldValue::Integer(raw.into()), // In kJ.
+                FLAG_MUSCLE => DbFieldValue::Float((raw as f64) / 10.0), // In 0.1%.
+                FLAG_IMPEDANCE => DbFieldValue::Float((raw as f64) / 10.0), // In 0.1 Ω.
+                FLAG_HEIGHT => DbFieldValue::Float(MeasReader::get_height(raw, imperial)),
+                _ => DbFieldValue::Float(MeasReader::get_mass(raw, imperial)),
+            };
+
+            record.add_field(field, value);
+        }
+
+        Ok(Some(record))
+    }
+
+    fn is_split(data: &[u8]) -> bool {
+        // data is a decoded measurement.
+
+        u16::from_le_bytes([data[0], data[1]]) & FLAG_MULTI_PACKET != 0
+    }
+
+    fn merge(first: Option<DbRecord>, second: Option<DbRecord>) -> Option<DbRecord> {
+        // The time and user of the split measurement are taken from its first packet.
+
+        let (mut first, second) = (first?, second?);
+
+        for (key, value) in second.get_fields() {
+            first.add_field(key, value.clone());
+        }
+
+        Some(first)
+    }
+}
+
+#[async_trait]
+impl Driver for DriverImpl {
+    async fn pair(&self) -> Result<(), String> {
+        self.pair().await.map_err(|e| format!("{}", e))
+    }
+
+    fn get_addr(&self) -> &Address {
+        &self.config.addr
+    }
+
+    async fn get_records(&self) -> Result<DbRecords, String> {
+        self.get_records().await.map_err(|e| format!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::driver::harness::Harness;
+
+    #[tokio::test]
+    async fn conformance() {
+        Harness::check(
+            "driver: GATT_Body_Composition\naddr: 00:11:22:33:44:55\ntz: Europe/Budapest",
+            include_str!("../../../tests/fixtures/gatt_body_composition/pair.txt"),
+            include_str!("../../../tests/fixtures/gatt_body_composition/fetch.txt"),
+        ).await;
+    }
+
+    #[tokio::test]
+    async fn conformance_live() {
+        Harness::check(
+            "driver: GATT_Body_Composition\naddr: 00:11:22:33:44:55\ntz: Europe/Budapest\nlive: true",
+            include_str!("../../../tests/fixtures/gatt_body_composition/pair.txt"),
+            include_str!("../../../tests/fixtures/gatt_body_composition/fetch_live.txt"),
+        ).await;
+    }
+}
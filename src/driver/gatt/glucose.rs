@@ -0,0 +1,308 @@
+//! # Bluetooth Glucose Service driver
+//!
+//! For any meter implementing the standard Glucose Service (0x1808), e.g.
+//! the Contour Next One. Unlike the units indicating their records on
+//! subscription, stored records are requested through the Record Access
+//! Control Point (RACP): the unit notifies the measurements (and their
+//! context, e.g. meal), then indicates the outcome on the RACP. Readings of
+//! control solution are discarded. The unit's clock is not set, use the
+//! vendor app for that if it drifts.
+
+use async_trait::async_trait;
+use bluer::Address;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tzfile::Tz;
+use uuid::{uuid, Uuid};
+
+use crate::btutil::{self, AdvPattern, BTLinkPtr, BTUtil};
+use crate::db::{DbFieldValue, DbRecord, DbRecords};
+use crate::driver::{Driver, DriverContext, PairStep};
+use crate::otel::Otel;
+use crate::status::DeviceState;
+use super::meas::{MeasReader, MeasStream};
+
+const SERVICE_ID: u16 = 0x1808;
+
+const MAIN_SERVICE: &Uuid = &uuid!("00001808-0000-1000-8000-00805f9b34fb");
+const MEAS_CHAR: &Uuid = &uuid!("00002a18-0000-1000-8000-00805f9b34fb");
+const CONTEXT_CHAR: &Uuid = &uuid!("00002a34-0000-1000-8000-00805f9b34fb");
+const RACP_CHAR: &Uuid = &uuid!("00002a52-0000-1000-8000-00805f9b34fb");
+
+const RACP_REPORT: u8 = 0x01; // Report stored records.
+const RACP_RESPONSE: u8 = 0x06;
+const RACP_ALL: u8 = 0x01; // Operator: all records.
+const RACP_NULL: u8 = 0x00;
+const RACP_SUCCESS: u8 = 0x01;
+const RACP_NO_RECORDS: u8 = 0x06;
+
+const FLAG_TIME_OFFSET: u8 = 0x01;
+const FLAG_CONCENTRATION: u8 = 0x02;
+const FLAG_MOL: u8 = 0x04; // Concentration in mol/L instead of kg/L.
+const FLAG_STATUS: u8 = 0x08;
+
+const CONTEXT_FLAG_CARB: u8 = 0x01;
+const CONTEXT_FLAG_MEAL: u8 = 0x02;
+const CONTEXT_FLAG_TESTER: u8 = 0x04;
+const CONTEXT_FLAG_EXERCISE: u8 = 0x08;
+const CONTEXT_FLAG_MEDICATION: u8 = 0x10;
+const CONTEXT_FLAG_HBA1C: u8 = 0x40;
+const CONTEXT_FLAG_EXTENDED: u8 = 0x80;
+
+const LOCATION_CONTROL: u8 = 0x04; // Sample location: control solution.
+const GLUCOSE_MOLAR_MASS: f64 = 180.16; // [g/mol]
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    addr: Address, // TODO: unique check
+    #[serde(default = "crate::timeutil::TimeUtil::get_local_tz", deserialize_with = "crate::timeutil::TimeUtil::parse_tz")]
+    tz: Tz, // Host's timezone if unset.
+    #[serde(default)]
+    keep_connected: bool, // Do not disconnect after pairing/fetching.
+}
+
+pub struct DriverImpl {
+    ctx: DriverContext,
+    config: Config,
+}
+
+impl DriverImpl {
+    pub fn new(ctx: DriverContext, config: Config) -> Self {
+        Self {
+            ctx,
+            config,
+        }
+    }
+
+    async fn pair(&self) -> btutil::Result<()> {
+        // Pair device.
+
+        self.ctx.set_pair_step(PairStep::Discovering);
+        let link = self.ctx.get_link(&self.config.addr, true).await?;
+
+        if link.is_paired().await? {
+            return Err("Device is already paired".into());
+        }
+
+        let result = self.pair_device(&link).await;
+
+        if !self.config.keep_connected {
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn pair_device(&self, link: &BTLinkPtr) -> btutil::Result<()> {
+        self.ctx.set_pair_step(PairStep::Connecting);
+        link.connect().await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_pair_step(PairStep::Bonding);
+        link.pair().await?;
+        self.ctx.set_pair_step(PairStep::Bonded);
+
+        Ok(())
+    }
+
+    async fn get_records(&self) -> btutil::Result<DbRecords> {
+        // Connect to device.
+
+        let link = self.ctx.get_link(&self.config.addr, false).await?;
+
+        if !link.is_paired().await? {
+            return Err("Device is not yet paired".into());
+        }
+
+        self.ctx.set_state(DeviceState::WaitingForAdvertisement);
+        self.ctx.wait_for_adv(&self.config.addr, &[AdvPattern::service(SERVICE_ID)]).await?;
+
+        let result = BTUtil::with_deadline(&link, self.ctx.fetch_timeout, self.fetch(&link)).await;
+
+        if !self.config.keep_connected { // Notification sessions are gone by now, since fetch() has finished.
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn fetch(&self, link: &BTLinkPtr) -> btutil::Result<DbRecords> {
+        self.ctx.check_policy(link).await?;
+        self.ctx.set_state(DeviceState::Connecting);
+        Otel::span("connect", link.connect()).await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_state(DeviceState::Fetching);
+
+        let status = self.ctx.get_status(link).await;
+
+        // Request all stored records, they are matched with their context by sequence number.
+
+        let mut meas_stream = MeasStream::new(&self.ctx, link, MAIN_SERVICE, MEAS_CHAR).await?;
+        let mut context_stream = MeasStream::new(&self.ctx, link, MAIN_SERVICE, CONTEXT_CHAR).await?;
+        let mut racp_stream = MeasStream::new(&self.ctx, link, MAIN_SERVICE, RACP_CHAR).await?;
+
+        link.write_char(MAIN_SERVICE, RACP_CHAR, &[RACP_REPORT, RACP_ALL]).await?;
+
+        let mut measurements = Vec::new();
+        let mut meals = HashMap::new();
+        let (mut meas_open, mut context_open) = (true, true);
+
+        loop {
+            // Measurements sent before the RACP response are taken first.
+
+            tokio::select! {
+                biased;
+                data = meas_stream.recv(), if meas_open => match data {
+                    Some(data) => measurements.extend(Self::decode_record(&self.config.tz, &data)?),
+                    None => meas_open = false,
+                },
+                data = context_stream.recv(), if context_open => match data {
+                    Some(data) => meals.extend(Self::decode_context(&data)?),
+                    None => context_open = false,
+                },
+                data = racp_stream.recv() => match data {
+                    Some(data) => {
+                        Self::check_racp(&data)?;
+                        break;
+                    },
+                    None => return Err("No response to record request".into()),
+                },
+            }
+        }
+
+        let mut records = DbRecords::new();
+
+        for (seq, mut record) in measurements {
+            if let Some(meal) = meals.get(&seq) {
+                record.add_tag("meal", meal);
+            }
+
+            self.ctx.buffer.add(&record);
+            records.push(record);
+        }
+
+        records.extend(status);
+
+        Ok(records)
+    }
+
+    fn check_racp(data: &[u8]) -> btutil::Result<()> {
+        if data.len() != 4 || data[0] != RACP_RESPONSE || data[1] != RACP_NULL || data[2] != RACP_REPORT {
+            return Err("Invalid response".into());
+        }
+
+        match data[3] {
+            RACP_SUCCESS | RACP_NO_RECORDS => Ok(()),
+            code => Err(btutil::Error::General(format!("Record request failed: {:02x}", code))),
+        }
+    }
+
+    pub fn decode_record(tz: &Tz, data: &[u8]) -> btutil::Result<Option<(u16, DbRecord)>> {
+        // Returns None for readings without a concentration or of control solution. Flags, sequence number, base time,
+        // then the optional time offset, concentration with type/sample location and sensor status.
+
+        let mut reader = MeasReader::new(data);
+        let flags = reader.get_u8()?;
+        let seq = reader.get_u16()?;
+        let ts = reader.get_ts(tz)?;
+        let offset = if flags & FLAG_TIME_OFFSET != 0 { reader.get_i16()? } else { 0 }; // [min]
+        let concentration = if flags & FLAG_CONCENTRATION != 0 { Some((reader.get_sfloat()?, reader.get_u8()?)) } else { None };
+        let status = if flags & FLAG_STATUS != 0 { Some(reader.get_u16()?) } else { None };
+
+        let (concentration, location) = match concentration {
+            Some((Some(concentration), type_location)) => (concentration, type_location >> 4),
+            _ => return Ok(None),
+        };
+
+        if location == LOCATION_CONTROL {
+            return Ok(None);
+        }
+
+        let glucose = if flags & FLAG_MOL != 0 {
+            concentration * 1000.0 * GLUCOSE_MOLAR_MASS / 10.0 // mol/L -> mg/dL
+        } else {
+            concentration * 100000.0 // kg/L -> mg/dL
+        };
+
+        let mut record = MeasReader::new_record(ts.map(|ts| ts + i64::from(offset) * 60_000_000_000), None);
+        record.add_field("glucose", DbFieldValue::Float((glucose * 10.0).round() / 10.0)); // SFLOAT has a decimal mantissa, undo binary noise.
+
+        if let Some(status) = status.filter(|status| *status != 0) {
+            record.add_field("sensor_status", DbFieldValue::Integer(status.into())); // Sensor status annunciation bits.
+        }
+
+        Ok(Some((seq, record)))
+    }
+
+    pub fn decode_context(data: &[u8]) -> btutil::Result<Option<(u16, &'static str)>> {
+        // Returns the meal of the measurement with the sequence number, None if not given.
+
+        let mut reader = MeasReader::new(data);
+        let flags = reader.get_u8()?;
+        let seq = reader.get_u16()?;
+
+        if flags & CONTEXT_FLAG_EXTENDED != 0 {
+            reader.skip(1)?;
+        }
+
+        if flags & CONTEXT_FLAG_CARB != 0 {
+            reader.skip(3)?; // Carbohydrate id and amount.
+        }
+
+        let meal = if flags & CONTEXT_FLAG_MEAL != 0 { Some(reader.get_u8()?) } else { None };
+
+        for (flag, len) in [(CONTEXT_FLAG_TESTER, 1), (CONTEXT_FLAG_EXERCISE, 3), (CONTEXT_FLAG_MEDICATION, 3), (CONTEXT_FLAG_HBA1C, 2)] {
+            if flags & flag != 0 {
+                reader.skip(len)?;
+            }
+        }
+
+        Ok(meal.map(|meal| (seq, Self::get_meal(meal))))
+    }
+
+    fn get_meal(meal: u8) -> &'static str {
+        match meal {
+            0x01 => "before",
+            0x02 => "after",
+            0x03 => "fasting",
+            0x04 => "casual",
+            0x05 => "bedtime",
+            _ => "unknown",
+        }
+    }
+}
+
+#[async_trait]
+impl Driver for DriverImpl {
+    async fn pair(&self) -> Result<(), String> {
+        self.pair().await.map_err(|e| format!("{}", e))
+    }
+
+    fn get_addr(&self) -> &Address {
+        &self.config.addr
+    }
+
+    fn get_pair_hint(&self) -> &'static str {
+        "put the unit in pairing mode (see instruction manual), then enter the PIN shown on the unit if asked"
+    }
+
+    async fn get_records(&self) -> Result<DbRecords, String> {
+        self.get_records().await.map_err(|e| format!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::driver::harness::Harness;
+
+    #[tokio::test]
+    async fn conformance() {
+        Harness::check(
+            "driver: GATT_Glucose\naddr: 00:11:22:33:44:66\ntz: Europe/Budapest",
+            include_str!("../../../tests/fixtures/gatt_glucose/pair.txt"),
+            include_str!("../../../tests/fixtures/gatt_glucose/fetch.txt"),
+        ).await;
+    }
+}
@@ -0,0 +1,204 @@
+//! # Bluetooth Health Thermometer Service driver
+//!
+//! For any thermometer implementing the standard Health Thermometer Service
+//! (0x1809): stored measurements are indicated on the Temperature Measurement
+//! characteristic once it is subscribed to (see meas.rs). Intermediate
+//! (still measuring) temperatures are not fetched. The unit's clock is not
+//! set.
+
+use async_trait::async_trait;
+use bluer::Address;
+use serde::Deserialize;
+use tzfile::Tz;
+use uuid::{uuid, Uuid};
+
+use crate::btutil::{self, AdvPattern, BTLinkPtr, BTUtil};
+use crate::db::{DbFieldValue, DbRecord, DbRecords};
+use crate::driver::{Driver, DriverContext, PairStep};
+use crate::otel::Otel;
+use crate::status::DeviceState;
+use super::meas::{MeasReader, MeasStream};
+
+const SERVICE_ID: u16 = 0x1809;
+
+const MAIN_SERVICE: &Uuid = &uuid!("00001809-0000-1000-8000-00805f9b34fb");
+const MEAS_CHAR: &Uuid = &uuid!("00002a1c-0000-1000-8000-00805f9b34fb");
+
+const FLAG_FAHRENHEIT: u8 = 0x01;
+const FLAG_TIMESTAMP: u8 = 0x02;
+const FLAG_TYPE: u8 = 0x04;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    addr: Address, // TODO: unique check
+    #[serde(default = "crate::timeutil::TimeUtil::get_local_tz", deserialize_with = "crate::timeutil::TimeUtil::parse_tz")]
+    tz: Tz, // Host's timezone if unset.
+    #[serde(default)]
+    keep_connected: bool, // Do not disconnect after pairing/fetching.
+}
+
+pub struct DriverImpl {
+    ctx: DriverContext,
+    config: Config,
+}
+
+impl DriverImpl {
+    pub fn new(ctx: DriverContext, config: Config) -> Self {
+        Self {
+            ctx,
+            config,
+        }
+    }
+
+    async fn pair(&self) -> btutil::Result<()> {
+        // Pair device.
+
+        self.ctx.set_pair_step(PairStep::Discovering);
+        let link = self.ctx.get_link(&self.config.addr, true).await?;
+
+        if link.is_paired().await? {
+            return Err("Device is already paired".into());
+        }
+
+        let result = self.pair_device(&link).await;
+
+        if !self.config.keep_connected {
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn pair_device(&self, link: &BTLinkPtr) -> btutil::Result<()> {
+        self.ctx.set_pair_step(PairStep::Connecting);
+        link.connect().await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_pair_step(PairStep::Bonding);
+        link.pair().await?;
+        self.ctx.set_pair_step(PairStep::Bonded);
+
+        Ok(())
+    }
+
+    async fn get_records(&self) -> btutil::Result<DbRecords> {
+        // Connect to device.
+
+        let link = self.ctx.get_link(&self.config.addr, false).await?;
+
+        if !link.is_paired().await? {
+            return Err("Device is not yet paired".into());
+        }
+
+        self.ctx.set_state(DeviceState::WaitingForAdvertisement);
+        self.ctx.wait_for_adv(&self.config.addr, &[AdvPattern::service(SERVICE_ID)]).await?;
+
+        let result = BTUtil::with_deadline(&link, self.ctx.fetch_timeout, self.fetch(&link)).await;
+
+        if !self.config.keep_connected { // Notification sessions are gone by now, since fetch() has finished.
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn fetch(&self, link: &BTLinkPtr) -> btutil::Result<DbRecords> {
+        self.ctx.check_policy(link).await?;
+        self.ctx.set_state(DeviceState::Connecting);
+        Otel::span("connect", link.connect()).await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_state(DeviceState::Fetching);
+
+        let status = self.ctx.get_status(link).await;
+
+        // Fetch measurements: subscribing makes the unit indicate the stored ones.
+
+        let mut records = DbRecords::new();
+        let mut stream = MeasStream::new(&self.ctx, link, MAIN_SERVICE, MEAS_CHAR).await?;
+
+        while let Some(data) = stream.recv().await {
+            if let Some(record) = Self::decode_record(&self.config.tz, &data)? {
+                self.ctx.buffer.add(&record);
+                records.push(record);
+            }
+        }
+
+        records.extend(status);
+
+        Ok(records)
+    }
+
+    pub fn decode_record(tz: &Tz, data: &[u8]) -> btutil::Result<Option<DbRecord>> {
+        // Returns None for unsuccessful measurements (NaN). Flags, temperature, then the optional timestamp and
+        // temperature type (site).
+
+        let mut reader = MeasReader::new(data);
+        let flags = reader.get_u8()?;
+
+        let temp = reader.get_float()?;
+        let ts = if flags & FLAG_TIMESTAMP != 0 { reader.get_ts(tz)? } else { None };
+        let site = if flags & FLAG_TYPE != 0 { Some(reader.get_u8()?) } else { None };
+
+        let temp = match temp {
+            Some(temp) if flags & FLAG_FAHRENHEIT != 0 => ((temp - 32.0) / 1.8 * 100.0).round() / 100.0, // Rounded to 0.01 °C.
+            Some(temp) => temp,
+            None => return Ok(None),
+        };
+
+        let mut record = MeasReader::new_record(ts, None);
+
+        if let Some(site) = site {
+            record.add_tag("site", Self::get_site(site));
+        }
+
+        record.add_field("temp", DbFieldValue::Float(temp));
+
+        Ok(Some(record))
+    }
+
+    fn get_site(site: u8) -> &'static str {
+        match site {
+            0x01 => "armpit",
+            0x02 => "body",
+            0x03 => "ear",
+            0x04 => "finger",
+            0x05 => "gastrointestinal",
+            0x06 => "mouth",
+            0x07 => "rectum",
+            0x08 => "toe",
+            0x09 => "tympanum",
+            _ => "unknown",
+        }
+    }
+}
+
+#[async_trait]
+impl Driver for DriverImpl {
+    async fn pair(&self) -> Result<(), String> {
+        self.pair().await.map_err(|e| format!("{}", e))
+    }
+
+    fn get_addr(&self) -> &Address {
+        &self.config.addr
+    }
+
+    async fn get_records(&self) -> Result<DbRecords, String> {
+        self.get_records().await.map_err(|e| format!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::driver::harness::Harness;
+
+    #[tokio::test]
+    async fn conformance() {
+        Harness::check(
+            "driver: GATT_Health_Thermometer\naddr: 00:11:22:33:44:77\ntz: Europe/Budapest",
+            include_str!("../../../tests/fixtures/gatt_health_thermometer/pair.txt"),
+            include_str!("../../../tests/fixtures/gatt_health_thermometer/fetch.txt"),
+        ).await;
+    }
+}
@@ -0,0 +1,346 @@
+//! # Bluetooth Heart Rate Service driver
+//!
+//! For any chest strap implementing the standard Heart Rate Service (0x180D),
+//! e.g. the Polar H10 or Garmin HRM straps. Straps have no memory, so this is
+//! a streaming driver: it stays connected while the strap is worn, and
+//! uploads a record per aggregation interval (mean, min and max heart rate,
+//! RMSSD) plus a record per RR interval. The strap stops notifying once it
+//! loses skin contact, which ends the stream until it advertises again. A
+//! triggered measurement is a spot reading over one aggregation interval.
+
+use async_trait::async_trait;
+use bluer::Address;
+use serde::Deserialize;
+use tokio::time::{self, Duration, Instant};
+use uuid::{uuid, Uuid};
+
+use crate::btutil::{self, AdvPattern, BTLinkPtr, BTUtil};
+use crate::db::{DbFieldValue, DbRecord, DbRecords};
+use crate::driver::{Driver, DriverContext, PairStep, RecordSender};
+use crate::otel::Otel;
+use crate::status::DeviceState;
+use crate::timeutil::TimeUtil;
+use super::meas::{MeasReader, MeasStream};
+
+const SERVICE_ID: u16 = 0x180d;
+
+const MAIN_SERVICE: &Uuid = &uuid!("0000180d-0000-1000-8000-00805f9b34fb");
+const MEAS_CHAR: &Uuid = &uuid!("00002a37-0000-1000-8000-00805f9b34fb");
+
+const FLAG_U16: u8 = 0x01; // Heart rate is 16-bit.
+const FLAG_CONTACT: u8 = 0x02; // Skin contact detected.
+const FLAG_CONTACT_SUPPORTED: u8 = 0x04;
+const FLAG_ENERGY: u8 = 0x08; // Energy expended present.
+const FLAG_RR: u8 = 0x10; // RR intervals present.
+
+const RR_RESOLUTION: f64 = 1000.0 / 1024.0; // [ms]
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    addr: Address, // TODO: unique check
+    #[serde(default = "Config::get_default_interval")]
+    interval: u32, // [s] Aggregation interval.
+}
+
+pub struct DriverImpl {
+    ctx: DriverContext,
+    config: Config,
+}
+
+#[derive(Default)]
+struct Window { // Measurements received since the last upload.
+    bpm: Vec<u16>,
+    beats: Vec<(i64, f64)>, // Time of beat [ns], RR interval ending with it [ms].
+}
+
+impl Config {
+    fn get_default_interval() -> u32 {
+        60
+    }
+}
+
+impl DriverImpl {
+    pub fn new(ctx: DriverContext, config: Config) -> Self {
+        Self {
+            ctx,
+            config,
+        }
+    }
+
+    async fn pair(&self) -> btutil::Result<()> {
+        // Pair device.
+
+        self.ctx.set_pair_step(PairStep::Discovering);
+        let link = self.ctx.get_link(&self.config.addr, true).await?;
+
+        if link.is_paired().await? {
+            return Err("Device is already paired".into());
+        }
+
+        let result = self.pair_device(&link).await;
+        BTUtil::disconnect(&link).await;
+
+        result
+    }
+
+    async fn pair_device(&self, link: &BTLinkPtr) -> btutil::Result<()> {
+        self.ctx.set_pair_step(PairStep::Connecting);
+        link.connect().await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_pair_step(PairStep::Bonding);
+        link.pair().await?;
+        self.ctx.set_pair_step(PairStep::Bonded);
+
+        Ok(())
+    }
+
+    async fn stream(&self, tx: &RecordSender) -> btutil::Result<()> {
+        // Connect to device.
+
+        let link = self.ctx.get_link(&self.config.addr, false).await?;
+
+        if !link.is_paired().await? {
+            return Err("Device is not yet paired".into());
+        }
+
+        self.ctx.set_state(DeviceState::WaitingForAdvertisement);
+        self.ctx.wait_for_adv(&self.config.addr, &[AdvPattern::service(SERVICE_ID)]).await?;
+
+        let result = self.stream_link(&link, tx).await;
+        BTUtil::disconnect(&link).await;
+
+        result
+    }
+
+    async fn stream_link(&self, link: &BTLinkPtr, tx: &RecordSender) -> btutil::Result<()> {
+        self.ctx.check_policy(link).await?;
+        self.ctx.set_state(DeviceState::Connecting);
+        Otel::span("connect", link.connect()).await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_state(DeviceState::Streaming);
+
+        let mut stream = MeasStream::new(&self.ctx, link, MAIN_SERVICE, MEAS_CHAR).await?;
+        let mut window = Window::default();
+        let period = Duration::from_secs(self.config.interval.max(1).into());
+        let mut interval = time::interval_at(Instant::now() + period, period);
+
+        loop {
+            tokio::select! {
+                data = stream.recv() => match data {
+                    Some(data) => if let Some((bpm, rr)) = Self::decode_meas(&data)? {
+                        window.add(TimeUtil::get_current_unix_ns(), bpm, &rr);
+                    },
+                    None => break, // Strap taken off (or out of range).
+                },
+                _ = interval.tick() => Self::send(tx, window.take(TimeUtil::get_current_unix_ns())).await?,
+            }
+        }
+
+        Self::send(tx, window.take(TimeUtil::get_current_unix_ns())).await
+    }
+
+    async fn measure(&self) -> btutil::Result<DbRecords> {
+        // Connect to device.
+
+        let link = self.ctx.get_link(&self.config.addr, false).await?;
+
+        if !link.is_paired().await? {
+            return Err("Device is not yet paired".into());
+        }
+
+        self.ctx.set_state(DeviceState::WaitingForAdvertisement);
+        self.ctx.wait_for_adv(&self.config.addr, &[AdvPattern::service(SERVICE_ID)]).await?;
+
+        let result = self.measure_link(&link).await;
+        BTUtil::disconnect(&link).await;
+
+        result
+    }
+
+    async fn measure_link(&self, link: &BTLinkPtr) -> btutil::Result<DbRecords> {
+        self.ctx.check_policy(link).await?;
+        self.ctx.set_state(DeviceState::Connecting);
+        Otel::span("connect", link.connect()).await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_state(DeviceState::Fetching);
+
+        let mut stream = MeasStream::new(&self.ctx, link, MAIN_SERVICE, MEAS_CHAR).await?;
+        let mut window = Window::default();
+        let end = time::sleep(Duration::from_secs(self.config.interval.max(1).into()));
+        tokio::pin!(end);
+
+        loop {
+            tokio::select! {
+                data = stream.recv() => match data {
+                    Some(data) => if let Some((bpm, rr)) = Self::decode_meas(&data)? {
+                        window.add(TimeUtil::get_current_unix_ns(), bpm, &rr);
+                    },
+                    None => break, // Strap taken off (or out of range), keep what was received.
+                },
+                _ = &mut end => break,
+            }
+        }
+
+        let records = window.take(TimeUtil::get_current_unix_ns());
+
+        if records.is_empty() {
+            return Err("No heart rate received, the strap has no skin contact".into());
+        }
+
+        Ok(records)
+    }
+
+    async fn send(tx: &RecordSender, records: DbRecords) -> btutil::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        tx.send(records).await.map_err(|_| "Record upload has stopped".into())
+    }
+
+    pub fn decode_meas(data: &[u8]) -> btutil::Result<Option<(u16, Vec<f64>)>> {
+        // Returns the heart rate [bpm] and the RR intervals [ms] since the last measurement, None without skin contact.
+        // Flags, heart rate (8 or 16-bit), then the optional energy expended and RR intervals (1/1024 s each).
+
+        let mut reader = MeasReader::new(data);
+        let flags = reader.get_u8()?;
+        let bpm = if flags & FLAG_U16 != 0 { reader.get_u16()? } else { reader.get_u8()?.into() };
+
+        if flags & FLAG_ENERGY != 0 {
+            reader.skip(2)?;
+        }
+
+        let mut rr = Vec::new();
+        if flags & FLAG_RR != 0 {
+            while !reader.is_empty() {
+                rr.push(f64::from(reader.get_u16()?) * RR_RESOLUTION);
+            }
+        }
+
+        if flags & FLAG_CONTACT_SUPPORTED != 0 && flags & FLAG_CONTACT == 0 || bpm == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some((bpm, rr)))
+    }
+}
+
+impl Window {
+    fn add(&mut self, ts: i64, bpm: u16, rr: &[f64]) {
+        // The last beat is at the time of reception, the earlier ones are reconstructed from the intervals.
+
+        self.bpm.push(bpm);
+
+        let mut beat = ts;
+        let start = self.beats.len();
+        for rr in rr.iter().rev() {
+            self.beats.insert(start, (beat, *rr));
+            beat -= (rr * 1_000_000.0) as i64;
+        }
+    }
+
+    fn take(&mut self, ts: i64) -> DbRecords {
+        let mut records = DbRecords::new();
+        let Window { bpm, beats } = std::mem::take(self);
+
+        if bpm.is_empty() {
+            return records;
+        }
+
+        let mean = bpm.iter().map(|bpm| f64::from(*bpm)).sum::<f64>() / bpm.len() as f64;
+
+        let mut record = DbRecord::new(ts);
+        record.add_field("bpm", DbFieldValue::Integer(mean.round() as i64));
+        record.add_field("bpm_min", DbFieldValue::Integer(bpm.iter().min().copied().unwrap_or_default().into()));
+        record.add_field("bpm_max", DbFieldValue::Integer(bpm.iter().max().copied().unwrap_or_default().into()));
+
+        if beats.len() >= 2 { // Root mean square of successive RR differences, a common HRV measure.
+            let squares: Vec<f64> = beats.windows(2).map(|pair| (pair[1].1 - pair[0].1).powi(2)).collect();
+            let rmssd = (squares.iter().sum::<f64>() / squares.len() as f64).sqrt();
+            record.add_field("rmssd", DbFieldValue::Float((rmssd * 10.0).round() / 10.0));
+        }
+
+        records.push(record);
+
+        for (ts, rr) in beats {
+            let mut record = DbRecord::new(ts);
+            record.add_field("rr", DbFieldValue::Float(rr));
+            records.push(record);
+        }
+
+        records
+    }
+}
+
+#[async_trait]
+impl Driver for DriverImpl {
+    async fn pair(&self) -> Result<(), String> {
+        self.pair().await.map_err(|e| format!("{}", e))
+    }
+
+    fn get_addr(&self) -> &Address {
+        &self.config.addr
+    }
+
+    fn get_pair_hint(&self) -> &'static str {
+        "wear the strap (moisten the electrodes), it only advertises while it has skin contact"
+    }
+
+    async fn get_records(&self) -> Result<DbRecords, String> {
+        Err(String::from("Heart rate is streamed, not fetched"))
+    }
+
+    async fn measure(&self) -> Result<DbRecords, String> {
+        self.measure().await.map_err(|e| format!("{}", e))
+    }
+
+    fn can_measure(&self) -> bool {
+        true
+    }
+
+    fn is_streaming(&self) -> bool {
+        true
+    }
+
+    async fn stream(&self, tx: RecordSender) -> Result<(), String> {
+        self.stream(&tx).await.map_err(|e| format!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::DbFieldValue;
+    use super::{DriverImpl, Window};
+
+    #[test]
+    fn decode_meas() {
+        assert_eq!(DriverImpl::decode_meas(&[0x06, 0x48]).ok(), Some(Some((72, Vec::new()))));
+        assert_eq!(DriverImpl::decode_meas(&[0x04, 0x48]).ok(), Some(None)); // No skin contact.
+        assert_eq!(DriverImpl::decode_meas(&[0x19, 0x48, 0x00, 0x10, 0x00, 0x00, 0x04, 0x00, 0x03]).ok(), Some(Some((72, vec![1000.0, 750.0]))));
+        assert!(DriverImpl::decode_meas(&[0x10, 0x48, 0x00, 0x04, 0x00]).is_err()); // Odd RR length.
+        assert!(DriverImpl::decode_meas(&[0x01, 0x48]).is_err());
+    }
+
+    #[test]
+    fn window() {
+        let mut window = Window::default();
+        window.add(10_000_000_000, 60, &[1000.0]);
+        window.add(12_000_000_000, 64, &[1100.0, 900.0]);
+        window.add(12_500_000_000, 68, &[]);
+
+        let records = window.take(13_000_000_000);
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0].get_ts(), 13_000_000_000);
+        assert!(matches!(records[0].get_field("bpm"), Some(DbFieldValue::Integer(64))));
+        assert!(matches!(records[0].get_field("bpm_min"), Some(DbFieldValue::Integer(60))));
+        assert!(matches!(records[0].get_field("bpm_max"), Some(DbFieldValue::Integer(68))));
+        assert!(matches!(records[0].get_field("rmssd"), Some(DbFieldValue::Float(rmssd)) if *rmssd == 158.1)); // sqrt((100^2 + 200^2) / 2)
+        assert_eq!(records.iter().skip(1).map(|record| record.get_ts()).collect::<Vec<_>>(), [10_000_000_000, 11_100_000_000, 12_000_000_000]);
+
+        assert!(window.take(14_000_000_000).is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+//! # Standard measurement characteristics
+//!
+//! Units following the Bluetooth health profiles indicate their stored
+//! measurements once the measurement characteristic is subscribed to. The
+//! values are little endian, optional ones are present according to the
+//! flags at the start of the measurement.
+
+use futures::StreamExt;
+use tokio::time::{self, Duration};
+use tzfile::Tz;
+use uuid::Uuid;
+
+use crate::btutil::{self, BTLinkPtr, BTRxStream};
+use crate::db::DbRecord;
+use crate::driver::{DriverContext, FetchMeterPtr};
+use crate::redact::Redact;
+use crate::timeutil::TimeUtil;
+use crate::tracelog::TraceLog;
+
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5); // The unit is done if it has nothing to indicate for this long.
+
+const USER_UNKNOWN: u8 = 0xff;
+
+const LB: f64 = 0.45359237; // [kg]
+const INCH: f64 = 0.0254; // [m]
+
+pub struct MeasStream {
+    rx_stream: BTRxStream,
+    trace: Option<String>, // Device id, if protocol tracing is enabled.
+    meter: FetchMeterPtr,
+}
+
+pub struct MeasReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl MeasStream {
+    pub async fn new(ctx: &DriverContext, link: &BTLinkPtr, service_uuid: &Uuid, char_uuid: &Uuid) -> btutil::Result<Self> {
+        let rx_stream = link.notify_char(service_uuid, char_uuid).await?;
+
+        Ok(Self {
+            rx_stream,
+            trace: if ctx.debug_protocol { Some(ctx.id.clone()) } else { None },
+            meter: FetchMeterPtr::clone(&ctx.meter),
+        })
+    }
+
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        // None once the unit is done (disconnected or quiet).
+
+        let data = match time::timeout(IDLE_TIMEOUT, self.rx_stream.next()).await {
+            Ok(Some(data)) => data,
+            _ => return None,
+        };
+        self.meter.add_bytes(data.len());
+
+        if let Some(id) = &self.trace {
+            TraceLog::write(id, &Redact::apply(&format!("rx: meas={}", hex::encode(&data))));
+        }
+
+        Some(data)
+    }
+}
+
+impl<'a> MeasReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+        }
+    }
+
+    pub fn get_u8(&mut self) -> btutil::Result<u8> {
+        Ok(self.get_bytes(1)?[0])
+    }
+
+    pub fn get_u16(&mut self) -> btutil::Result<u16> {
+        let data = self.get_bytes(2)?;
+        Ok(u16::from_le_bytes([data[0], data[1]]))
+    }
+
+    pub fn get_ts(&mut self, tz: &Tz) -> btutil::Result<Option<i64>> {
+        // Date time in the unit's local time, None if the unit's clock has not been set (year is 0).
+
+        let data = self.get_bytes(7)?;
+
+        match u16::from_le_bytes([data[0], data[1]]) {
+            0 => Ok(None),
+            year => match TimeUtil::get_ts(tz, year, data[2], data[3], data[4], data[5], data[6]) {
+                Some(ts) => Ok(Some(ts)),
+                None => Err("Invalid timestamp".into()),
+            },
+        }
+    }
+
+    pub fn get_i16(&mut self) -> btutil::Result<i16> {
+        let data = self.get_bytes(2)?;
+        Ok(i16::from_le_bytes([data[0], data[1]]))
+    }
+
+    pub fn get_sfloat(&mut self) -> btutil::Result<Option<f64>> {
+        // IEEE 11073 16-bit float: 4-bit exponent, 12-bit mantissa (both signed). None for the special values (NaN,
+        // not at this resolution, infinities).
+
+        let raw = self.get_u16()?;
+
+        if (0x07fe..=0x0802).contains(&raw) {
+            return Ok(None);
+        }
+
+        let exponent = (raw as i16) >> 12;
+        let mantissa = ((raw << 4) as i16) >> 4;
+
+        Ok(Some(Self::get_decimal(mantissa.into(), exponent.into())))
+    }
+
+    pub fn get_float(&mut self) -> btutil::Result<Option<f64>> {
+        // IEEE 11073 32-bit float: 8-bit exponent, 24-bit mantissa (both signed). None for the special values.
+
+        let data = self.get_bytes(4)?;
+        let raw = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+
+        if (0x007ffffe..=0x00800002).contains(&raw) {
+            return Ok(None);
+        }
+
+        let exponent = (raw as i32) >> 24;
+        let mantissa = ((raw << 8) as i32) >> 8;
+
+        Ok(Some(Self::get_decimal(mantissa, exponent)))
+    }
+
+    fn get_decimal(mantissa: i32, exponent: i32) -> f64 {
+        // Dividing by a power of ten keeps e.g. 366e-1 at 36.6 (multiplying by 0.1 would not).
+
+        if exponent < 0 {
+            (mantissa as f64) / 10f64.powi(-exponent)
+        } else {
+            (mantissa as f64) * 10f64.powi(exponent)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    pub fn skip(&mut self, len: usize) -> btutil::Result<()> {
+        self.get_bytes(len).map(|_| ())
+    }
+
+    fn get_bytes(&mut self, len: usize) -> btutil::Result<&'a [u8]> {
+        match self.data.get(self.pos..self.pos + len) {
+            Some(data) => {
+                self.pos += len;
+                Ok(data)
+            },
+            None => Err("Measurement is too short".into()),
+        }
+    }
+
+    pub fn new_record(ts: Option<i64>, user: Option<u8>) -> DbRecord {
+        // Without a timestamp, the time of reception is used. The user tag is the unit's user index, if known.
+
+        let mut record = DbRecord::new(ts.unwrap_or_else(|| TimeUtil::get_ts_unix(TimeUtil::get_current_unix())));
+
+        if let Some(user) = user.filter(|user| *user != USER_UNKNOWN) {
+            record.add_tag("user", &format!("{}", user));
+        }
+
+        record
+    }
+
+    pub fn get_mass(raw: u16, imperial: bool) -> f64 {
+        // Units report mass in 0.01 lb or 5 g, returned in kg.
+
+        if imperial { (raw as f64) / 100.0 * LB } else { (raw as f64) / 200.0 }
+    }
+
+    pub fn get_height(raw: u16, imperial: bool) -> f64 {
+        // Units report height in 0.1 in or mm, returned in m.
+
+        if imperial { (raw as f64) / 10.0 * INCH } else { (raw as f64) / 1000.0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MeasReader;
+
+    #[test]
+    fn sfloat() {
+        let mut reader = MeasReader::new(&[0x72, 0xb0, 0xff, 0x07, 0xff, 0x0f]);
+        assert!(reader.get_sfloat().ok().flatten().is_some_and(|value| (value - 114e-5).abs() < 1e-12)); // 114 mg/dL in kg/L.
+        assert_eq!(reader.get_sfloat().ok(), Some(None)); // NaN.
+        assert_eq!(reader.get_sfloat().ok(), Some(Some(-1.0)));
+        assert!(reader.get_sfloat().is_err());
+    }
+
+    #[test]
+    fn float() {
+        let mut reader = MeasReader::new(&[0x6e, 0x01, 0x00, 0xff, 0xff, 0xff, 0x7f, 0x00, 0x18, 0xfc, 0xff, 0x00, 0x00]);
+        assert_eq!(reader.get_float().ok(), Some(Some(36.6)));
+        assert_eq!(reader.get_float().ok(), Some(None)); // NaN.
+        assert_eq!(reader.get_float().ok(), Some(Some(-1000.0)));
+        assert!(reader.get_float().is_err());
+    }
+}
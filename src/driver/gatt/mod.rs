@@ -0,0 +1,7 @@
+pub mod body_composition;
+pub mod glucose;
+pub mod health_thermometer;
+pub mod heart_rate;
+pub mod weight_scale;
+
+pub mod meas;
@@ -0,0 +1,208 @@
+//! # Bluetooth Weight Scale Service driver
+//!
+//! For any scale implementing the standard Weight Scale Service (0x181D):
+//! stored measurements are indicated on the Weight Measurement characteristic
+//! once it is subscribed to (see meas.rs). Units without memory (live) notify
+//! their readings while the weight settles instead, the last one is the
+//! result. The unit's clock is not set, use the vendor app for that if it
+//! drifts.
+
+use async_trait::async_trait;
+use bluer::Address;
+use serde::Deserialize;
+use tzfile::Tz;
+use uuid::{uuid, Uuid};
+
+use crate::btutil::{self, AdvPattern, BTLinkPtr, BTUtil};
+use crate::db::{DbFieldValue, DbRecord, DbRecords};
+use crate::driver::{Driver, DriverContext, PairStep};
+use crate::otel::Otel;
+use crate::status::DeviceState;
+use super::meas::{MeasReader, MeasStream};
+
+const SERVICE_ID: u16 = 0x181d;
+
+const MAIN_SERVICE: &Uuid = &uuid!("0000181d-0000-1000-8000-00805f9b34fb");
+const MEAS_CHAR: &Uuid = &uuid!("00002a9d-0000-1000-8000-00805f9b34fb");
+
+const FLAG_IMPERIAL: u8 = 0x01;
+const FLAG_TIMESTAMP: u8 = 0x02;
+const FLAG_USER: u8 = 0x04;
+const FLAG_BMI: u8 = 0x08;
+
+const WEIGHT_UNKNOWN: u16 = 0xffff; // Measurement unsuccessful.
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    addr: Address, // TODO: unique check
+    #[serde(default = "crate::timeutil::TimeUtil::get_local_tz", deserialize_with = "crate::timeutil::TimeUtil::parse_tz")]
+    tz: Tz, // Host's timezone if unset.
+    #[serde(default)]
+    keep_connected: bool, // Do not disconnect after pairing/fetching.
+    #[serde(default)]
+    live: bool, // The unit has no memory, it notifies readings during the weigh-in: only the last (settled) one is kept.
+}
+
+pub struct DriverImpl {
+    ctx: DriverContext,
+    config: Config,
+}
+
+impl DriverImpl {
+    pub fn new(ctx: DriverContext, config: Config) -> Self {
+        Self {
+            ctx,
+            config,
+        }
+    }
+
+    async fn pair(&self) -> btutil::Result<()> {
+        // Pair device.
+
+        self.ctx.set_pair_step(PairStep::Discovering);
+        let link = self.ctx.get_link(&self.config.addr, true).await?;
+
+        if link.is_paired().await? {
+            return Err("Device is already paired".into());
+        }
+
+        let result = self.pair_device(&link).await;
+
+        if !self.config.keep_connected {
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn pair_device(&self, link: &BTLinkPtr) -> btutil::Result<()> {
+        self.ctx.set_pair_step(PairStep::Connecting);
+        link.connect().await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_pair_step(PairStep::Bonding);
+        link.pair().await?;
+        self.ctx.set_pair_step(PairStep::Bonded);
+
+        Ok(())
+    }
+
+    async fn get_records(&self) -> btutil::Result<DbRecords> {
+        // Connect to device.
+
+        let link = self.ctx.get_link(&self.config.addr, false).await?;
+
+        if !link.is_paired().await? {
+            return Err("Device is not yet paired".into());
+        }
+
+        self.ctx.set_state(DeviceState::WaitingForAdvertisement);
+        self.ctx.wait_for_adv(&self.config.addr, &[AdvPattern::service(SERVICE_ID)]).await?;
+
+        let result = BTUtil::with_deadline(&link, self.ctx.fetch_timeout, self.fetch(&link)).await;
+
+        if !self.config.keep_connected { // Notification sessions are gone by now, since fetch() has finished.
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn fetch(&self, link: &BTLinkPtr) -> btutil::Result<DbRecords> {
+        self.ctx.check_policy(link).await?;
+        self.ctx.set_state(DeviceState::Connecting);
+        Otel::span("connect", link.connect()).await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_state(DeviceState::Fetching);
+
+        let status = self.ctx.get_status(link).await;
+
+        // Fetch measurements: subscribing makes the unit indicate the stored ones.
+
+        let mut records = DbRecords::new();
+        let mut stream = MeasStream::new(&self.ctx, link, MAIN_SERVICE, MEAS_CHAR).await?;
+
+        while let Some(data) = stream.recv().await {
+            if let Some(record) = Self::decode_record(&self.config.tz, &data)? {
+                if self.config.live {
+                    records.clear(); // Superseded interim reading, not worth uploading if the weigh-in is cut short.
+                } else {
+                    self.ctx.buffer.add(&record);
+                }
+
+                records.push(record);
+            }
+        }
+
+        records.extend(status);
+
+        Ok(records)
+    }
+
+    pub fn decode_record(tz: &Tz, data: &[u8]) -> btutil::Result<Option<DbRecord>> {
+        // Returns None for unsuccessful measurements. Flags, weight, then the optional timestamp, user id and BMI/height.
+
+        let mut reader = MeasReader::new(data);
+        let flags = reader.get_u8()?;
+        let imperial = flags & FLAG_IMPERIAL != 0;
+
+        let raw_weight = reader.get_u16()?;
+        let ts = if flags & FLAG_TIMESTAMP != 0 { reader.get_ts(tz)? } else { None };
+        let user = if flags & FLAG_USER != 0 { Some(reader.get_u8()?) } else { None };
+        let bmi = if flags & FLAG_BMI != 0 { Some((reader.get_u16()?, reader.get_u16()?)) } else { None };
+
+        if raw_weight == WEIGHT_UNKNOWN {
+            return Ok(None);
+        }
+
+        let mut record = MeasReader::new_record(ts, user);
+        record.add_field("weight", DbFieldValue::Float(MeasReader::get_mass(raw_weight, imperial)));
+
+        if let Some((bmi, height)) = bmi {
+            record.add_field("bmi", DbFieldValue::Float((bmi as f64) / 10.0));
+            record.add_field("height", DbFieldValue::Float(MeasReader::get_height(height, imperial)));
+        }
+
+        Ok(Some(record))
+    }
+}
+
+#[async_trait]
+impl Driver for DriverImpl {
+    async fn pair(&self) -> Result<(), String> {
+        self.pair().await.map_err(|e| format!("{}", e))
+    }
+
+    fn get_addr(&self) -> &Address {
+        &self.config.addr
+    }
+
+    async fn get_records(&self) -> Result<DbRecords, String> {
+        self.get_records().await.map_err(|e| format!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::driver::harness::Harness;
+
+    #[tokio::test]
+    async fn conformance() {
+        Harness::check(
+            "driver: GATT_Weight_Scale\naddr: 00:11:22:33:44:55\ntz: Europe/Budapest",
+            include_str!("../../../tests/fixtures/gatt_weight_scale/pair.txt"),
+            include_str!("../../../tests/fixtures/gatt_weight_scale/fetch.txt"),
+        ).await;
+    }
+
+    #[tokio::test]
+    async fn conformance_live() {
+        Harness::check(
+            "driver: GATT_Weight_Scale\naddr: 00:11:22:33:44:55\ntz: Europe/Budapest\nlive: true",
+            include_str!("../../../tests/fixtures/gatt_weight_scale/pair.txt"),
+            include_str!("../../../tests/fixtures/gatt_weight_scale/fetch_live.txt"),
+        ).await;
+    }
+}
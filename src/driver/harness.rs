@@ -0,0 +1,490 @@
+//! # Driver conformance harness
+//!
+//! Runs a driver against a scripted fake unit instead of BlueZ. A transcript
+//! describes what the unit looks like and the exchange the driver is expected
+//! to make, one item per line (`#` starts a comment):
+//!
+//! ```text
+//! paired false                 # Pairing state of the unit.
+//! manufacturer OMRONHEALTHCARE # Device information (model and firmware too).
+//! checksum                     # Optional: the protocol detects corrupt packets.
+//! alias tx 00002a00-...        # Short name for a characteristic.
+//! > tx 0800 ?? 15              # Expected write, ?? matches any byte (e.g. current time).
+//! < rx 0880 0015               # Notification sent by the unit after the previous write.
+//! adv 181b 0224b2070101        # Service data of an advertisement, for passive drivers.
+//! expect 2024-05-01T08:30:00Z  # Timestamp of a record the fetch must return.
+//! ```
+//!
+//! Besides the happy path, every notification (and advertisement) is replayed
+//! truncated (and with its last byte flipped, if the protocol has a
+//! checksum): the driver has to return an error, without panicking or
+//! hanging.
+
+use async_trait::async_trait;
+use bluer::{Address, UuidExt};
+use config::{Config, File, FileFormat};
+use futures::stream;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use tokio::time::{self, Duration};
+use uuid::Uuid;
+
+use crate::btutil::{self, AdvData, AdvPattern, BTBackend, BTBackendPtr, BTLink, BTLinkPtr, BTRxStream, FIRMWARE_CHAR, MANUFACTURER_CHAR, MODEL_CHAR};
+use crate::db::DbRecords;
+use crate::driver::{self, DriverConfig, DriverContext};
+use crate::status::StatusPtr;
+use crate::store::{Store, StorePtr};
+use crate::timeutil::TimeUtil;
+
+const TIMEOUT: Duration = Duration::from_secs(10); // The fake unit answers immediately, anything longer is a hang.
+
+#[derive(Clone)]
+enum Step {
+    Write(Uuid, Vec<Option<u8>>), // None matches any byte.
+    Notify(Uuid, Vec<u8>),
+}
+
+#[derive(Clone)]
+struct Transcript {
+    paired: bool,
+    manufacturer: String,
+    model: String,
+    firmware: String,
+    checksum: bool,
+    steps: Vec<Step>,
+    advs: Vec<(Uuid, Vec<u8>)>, // Service data of advertisements, in order.
+    expected: Vec<i64>, // Record timestamps [ns]
+}
+
+impl Transcript {
+    fn parse(s: &str) -> Self {
+        let mut transcript = Self {
+            paired: false,
+            manufacturer: String::new(),
+            model: String::new(),
+            firmware: String::new(),
+            checksum: false,
+            steps: Vec::new(),
+            advs: Vec::new(),
+            expected: Vec::new(),
+        };
+        let mut aliases = HashMap::new();
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let rest = rest.trim();
+            let error = |what: &str| -> ! { panic!("transcript line {}: {}", i + 1, what) };
+
+            match key {
+                "paired" => transcript.paired = rest.parse().unwrap_or_else(|_| error("invalid bool")),
+                "manufacturer" => transcript.manufacturer = String::from(rest),
+                "model" => transcript.model = String::from(rest),
+                "firmware" => transcript.firmware = String::from(rest),
+                "checksum" => transcript.checksum = true,
+                "alias" => {
+                    let (name, uuid) = rest.split_once(' ').unwrap_or_else(|| error("missing uuid"));
+                    aliases.insert(String::from(name), uuid.trim().parse::<Uuid>().unwrap_or_else(|_| error("invalid uuid")));
+                },
+                ">" | "<" => {
+                    let (name, data) = rest.split_once(' ').unwrap_or((rest, ""));
+                    let char_uuid = *aliases.get(name).unwrap_or_else(|| error("unknown alias"));
+                    let data: String = data.split_whitespace().collect();
+                    if !data.len().is_multiple_of(2) {
+                        error("odd number of hex digits");
+                    }
+
+                    let data: Vec<Option<u8>> = (0..data.len()).step_by(2).map(|j| match &data[j..j + 2] {
+                        "??" => None,
+                        byte => Some(u8::from_str_radix(byte, 16).unwrap_or_else(|_| error("invalid hex"))),
+                    }).collect();
+
+                    transcript.steps.push(if key == ">" {
+                        Step::Write(char_uuid, data)
+                    } else {
+                        Step::Notify(char_uuid, data.into_iter().map(|byte| byte.unwrap_or_else(|| error("wildcard in notification"))).collect())
+                    });
+                },
+                "adv" => {
+                    let (service, data) = rest.split_once(' ').unwrap_or((rest, ""));
+                    let service = u16::from_str_radix(service, 16).unwrap_or_else(|_| error("invalid service"));
+                    let data: String = data.split_whitespace().collect();
+                    transcript.advs.push((Uuid::from_u16(service), hex::decode(data).unwrap_or_else(|_| error("invalid hex"))));
+                },
+                "expect" => transcript.expected.push(TimeUtil::parse_rfc3339(rest).unwrap_or_else(|e| error(&e))),
+                _ => error("unknown item"),
+            }
+        }
+
+        transcript
+    }
+
+    fn get_mutations(&self) -> Vec<(String, Self)> {
+        // Corrupt one notification at a time.
+
+        let mut mutations = Vec::new();
+
+        for (i, step) in self.steps.iter().enumerate() {
+            let data = match step {
+                Step::Notify(_, data) if !data.is_empty() => data,
+                _ => continue,
+            };
+
+            let mut truncated = data.clone();
+            truncated.pop();
+            mutations.push((format!("truncated notification at step {}", i + 1), self.with_notify(i, truncated)));
+
+            if self.checksum {
+                let mut flipped = data.clone();
+                *flipped.last_mut().unwrap() ^= 0xff;
+                mutations.push((format!("corrupt notification at step {}", i + 1), self.with_notify(i, flipped)));
+            }
+        }
+
+        for (i, (_, data)) in self.advs.iter().enumerate().filter(|(_, (_, data))| !data.is_empty()) {
+            let mut transcript = self.clone();
+            transcript.advs[i].1.truncate(data.len() - 1);
+            mutations.push((format!("truncated advertisement {}", i + 1), transcript));
+        }
+
+        mutations
+    }
+
+    fn with_notify(&self, i: usize, data: Vec<u8>) -> Self {
+        let mut transcript = self.clone();
+
+        if let Step::Notify(_, step_data) = &mut transcript.steps[i] {
+            *step_data = data;
+        }
+
+        transcript
+    }
+
+    fn with_paired(&self, paired: bool) -> Self {
+        let mut transcript = self.clone();
+        transcript.paired = paired;
+
+        transcript
+    }
+}
+
+struct LinkState {
+    paired: bool,
+    connected: bool,
+    steps: VecDeque<Step>,
+    queues: HashMap<Uuid, VecDeque<Vec<u8>>>, // Notifications sent, but not yet received by the driver.
+}
+
+impl LinkState {
+    fn deliver(&mut self) {
+        // Notifications following a write are sent right away.
+
+        while let Some(Step::Notify(char_uuid, data)) = self.steps.front() {
+            self.queues.entry(*char_uuid).or_default().push_back(data.clone());
+            self.steps.pop_front();
+        }
+    }
+
+    fn is_consumed(&self) -> bool {
+        self.steps.is_empty() && self.queues.values().all(|queue| queue.is_empty())
+    }
+}
+
+struct FakeLink {
+    info: HashMap<Uuid, String>,
+    state: Arc<Mutex<LinkState>>, // Shared with the notification streams.
+}
+
+impl FakeLink {
+    fn new(transcript: &Transcript) -> Self {
+        let mut state = LinkState {
+            paired: transcript.paired,
+            connected: false,
+            steps: transcript.steps.iter().cloned().collect(),
+            queues: HashMap::new(),
+        };
+        state.deliver();
+
+        Self {
+            info: HashMap::from([
+                (*MANUFACTURER_CHAR, transcript.manufacturer.clone()),
+                (*MODEL_CHAR, transcript.model.clone()),
+                (*FIRMWARE_CHAR, transcript.firmware.clone()),
+            ]),
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    fn check_connected(&self) -> btutil::Result<()> {
+        if self.state.lock().unwrap().connected {
+            Ok(())
+        } else {
+            Err("Not connected".into())
+        }
+    }
+}
+
+#[async_trait]
+impl BTLink for FakeLink {
+    async fn is_paired(&self) -> btutil::Result<bool> {
+        Ok(self.state.lock().unwrap().paired)
+    }
+
+    async fn pair(&self) -> btutil::Result<()> {
+        self.check_connected()?;
+        self.state.lock().unwrap().paired = true;
+
+        Ok(())
+    }
+
+    async fn is_connected(&self) -> btutil::Result<bool> {
+        Ok(self.state.lock().unwrap().connected)
+    }
+
+    async fn connect(&self) -> btutil::Result<()> {
+        self.state.lock().unwrap().connected = true;
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> btutil::Result<()> {
+        self.state.lock().unwrap().connected = false;
+        Ok(())
+    }
+
+    async fn read_char(&self, _service_uuid: &Uuid, char_uuid: &Uuid) -> btutil::Result<Vec<u8>> {
+        self.check_connected()?;
+
+        match self.info.get(char_uuid) {
+            Some(value) => Ok(value.clone().into_bytes()),
+            None => Err("Characteristic not found".into()),
+        }
+    }
+
+    async fn write_char(&self, _service_uuid: &Uuid, char_uuid: &Uuid, data: &[u8]) -> btutil::Result<()> {
+        self.check_connected()?;
+
+        let mut state = self.state.lock().unwrap();
+
+        match state.steps.pop_front() {
+            Some(Step::Write(step_uuid, pattern)) if step_uuid == *char_uuid && pattern.len() == data.len() &&
+                pattern.iter().zip(data).all(|(expected, byte)| expected.is_none_or(|expected| expected == *byte)) => (),
+            _ => return Err(btutil::Error::General(format!("Unexpected write: {} {}", char_uuid, hex::encode(data)))),
+        }
+
+        state.deliver();
+
+        Ok(())
+    }
+
+    async fn notify_char(&self, _service_uuid: &Uuid, char_uuid: &Uuid) -> btutil::Result<BTRxStream> {
+        // The stream ends when the unit has nothing more to say, like a dropped connection.
+
+        self.check_connected()?;
+
+        let state = Arc::clone(&self.state);
+        let char_uuid = *char_uuid;
+
+        Ok(Box::pin(stream::poll_fn(move |_| {
+            Poll::Ready(state.lock().unwrap().queues.get_mut(&char_uuid).and_then(|queue| queue.pop_front()))
+        })))
+    }
+}
+
+struct FakeBackend {
+    link: Arc<FakeLink>,
+    advs: Option<Mutex<VecDeque<AdvData>>>, // Advertisements carry no data if the transcript has none.
+}
+
+#[async_trait]
+impl BTBackend for FakeBackend {
+    async fn get_link(&self, _addr: &Address, _do_disco: bool) -> btutil::Result<BTLinkPtr> {
+        Ok(Arc::clone(&self.link) as BTLinkPtr)
+    }
+
+    async fn wait_for_adv(&self, _addr: &Address, _patterns: &[AdvPattern]) -> btutil::Result<AdvData> {
+        match &self.advs {
+            Some(advs) => advs.lock().unwrap().pop_front().ok_or_else(|| "Failed to receive advertisements".into()), // Unit is gone.
+            None => Ok(AdvData::default()),
+        }
+    }
+
+    fn rearm_adv(&self) {
+    }
+
+    async fn get_adapter(&self) -> btutil::Result<String> {
+        Ok(String::from("00:00:00:00:00:00"))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Pair,
+    Repair,
+    Fetch,
+}
+
+pub struct Harness {
+    driver_config: String, // YAML, as in driver_config of a device.
+}
+
+impl Harness {
+    pub async fn check(driver_config: &str, pair: &str, fetch: &str) {
+        // Panics if the driver does not conform.
+
+        let harness = Self {
+            driver_config: String::from(driver_config),
+        };
+        let pair = Transcript::parse(pair);
+        let fetch = Transcript::parse(fetch);
+
+        // Pairing flow.
+
+        let (result, backend) = harness.run("pair", Op::Pair, &pair).await;
+        if let Err(e) = result {
+            panic!("pair: failed: {}", e);
+        }
+
+        {
+            let state = backend.link.state.lock().unwrap();
+            assert!(state.paired, "pair: unit is not paired");
+            assert!(state.is_consumed(), "pair: transcript is not consumed");
+        }
+
+        let (result, _) = harness.run("pair", Op::Pair, &pair.with_paired(true)).await;
+        assert!(result.is_err(), "pair: already paired unit is accepted");
+
+        // Happy-path fetch.
+
+        let (result, backend) = harness.run("fetch", Op::Fetch, &fetch).await;
+        let records = result.unwrap_or_else(|e| panic!("fetch: failed: {}", e));
+
+        assert!(backend.link.state.lock().unwrap().is_consumed(), "fetch: transcript is not consumed");
+
+        let mut ts: Vec<i64> = records.iter().map(|record| record.get_ts()).collect();
+        let mut expected = fetch.expected.clone();
+        ts.sort();
+        expected.sort();
+        assert_eq!(ts, expected, "fetch: unexpected record timestamps");
+
+        let (result, _) = harness.run("fetch", Op::Fetch, &fetch.with_paired(false)).await;
+        assert!(result.is_err(), "fetch: unpaired unit is accepted");
+
+        // Corrupt packets.
+
+        for (op_name, op, transcript) in [("pair", Op::Pair, &pair), ("fetch", Op::Fetch, &fetch)] {
+            for (what, mutation) in transcript.get_mutations() {
+                let (result, _) = harness.run(op_name, op, &mutation).await;
+                assert!(result.is_err(), "{}: {} is accepted", op_name, what);
+            }
+        }
+    }
+
+    pub async fn check_passive(driver_config: &str, fetch: &str) {
+        // Broadcast-only units, the records are decoded from the advertisements. Panics if the driver does not conform.
+
+        let harness = Self {
+            driver_config: String::from(driver_config),
+        };
+        let fetch = Transcript::parse(fetch);
+
+        let (result, backend) = harness.run("fetch", Op::Fetch, &fetch).await;
+        let records = result.unwrap_or_else(|e| panic!("fetch: failed: {}", e));
+
+        assert!(backend.advs.as_ref().is_some_and(|advs| advs.lock().unwrap().is_empty()), "fetch: transcript is not consumed");
+
+        let mut ts: Vec<i64> = records.iter().map(|record| record.get_ts()).collect();
+        let mut expected = fetch.expected.clone();
+        ts.sort();
+        expected.sort();
+        assert_eq!(ts, expected, "fetch: unexpected record timestamps");
+
+        for (what, mutation) in fetch.get_mutations() {
+            let (result, _) = harness.run("fetch", Op::Fetch, &mutation).await;
+            assert!(result.is_err(), "fetch: {} is accepted", what);
+        }
+    }
+
+    pub async fn check_repair(driver_config: &str, repair: &str) {
+        // Re-pairing over an existing bond, panics if the driver does not conform.
+
+        let harness = Self {
+            driver_config: String::from(driver_config),
+        };
+        let repair = Transcript::parse(repair);
+
+        let (result, backend) = harness.run("repair", Op::Repair, &repair).await;
+        if let Err(e) = result {
+            panic!("repair: failed: {}", e);
+        }
+
+        assert!(backend.link.state.lock().unwrap().is_consumed(), "repair: transcript is not consumed");
+
+        let (result, _) = harness.run("repair", Op::Repair, &repair.with_paired(false)).await;
+        assert!(result.is_err(), "repair: unpaired unit is accepted");
+
+        for (what, mutation) in repair.get_mutations() {
+            let (result, _) = harness.run("repair", Op::Repair, &mutation).await;
+            assert!(result.is_err(), "repair: {} is accepted", what);
+        }
+    }
+
+    pub fn link(transcript: &str) -> (DriverContext, BTLinkPtr, impl Fn() -> bool) {
+        // Connected fake unit for testing shared protocol routines, the closure tells if the transcript is consumed.
+
+        let link = Arc::new(FakeLink::new(&Transcript::parse(transcript)));
+        link.state.lock().unwrap().connected = true;
+
+        let backend = Arc::new(FakeBackend {
+            link: Arc::clone(&link),
+            advs: None,
+        });
+        let ctx = DriverContext::new("harness", false, StatusPtr::default(), backend as BTBackendPtr, StorePtr::new(Store::open(None).unwrap()));
+        let state = Arc::clone(&link.state);
+
+        (ctx, link as BTLinkPtr, move || state.lock().unwrap().is_consumed())
+    }
+
+    async fn run(&self, op_name: &str, op: Op, transcript: &Transcript) -> (Result<DbRecords, String>, Arc<FakeBackend>) {
+        let backend = Arc::new(FakeBackend {
+            link: Arc::new(FakeLink::new(transcript)),
+            advs: if transcript.advs.is_empty() { None } else { Some(Mutex::new(transcript.advs.iter().map(|(service, data)| AdvData {
+                service_data: HashMap::from([(*service, data.clone())]),
+                ..Default::default()
+            }).collect())) },
+        });
+        let ctx = DriverContext::new("harness", false, StatusPtr::default(), Arc::clone(&backend) as BTBackendPtr, StorePtr::new(Store::open(None).unwrap()));
+        let driver = driver::create(ctx, self.get_driver_config());
+
+        // Run driver in its own task, so a panic can be told apart from a failed assertion.
+
+        let handle = tokio::spawn(async move {
+            match op {
+                Op::Pair => driver.pair().await.map(|_| DbRecords::new()),
+                Op::Repair => driver.repair().await.map(|_| DbRecords::new()),
+                Op::Fetch => match driver.get_records().await {
+                    Ok(records) => driver.commit().await.map(|_| records),
+                    Err(e) => Err(e),
+                },
+            }
+        });
+
+        match time::timeout(TIMEOUT, handle).await {
+            Ok(Ok(result)) => (result, backend),
+            Ok(Err(e)) => panic!("{}: driver panicked: {}", op_name, e),
+            Err(_) => panic!("{}: driver hung", op_name),
+        }
+    }
+
+    fn get_driver_config(&self) -> DriverConfig {
+        Config::builder()
+            .add_source(File::from_str(&self.driver_config, FileFormat::Yaml))
+            .build()
+            .and_then(|config| config.try_deserialize())
+            .unwrap_or_else(|e| panic!("invalid driver config: {}", e))
+    }
+}
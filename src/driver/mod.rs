@@ -1,29 +1,517 @@
 use async_trait::async_trait;
+use bluer::Address;
 use serde::Deserialize;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{mpsc, Notify};
+use tokio::time::{self, Duration, Instant};
 
-use crate::db::DbRecords;
+use crate::btutil::{self, AdvData, AdvPattern, BTBackendPtr, BTDeviceInfo, BTLinkPtr, BTUtil};
+use crate::control::TriggerPtr;
+use crate::db::{DbFieldValue, DbRecord, DbRecords};
+use crate::device::WindowConfig;
+use crate::otel::Otel;
+use crate::status::{DeviceState, StatusPtr};
+use crate::store::StorePtr;
+use crate::timeutil::TimeUtil;
+use fingerprint::{Fingerprint, IdentityCheck};
+use omron::model::Model;
 
+mod beurer;
+mod gatt;
 mod omron;
+mod withings;
+mod xiaomi;
+
+pub mod fingerprint;
+
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
+
+#[cfg(any(test, feature = "harness"))]
+pub mod harness;
+
+#[derive(Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Poll { // When to connect to the unit.
+    #[default]
+    Adv, // On its advertisement.
+    Direct, // Right away, on the sleep schedule, for units which are always connectable but don't advertise as expected.
+}
+
+pub const STATUS_MEAS: &str = "phd_device_status"; // Device-side status (e.g. battery), reported by drivers along with the records.
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 #[serde(tag = "driver")]
 #[allow(non_camel_case_types)]
 pub enum DriverConfig { // Keep enum sorted and grouped by manufacturer.
-    Omron_HEM_7361T(omron::hem_7361t::Config),
+    Beurer_BF700(beurer::bf::Config),
+    Beurer_BF720(beurer::bf::Config),
+    Beurer_BM57(beurer::bm::Config),
+    Beurer_BM64(beurer::bm::Config),
+    GATT_Body_Composition(gatt::body_composition::Config),
+    GATT_Glucose(gatt::glucose::Config),
+    GATT_Health_Thermometer(gatt::health_thermometer::Config),
+    GATT_Heart_Rate(gatt::heart_rate::Config),
+    GATT_Weight_Scale(gatt::weight_scale::Config),
+    #[serde(deserialize_with = "omron::hem::Config::parse_experimental")]
+    Omron_BP7900(omron::hem::Config),
+    #[serde(deserialize_with = "omron::hbf_702t::Config::parse_experimental")]
+    Omron_HBF_702T(omron::hbf_702t::Config),
+    Omron_HEM_6232T(omron::hem::Config),
+    Omron_HEM_7143T(omron::hem::Config),
+    Omron_HEM_7155T(omron::hem::Config),
+    Omron_HEM_7322T(omron::hem::Config),
+    Omron_HEM_7361T(omron::hem::Config),
     Omron_HN_300T2(omron::hn_300t2::Config),
+    Sanitas_SBF70(beurer::bf::Config),
+    Withings_Body(withings::body::Config),
+    Withings_Thermo(withings::thermo::Config),
+    Xiaomi_XMTZC05HM(xiaomi::xmtzc05hm::Config),
+}
+
+impl DriverConfig {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            DriverConfig::Beurer_BF700(_) => "Beurer_BF700",
+            DriverConfig::Beurer_BF720(_) => "Beurer_BF720",
+            DriverConfig::Beurer_BM57(_) => "Beurer_BM57",
+            DriverConfig::Beurer_BM64(_) => "Beurer_BM64",
+            DriverConfig::GATT_Body_Composition(_) => "GATT_Body_Composition",
+            DriverConfig::GATT_Glucose(_) => "GATT_Glucose",
+            DriverConfig::GATT_Health_Thermometer(_) => "GATT_Health_Thermometer",
+            DriverConfig::GATT_Heart_Rate(_) => "GATT_Heart_Rate",
+            DriverConfig::GATT_Weight_Scale(_) => "GATT_Weight_Scale",
+            DriverConfig::Omron_BP7900(_) => "Omron_BP7900",
+            DriverConfig::Omron_HBF_702T(_) => "Omron_HBF_702T",
+            DriverConfig::Omron_HEM_6232T(_) => "Omron_HEM_6232T",
+            DriverConfig::Omron_HEM_7143T(_) => "Omron_HEM_7143T",
+            DriverConfig::Omron_HEM_7155T(_) => "Omron_HEM_7155T",
+            DriverConfig::Omron_HEM_7322T(_) => "Omron_HEM_7322T",
+            DriverConfig::Omron_HEM_7361T(_) => "Omron_HEM_7361T",
+            DriverConfig::Omron_HN_300T2(_) => "Omron_HN_300T2",
+            DriverConfig::Sanitas_SBF70(_) => "Sanitas_SBF70",
+            DriverConfig::Withings_Body(_) => "Withings_Body",
+            DriverConfig::Withings_Thermo(_) => "Withings_Thermo",
+            DriverConfig::Xiaomi_XMTZC05HM(_) => "Xiaomi_XMTZC05HM",
+        }
+    }
 }
 
 #[async_trait]
 pub trait Driver { // TODO: Have "driver-classes" to simplify coding of additional drivers/reduce boilerplate code?
     async fn pair(&self) -> Result<(), String>;
+
+    async fn repair(&self) -> Result<(), String> { // Rewrite the application-level key over an existing bond.
+        Err(String::from("Re-pairing is not supported by driver, remove the bond and pair again"))
+    }
+
+    fn get_addr(&self) -> &Address;
+
+    fn get_secret(&self) -> Option<&[u8]> { // Key written to the unit during pairing.
+        None
+    }
+
+    fn get_pair_hint(&self) -> &'static str { // How to put the unit in pairing mode.
+        "put the unit in pairing mode (see instruction manual)"
+    }
+
     async fn get_records(&self) -> Result<DbRecords, String>;
+
+    async fn commit(&self) -> Result<(), String> { // The records of the last get_records() are written to the DB (or parked).
+        Ok(())
+    }
+
+    async fn measure(&self) -> Result<DbRecords, String> { // Start a measurement on the unit and return its result.
+        Err(String::from("Triggered measurement is not supported by driver"))
+    }
+
+    fn can_measure(&self) -> bool { // Whether measure() is implemented, so the APIs can refuse the trigger right away.
+        false
+    }
+
+    fn is_streaming(&self) -> bool { // Streaming drivers deliver records through stream() instead of get_records().
+        false
+    }
+
+    async fn stream(&self, _tx: RecordSender) -> Result<(), String> { // Deliver records incrementally until the device goes away.
+        Err(String::from("Streaming is not supported by driver"))
+    }
+
+    fn is_passive(&self) -> bool { // Passive drivers decode advertisements in get_records(), the unit is neither paired nor connected.
+        false
+    }
+}
+
+pub type RecordSender = mpsc::Sender<DbRecords>;
+
+#[derive(Default)]
+pub struct FetchMeter { // Shared between the driver and the device task, which collects it after each fetch.
+    bytes_read: AtomicU64,
+    adv_at: Mutex<Option<Instant>>,
+}
+
+pub type FetchMeterPtr = Arc<FetchMeter>;
+
+impl FetchMeter {
+    pub fn add_bytes(&self, len: usize) {
+        self.bytes_read.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn take_bytes(&self) -> u64 {
+        self.bytes_read.swap(0, Ordering::Relaxed)
+    }
+
+    fn mark_adv(&self) {
+        *self.adv_at.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn take_duration(&self) -> Option<Duration> { // Time elapsed since the advertisement was received.
+        self.adv_at.lock().unwrap().take().map(|adv_at| adv_at.elapsed())
+    }
+}
+
+#[derive(Default)]
+pub struct FetchBuffer { // Records decoded so far, the device task uploads them while the fetch goes on (or fails midway).
+    state: Mutex<FetchBufferState>,
+    added: Notify,
+}
+
+#[derive(Default)]
+struct FetchBufferState {
+    records: DbRecords,
+    closed: bool, // The fetch has finished.
+}
+
+pub type FetchBufferPtr = Arc<FetchBuffer>;
+
+impl FetchBuffer {
+    pub fn add(&self, record: &DbRecord) {
+        self.extend(std::slice::from_ref(record));
+    }
+
+    pub fn extend(&self, records: &[DbRecord]) {
+        // Records added must also be the first ones returned by get_records() (in the same order) if the fetch succeeds,
+        // the device task drops them from there.
+
+        self.state.lock().unwrap().records.extend_from_slice(records);
+        self.added.notify_one();
+    }
+
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.added.notify_one();
+    }
+
+    pub async fn next(&self) -> Option<DbRecords> {
+        // Waits for the records added since the previous call, None once closed and drained (the buffer is reusable
+        // then).
+
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+
+                if !state.records.is_empty() {
+                    return Some(mem::take(&mut state.records));
+                }
+
+                if state.closed {
+                    state.closed = false;
+                    return None;
+                }
+            }
+
+            self.added.notified().await;
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum PairStep {
+    Discovering,
+    Connecting,
+    Bonding,
+    Bonded,
+    WritingKey,
+    SyncingTime,
+}
+
+impl PairStep {
+    pub fn get_msg(&self) -> &'static str {
+        match self {
+            PairStep::Discovering => "discovering unit",
+            PairStep::Connecting => "connecting",
+            PairStep::Bonding => "bonding, confirm on the unit if it asks",
+            PairStep::Bonded => "unit confirmed pairing",
+            PairStep::WritingKey => "writing key",
+            PairStep::SyncingTime => "synchronizing time",
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool { // Failing here usually means that the unit left pairing mode too early.
+        matches!(self, PairStep::Connecting | PairStep::Bonding)
+    }
+}
+
+#[derive(Default)]
+pub struct PairProgress { // Shared between the driver and Device::pair(), which checks where pairing failed.
+    step: Mutex<Option<PairStep>>,
 }
 
-pub fn create(id: &str, config: DriverConfig) -> Box<dyn Driver + Send> { // Send is needed because of async.
-    // TODO: replace id parameter with logger(?)
+pub type PairProgressPtr = Arc<PairProgress>;
+
+impl PairProgress {
+    fn set_step(&self, step: PairStep) {
+        *self.step.lock().unwrap() = Some(step);
+    }
+
+    pub fn take_step(&self) -> Option<PairStep> {
+        self.step.lock().unwrap().take()
+    }
+}
+
+pub struct DriverContext {
+    pub id: String,
+    pub driver: &'static str, // Driver name, set by create().
+    pub identity_check: Option<IdentityCheck>, // Depends on the driver's fingerprints if unset, see IdentityCheck::get_default().
+    pub debug_protocol: bool, // Trace decoded protocol traffic.
+    pub fetch_timeout: Option<Duration>, // Deadline for a fetch, counted from receiving the advertisement.
+    pub full_read: bool, // Read the whole memory, ignoring unread record counts (full backfill).
+    pub adv_timeout: Option<Duration>, // Log a heartbeat and re-register the monitor if no advertisement arrives within this.
+    pub adv_direct_fallback: bool, // Connect anyway once adv_timeout has passed.
+    pub poll: Poll,
+    pub skip_if_connected: bool, // Leave the device alone if somebody else (e.g. vendor app) is connected to it.
+    pub window: Option<WindowConfig>,
+    pub trigger: TriggerPtr, // Fired when a fetch is triggered (e.g. through the API), see control.rs.
+    pub meter: FetchMeterPtr,
+    pub buffer: FetchBufferPtr,
+    pub pair_progress: PairProgressPtr,
+    status: StatusPtr,
+    backend: BTBackendPtr,
+    store: StorePtr,
+}
+
+impl DriverContext {
+    pub fn new(id: &str, debug_protocol: bool, status: StatusPtr, backend: BTBackendPtr, store: StorePtr) -> Self {
+        Self {
+            id: String::from(id),
+            driver: "",
+            identity_check: None,
+            debug_protocol,
+            fetch_timeout: None,
+            full_read: false,
+            adv_timeout: None,
+            adv_direct_fallback: false,
+            poll: Poll::default(),
+            skip_if_connected: false,
+            window: None,
+            trigger: TriggerPtr::default(),
+            meter: FetchMeterPtr::default(),
+            buffer: FetchBufferPtr::default(),
+            pair_progress: PairProgressPtr::default(),
+            status,
+            backend,
+            store,
+        }
+    }
+
+    pub fn set_state(&self, state: DeviceState) {
+        self.status.set_state(&self.id, state);
+    }
+
+    pub fn set_pair_step(&self, step: PairStep) {
+        println!("{}: {}", self.id, step.get_msg());
+        self.pair_progress.set_step(step);
+    }
+
+    pub async fn get_link(&self, addr: &Address, do_disco: bool) -> btutil::Result<BTLinkPtr> {
+        self.backend.get_link(addr, do_disco).await
+    }
+
+    pub async fn wait_for_adv(&self, addr: &Address, patterns: &[AdvPattern]) -> btutil::Result<()> {
+        // Passive listen for advertisements, through the shared scanner. Returns without an advertisement only if
+        // falling back to direct connection, polling directly or the fetch is triggered.
+
+        let triggered = self.trigger.take(); // Also clears a trigger which is moot with direct polling.
+
+        if self.poll == Poll::Direct {
+            return Ok(());
+        }
+
+        if triggered {
+            println!("{}: fetch triggered, connecting directly", self.id);
+            return Ok(());
+        }
+
+        tokio::select! {
+            result = self.listen_adv(addr, patterns) => result,
+            _ = self.trigger.wait() => {
+                self.trigger.take();
+                println!("{}: fetch triggered, connecting directly", self.id);
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn recv_adv(&self, addr: &Address, patterns: &[AdvPattern]) -> btutil::Result<AdvData> {
+        // Next advertisement of a broadcast-only unit, there is nothing to connect to when polling directly or triggered.
+
+        let adv_data = Otel::span("wait_for_adv", self.backend.wait_for_adv(addr, patterns)).await?;
+        self.seen_adv();
+
+        Ok(adv_data)
+    }
+
+    async fn listen_adv(&self, addr: &Address, patterns: &[AdvPattern]) -> btutil::Result<()> {
+        let timeout = match self.adv_timeout {
+            Some(timeout) => timeout,
+            None => {
+                Otel::span("wait_for_adv", self.backend.wait_for_adv(addr, patterns)).await?;
+                self.seen_adv();
+                return Ok(());
+            }
+        };
+
+        Otel::span("wait_for_adv", async {
+            loop {
+                match time::timeout(timeout, self.backend.wait_for_adv(addr, patterns)).await {
+                    Ok(result) => {
+                        result?;
+                        self.seen_adv();
+                        return Ok(());
+                    },
+                    Err(_) => {
+                        self.backend.rearm_adv();
+
+                        if self.adv_direct_fallback {
+                            println!("{}: no advertisement for {} s, connecting directly", self.id, timeout.as_secs());
+                            return Ok(());
+                        }
+
+                        println!("{}: no advertisement for {} s, still waiting", self.id, timeout.as_secs());
+                    }
+                }
+            }
+        }).await
+    }
+
+    pub async fn check_policy(&self, link: &BTLinkPtr) -> btutil::Result<()> {
+        // Co-existence with vendor apps, called right before connecting.
+
+        if let Some(window) = &self.window {
+            if window.get_secs_until() > 0 {
+                return Err("Outside of fetch window".into());
+            }
+        }
+
+        if self.skip_if_connected && link.is_connected().await? {
+            return Err("Device is connected by another client, skipping".into());
+        }
+
+        Ok(())
+    }
+
+    pub async fn check_device(&self, link: &BTLinkPtr) -> btutil::Result<()> {
+        // Make sure the unit is handled by this driver, see fingerprints.toml.
+
+        let identity_check = self.identity_check.unwrap_or_else(|| IdentityCheck::get_default(self.driver));
+
+        if let IdentityCheck::Off = identity_check {
+            return Ok(());
+        }
+
+        if Fingerprint::is_generic(self.driver) { // Any unit speaking the standard profile, device information is optional.
+            if let Err(e) = self.get_device_info(link).await {
+                eprintln!("{}: unable to read device information: {}", self.id, e);
+            }
+
+            return Ok(());
+        }
+
+        let device_info = self.get_device_info(link).await?;
+
+        match (Fingerprint::check(self.driver, &device_info), identity_check) {
+            (Ok(()), _) => Ok(()),
+            (Err(e), IdentityCheck::Relaxed) if Fingerprint::is_manufacturer(self.driver, &device_info.manufacturer) => {
+                eprintln!("{}: {}, continuing since the manufacturer matches", self.id, e);
+                Ok(())
+            },
+            (Err(e), IdentityCheck::Relaxed) if Fingerprint::is_assumed(self.driver) && Fingerprint::find(&device_info).is_none() => {
+                eprintln!("{}: {}, continuing since the driver's fingerprint is not confirmed yet, please report the unit's manufacturer and model", self.id, e);
+                Ok(())
+            },
+            (Err(e), _) => Err(btutil::Error::General(e)),
+        }
+    }
+
+    async fn get_device_info(&self, link: &BTLinkPtr) -> btutil::Result<BTDeviceInfo> {
+        // Firmware version is kept in the store, for version tags.
+
+        let device_info = BTUtil::get_device_info(link).await?;
+        self.store.update_device(&self.id, |entry| entry.firmware = Some(device_info.firmware.clone()));
+
+        Ok(device_info)
+    }
+
+    pub async fn get_status(&self, link: &BTLinkPtr) -> Option<DbRecord> {
+        // Battery level of the standard Battery Service, drivers add their vendor specific status. Best effort, a unit
+        // without a readable status shouldn't fail the fetch.
+
+        let battery = match BTUtil::get_battery_level(link).await {
+            Ok(battery) => battery,
+            Err(e) => {
+                eprintln!("{}: unable to read device status: {}", self.id, e);
+                return None;
+            }
+        };
+
+        let mut record = Self::get_status_record();
+        record.add_field("battery", DbFieldValue::Integer(battery.into()));
+
+        Some(record)
+    }
+
+    pub fn get_status_record() -> DbRecord {
+        let mut record = DbRecord::new(TimeUtil::get_ts_unix(TimeUtil::get_current_unix()));
+        record.set_meas(STATUS_MEAS);
+        record
+    }
+
+    fn seen_adv(&self) {
+        self.meter.mark_adv();
+
+        let now = TimeUtil::get_current_unix();
+        self.status.set_last_adv(&self.id, Some(now));
+        self.store.update_device(&self.id, |entry| entry.last_adv = Some(now));
+    }
+}
+
+pub fn create(mut ctx: DriverContext, config: DriverConfig) -> Box<dyn Driver + Send + Sync> { // Send and Sync are needed because of async.
+    ctx.driver = config.get_name();
+
     match config {
-        DriverConfig::Omron_HEM_7361T(config) => Box::new(omron::hem_7361t::DriverImpl::new(id, config)),
-        DriverConfig::Omron_HN_300T2(config) => Box::new(omron::hn_300t2::DriverImpl::new(id, config)),
+        DriverConfig::Beurer_BF700(config) => Box::new(beurer::bf::DriverImpl::new(ctx, beurer::bf::START_BEURER, config)),
+        DriverConfig::Beurer_BF720(config) => Box::new(beurer::bf::DriverImpl::new(ctx, beurer::bf::START_BEURER, config)),
+        DriverConfig::Beurer_BM57(config) => Box::new(beurer::bm::DriverImpl::new(ctx, config)),
+        DriverConfig::Beurer_BM64(config) => Box::new(beurer::bm::DriverImpl::new(ctx, config)),
+        DriverConfig::GATT_Body_Composition(config) => Box::new(gatt::body_composition::DriverImpl::new(ctx, config)),
+        DriverConfig::GATT_Glucose(config) => Box::new(gatt::glucose::DriverImpl::new(ctx, config)),
+        DriverConfig::GATT_Health_Thermometer(config) => Box::new(gatt::health_thermometer::DriverImpl::new(ctx, config)),
+        DriverConfig::GATT_Heart_Rate(config) => Box::new(gatt::heart_rate::DriverImpl::new(ctx, config)),
+        DriverConfig::GATT_Weight_Scale(config) => Box::new(gatt::weight_scale::DriverImpl::new(ctx, config)),
+        DriverConfig::Omron_BP7900(config) => Box::new(omron::hem::DriverImpl::new(ctx, Model::get("bp7900"), config)),
+        DriverConfig::Omron_HBF_702T(config) => Box::new(omron::hbf_702t::DriverImpl::new(ctx, config)),
+        DriverConfig::Omron_HEM_6232T(config) => Box::new(omron::hem::DriverImpl::new(ctx, Model::get("hem_6232t"), config)),
+        DriverConfig::Omron_HEM_7143T(config) => Box::new(omron::hem::DriverImpl::new(ctx, Model::get("hem_7143t"), config)),
+        DriverConfig::Omron_HEM_7155T(config) => Box::new(omron::hem::DriverImpl::new(ctx, Model::get("hem_7155t"), config)),
+        DriverConfig::Omron_HEM_7322T(config) => Box::new(omron::hem::DriverImpl::new(ctx, Model::get("hem_7322t"), config)),
+        DriverConfig::Omron_HEM_7361T(config) => Box::new(omron::hem::DriverImpl::new(ctx, Model::get("hem_7361t"), config)),
+        DriverConfig::Omron_HN_300T2(config) => Box::new(omron::hn_300t2::DriverImpl::new(ctx, config)),
+        DriverConfig::Sanitas_SBF70(config) => Box::new(beurer::bf::DriverImpl::new(ctx, beurer::bf::START_SANITAS, config)),
+        DriverConfig::Withings_Body(config) => Box::new(withings::body::DriverImpl::new(ctx, config)),
+        DriverConfig::Withings_Thermo(config) => Box::new(withings::thermo::DriverImpl::new(ctx, config)),
+        DriverConfig::Xiaomi_XMTZC05HM(config) => Box::new(xiaomi::xmtzc05hm::DriverImpl::new(ctx, config)),
     }
 }
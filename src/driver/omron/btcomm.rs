@@ -1,23 +1,43 @@
 //! # Omron specific RX/TX routines
 
-use bluer::Device;
-use bluer::gatt::remote::Characteristic;
-use futures::{Stream, StreamExt};
+use futures::StreamExt;
+use serde::Deserialize;
+use std::collections::VecDeque;
 use std::iter;
-use std::pin::Pin;
 use uuid::Uuid;
 
-use crate::btutil::{self, BTUtil};
+use crate::btutil::{self, BTLinkPtr, BTRxStream};
+use crate::db::{DbRecord, DbRecords};
+use crate::driver::{DriverContext, FetchBufferPtr, FetchMeterPtr};
+use crate::otel::Otel;
+use crate::redact::Redact;
+use crate::tracelog::TraceLog;
 
 const PKT_HDR_SIZE: usize = 4; // Including len, op and crc.
+const READ_OVERHEAD: usize = 3; // Address and length in read response.
+const WRITE_OVERHEAD: usize = 4; // Address, length and trailing zero in write command.
+const MIN_BLOCK_SIZE: usize = 0x08; // Give up lowering the block size below this.
+const PIPELINE_DEPTH: usize = 2; // EEPROM reads in flight if pipelining: the next one is issued while the previous one is answered.
 
 pub struct BTComm {
-    tx_chars: Vec<Characteristic>,
-    rx_streams: Vec<BTCommRxStream>,
+    link: BTLinkPtr,
+    service_uuid: Uuid,
+    tx_chars: Vec<Uuid>,
+    rx_streams: Vec<BTRxStream>,
     cmd_chunk_size: usize,
+    trace: Option<String>, // Device id, if protocol tracing is enabled.
+    block_limit: u8, // Largest EEPROM block size accepted by the unit so far.
+    pipeline: bool,
+    meter: FetchMeterPtr,
+    buffer: FetchBufferPtr,
 }
 
-type BTCommRxStream = Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>; // See return value of Characteristic->notify().
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserBank { // EEPROM region holding the records of a user, in user order.
+    pub start: u16,
+    pub count: usize, // Number of record slots.
+}
 
 pub struct BTCommCmdResp {
     op: u16,
@@ -25,20 +45,14 @@ pub struct BTCommCmdResp {
 }
 
 impl BTComm {
+    pub const MAX_BLOCK_SIZE: u8 = u8::MAX; // Let read_eeprom()/write_eeprom() use the largest block size accepted by the unit.
+
     // TODO: Implement retry and timeout for bt operations.
     // TODO: connect timeout/pair timeout.
 
-    pub async fn new(device: &Device, service_uuid: &Uuid, tx_char_uuids: &[&Uuid], rx_char_uuids: &[&Uuid], cmd_chunk_size: usize) -> btutil::Result<Self> {
-        assert!(!tx_char_uuids.is_empty() && !rx_char_uuids.is_empty());
-        let service = BTUtil::lookup_service(device, service_uuid).await?;
-
-        // Obtain characteristic for TX.
-
-        let mut tx_chars = Vec::new();
-
-        for tx_char_uuid in tx_char_uuids {
-            let tx_char = BTUtil::lookup_char(&service, tx_char_uuid).await?;
-            tx_chars.push(tx_char);
+    pub async fn new(ctx: &DriverContext, link: &BTLinkPtr, service_uuid: &Uuid, tx_char_uuids: &[&Uuid], rx_char_uuids: &[&Uuid], cmd_chunk_size: usize) -> btutil::Result<Self> {
+        if tx_char_uuids.is_empty() || rx_char_uuids.is_empty() || cmd_chunk_size == 0 {
+            return Err("Invalid lane configuration".into());
         }
 
         // Obtain streams for RX.
@@ -46,29 +60,39 @@ impl BTComm {
         let mut rx_streams = Vec::new();
 
         for rx_char_uuid in rx_char_uuids {
-            let rx_char = BTUtil::lookup_char(&service, rx_char_uuid).await?;
-            let rx_stream = rx_char.notify().await?;
-            let rx_stream: BTCommRxStream = Box::pin(rx_stream);
-            rx_streams.push(rx_stream);
+            rx_streams.push(link.notify_char(service_uuid, rx_char_uuid).await?);
         }
 
         Ok(Self {
-            tx_chars,
+            link: BTLinkPtr::clone(link),
+            service_uuid: *service_uuid,
+            tx_chars: tx_char_uuids.iter().map(|tx_char_uuid| **tx_char_uuid).collect(),
             rx_streams,
             cmd_chunk_size,
+            trace: if ctx.debug_protocol { Some(ctx.id.clone()) } else { None },
+            block_limit: u8::MAX,
+            pipeline: false,
+            meter: FetchMeterPtr::clone(&ctx.meter),
+            buffer: FetchBufferPtr::clone(&ctx.buffer),
         })
     }
 
     pub async fn raw(&mut self, tx_data: &[u8], rx_data: &mut [u8]) -> btutil::Result<()> {
         // Write data.
 
-        assert!(self.tx_chars.len() == 1 && self.rx_streams.len() == 1);
-        self.tx_chars[0].write(tx_data).await?;
+        if self.tx_chars.len() != 1 || self.rx_streams.len() != 1 {
+            return Err("Raw exchange needs a single lane".into());
+        }
+
+        self.trace(|| format!("raw tx: {}", hex::encode(tx_data)));
+        self.link.write_char(&self.service_uuid, &self.tx_chars[0], tx_data).await?;
 
         // Read data.
 
         match self.rx_streams[0].next().await {
             Some(buf) => {
+                self.meter.add_bytes(buf.len());
+                self.trace(|| format!("raw rx: {}", hex::encode(&buf)));
                 let rx_data_len = rx_data.len();
 
                 if buf.len() < rx_data_len {
@@ -82,14 +106,27 @@ impl BTComm {
         }
     }
 
+    pub fn set_pipeline(&mut self, pipeline: bool) {
+        // Only for units known to queue commands, the responses arrive in order on the RX lanes.
+
+        self.pipeline = pipeline;
+    }
+
     pub async fn cmd(&mut self, op: u16, data: &[u8]) -> btutil::Result<BTCommCmdResp> {
+        self.send_cmd(op, data).await?;
+        self.recv_resp().await
+    }
+
+    async fn send_cmd(&mut self, op: u16, data: &[u8]) -> btutil::Result<()> {
         // Construct packet.
 
         let pkt_len = data.len() + PKT_HDR_SIZE;
-        assert!(pkt_len <= self.tx_chars.len() * self.cmd_chunk_size);
+        if pkt_len > self.tx_chars.len() * self.cmd_chunk_size || pkt_len > u8::MAX.into() { // Must fit into the lanes and the length field.
+            return Err("Command is too long".into());
+        }
 
         let mut pkt = Vec::new();
-        pkt.push(pkt_len.try_into().unwrap()); // Make sure we fit in u8.
+        pkt.push(pkt_len as u8);
         pkt.push((op >> 8) as u8);
         pkt.push((op & 0xff) as u8);
         pkt.extend_from_slice(data);
@@ -98,10 +135,16 @@ impl BTComm {
 
         // Write command.
 
+        self.trace(|| format!("cmd tx: op={:04x} len={} data={}", op, data.len(), hex::encode(data)));
+
         for (tx_char, buf) in iter::zip(&self.tx_chars, pkt.chunks(self.cmd_chunk_size)) {
-            tx_char.write(buf).await?;
+            self.link.write_char(&self.service_uuid, tx_char, buf).await?;
         }
 
+        Ok(())
+    }
+
+    async fn recv_resp(&mut self) -> btutil::Result<BTCommCmdResp> {
         // Receive response.
 
         let mut pkt = Vec::new();
@@ -112,6 +155,7 @@ impl BTComm {
                 Some(buf) => buf,
                 None => return Err("Unable to receive packet".into()),
             };
+            self.meter.add_bytes(buf.len());
 
             if i == 0 { // First chunk.
                 if buf.is_empty() {
@@ -130,15 +174,30 @@ impl BTComm {
             }
         }
 
-        if pkt.len() < pkt_len {
+        // Process response.
+
+        let resp = Self::decode_resp(&pkt)?;
+
+        self.trace(|| format!("cmd rx: op={:04x} len={} data={}", resp.op, resp.data.len(), hex::encode(&resp.data)));
+
+        Ok(resp)
+    }
+
+    pub fn decode_resp(pkt: &[u8]) -> btutil::Result<BTCommCmdResp> {
+        // Response as received on the RX lanes, anything after the length given in the header is ignored.
+
+        let pkt_len: usize = match pkt.first() {
+            Some(pkt_len) => (*pkt_len).into(),
+            None => return Err("Received packet is too short".into()),
+        };
+
+        if pkt_len < PKT_HDR_SIZE || pkt.len() < pkt_len {
             return Err("Received packet is too short".into());
         }
 
-        pkt.truncate(pkt_len);
+        let pkt = &pkt[..pkt_len];
 
-        // Process response.
-
-        if Self::crc(&pkt) != 0 {
+        if Self::crc(pkt) != 0 {
             return Err("CRC error in received packet".into());
         }
 
@@ -152,6 +211,24 @@ impl BTComm {
         })
     }
 
+    pub fn decode_read_resp(addr: u16, todo: usize, resp: &BTCommCmdResp) -> Option<&[u8]> {
+        // Returns None if the unit rejected the request.
+
+        let resp_data = &resp.data;
+
+        if resp.op != 0x8100 || resp_data.len() < 3 {
+            return None;
+        }
+
+        let resp_addr = (resp_data[0] as u16) << 8 | (resp_data[1] as u16);
+        let resp_todo = resp_data[2] as usize;
+        if resp_addr != addr || resp_todo != todo {
+            return None;
+        }
+
+        Some(&resp_data[3..])
+    }
+
     pub async fn start_trans(&mut self) -> btutil::Result<()> {
         let resp = self.cmd(0x0000, &[0x00, 0x00, 0x10, 0x00]).await?;
         if resp.op != 0x8000 {
@@ -171,83 +248,229 @@ impl BTComm {
     }
 
     pub async fn read_eeprom(&mut self, start: u16, data: &mut [u8], block_size: u8) -> btutil::Result<bool> {
-        assert!(block_size > 0);
+        Otel::span("read_eeprom", self.read_blocks(start, data, block_size)).await
+    }
 
-        let mut cmd_data = Vec::new(); // Allocate command buffer.
-        let mut addr = start;
+    async fn read_blocks(&mut self, start: u16, data: &mut [u8], block_size: u8) -> btutil::Result<bool> {
+        // block_size is an upper limit: it is reduced to fit into the RX lanes, and lowered further if the unit rejects it.
 
-        for buf in data.chunks_mut(block_size.into()) {
-            let todo = buf.len();
-            assert!(todo > 0);
+        if block_size == 0 {
+            return Err("Invalid block size".into());
+        }
 
-            cmd_data.clear();
-            cmd_data.push((addr >> 8) as u8);
-            cmd_data.push((addr & 0xff) as u8);
-            cmd_data.push(todo.try_into().unwrap()); // Make sure we fit in u8.
-            cmd_data.push(0x00);
+        let depth = if self.pipeline { PIPELINE_DEPTH } else { 1 };
+        let mut pending = VecDeque::new(); // Lengths of the reads in flight, the first one starts at offset.
+        let mut offset = 0; // Received up to here.
+        let mut next = 0; // Requested up to here.
 
-            let resp = self.cmd(0x0100, &cmd_data).await?;
-            let resp_data = resp.data;
-            let resp_data_len = resp_data.len();
+        while offset < data.len() {
+            while pending.len() < depth && next < data.len() {
+                let todo = (data.len() - next).min(self.get_block_size(block_size, self.rx_streams.len(), READ_OVERHEAD)?.into());
 
-            if resp.op != 0x8100 || resp_data_len < 3 {
-                return Err("Invalid response".into());
+                self.send_read(Self::get_addr(start, next)?, todo).await?;
+                pending.push_back(todo);
+                next += todo;
             }
 
-            let resp_addr = (resp_data[0] as u16) << 8 | (resp_data[1] as u16);
-            let resp_todo = resp_data[2] as usize;
-            if resp_addr != addr || resp_todo != todo {
-                return Err("Invalid response".into());
+            let todo = pending.pop_front().unwrap(); // Something is in flight until everything is received.
+            let resp_data = self.recv_read(Self::get_addr(start, offset)?, todo).await?;
+
+            if let Some(resp_data) = resp_data.as_ref().filter(|resp_data| resp_data.len() >= todo) { // TODO: do we need to consider padding?
+                data[offset..offset + todo].copy_from_slice(&resp_data[..todo]);
+                offset += todo;
+                continue;
             }
 
-            let expected = 3 + todo;
-            if resp_data_len < expected { // TODO: do we need to consider padding?
-                return Ok(false);
+            // Drain the answers to the reads issued after this one, then go on from here.
+
+            while pending.pop_front().is_some() {
+                self.recv_resp().await?;
             }
-            buf.copy_from_slice(&resp_data[3..expected]);
 
-            addr += todo as u16;
+            next = offset;
+
+            match resp_data {
+                Some(_) => return Ok(false),
+                None => self.lower_block_size(todo)?,
+            }
         }
 
         Ok(true)
     }
 
-    pub async fn write_eeprom(&mut self, start: u16, data: &[u8], block_size: u8) -> btutil::Result<()> {
-        assert!(block_size > 0);
+    pub async fn read_banks<F>(&mut self, banks: &[UserBank], rec_len: usize, block_size: u8, decode: F) -> btutil::Result<DbRecords> where F: Fn(usize, &[u8]) -> Option<DbRecord> {
+        // decode() gets the user index (0-based) and a record slot, returns None for empty/unreadable slots.
+        // Records of finished banks are put into the fetch buffer, so they are uploaded while the next bank is read (and
+        // kept if the connection drops later).
 
-        let mut cmd_data = Vec::new(); // Allocate command buffer.
-        let mut addr = start;
+        let mut records = DbRecords::new();
 
-        for buf in data.chunks(block_size.into()) {
-            let todo = buf.len();
-            assert!(todo > 0);
+        for (user, bank) in banks.iter().enumerate() {
+            let start = records.len();
 
-            cmd_data.clear();
-            cmd_data.push((addr >> 8) as u8);
-            cmd_data.push((addr & 0xff) as u8);
-            cmd_data.push(todo.try_into().unwrap()); // Make sure we fit in u8.
-            cmd_data.extend_from_slice(buf);
-            cmd_data.push(0x00);
+            // Read the whole user bank with large blocks spanning all TX/RX lanes, this needs much less round trips (and
+            // pipelined, if set).
 
-            let resp = self.cmd(0x01c0, &cmd_data).await?;
-            let resp_data = resp.data;
+            let mut data = vec![0; bank.count * rec_len];
 
-            if resp.op != 0x81c0 || resp_data.len() < 2 {
-                return Err("Invalid response".into());
+            if self.read_eeprom(bank.start, &mut data, block_size).await? {
+                Otel::sync_span("decode", || records.extend(data.chunks(rec_len).filter_map(|slot| decode(user, slot))));
+            } else {
+                // Unit returned short data, fall back to reading records one by one and skip the unreadable ones.
+
+                for i in 0..bank.count {
+                    let addr = Self::get_addr(bank.start, i * rec_len)?;
+                    let mut slot = vec![0; rec_len];
+
+                    if self.read_eeprom(addr, &mut slot, Self::get_block_len(rec_len)?).await? {
+                        if let Some(record) = decode(user, &slot) {
+                            records.push(record);
+                        }
+                    }
+                }
             }
 
-            let resp_addr = (resp_data[0] as u16) << 8 | (resp_data[1] as u16);
-            if resp_addr != addr { // TODO: do we need to check todo (like in read_eeprom)?
-                return Err("Invalid response".into());
+            self.buffer.extend(&records[start..]);
+        }
+
+        Ok(records)
+    }
+
+    pub async fn write_eeprom(&mut self, start: u16, data: &[u8], block_size: u8) -> btutil::Result<()> {
+        // block_size is an upper limit: it is reduced to fit into the TX lanes, and lowered further if the unit rejects it.
+
+        if block_size == 0 {
+            return Err("Invalid block size".into());
+        }
+
+        let mut offset = 0;
+
+        while offset < data.len() {
+            let addr = Self::get_addr(start, offset)?;
+            let todo = (data.len() - offset).min(self.get_block_size(block_size, self.tx_chars.len(), WRITE_OVERHEAD)?.into());
+
+            if self.write_block(addr, &data[offset..offset + todo]).await? {
+                offset += todo;
+            } else {
+                self.lower_block_size(todo)?;
             }
+        }
+
+        Ok(())
+    }
+
+    async fn send_read(&mut self, addr: u16, todo: usize) -> btutil::Result<()> {
+        let cmd_data = [(addr >> 8) as u8, (addr & 0xff) as u8, Self::get_block_len(todo)?, 0x00];
+
+        self.trace(|| format!("read_eeprom: addr={:04x} len={}", addr, todo));
+
+        self.send_cmd(0x0100, &cmd_data).await
+    }
+
+    async fn recv_read(&mut self, addr: u16, todo: usize) -> btutil::Result<Option<Vec<u8>>> {
+        // Returns None if the unit rejected the request.
+
+        let resp = self.recv_resp().await?;
+
+        Ok(Self::decode_read_resp(addr, todo, &resp).map(Vec::from))
+    }
+
+    async fn write_block(&mut self, addr: u16, buf: &[u8]) -> btutil::Result<bool> {
+        // Returns false if the unit rejected the request.
+
+        let todo = buf.len();
+
+        let mut cmd_data = Vec::new();
+        cmd_data.push((addr >> 8) as u8);
+        cmd_data.push((addr & 0xff) as u8);
+        cmd_data.push(Self::get_block_len(todo)?);
+        cmd_data.extend_from_slice(buf);
+        cmd_data.push(0x00);
+
+        self.trace(|| format!("write_eeprom: addr={:04x} len={}", addr, todo));
+
+        let resp = self.cmd(0x01c0, &cmd_data).await?;
+        let resp_data = resp.data;
+
+        if resp.op != 0x81c0 || resp_data.len() < 2 {
+            return Ok(false);
+        }
+
+        let resp_addr = (resp_data[0] as u16) << 8 | (resp_data[1] as u16);
+        Ok(resp_addr == addr) // TODO: do we need to check todo (like in read_eeprom)?
+    }
 
-            addr += todo as u16;
+    fn get_block_size(&self, block_size: u8, lanes: usize, overhead: usize) -> btutil::Result<u8> {
+        // Largest block the packet (limited by the lanes and by the u8 length field) can carry.
+
+        let pkt_len = (lanes * self.cmd_chunk_size).min(u8::MAX.into());
+
+        match pkt_len.checked_sub(PKT_HDR_SIZE + overhead) {
+            Some(fit) if fit > 0 => Ok(block_size.min(fit as u8).min(self.block_limit)),
+            _ => Err("Lanes are too small for EEPROM access".into()),
         }
+    }
+
+    fn get_addr(start: u16, offset: usize) -> btutil::Result<u16> {
+        match u16::try_from(offset).ok().and_then(|offset| start.checked_add(offset)) {
+            Some(addr) => Ok(addr),
+            None => Err("EEPROM address is out of range".into()),
+        }
+    }
+
+    fn get_block_len(todo: usize) -> btutil::Result<u8> {
+        u8::try_from(todo).map_err(|_| "Block is too large".into())
+    }
+
+    fn lower_block_size(&mut self, todo: usize) -> btutil::Result<()> {
+        if todo <= MIN_BLOCK_SIZE {
+            return Err("Invalid response".into());
+        }
+
+        self.block_limit = Self::get_block_len(todo / 2)?;
+        self.trace(|| format!("block size lowered to {}", self.block_limit));
 
         Ok(())
     }
 
+    fn trace<F>(&self, f: F) where F: FnOnce() -> String {
+        if let Some(id) = &self.trace {
+            TraceLog::write(id, &Redact::apply(&f()));
+        }
+    }
+
+    pub fn checksum(data: &[u8]) -> u8 { // Used in EEPROM blocks, e.g. time sync.
+        data.iter().fold(0, |acc, b| acc.wrapping_add(*b))
+    }
+
     fn crc(pkt: &[u8]) -> u8 {
         pkt.iter().fold(0, |acc, b| acc ^ b)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::driver::harness::Harness;
+    use super::BTComm;
+
+    #[tokio::test]
+    async fn pipeline() {
+        // Two blocks in flight, the first one is rejected: the answer to the second one is dropped and both are read
+        // again with a lowered block size.
+
+        let (ctx, link, is_consumed) = Harness::link(include_str!("../../../tests/fixtures/omron_btcomm/pipeline.txt"));
+        let service = Uuid::nil();
+        let tx: Vec<Uuid> = ["db5b55e0-aee7-11e1-965e-0002a5d5c51b", "e0b8a060-aee7-11e1-92f4-0002a5d5c51b"].iter().map(|uuid| uuid.parse().unwrap()).collect();
+        let rx: Vec<Uuid> = ["49123040-aee8-11e1-a74d-0002a5d5c51b", "4d0bf320-aee8-11e1-a0d9-0002a5d5c51b"].iter().map(|uuid| uuid.parse().unwrap()).collect();
+
+        let mut comm = BTComm::new(&ctx, &link, &service, &tx.iter().collect::<Vec<_>>(), &rx.iter().collect::<Vec<_>>(), 0x10).await.unwrap_or_else(|e| panic!("{}", e));
+        comm.set_pipeline(true);
+
+        let mut data = [0; 0x20];
+        assert!(comm.read_eeprom(0x0100, &mut data, 0x10).await.unwrap_or_else(|e| panic!("{}", e)));
+        assert_eq!(data.to_vec(), (0..0x20).collect::<Vec<u8>>());
+        assert!(is_consumed());
+    }
+}
@@ -0,0 +1,298 @@
+//! # Omron HBF-702T driver
+//!
+//! Body composition scale (VIVA) with four user slots, speaking the same
+//! EEPROM protocol as the HN-300T2. Its memory map is not documented by the
+//! reverse engineering projects this crate is based on: the user banks and
+//! the record layout below are assumed (HN-300T2 style records extended with
+//! the body composition values), please report a protocol trace
+//! (debug_protocol) if records come out wrong. The time sync is written to
+//! an assumed address too, so the driver is experimental: it has to be
+//! enabled with `experimental: true`.
+
+use async_trait::async_trait;
+use bluer::Address;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use tzfile::Tz;
+use uuid::{uuid, Uuid};
+
+use crate::btutil::{self, AdvPattern, BTLinkPtr, BTUtil};
+use crate::db::{DbFieldValue, DbRecord, DbRecords};
+use crate::driver::{Driver, DriverContext, PairStep};
+use crate::otel::Otel;
+use crate::status::DeviceState;
+use crate::timeutil::TimeUtil;
+use super::btcomm::{BTComm, UserBank};
+
+const COMPANY_ID: u16 = 0x020e; // Omron.
+
+
+const MAIN_SERVICE: &Uuid = &uuid!("0000fe4a-0000-1000-8000-00805f9b34fb");
+const TX_CHAR: &Uuid = &uuid!("db5b55e0-aee7-11e1-965e-0002a5d5c51b");
+const RX_CHAR: &Uuid = &uuid!("49123040-aee8-11e1-a74d-0002a5d5c51b");
+
+const CMD_CHUNK_SIZE: usize = 0xff; // Use large size, so commands are not chunked. // TODO: Use Option<usize>?
+
+const TIMESYNC_ADDR: u16 = 0x0248;
+const TIMESYNC_LEN: usize = 0x08;
+
+const USER_BANKS: &[UserBank] = &[ // The user tag is the bank's position (1-based).
+    UserBank { start: 0x02c0, count: 30 },
+    UserBank { start: 0x04a0, count: 30 },
+    UserBank { start: 0x0680, count: 30 },
+    UserBank { start: 0x0860, count: 30 },
+];
+const REC_LEN: usize = 0x10;
+
+const YEAR: u16 = 2000;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    addr: Address, // TODO: unique check
+    #[serde(default = "crate::timeutil::TimeUtil::get_local_tz", deserialize_with = "crate::timeutil::TimeUtil::parse_tz")]
+    tz: Tz, // Host's timezone if unset.
+    #[serde(default)]
+    keep_connected: bool, // Do not disconnect after pairing/fetching.
+    #[serde(default)]
+    experimental: bool, // Opt-in, the memory map is not verified on a unit.
+}
+
+impl Config {
+    pub fn parse_experimental<'de, D>(deserializer: D) -> Result<Config, D::Error> where D: Deserializer<'de> {
+        // The time sync writes into the EEPROM at an assumed address, which might hold the unit's settings instead.
+
+        let config = Config::deserialize(deserializer)?;
+
+        if !config.experimental {
+            return Err(D::Error::custom("the driver's memory map is not verified on a unit, set experimental: true to use it anyway"));
+        }
+
+        Ok(config)
+    }
+}
+
+pub struct DriverImpl {
+    ctx: DriverContext,
+    config: Config,
+}
+
+impl DriverImpl {
+    pub fn new(ctx: DriverContext, config: Config) -> Self {
+        Self {
+            ctx,
+            config,
+        }
+    }
+
+    async fn pair(&self) -> btutil::Result<()> {
+        // Pair device.
+
+        self.ctx.set_pair_step(PairStep::Discovering);
+        let link = self.ctx.get_link(&self.config.addr, true).await?;
+
+        if link.is_paired().await? {
+            return Err("Device is already paired".into());
+        }
+
+        let result = self.pair_device(&link).await;
+
+        if !self.config.keep_connected {
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn pair_device(&self, link: &BTLinkPtr) -> btutil::Result<()> {
+        self.ctx.set_pair_step(PairStep::Connecting);
+        link.connect().await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_pair_step(PairStep::Bonding);
+        link.pair().await?;
+        self.ctx.set_pair_step(PairStep::Bonded);
+
+        // Synchronize time.
+
+        self.ctx.set_pair_step(PairStep::SyncingTime);
+
+        let mut comm = BTComm::new(&self.ctx, link, MAIN_SERVICE, &[TX_CHAR], &[RX_CHAR], CMD_CHUNK_SIZE).await?;
+        comm.start_trans().await?;
+
+        self.sync_time(&mut comm).await?;
+
+        comm.end_trans().await?;
+
+        Ok(())
+    }
+
+    async fn get_records(&self) -> btutil::Result<DbRecords> {
+        // Connect to device.
+
+        let link = self.ctx.get_link(&self.config.addr, false).await?;
+
+        if !link.is_paired().await? {
+            return Err("Device is not yet paired".into());
+        }
+
+        self.ctx.set_state(DeviceState::WaitingForAdvertisement);
+        self.ctx.wait_for_adv(&self.config.addr, &[AdvPattern::manufacturer(COMPANY_ID, &[], &[])]).await?;
+
+        let result = BTUtil::with_deadline(&link, self.ctx.fetch_timeout, self.fetch(&link)).await;
+
+        if !self.config.keep_connected { // Notification sessions are gone by now, since fetch() has finished.
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn fetch(&self, link: &BTLinkPtr) -> btutil::Result<DbRecords> {
+        self.ctx.check_policy(link).await?;
+        self.ctx.set_state(DeviceState::Connecting);
+        Otel::span("connect", link.connect()).await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_state(DeviceState::Fetching);
+
+        let status = self.ctx.get_status(link).await;
+
+        // Exchange data.
+
+        let mut comm = BTComm::new(&self.ctx, link, MAIN_SERVICE, &[TX_CHAR], &[RX_CHAR], CMD_CHUNK_SIZE).await?;
+        comm.start_trans().await?;
+
+        // Synchronize time.
+
+        self.sync_time(&mut comm).await?;
+
+        // Fetch measurements.
+
+        let mut records = comm.read_banks(USER_BANKS, REC_LEN, BTComm::MAX_BLOCK_SIZE, |user, data| self.get_record(user, data)).await?;
+
+        comm.end_trans().await?;
+
+        records.extend(status);
+
+        Ok(records)
+    }
+
+    fn get_record(&self, user: usize, data: &[u8]) -> Option<DbRecord> {
+        match Self::decode_record(&self.config.tz, user, data) {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("{}: skipping corrupt record: {}", self.ctx.id, e);
+                None
+            }
+        }
+    }
+
+    pub fn decode_record(tz: &Tz, user: usize, data: &[u8]) -> btutil::Result<Option<DbRecord>> {
+        // Returns None for empty slots.
+
+        if data.len() < REC_LEN {
+            return Err("Record is too short".into());
+        }
+
+        let raw_weight = (data[0] as u16) << 8 | (data[1] as u16);
+        let sec = data[7];
+
+        if raw_weight == 0xffff || sec == 63 { // Discard uninitialized/time-desynced data.
+            return Ok(None);
+        }
+
+        let weight = (raw_weight as f64) / 20.0; // Unit reports weight in 50g.
+        let fat = u16::from_be_bytes([data[8], data[9]]);
+        let visceral_fat = data[10];
+        let muscle = u16::from_be_bytes([data[11], data[12]]);
+        let bmi = u16::from_be_bytes([data[13], data[14]]);
+        let year = YEAR + (data[2] as u16);
+        let month = data[3];
+        let day = data[4];
+        let hour = data[5];
+        let min = data[6];
+
+        let ts = match TimeUtil::get_ts(tz, year, month, day, hour, min, sec) {
+            Some(ts) => ts,
+            None => return Err("Invalid timestamp".into()), // Partially written slot, don't produce garbage.
+        };
+        let mut record = DbRecord::new(ts);
+        record.add_tag("user", &format!("{}", user + 1));
+        record.add_field("weight", DbFieldValue::Float(weight));
+        record.add_field("fat", DbFieldValue::Float((fat as f64) / 10.0)); // Body fat in 0.1%.
+        record.add_field("visceral_fat", DbFieldValue::Float((visceral_fat as f64) / 2.0)); // Visceral fat level in 0.5 steps.
+        record.add_field("muscle", DbFieldValue::Float((muscle as f64) / 10.0)); // Skeletal muscle in 0.1%.
+        record.add_field("bmi", DbFieldValue::Float((bmi as f64) / 10.0)); // From the height set on the unit, in 0.1.
+
+        Ok(Some(record))
+    }
+
+    async fn sync_time(&self, comm: &mut BTComm) -> btutil::Result<()> {
+        let mut data = [0; TIMESYNC_LEN];
+        let data_len = data.len();
+
+        let current = TimeUtil::get_current(&self.config.tz);
+        data[0] = match current.year.checked_sub(YEAR).and_then(|year| u8::try_from(year).ok()) {
+            Some(year) => year,
+            None => return Err("Host time is out of range".into()),
+        };
+        data[1] = current.month;
+        data[2] = current.day;
+        data[3] = current.hour;
+        data[4] = current.min;
+        data[5] = current.sec;
+        data[6] = BTComm::checksum(&data[..6]);
+        data[7] = 0xff;
+        
+        comm.write_eeprom(TIMESYNC_ADDR, &data, data_len.try_into().unwrap()).await
+    }
+}
+
+#[async_trait]
+impl Driver for DriverImpl {
+    async fn pair(&self) -> Result<(), String> {
+        self.pair().await.map_err(|e| format!("{}", e))
+    }
+
+    fn get_addr(&self) -> &Address {
+        &self.config.addr
+    }
+
+    fn get_pair_hint(&self) -> &'static str {
+        "hold the Bluetooth button until a flashing \"P\" appears on the display"
+    }
+
+    async fn get_records(&self) -> Result<DbRecords, String> {
+        self.get_records().await.map_err(|e| format!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{Config, File, FileFormat};
+
+    use crate::driver::DriverConfig;
+    use crate::driver::harness::Harness;
+
+    #[test]
+    fn experimental() {
+        let parse = |driver_config: &str| Config::builder()
+            .add_source(File::from_str(driver_config, FileFormat::Yaml))
+            .build()
+            .and_then(|config| config.try_deserialize::<DriverConfig>())
+            .is_ok();
+
+        assert!(!parse("driver: Omron_HBF_702T\naddr: e2:81:4c:34:56:78"));
+        assert!(parse("driver: Omron_HBF_702T\naddr: e2:81:4c:34:56:78\nexperimental: true"));
+    }
+
+    #[tokio::test]
+    async fn conformance() {
+        Harness::check(
+            "driver: Omron_HBF_702T\naddr: e2:81:4c:34:56:78\ntz: Europe/Budapest\nexperimental: true",
+            include_str!("../../../tests/fixtures/omron_hbf_702t/pair.txt"),
+            include_str!("../../../tests/fixtures/omron_hbf_702t/fetch.txt"),
+        ).await;
+    }
+}
@@ -0,0 +1,676 @@
+//! # Omron blood pressure monitor driver
+//!
+//! Generic driver for the HEM-xxxx units sharing the same protocol, the
+//! per-model memory map comes from a descriptor (see model.rs). ECG
+//! summaries are returned as separate records with the ecg and ecg_bpm
+//! fields, a recording taken along with a blood pressure measurement has the
+//! same timestamp and user tag, so they end up in the same point.
+//!
+//! This driver is based on:
+//! - [omblepy](https://github.com/userx14/omblepy)
+//! - [ubpm](https://codeberg.org/LazyT/ubpm)
+
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use bluer::Address;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use tzfile::Tz;
+use uuid::Uuid;
+
+use crate::btutil::{self, BTLinkPtr, BTUtil};
+use crate::db::{DbFieldValue, DbRecord, DbRecords};
+use crate::driver::{Driver, DriverContext, PairStep};
+use crate::otel::Otel;
+use crate::status::DeviceState;
+use crate::timeutil::TimeUtil;
+use super::btcomm::BTComm;
+use super::model::{EcgRecordLayout, Model, Pairing, StatusLayout};
+
+const CMD_CHUNK_SIZE: usize = 0x10;
+const SECRET_LEN: usize = 0x10;
+
+const YEAR: u16 = 2000;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    addr: Address, // TODO: unique check
+    #[serde(default, deserialize_with = "Config::parse_secret")]
+    secret: Option<[u8; SECRET_LEN]>, // Needed by models with secret pairing.
+    #[serde(default = "crate::timeutil::TimeUtil::get_local_tz", deserialize_with = "crate::timeutil::TimeUtil::parse_tz")]
+    tz: Tz, // Host's timezone if unset.
+    #[serde(default)]
+    keep_connected: bool, // Do not disconnect after pairing/fetching.
+    #[serde(default)]
+    track_unread: bool, // Skip reading the user banks if there are no unread records, mark records as read once committed.
+    #[serde(default)]
+    experimental: bool, // Opt-in for models whose memory map is not verified on a unit.
+}
+
+impl Config {
+    pub fn parse_experimental<'de, D>(deserializer: D) -> Result<Config, D::Error> where D: Deserializer<'de> {
+        // The time sync writes into the EEPROM, a guessed memory map might corrupt the unit's settings.
+
+        let config = Config::deserialize(deserializer)?;
+
+        if !config.experimental {
+            return Err(D::Error::custom("the driver's memory map is not verified on a unit, set experimental: true to use it anyway"));
+        }
+
+        Ok(config)
+    }
+
+    fn parse_secret<'de, D>(deserializer: D) -> Result<Option<[u8; SECRET_LEN]>, D::Error> where D: Deserializer<'de> {
+        hex::serde::deserialize(deserializer).map(Some)
+    }
+}
+
+pub struct DriverImpl {
+    ctx: DriverContext,
+    model: &'static Model,
+    config: Config,
+    pending_read: Mutex<Option<Vec<u8>>>, // Unread record counts of the last fetch, cleared on the unit by commit().
+}
+
+impl DriverImpl {
+    pub fn new(ctx: DriverContext, model: &'static Model, config: Config) -> Self {
+        Self {
+            ctx,
+            model,
+            config,
+            pending_read: Mutex::new(None),
+        }
+    }
+
+    async fn pair(&self) -> btutil::Result<()> {
+        // Pair device.
+
+        self.ctx.set_pair_step(PairStep::Discovering);
+        let link = self.ctx.get_link(&self.config.addr, true).await?;
+
+        if link.is_paired().await? {
+            return Err("Device is already paired".into());
+        }
+
+        let result = self.pair_device(&link).await;
+
+        if !self.config.keep_connected {
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn pair_device(&self, link: &BTLinkPtr) -> btutil::Result<()> {
+        let secret = self.get_secret()?;
+
+        self.ctx.set_pair_step(PairStep::Connecting);
+        link.connect().await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_pair_step(PairStep::Bonding);
+        link.pair().await?;
+        self.ctx.set_pair_step(PairStep::Bonded);
+
+        // Write secret key.
+
+        if let (Some(secret), Some(unlock_char)) = (secret, &self.model.unlock_char) {
+            self.write_key(link, unlock_char, secret).await?;
+        }
+
+        self.pair_sync_time(link).await
+    }
+
+    async fn repair(&self) -> btutil::Result<()> {
+        // Rewrite the secret key over the existing bond, the unit has to be in pairing mode for this too.
+
+        let (secret, unlock_char) = match (self.get_secret()?, &self.model.unlock_char) {
+            (Some(secret), Some(unlock_char)) => (secret, unlock_char),
+            _ => return Err(btutil::Error::General(format!("{} is paired without a key, there is nothing to rewrite", self.model.name))),
+        };
+
+        self.ctx.set_pair_step(PairStep::Discovering);
+        let link = self.ctx.get_link(&self.config.addr, true).await?;
+
+        if !link.is_paired().await? {
+            return Err("Device is not yet paired".into());
+        }
+
+        let result = self.repair_device(&link, unlock_char, secret).await;
+
+        if !self.config.keep_connected {
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn repair_device(&self, link: &BTLinkPtr, unlock_char: &Uuid, secret: &[u8; SECRET_LEN]) -> btutil::Result<()> {
+        self.ctx.set_pair_step(PairStep::Connecting);
+        link.connect().await?;
+        self.ctx.check_device(link).await?;
+
+        self.write_key(link, unlock_char, secret).await?;
+        self.pair_sync_time(link).await
+    }
+
+    async fn write_key(&self, link: &BTLinkPtr, unlock_char: &Uuid, secret: &[u8; SECRET_LEN]) -> btutil::Result<()> {
+        self.ctx.set_pair_step(PairStep::WritingKey);
+
+        let mut comm = BTComm::new(&self.ctx, link, &self.model.service, &[unlock_char], &[unlock_char], CMD_CHUNK_SIZE).await?;
+
+        let mut tx_data = [0_u8; SECRET_LEN + 1];
+        tx_data[0] = 0x02;
+
+        let mut rx_data = [0_u8; 2];
+
+        comm.raw(&tx_data, &mut rx_data).await?;
+        if rx_data != [0x82, 0x00] {
+            return Err("Invalid response".into());
+        }
+
+        tx_data[0] = 0x00;
+        tx_data[1..].copy_from_slice(secret);
+
+        comm.raw(&tx_data, &mut rx_data).await?;
+        if rx_data != [0x80, 0x00] {
+            return Err("Invalid response".into());
+        }
+
+        Ok(())
+    }
+
+    async fn pair_sync_time(&self, link: &BTLinkPtr) -> btutil::Result<()> {
+        self.ctx.set_pair_step(PairStep::SyncingTime);
+
+        let mut comm = self.get_comm(link).await?;
+        comm.start_trans().await?;
+
+        if !self.sync_time(&mut comm).await? {
+            return Err("Checksum error in time sync block".into());
+        }
+
+        comm.end_trans().await
+    }
+
+    async fn get_records(&self) -> btutil::Result<DbRecords> {
+        // Connect to device.
+
+        let link = self.ctx.get_link(&self.config.addr, false).await?;
+
+        if !link.is_paired().await? {
+            return Err("Device is not yet paired".into());
+        }
+
+        self.ctx.set_state(DeviceState::WaitingForAdvertisement);
+        self.ctx.wait_for_adv(&self.config.addr, &self.model.adv_patterns).await?;
+
+        *self.pending_read.lock().unwrap() = None;
+
+        let result = BTUtil::with_deadline(&link, self.ctx.fetch_timeout, self.fetch(&link)).await;
+
+        // With records to mark as read, stay connected until commit(), the unit is likely still advertising then.
+        if !self.config.keep_connected && (result.is_err() || self.pending_read.lock().unwrap().is_none()) {
+            BTUtil::disconnect(&link).await; // Notification sessions are gone by now, since fetch() has finished.
+        }
+
+        result
+    }
+
+    async fn commit(&self) -> btutil::Result<()> {
+        let data = match self.pending_read.lock().unwrap().take() {
+            Some(data) => data,
+            None => return Ok(()),
+        };
+
+        let link = self.ctx.get_link(&self.config.addr, false).await?;
+        let result = BTUtil::with_deadline(&link, self.ctx.fetch_timeout, self.commit_device(&link, &data)).await;
+
+        if !self.config.keep_connected {
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn commit_device(&self, link: &BTLinkPtr, fetched: &[u8]) -> btutil::Result<()> {
+        if !link.is_connected().await? { // Dropped by the unit during the upload.
+            link.connect().await?;
+
+            if let (Some(secret), Some(unlock_char)) = (self.get_secret()?, &self.model.unlock_char) {
+                self.unlock(link, unlock_char, secret).await?;
+            }
+        }
+
+        let mut comm = self.get_comm(link).await?;
+        comm.start_trans().await?;
+
+        // Records taken since the fetch would be marked read without being fetched, leave them to the next fetch.
+
+        let data = self.read_unread(&mut comm).await?;
+
+        if data[..data.len() / 2] == fetched[..fetched.len() / 2] {
+            self.mark_read(&mut comm, data).await?;
+        } else {
+            println!("{}: new records since fetching, not marking records as read", self.ctx.id);
+        }
+
+        comm.end_trans().await
+    }
+
+    async fn fetch(&self, link: &BTLinkPtr) -> btutil::Result<DbRecords> {
+        let secret = self.get_secret()?;
+
+        if self.config.track_unread && self.model.unread.is_none() {
+            return Err(btutil::Error::General(format!("{} has no unread record counts, unset track_unread", self.model.name)));
+        }
+
+        self.ctx.check_policy(link).await?;
+        self.ctx.set_state(DeviceState::Connecting);
+        Otel::span("connect", link.connect()).await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_state(DeviceState::Fetching);
+
+        let mut status = self.ctx.get_status(link).await;
+
+        // Unlock device with secret key.
+
+        if let (Some(secret), Some(unlock_char)) = (secret, &self.model.unlock_char) {
+            Otel::span("unlock", self.unlock(link, unlock_char, secret)).await?;
+        }
+
+        // Exchange data.
+
+        let mut records;
+
+        {
+            let mut comm = self.get_comm(link).await?;
+            comm.start_trans().await?;
+
+            // Synchronize time.
+
+            if !self.sync_time(&mut comm).await? { // The records are still worth reading.
+                eprintln!("{}: checksum error in time sync block, not synchronizing time", self.ctx.id);
+            }
+
+            // Read error and status registers, added to the battery level.
+
+            if let Some(layout) = &self.model.status {
+                if let Some(data) = self.read_status(&mut comm, layout).await? {
+                    Self::decode_status(layout, &data, status.get_or_insert_with(DriverContext::get_status_record));
+                }
+            }
+
+            // Fetch measurements.
+
+            let unread = if self.config.track_unread && !self.ctx.full_read { Some(self.read_unread(&mut comm).await?) } else { None };
+
+            if unread.as_ref().is_some_and(|data| Self::get_unread_count(data) == 0) { // Nothing new, keep the connection short.
+                println!("{}: no unread records, skipping user banks", self.ctx.id);
+                records = DbRecords::new();
+            } else {
+                records = comm.read_banks(&self.model.banks, self.model.record.len, self.model.block_size, |user, data| self.get_record(user, data)).await?;
+            }
+
+            // Fetch ECG summaries, these are not covered by the unread record counts.
+
+            if let Some(ecg) = &self.model.ecg {
+                records.extend(comm.read_banks(&ecg.banks, ecg.record.len, self.model.block_size, |user, data| self.get_ecg_record(user, data)).await?);
+            }
+
+            comm.end_trans().await?;
+
+            // Marked read by commit(), once the records are written to the DB.
+
+            if unread.as_ref().is_some_and(|data| Self::get_unread_count(data) != 0) {
+                *self.pending_read.lock().unwrap() = unread;
+            }
+        }
+
+        records.extend(status);
+
+        Ok(records)
+    }
+
+    async fn get_comm(&self, link: &BTLinkPtr) -> btutil::Result<BTComm> {
+        let tx_chars: Vec<&Uuid> = self.model.tx_chars.iter().collect();
+        let rx_chars: Vec<&Uuid> = self.model.rx_chars.iter().collect();
+
+        let mut comm = BTComm::new(&self.ctx, link, &self.model.service, &tx_chars, &rx_chars, CMD_CHUNK_SIZE).await?;
+        comm.set_pipeline(self.model.pipeline);
+
+        Ok(comm)
+    }
+
+    fn get_secret(&self) -> btutil::Result<Option<&[u8; SECRET_LEN]>> {
+        match (self.model.pairing, &self.config.secret) {
+            (Pairing::Secret, Some(secret)) => Ok(Some(secret)),
+            (Pairing::Secret, None) => Err(btutil::Error::General(format!("{} needs a secret", self.model.name))),
+            (Pairing::Bond, _) => Ok(None), // Nothing to write, a configured secret is ignored.
+        }
+    }
+
+    async fn unlock(&self, link: &BTLinkPtr, unlock_char: &Uuid, secret: &[u8; SECRET_LEN]) -> btutil::Result<()> {
+        let mut comm = BTComm::new(&self.ctx, link, &self.model.service, &[unlock_char], &[unlock_char], CMD_CHUNK_SIZE).await?;
+
+        let mut tx_data = [0_u8; SECRET_LEN + 1];
+        tx_data[0] = 0x01;
+        tx_data[1..].copy_from_slice(secret);
+
+        let mut rx_data = [0_u8; 2];
+
+        comm.raw(&tx_data, &mut rx_data).await?;
+        if rx_data != [0x81, 0x00] {
+            return Err("Invalid response".into());
+        }
+
+        Ok(())
+    }
+
+    fn get_record(&self, user: usize, data: &[u8]) -> Option<DbRecord> {
+        self.skip_corrupt(Self::decode_record(self.model, &self.config.tz, user, data))
+    }
+
+    fn get_ecg_record(&self, user: usize, data: &[u8]) -> Option<DbRecord> {
+        let layout = &self.model.ecg.as_ref().unwrap().record; // Only called for models with ECG.
+        self.skip_corrupt(Self::decode_ecg_record(layout, &self.config.tz, user, data))
+    }
+
+    fn skip_corrupt(&self, result: btutil::Result<Option<DbRecord>>) -> Option<DbRecord> {
+        match result {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("{}: skipping corrupt record: {}", self.ctx.id, e);
+                None
+            }
+        }
+    }
+
+    pub fn decode_record(model: &Model, tz: &Tz, user: usize, data: &[u8]) -> btutil::Result<Option<DbRecord>> {
+        // Returns None for empty slots.
+
+        let layout = &model.record;
+
+        if data.len() < layout.len {
+            return Err("Record is too short".into());
+        }
+
+        let sec = layout.get(&layout.sec, data);
+
+        if sec == 63 { // Discard uninitialized/time-desynced data.
+            return Ok(None);
+        }
+
+        let ts = Self::get_ts(tz, [&layout.year, &layout.month, &layout.day, &layout.hour, &layout.min].map(|field| layout.get(field, data)), sec)?;
+        let bpm = layout.get(&layout.bpm, data);
+        let dia = layout.get(&layout.dia, data);
+        let sys = layout.get(&layout.sys, data);
+        let mov = layout.get_bool(&layout.mov, data);
+        let ihb = layout.get_bool(&layout.ihb, data);
+
+        let mut record = DbRecord::new(ts);
+        record.add_tag("user", &format!("{}", user + 1));
+        record.add_field("bpm", DbFieldValue::Integer(bpm.into()));
+        record.add_field("dia", DbFieldValue::Integer(dia.into()));
+        record.add_field("sys", DbFieldValue::Integer(sys.into()));
+        record.add_field("mov", DbFieldValue::Bool(mov));
+        record.add_field("ihb", DbFieldValue::Bool(ihb));
+
+        for (name, field) in [("position", &layout.position), ("cuff", &layout.cuff)] { // Only in formats having them.
+            if let Some(field) = field {
+                record.add_field(name, DbFieldValue::Bool(layout.get_bool(field, data)));
+            }
+        }
+
+        Ok(Some(record))
+    }
+
+    pub fn decode_ecg_record(layout: &EcgRecordLayout, tz: &Tz, user: usize, data: &[u8]) -> btutil::Result<Option<DbRecord>> {
+        // Returns None for empty slots.
+
+        if data.len() < layout.len {
+            return Err("Record is too short".into());
+        }
+
+        let sec = layout.get(&layout.sec, data);
+
+        if sec == 63 { // Discard uninitialized/time-desynced data.
+            return Ok(None);
+        }
+
+        let ts = Self::get_ts(tz, [&layout.year, &layout.month, &layout.day, &layout.hour, &layout.min].map(|field| layout.get(field, data)), sec)?;
+        let bpm = layout.get(&layout.bpm, data);
+
+        let mut record = DbRecord::new(ts);
+        record.add_tag("user", &format!("{}", user + 1));
+        record.add_field("ecg", DbFieldValue::String(String::from(layout.get_result(data))));
+        record.add_field("ecg_bpm", DbFieldValue::Integer(bpm.into()));
+
+        Ok(Some(record))
+    }
+
+    fn get_ts(tz: &Tz, [year, month, day, hour, min]: [u16; 5], sec: u16) -> btutil::Result<i64> {
+        let get_u8 = |value: u16| u8::try_from(value).map_err(|_| btutil::Error::from("Invalid timestamp"));
+
+        match TimeUtil::get_ts(tz, year, get_u8(month)?, get_u8(day)?, get_u8(hour)?, get_u8(min)?, get_u8(sec)?) {
+            Some(ts) => Ok(ts),
+            None => Err("Invalid timestamp".into()), // Partially written slot, don't produce garbage.
+        }
+    }
+
+    async fn read_unread(&self, comm: &mut BTComm) -> btutil::Result<Vec<u8>> {
+        let layout = self.model.unread.as_ref().unwrap(); // Checked before fetching.
+        let mut data = vec![0; layout.len];
+
+        if !comm.read_eeprom(layout.read, &mut data, self.model.block_size).await? {
+            return Err("Read error".into());
+        }
+
+        Ok(data)
+    }
+
+    async fn read_status(&self, comm: &mut BTComm, layout: &StatusLayout) -> btutil::Result<Option<Vec<u8>>> {
+        // Best effort like the battery level, a link error still fails the fetch.
+
+        let mut data = vec![0; layout.len];
+
+        if !comm.read_eeprom(layout.read, &mut data, self.model.block_size).await? {
+            eprintln!("{}: unable to read status registers", self.ctx.id);
+            return Ok(None);
+        }
+
+        Ok(Some(data))
+    }
+
+    pub fn decode_status(layout: &StatusLayout, data: &[u8], record: &mut DbRecord) {
+        if let Some(error) = &layout.error {
+            record.add_field("error", DbFieldValue::Integer(layout.get(error, data).into()));
+        }
+
+        if let Some(low_battery) = &layout.low_battery {
+            record.add_field("low_battery", DbFieldValue::Bool(layout.get(low_battery, data) != 0));
+        }
+    }
+
+    async fn mark_read(&self, comm: &mut BTComm, mut data: Vec<u8>) -> btutil::Result<()> {
+        // Clear the unread record counts, keep the write pointers.
+
+        let layout = self.model.unread.as_ref().unwrap(); // Checked before fetching.
+        data[layout.len / 2..].fill(0x00);
+
+        comm.write_eeprom(layout.write, &data, self.model.block_size).await
+    }
+
+    fn get_unread_count(data: &[u8]) -> u32 {
+        data[data.len() / 2..].chunks(2).map(|count| u16::from_le_bytes([count[0], count[1]]) as u32).sum()
+    }
+
+    async fn sync_time(&self, comm: &mut BTComm) -> btutil::Result<bool> {
+        // Returns false if the settings block is corrupt, it is left alone then.
+
+        let layout = &self.model.timesync;
+        let offset = layout.offset;
+        let mut data = vec![0; layout.len];
+        let block_size = u8::try_from(layout.len).map_err(|_| btutil::Error::from("Time sync block is too long"))?; // In one block, checked by Model::load().
+
+        if !comm.read_eeprom(layout.read, &mut data, block_size).await? {
+            return Err("Read error".into());
+        }
+
+        if BTComm::checksum(&data[..offset + 6]) != data[offset + 6] { // Don't write back corrupt settings.
+            return Ok(false);
+        }
+
+        let current = TimeUtil::get_current(&self.config.tz);
+        data[offset] = match current.year.checked_sub(YEAR).and_then(|year| u8::try_from(year).ok()) {
+            Some(year) => year,
+            None => return Err("Host time is out of range".into()),
+        };
+        data[offset + 1] = current.month;
+        data[offset + 2] = current.day;
+        data[offset + 3] = current.hour;
+        data[offset + 4] = current.min;
+        data[offset + 5] = current.sec;
+        data[offset + 6] = BTComm::checksum(&data[..offset + 6]);
+        data[offset + 7] = 0x00;
+
+        comm.write_eeprom(layout.write, &data, block_size).await?;
+
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl Driver for DriverImpl {
+    async fn pair(&self) -> Result<(), String> {
+        self.pair().await.map_err(|e| format!("{}", e))
+    }
+
+    async fn repair(&self) -> Result<(), String> {
+        self.repair().await.map_err(|e| format!("{}", e))
+    }
+
+    fn get_addr(&self) -> &Address {
+        &self.config.addr
+    }
+
+    fn get_secret(&self) -> Option<&[u8]> {
+        match self.model.pairing {
+            Pairing::Secret => self.config.secret.as_ref().map(|secret| secret.as_slice()),
+            Pairing::Bond => None,
+        }
+    }
+
+    fn get_pair_hint(&self) -> &'static str {
+        "hold the Bluetooth button until a flashing \"P\" appears on the display"
+    }
+
+    async fn get_records(&self) -> Result<DbRecords, String> {
+        self.get_records().await.map_err(|e| format!("{}", e))
+    }
+
+    async fn commit(&self) -> Result<(), String> {
+        self.commit().await.map_err(|e| format!("Unable to mark records as read: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{Config, File, FileFormat};
+
+    use crate::driver::DriverConfig;
+    use crate::driver::harness::Harness;
+
+    #[test]
+    fn experimental() {
+        let parse = |driver_config: &str| Config::builder()
+            .add_source(File::from_str(driver_config, FileFormat::Yaml))
+            .build()
+            .and_then(|config| config.try_deserialize::<DriverConfig>())
+            .is_ok();
+
+        assert!(!parse("driver: Omron_BP7900\naddr: 28:ff:b2:77:88:99\nsecret: deadbeefdeadbeefdeadbeefdeadbeef"));
+        assert!(parse("driver: Omron_BP7900\naddr: 28:ff:b2:77:88:99\nsecret: deadbeefdeadbeefdeadbeefdeadbeef\nexperimental: true"));
+        assert!(parse("driver: Omron_HEM_7361T\naddr: 28:ff:b2:77:88:99\nsecret: deadbeefdeadbeefdeadbeefdeadbeef"));
+    }
+
+    #[tokio::test]
+    async fn conformance_bp7900() {
+        Harness::check(
+            "driver: Omron_BP7900\naddr: 28:ff:b2:77:88:99\nsecret: deadbeefdeadbeefdeadbeefdeadbeef\ntz: Europe/Budapest\nexperimental: true",
+            include_str!("../../../tests/fixtures/omron_bp7900/pair.txt"),
+            include_str!("../../../tests/fixtures/omron_bp7900/fetch.txt"),
+        ).await;
+    }
+
+    #[tokio::test]
+    async fn conformance_hem_6232t() {
+        Harness::check(
+            "driver: Omron_HEM_6232T\naddr: 28:ff:b2:65:43:21\nsecret: deadbeefdeadbeefdeadbeefdeadbeef\ntz: Europe/Budapest",
+            include_str!("../../../tests/fixtures/omron_hem_6232t/pair.txt"),
+            include_str!("../../../tests/fixtures/omron_hem_6232t/fetch.txt"),
+        ).await;
+    }
+
+    #[tokio::test]
+    async fn conformance_hem_7143t() {
+        Harness::check(
+            "driver: Omron_HEM_7143T\naddr: 28:ff:b2:11:22:33\nsecret: deadbeefdeadbeefdeadbeefdeadbeef\ntz: Europe/Budapest\ntrack_unread: true",
+            include_str!("../../../tests/fixtures/omron_hem_7143t/pair.txt"),
+            include_str!("../../../tests/fixtures/omron_hem_7143t/fetch.txt"),
+        ).await;
+    }
+
+    #[tokio::test]
+    async fn conformance_hem_7155t() {
+        Harness::check(
+            "driver: Omron_HEM_7155T\naddr: 28:ff:b2:12:34:56\nsecret: deadbeefdeadbeefdeadbeefdeadbeef\ntz: Europe/Budapest",
+            include_str!("../../../tests/fixtures/omron_hem_7155t/pair.txt"),
+            include_str!("../../../tests/fixtures/omron_hem_7155t/fetch.txt"),
+        ).await;
+    }
+
+    #[tokio::test]
+    async fn conformance_hem_7322t() {
+        Harness::check(
+            "driver: Omron_HEM_7322T\naddr: 00:5f:bf:12:34:56\ntz: Europe/Budapest",
+            include_str!("../../../tests/fixtures/omron_hem_7322t/pair.txt"),
+            include_str!("../../../tests/fixtures/omron_hem_7322t/fetch.txt"),
+        ).await;
+    }
+
+    #[tokio::test]
+    async fn conformance_hem_7361t() {
+        Harness::check(
+            "driver: Omron_HEM_7361T\naddr: 34:f7:f2:15:29:ca\nsecret: deadbeefdeadbeefdeadbeefdeadbeef\ntz: Europe/Budapest",
+            include_str!("../../../tests/fixtures/omron_hem_7361t/pair.txt"),
+            include_str!("../../../tests/fixtures/omron_hem_7361t/fetch.txt"),
+        ).await;
+    }
+
+    #[tokio::test]
+    async fn conformance_hem_7361t_repair() {
+        Harness::check_repair(
+            "driver: Omron_HEM_7361T\naddr: 34:f7:f2:15:29:ca\nsecret: deadbeefdeadbeefdeadbeefdeadbeef\ntz: Europe/Budapest",
+            include_str!("../../../tests/fixtures/omron_hem_7361t/repair.txt"),
+        ).await;
+    }
+
+    #[tokio::test]
+    async fn conformance_hem_7361t_track_unread() {
+        for fetch in [
+            include_str!("../../../tests/fixtures/omron_hem_7361t/fetch_unread.txt"),
+            include_str!("../../../tests/fixtures/omron_hem_7361t/fetch_none.txt"),
+            include_str!("../../../tests/fixtures/omron_hem_7361t/fetch_corrupt_timesync.txt"),
+        ] {
+            Harness::check(
+                "driver: Omron_HEM_7361T\naddr: 34:f7:f2:15:29:ca\nsecret: deadbeefdeadbeefdeadbeefdeadbeef\ntz: Europe/Budapest\ntrack_unread: true",
+                include_str!("../../../tests/fixtures/omron_hem_7361t/pair.txt"),
+                fetch,
+            ).await;
+        }
+    }
+}
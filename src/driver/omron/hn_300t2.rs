@@ -1,22 +1,21 @@
 //! # Omron HN-300T2 driver
 
 use async_trait::async_trait;
-use bluer::{Address, Device};
-use bluer::monitor::{data_type, Pattern};
+use bluer::Address;
 use serde::Deserialize;
 use tzfile::Tz;
 use uuid::{uuid, Uuid};
 
-use crate::btutil::{self, BTUtil};
+use crate::btutil::{self, AdvPattern, BTLinkPtr, BTUtil};
 use crate::db::{DbFieldValue, DbRecord, DbRecords};
-use crate::driver::Driver;
+use crate::driver::{Driver, DriverContext, PairStep};
+use crate::otel::Otel;
+use crate::status::DeviceState;
 use crate::timeutil::TimeUtil;
-use super::btcomm::BTComm;
+use super::btcomm::{BTComm, UserBank};
 
-const PATTERN_CONTENT: &[u8] = &[0x0e, 0x02];
+const COMPANY_ID: u16 = 0x020e; // Omron.
 
-const MANUFACTURER: &str = "OMRONHEALTHCARE";
-const MODEL: &str = "HN300T2IntelliIT";
 
 const MAIN_SERVICE: &Uuid = &uuid!("0000fe4a-0000-1000-8000-00805f9b34fb");
 const TX_CHAR: &Uuid = &uuid!("db5b55e0-aee7-11e1-965e-0002a5d5c51b");
@@ -27,8 +26,9 @@ const CMD_CHUNK_SIZE: usize = 0xff; // Use large size, so commands are not chunk
 const TIMESYNC_ADDR: u16 = 0x0248;
 const TIMESYNC_LEN: usize = 0x08;
 
-const REC_START: u16 = 0x02c0;
-const REC_COUNT: usize = 30;
+const USER_BANKS: &[UserBank] = &[ // Single-user unit, records are not tagged.
+    UserBank { start: 0x02c0, count: 30 },
+];
 const REC_LEN: usize = 0x10;
 
 const YEAR: u16 = 2000;
@@ -37,19 +37,40 @@ const YEAR: u16 = 2000;
 #[serde(deny_unknown_fields)]
 pub struct Config {
     addr: Address, // TODO: unique check
-    #[serde(deserialize_with = "crate::timeutil::TimeUtil::parse_tz")]
-    tz: Tz,
+    #[serde(default = "crate::timeutil::TimeUtil::get_local_tz", deserialize_with = "crate::timeutil::TimeUtil::parse_tz")]
+    tz: Tz, // Host's timezone if unset.
+    #[serde(default)]
+    keep_connected: bool, // Do not disconnect after pairing/fetching.
+    unit: Option<WeightUnit>, // Display unit set on the scale, its location in the settings area is unknown.
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WeightUnit {
+    Kg,
+    Lb,
+    St, // Stones and pounds.
+}
+
+impl WeightUnit {
+    fn get_name(&self) -> &'static str {
+        match self {
+            WeightUnit::Kg => "kg",
+            WeightUnit::Lb => "lb",
+            WeightUnit::St => "st",
+        }
+    }
 }
 
 pub struct DriverImpl {
-    id: String,
+    ctx: DriverContext,
     config: Config,
 }
 
 impl DriverImpl {
-    pub fn new(id: &str, config: Config) -> Self {
+    pub fn new(ctx: DriverContext, config: Config) -> Self {
         Self {
-            id: String::from(id),
+            ctx,
             config,
         }
     }
@@ -57,20 +78,36 @@ impl DriverImpl {
     async fn pair(&self) -> btutil::Result<()> {
         // Pair device.
 
-        let (session, _, device) = BTUtil::get_device(&self.config.addr, true).await?;
+        self.ctx.set_pair_step(PairStep::Discovering);
+        let link = self.ctx.get_link(&self.config.addr, true).await?;
 
-        if device.is_paired().await? {
+        if link.is_paired().await? {
             return Err("Device is already paired".into());
         }
 
-        device.connect().await?;
-        self.check_device(&device).await?;
+        let result = self.pair_device(&link).await;
 
-        BTUtil::pair(&session, &device).await?;
+        if !self.config.keep_connected {
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn pair_device(&self, link: &BTLinkPtr) -> btutil::Result<()> {
+        self.ctx.set_pair_step(PairStep::Connecting);
+        link.connect().await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_pair_step(PairStep::Bonding);
+        link.pair().await?;
+        self.ctx.set_pair_step(PairStep::Bonded);
 
         // Synchronize time.
 
-        let mut comm = BTComm::new(&device, MAIN_SERVICE, &[TX_CHAR], &[RX_CHAR], CMD_CHUNK_SIZE).await?;
+        self.ctx.set_pair_step(PairStep::SyncingTime);
+
+        let mut comm = BTComm::new(&self.ctx, link, MAIN_SERVICE, &[TX_CHAR], &[RX_CHAR], CMD_CHUNK_SIZE).await?;
         comm.start_trans().await?;
 
         self.sync_time(&mut comm).await?;
@@ -83,29 +120,37 @@ impl DriverImpl {
     async fn get_records(&self) -> btutil::Result<DbRecords> {
         // Connect to device.
 
-        let (_, adapter, device) = BTUtil::get_device(&self.config.addr, false).await?;
+        let link = self.ctx.get_link(&self.config.addr, false).await?;
 
-        if !device.is_paired().await? {
+        if !link.is_paired().await? {
             return Err("Device is not yet paired".into());
         }
 
-        let pattern = Pattern {
-            data_type: data_type::MANUFACTURER_SPECIFIC_DATA,
-            start_position: 0,
-            content: PATTERN_CONTENT.to_vec(),
-        };
-        BTUtil::wait_for_adv(&adapter, &device, pattern).await?;
+        self.ctx.set_state(DeviceState::WaitingForAdvertisement);
+        self.ctx.wait_for_adv(&self.config.addr, &[AdvPattern::manufacturer(COMPANY_ID, &[], &[])]).await?;
 
-        println!("{}: received advertisement, trying to connect", self.id);
+        let result = BTUtil::with_deadline(&link, self.ctx.fetch_timeout, self.fetch(&link)).await;
 
-        device.connect().await?;
-        self.check_device(&device).await?;
+        if !self.config.keep_connected { // Notification sessions are gone by now, since fetch() has finished.
+            BTUtil::disconnect(&link).await;
+        }
 
-        // Exchange data.
+        result
+    }
+
+    async fn fetch(&self, link: &BTLinkPtr) -> btutil::Result<DbRecords> {
+        self.ctx.check_policy(link).await?;
+        self.ctx.set_state(DeviceState::Connecting);
+        Otel::span("connect", link.connect()).await?;
+        self.ctx.check_device(link).await?;
 
-        let mut records = DbRecords::new();
+        self.ctx.set_state(DeviceState::Fetching);
+
+        let status = self.ctx.get_status(link).await;
+
+        // Exchange data.
 
-        let mut comm = BTComm::new(&device, MAIN_SERVICE, &[TX_CHAR], &[RX_CHAR], CMD_CHUNK_SIZE).await?;
+        let mut comm = BTComm::new(&self.ctx, link, MAIN_SERVICE, &[TX_CHAR], &[RX_CHAR], CMD_CHUNK_SIZE).await?;
         comm.start_trans().await?;
 
         // Synchronize time.
@@ -120,47 +165,62 @@ impl DriverImpl {
         //    \-- & 0x1f: next available measurement slot
         //let d = comm.read_eeprom(0x01a0, 0xc).await?.ok_or(btutil::Error::Other(format!("Read error")))?; // 0x0230 write
 
-        let mut addr = REC_START;
-
-        for _ in 0..REC_COUNT {
-            let mut data = [0; REC_LEN];
-            let data_len = data.len();
-
-            if comm.read_eeprom(addr, &mut data, data_len.try_into().unwrap()).await? {
-                let raw_weight = (data[0] as u16) << 8 | (data[1] as u16);
-                let sec = data[7];
-
-                if raw_weight != 0xffff && sec != 63 { // Discard uninitialized/time-desynced data.
-                    let weight = (raw_weight as f64) / 20.0; // Unit reports weight in 50g.
-                    let year = YEAR + (data[2] as u16);
-                    let month = data[3];
-                    let day = data[4];
-                    let hour = data[5];
-                    let min = data[6];
-
-                    let ts = TimeUtil::get_ts(&self.config.tz, year, month, day, hour, min, sec).ok_or(btutil::Error::General("Unable to make ts".into()))?;
-                    let mut record = DbRecord::new(ts);
-                    record.add_field("weight", DbFieldValue::Float(weight));
-                    
-                    records.push(record);
-                }
-            }
-
-            addr += REC_LEN as u16;
-        }
+        let mut records = comm.read_banks(USER_BANKS, REC_LEN, BTComm::MAX_BLOCK_SIZE, |_, data| self.get_record(data)).await?;
 
         comm.end_trans().await?;
 
+        records.extend(status);
+
         Ok(records)
     }
 
-    async fn check_device(&self, device: &Device) -> btutil::Result<()> {
-        let device_info = BTUtil::get_device_info(device).await?;
-        if !(device_info.manufacturer == MANUFACTURER && device_info.model == MODEL) {
-            return Err("Unknown device".into());
+    fn get_record(&self, data: &[u8]) -> Option<DbRecord> {
+        match Self::decode_record(&self.config.tz, self.config.unit, data) {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("{}: skipping corrupt record: {}", self.ctx.id, e);
+                None
+            }
+        }
+    }
+
+    pub fn decode_record(tz: &Tz, unit: Option<WeightUnit>, data: &[u8]) -> btutil::Result<Option<DbRecord>> {
+        // Returns None for empty slots. Records are tagged with the display unit if it's configured, so the ones of lb/st
+        // units can be told apart (and fixed) once their raw weight's resolution is known.
+
+        if data.len() < REC_LEN {
+            return Err("Record is too short".into());
         }
 
-        Ok(())
+        let raw_weight = (data[0] as u16) << 8 | (data[1] as u16);
+        let sec = data[7];
+
+        if raw_weight == 0xffff || sec == 63 { // Discard uninitialized/time-desynced data.
+            return Ok(None);
+        }
+
+        let weight = (raw_weight as f64) / 20.0; // Unit reports weight in 50g.
+        // TODO: The display unit (kg/lb/st) is kept somewhere in the settings area, but its location is unknown. Records of
+        // units switched to lb/st could not be checked yet, decode them in 50g (only tagged) until a transcript shows their
+        // resolution.
+        let year = YEAR + (data[2] as u16);
+        let month = data[3];
+        let day = data[4];
+        let hour = data[5];
+        let min = data[6];
+
+        let ts = match TimeUtil::get_ts(tz, year, month, day, hour, min, sec) {
+            Some(ts) => ts,
+            None => return Err("Invalid timestamp".into()), // Partially written slot, don't produce garbage.
+        };
+        let mut record = DbRecord::new(ts);
+        record.add_field("weight", DbFieldValue::Float(weight));
+
+        if let Some(unit) = unit {
+            record.add_tag("unit", unit.get_name());
+        }
+
+        Ok(Some(record))
     }
 
     async fn sync_time(&self, comm: &mut BTComm) -> btutil::Result<()> {
@@ -168,14 +228,16 @@ impl DriverImpl {
         let data_len = data.len();
 
         let current = TimeUtil::get_current(&self.config.tz);
-        data[0] = (current.year - YEAR).try_into().unwrap();
+        data[0] = match current.year.checked_sub(YEAR).and_then(|year| u8::try_from(year).ok()) {
+            Some(year) => year,
+            None => return Err("Host time is out of range".into()),
+        };
         data[1] = current.month;
         data[2] = current.day;
         data[3] = current.hour;
         data[4] = current.min;
         data[5] = current.sec;
-        let sum: u16 = data.iter().map(|b| *b as u16).sum();
-        data[6] = sum as u8;
+        data[6] = BTComm::checksum(&data[..6]);
         data[7] = 0xff;
         
         comm.write_eeprom(TIMESYNC_ADDR, &data, data_len.try_into().unwrap()).await
@@ -188,7 +250,52 @@ impl Driver for DriverImpl {
         self.pair().await.map_err(|e| format!("{}", e))
     }
 
+    fn get_addr(&self) -> &Address {
+        &self.config.addr
+    }
+
+    fn get_pair_hint(&self) -> &'static str {
+        "hold the Bluetooth button until a flashing \"P\" appears on the display"
+    }
+
     async fn get_records(&self) -> Result<DbRecords, String> {
         self.get_records().await.map_err(|e| format!("{}", e))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tzfile::Tz;
+
+    use crate::db::DbFieldValue;
+    use crate::driver::harness::Harness;
+    use super::{DriverImpl, WeightUnit};
+
+    #[test]
+    fn decode_record() {
+        let tz = Tz::named("Europe/Budapest").unwrap();
+        let data = hex::decode("05a0180501081e00ffffffffffffffff").unwrap(); // Raw weight 1440.
+        let decode = |unit| DriverImpl::decode_record(&tz, unit, &data).ok().flatten().unwrap();
+
+        let record = decode(None);
+        assert!(matches!(record.get_field("weight"), Some(DbFieldValue::Float(weight)) if (*weight - 72.0).abs() < 0.001));
+        assert_eq!(record.get_tag("unit"), None);
+
+        let record = decode(Some(WeightUnit::Kg));
+        assert!(matches!(record.get_field("weight"), Some(DbFieldValue::Float(weight)) if (*weight - 72.0).abs() < 0.001));
+        assert_eq!(record.get_tag("unit"), Some("kg"));
+
+        let record = decode(Some(WeightUnit::St)); // Not converted.
+        assert!(matches!(record.get_field("weight"), Some(DbFieldValue::Float(weight)) if (*weight - 72.0).abs() < 0.001));
+        assert_eq!(record.get_tag("unit"), Some("st"));
+    }
+
+    #[tokio::test]
+    async fn conformance() {
+        Harness::check(
+            "driver: Omron_HN_300T2\naddr: e2:81:4c:12:19:bc\ntz: Europe/Budapest",
+            include_str!("../../../tests/fixtures/omron_hn_300t2/pair.txt"),
+            include_str!("../../../tests/fixtures/omron_hn_300t2/fetch.txt"),
+        ).await;
+    }
+}
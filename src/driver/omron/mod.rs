@@ -1,4 +1,6 @@
-pub mod hem_7361t;
+pub mod hbf_702t;
+pub mod hem;
 pub mod hn_300t2;
 
-mod btcomm;
+pub mod btcomm;
+pub mod model;
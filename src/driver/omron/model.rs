@@ -0,0 +1,353 @@
+//! # Omron blood pressure monitor descriptors
+//!
+//! Per-model memory maps (characteristics, EEPROM addresses, record bit
+//! layout) are kept in the TOML files in models/, embedded at build time and
+//! consumed by the generic driver in hem.rs. Supporting a new variant is a
+//! descriptor, an entry in MODELS, its fingerprint and a conformance test.
+//!
+//! Record fields are given as bit ranges (inclusive) counted from the most
+//! significant bit of the record read as a single number in the given byte
+//! order, the same notation as omblepy's.
+//!
+//! Units recording ECG (Omron Complete) keep a summary of each recording
+//! (time, heart rate, classification) in a separate region, given by the
+//! optional ecg section.
+//!
+//! The unit's error and status registers (last error code, e.g. a cuff
+//! error, and the low battery flag) are given by the optional status
+//! section, read at each data retrieval. Their location is not known for
+//! the supported units, so none of the descriptors has it yet.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use uuid::Uuid;
+
+use crate::btutil::AdvPattern;
+use super::btcomm::{BTComm, UserBank};
+
+const MODELS: &[(&str, &str)] = &[ // Keyed by file name.
+    ("bp7900", include_str!("models/bp7900.toml")),
+    ("hem_6232t", include_str!("models/hem_6232t.toml")),
+    ("hem_7143t", include_str!("models/hem_7143t.toml")),
+    ("hem_7155t", include_str!("models/hem_7155t.toml")),
+    ("hem_7322t", include_str!("models/hem_7322t.toml")),
+    ("hem_7361t", include_str!("models/hem_7361t.toml")),
+];
+
+const MAX_FIELD_BITS: usize = 16;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Model {
+    pub name: String,
+    pub adv_patterns: Vec<AdvPattern>, // Advertisement variants across firmware revisions.
+    pub pairing: Pairing,
+    pub service: Uuid,
+    pub unlock_char: Option<Uuid>, // Needed for secret pairing.
+    pub tx_chars: Vec<Uuid>,
+    pub rx_chars: Vec<Uuid>,
+    #[serde(default = "Model::get_default_block_size")]
+    pub block_size: u8, // Largest EEPROM block to read/write at once, for units rejecting (or mishandling) larger ones.
+    #[serde(default)]
+    pub pipeline: bool, // Unit takes the next EEPROM read while answering the previous one.
+    pub timesync: TimeSyncLayout,
+    pub unread: Option<UnreadLayout>, // Unit keeps unread record counts.
+    pub banks: Vec<UserBank>, // The user tag is the bank's position (1-based).
+    pub record: RecordLayout,
+    pub ecg: Option<EcgLayout>,
+    pub status: Option<StatusLayout>,
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pairing {
+    Secret, // A secret key is written during pairing, the unit is unlocked with it before fetching.
+    Bond, // Bonding only.
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeSyncLayout {
+    pub read: u16,
+    pub write: u16,
+    pub len: usize,
+    pub offset: usize, // Year (since 2000), month, day, hour, min, sec, then checksum of the block up to here and a zero byte.
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UnreadLayout {
+    pub read: u16,
+    pub write: u16,
+    pub len: usize, // Per user: last written slot, then unread record count (u16, little endian).
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Endian {
+    Big,
+    Little,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordLayout {
+    pub len: usize,
+    pub endian: Endian,
+    pub sys: BitField,
+    pub dia: BitField,
+    pub bpm: BitField,
+    pub year: BitField,
+    pub month: BitField,
+    pub day: BitField,
+    pub hour: BitField,
+    pub min: BitField,
+    pub sec: BitField,
+    pub mov: BitField, // Body movement detected.
+    pub ihb: BitField, // Irregular heart beat.
+    pub position: Option<BitField>, // Improper measurement position (positioning sensor).
+    pub cuff: Option<BitField>, // Cuff wrapped too loosely (cuff wrap guide).
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EcgLayout {
+    pub banks: Vec<UserBank>, // Same user order as the blood pressure banks.
+    pub record: EcgRecordLayout,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EcgRecordLayout {
+    pub len: usize,
+    pub endian: Endian,
+    pub results: Vec<String>, // Classification names, indexed by the raw value.
+    pub result: BitField,
+    pub bpm: BitField,
+    pub year: BitField,
+    pub month: BitField,
+    pub day: BitField,
+    pub hour: BitField,
+    pub min: BitField,
+    pub sec: BitField,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatusLayout {
+    pub read: u16,
+    pub len: usize,
+    pub endian: Endian,
+    pub error: Option<BitField>, // Last error code, 0 if none.
+    pub low_battery: Option<BitField>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BitField {
+    bits: [usize; 2], // First and last bit.
+    #[serde(default)]
+    offset: u16, // Added to the raw value.
+}
+
+impl Model {
+    pub fn get(key: &str) -> &'static Model {
+        // Descriptors are embedded and covered by tests, an invalid one is a bug.
+
+        static LOADED: OnceLock<HashMap<&'static str, Model>> = OnceLock::new();
+
+        let models = LOADED.get_or_init(|| MODELS.iter().map(|(key, descriptor)| match Self::load(descriptor) {
+            Ok(model) => (*key, model),
+            Err(e) => panic!("Invalid Omron model descriptor {}: {}", key, e),
+        }).collect());
+
+        models.get(key).unwrap_or_else(|| panic!("Unknown Omron model: {}", key))
+    }
+
+    fn get_default_block_size() -> u8 {
+        BTComm::MAX_BLOCK_SIZE
+    }
+
+    fn load(descriptor: &str) -> Result<Model, String> {
+        let model: Model = toml::from_str(descriptor).map_err(|e| e.to_string())?;
+
+        if model.adv_patterns.is_empty() {
+            return Err(String::from("No advertisement patterns"));
+        }
+
+        if !model.adv_patterns.iter().all(AdvPattern::is_valid) {
+            return Err(String::from("Advertisement pattern mask is longer than its data"));
+        }
+
+        if model.pairing == Pairing::Secret && model.unlock_char.is_none() {
+            return Err(String::from("Secret pairing needs unlock_char"));
+        }
+
+        if model.tx_chars.is_empty() || model.rx_chars.is_empty() {
+            return Err(String::from("No TX/RX characteristics"));
+        }
+
+        if model.block_size == 0 {
+            return Err(String::from("Invalid block size"));
+        }
+
+        if model.timesync.offset + 8 > model.timesync.len {
+            return Err(String::from("Time sync block is too short"));
+        }
+
+        if model.timesync.len > usize::from(u8::MAX) { // Read and written in one block.
+            return Err(String::from("Time sync block is too long"));
+        }
+
+        if model.unread.as_ref().is_some_and(|unread| unread.len % 4 != 0) {
+            return Err(String::from("Unread block length must be a multiple of 4"));
+        }
+
+        if model.banks.is_empty() {
+            return Err(String::from("No user banks"));
+        }
+
+        let record = &model.record;
+        Self::check_fields(record.len, &[&record.sys, &record.dia, &record.bpm, &record.year, &record.month, &record.day, &record.hour, &record.min, &record.sec, &record.mov, &record.ihb])?;
+        Self::check_fields(record.len, &[&record.position, &record.cuff].into_iter().flatten().collect::<Vec<_>>())?;
+
+        if let Some(ecg) = &model.ecg {
+            if ecg.banks.is_empty() || ecg.record.results.is_empty() {
+                return Err(String::from("No ECG banks or results"));
+            }
+
+            let record = &ecg.record;
+            Self::check_fields(record.len, &[&record.result, &record.bpm, &record.year, &record.month, &record.day, &record.hour, &record.min, &record.sec])?;
+        }
+
+        if let Some(status) = &model.status {
+            if status.error.is_none() && status.low_battery.is_none() {
+                return Err(String::from("No status registers"));
+            }
+
+            Self::check_fields(status.len, &[&status.error, &status.low_battery].into_iter().flatten().collect::<Vec<_>>())?;
+        }
+
+        Ok(model)
+    }
+
+    fn check_fields(len: usize, fields: &[&BitField]) -> Result<(), String> {
+        for field in fields {
+            let [first, last] = field.bits;
+
+            if first > last || last >= len * 8 || last - first >= MAX_FIELD_BITS {
+                return Err(format!("Invalid bit range {}-{}", first, last));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl BitField {
+    fn get(&self, len: usize, endian: Endian, data: &[u8]) -> u16 {
+        // data is at least len long.
+
+        let [first, last] = self.bits;
+        let value = (first..=last).fold(0, |value, bit| {
+            let index = match endian {
+                Endian::Big => bit / 8,
+                Endian::Little => len - 1 - bit / 8,
+            };
+
+            (value << 1) | ((data[index] >> (7 - bit % 8)) & 0x01) as u16
+        });
+
+        value.wrapping_add(self.offset)
+    }
+}
+
+impl RecordLayout {
+    pub fn get(&self, field: &BitField, data: &[u8]) -> u16 {
+        field.get(self.len, self.endian, data)
+    }
+
+    pub fn get_bool(&self, field: &BitField, data: &[u8]) -> bool {
+        self.get(field, data) != 0
+    }
+}
+
+impl EcgRecordLayout {
+    pub fn get(&self, field: &BitField, data: &[u8]) -> u16 {
+        field.get(self.len, self.endian, data)
+    }
+
+    pub fn get_result(&self, data: &[u8]) -> &str {
+        self.results.get(usize::from(self.get(&self.result, data))).map_or("unknown", |result| result.as_str())
+    }
+}
+
+impl StatusLayout {
+    pub fn get(&self, field: &BitField, data: &[u8]) -> u16 {
+        field.get(self.len, self.endian, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Model, MODELS};
+
+    #[test]
+    fn descriptors() {
+        for (key, _) in MODELS {
+            let model = Model::get(key);
+            assert_eq!(model.record.get(&model.record.sec, &[0xff; 0x10]), 63, "{}", key);
+
+            if let Some(ecg) = &model.ecg {
+                assert_eq!(ecg.record.get(&ecg.record.sec, &[0xff; 0x10]), 63, "{}", key);
+            }
+        }
+    }
+
+    #[test]
+    fn indicators() {
+        // Optional position and cuff bits, appended to the record section.
+
+        let descriptor = MODELS.iter().find(|(key, _)| *key == "hem_7361t").unwrap().1;
+        let load = |fields: &str| Model::load(&format!("{}\n{}\n", descriptor, fields));
+
+        let model = load("position = { bits = [67, 67] }\ncuff = { bits = [66, 66] }").unwrap();
+        let record = &model.record;
+        let mut data = [0x00; 0x10];
+        data[0x10 - 1 - 67 / 8] = 0x10; // Bit 67 only.
+        assert!(record.get_bool(record.position.as_ref().unwrap(), &data));
+        assert!(!record.get_bool(record.cuff.as_ref().unwrap(), &data));
+
+        assert!(load("").unwrap().record.position.is_none());
+        assert!(load("cuff = { bits = [128, 128] }").is_err()); // Past the record.
+    }
+
+    #[test]
+    fn status() {
+        // Optional error and status registers, appended after the record section.
+
+        let descriptor = MODELS.iter().find(|(key, _)| *key == "hem_7361t").unwrap().1;
+        let load = |section: &str| Model::load(&format!("{}\n[status]\n{}\n", descriptor, section));
+
+        let model = load("read = 0x0260\nlen = 4\nendian = \"big\"\nerror = { bits = [0, 7] }\nlow_battery = { bits = [15, 15] }").unwrap();
+        let status = model.status.as_ref().unwrap();
+        let data = [0x02, 0x01, 0x00, 0x00];
+        assert_eq!(status.get(status.error.as_ref().unwrap(), &data), 2);
+        assert_eq!(status.get(status.low_battery.as_ref().unwrap(), &data), 1);
+
+        assert!(Model::get("hem_7361t").status.is_none());
+        assert!(load("read = 0x0260\nlen = 4\nendian = \"big\"").is_err()); // No registers.
+        assert!(load("read = 0x0260\nlen = 1\nendian = \"big\"\nerror = { bits = [0, 15] }").is_err()); // Past the block.
+    }
+
+    #[test]
+    fn timesync() {
+        let descriptor = MODELS.iter().find(|(key, _)| *key == "hem_7361t").unwrap().1;
+        let load = |timesync: &str| Model::load(&descriptor.replace("timesync = { read = 0x003c, write = 0x0080, len = 0x10, offset = 8 }", timesync));
+
+        assert!(load("timesync = { read = 0x003c, write = 0x0080, len = 0xff, offset = 8 }").is_ok());
+        assert!(load("timesync = { read = 0x003c, write = 0x0080, len = 0x100, offset = 8 }").is_err()); // Not in one block.
+        assert!(load("timesync = { read = 0x003c, write = 0x0080, len = 0x0f, offset = 8 }").is_err()); // Too short.
+    }
+}
@@ -0,0 +1,287 @@
+//! # Withings Body / Body+ driver
+//!
+//! The scales talk WPP, like the Thermo. They store the measurements taken
+//! while offline (until synced), each one is a group of values tagged with the
+//! Withings user id the scale recognized. Values are integers with a decimal
+//! exponent, timestamps are in UTC.
+
+use async_trait::async_trait;
+use bluer::Address;
+use serde::Deserialize;
+use uuid::{uuid, Uuid};
+
+use crate::btutil::{self, AdvPattern, BTLinkPtr, BTUtil};
+use crate::db::{DbFieldValue, DbRecord, DbRecords};
+use crate::driver::{Driver, DriverContext, PairStep};
+use crate::otel::Otel;
+use crate::status::DeviceState;
+use crate::timeutil::TimeUtil;
+use super::wpp::{WppComm, WppPkt, WppTlv};
+
+const COMPANY_ID: u16 = 0x03ff; // Withings.
+
+const MAIN_SERVICE: &Uuid = &uuid!("00000020-5749-5448-0037-000000000000");
+const MAIN_CHAR: &Uuid = &uuid!("00000024-5749-5448-0037-000000000000");
+
+const CHUNK_SIZE: usize = 20;
+
+const CMD_PROBE: u16 = 0x0101;
+const CMD_SET_TIME: u16 = 0x0501;
+const CMD_GET_MEAS: u16 = 0x0918;
+
+const TLV_TIME: u16 = 0x0501;
+const TLV_MEAS_GROUP: u16 = 0x0902;
+const TLV_MEAS_VALUE: u16 = 0x0905;
+
+const GROUP_LEN: usize = 8;
+const VALUE_LEN: usize = 7;
+
+// Measure types, as in the Withings API.
+const TYPE_WEIGHT: u16 = 1;
+const TYPE_FAT_FREE_MASS: u16 = 5;
+const TYPE_FAT_RATIO: u16 = 6;
+const TYPE_FAT_MASS: u16 = 8;
+const TYPE_MUSCLE_MASS: u16 = 76;
+const TYPE_HYDRATION: u16 = 77;
+const TYPE_BONE_MASS: u16 = 88;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    addr: Address, // TODO: unique check
+    #[serde(default)]
+    keep_connected: bool, // Do not disconnect after pairing/fetching.
+}
+
+pub struct DriverImpl {
+    ctx: DriverContext,
+    config: Config,
+}
+
+impl DriverImpl {
+    pub fn new(ctx: DriverContext, config: Config) -> Self {
+        Self {
+            ctx,
+            config,
+        }
+    }
+
+    async fn pair(&self) -> btutil::Result<()> {
+        // Pair device.
+
+        self.ctx.set_pair_step(PairStep::Discovering);
+        let link = self.ctx.get_link(&self.config.addr, true).await?;
+
+        if link.is_paired().await? {
+            return Err("Device is already paired".into());
+        }
+
+        let result = self.pair_device(&link).await;
+
+        if !self.config.keep_connected {
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn pair_device(&self, link: &BTLinkPtr) -> btutil::Result<()> {
+        self.ctx.set_pair_step(PairStep::Connecting);
+        link.connect().await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_pair_step(PairStep::Bonding);
+        link.pair().await?;
+        self.ctx.set_pair_step(PairStep::Bonded);
+
+        // Synchronize time.
+
+        self.ctx.set_pair_step(PairStep::SyncingTime);
+
+        let mut comm = WppComm::new(&self.ctx, link, MAIN_SERVICE, MAIN_CHAR, CHUNK_SIZE).await?;
+        comm.cmd(CMD_PROBE, &[]).await?;
+
+        self.sync_time(&mut comm).await
+    }
+
+    async fn get_records(&self) -> btutil::Result<DbRecords> {
+        // Connect to device.
+
+        let link = self.ctx.get_link(&self.config.addr, false).await?;
+
+        if !link.is_paired().await? {
+            return Err("Device is not yet paired".into());
+        }
+
+        self.ctx.set_state(DeviceState::WaitingForAdvertisement);
+        self.ctx.wait_for_adv(&self.config.addr, &[AdvPattern::manufacturer(COMPANY_ID, &[], &[])]).await?;
+
+        let result = BTUtil::with_deadline(&link, self.ctx.fetch_timeout, self.fetch(&link)).await;
+
+        if !self.config.keep_connected { // Notification sessions are gone by now, since fetch() has finished.
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn fetch(&self, link: &BTLinkPtr) -> btutil::Result<DbRecords> {
+        self.ctx.check_policy(link).await?;
+        self.ctx.set_state(DeviceState::Connecting);
+        Otel::span("connect", link.connect()).await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_state(DeviceState::Fetching);
+
+        // Exchange data.
+
+        let mut records = DbRecords::new();
+
+        let mut comm = WppComm::new(&self.ctx, link, MAIN_SERVICE, MAIN_CHAR, CHUNK_SIZE).await?;
+        comm.cmd(CMD_PROBE, &[]).await?;
+
+        // Synchronize time.
+
+        self.sync_time(&mut comm).await?;
+
+        // Fetch measurements: the unit sends one packet per measurement group, terminated by an empty packet.
+
+        comm.send(CMD_GET_MEAS, &[]).await?;
+
+        loop {
+            let pkt = comm.recv().await?;
+            if pkt.cmd != CMD_GET_MEAS {
+                return Err("Invalid response".into());
+            }
+
+            if pkt.get_tlv(TLV_MEAS_GROUP).is_none() {
+                break;
+            }
+
+            if let Some(record) = Self::decode_record(&pkt)? {
+                self.ctx.buffer.add(&record);
+                records.push(record);
+            }
+        }
+
+        Ok(records)
+    }
+
+    pub fn decode_record(pkt: &WppPkt) -> btutil::Result<Option<DbRecord>> {
+        // Returns None for measurements to be discarded. The group has the timestamp and user id (0 if the scale didn't
+        // recognize the user), followed by the values: type, value and exponent each.
+
+        let group = match pkt.get_tlv(TLV_MEAS_GROUP) {
+            Some(group) => &group.value,
+            None => return Err("Invalid response".into()),
+        };
+
+        if group.len() < GROUP_LEN {
+            return Err("Invalid response".into());
+        }
+
+        let ts = u32::from_be_bytes([group[0], group[1], group[2], group[3]]);
+        let user = u32::from_be_bytes([group[4], group[5], group[6], group[7]]);
+
+        if ts == 0 { // Discard measurements taken before time was set.
+            return Ok(None);
+        }
+
+        let mut record = DbRecord::new(TimeUtil::get_ts_unix(ts.into()));
+
+        if user != 0 {
+            record.add_tag("user", &user.to_string());
+        }
+
+        for tlv in pkt.tlvs.iter().filter(|tlv| tlv.typ == TLV_MEAS_VALUE) {
+            let data = &tlv.value;
+
+            if data.len() < VALUE_LEN {
+                return Err("Invalid response".into());
+            }
+
+            let typ = u16::from_be_bytes([data[0], data[1]]);
+            let value = f64::from(i32::from_be_bytes([data[2], data[3], data[4], data[5]])) * 10f64.powi((data[6] as i8).into());
+
+            let name = match typ {
+                TYPE_WEIGHT => "weight",
+                TYPE_FAT_FREE_MASS => "fat_free_mass",
+                TYPE_FAT_RATIO => "fat",
+                TYPE_FAT_MASS => "fat_mass",
+                TYPE_MUSCLE_MASS => "muscle_mass",
+                TYPE_HYDRATION => "body_water",
+                TYPE_BONE_MASS => "bone_mass",
+                _ => continue, // E.g. heart rate of the Body Cardio.
+            };
+
+            record.add_field(name, DbFieldValue::Float(value));
+        }
+
+        if record.get_field("weight").is_none() { // Body composition alone is not a measurement.
+            return Ok(None);
+        }
+
+        Ok(Some(record))
+    }
+
+    async fn sync_time(&self, comm: &mut WppComm) -> btutil::Result<()> {
+        let current = match u32::try_from(TimeUtil::get_current_unix()) {
+            Ok(current) => current,
+            Err(_) => return Err("Host time is out of range".into()),
+        };
+        comm.cmd(CMD_SET_TIME, &[WppTlv::new(TLV_TIME, &current.to_be_bytes())]).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Driver for DriverImpl {
+    async fn pair(&self) -> Result<(), String> {
+        self.pair().await.map_err(|e| format!("{}", e))
+    }
+
+    fn get_addr(&self) -> &Address {
+        &self.config.addr
+    }
+
+    fn get_pair_hint(&self) -> &'static str {
+        "step on the scale to wake it up, then start pairing"
+    }
+
+    async fn get_records(&self) -> Result<DbRecords, String> {
+        self.get_records().await.map_err(|e| format!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::DbFieldValue;
+    use crate::driver::harness::Harness;
+    use crate::driver::withings::wpp::WppPkt;
+    use super::DriverImpl;
+
+    #[test]
+    fn decode_record() {
+        let decode = |data: &str| WppPkt::decode(&hex::decode(data).unwrap()).ok().map(|pkt| DriverImpl::decode_record(&pkt));
+
+        // Weight and fat ratio of user 1.
+
+        let record = decode("010918002209020008663332e8000000010905000700010001195efd0905000700060000010dff").unwrap().ok().flatten().unwrap();
+        assert_eq!(record.get_tag("user"), Some("1"));
+        assert!(matches!(record.get_field("weight"), Some(DbFieldValue::Float(weight)) if (*weight - 72.03).abs() < 0.001));
+        assert!(matches!(record.get_field("fat"), Some(DbFieldValue::Float(fat)) if (*fat - 26.9).abs() < 0.001));
+
+        assert!(matches!(decode("01091800170902000800000000000000000905000700010001195efd"), Some(Ok(None)))); // Clock unset.
+        assert!(matches!(decode("010918001609020008663332e8000000000905000600010001195e"), Some(Err(_)))); // Truncated value.
+    }
+
+    #[tokio::test]
+    async fn conformance() {
+        Harness::check(
+            "driver: Withings_Body\naddr: 00:24:e4:12:34:78",
+            include_str!("../../../tests/fixtures/withings_body/pair.txt"),
+            include_str!("../../../tests/fixtures/withings_body/fetch.txt"),
+        ).await;
+    }
+}
@@ -0,0 +1,5 @@
+pub mod body;
+
+pub mod thermo;
+
+pub mod wpp;
@@ -0,0 +1,238 @@
+//! # Withings Thermo driver
+//!
+//! The unit stores measurements taken while offline, timestamps are in UTC.
+
+use async_trait::async_trait;
+use bluer::Address;
+use serde::Deserialize;
+use uuid::{uuid, Uuid};
+
+use crate::btutil::{self, AdvPattern, BTLinkPtr, BTUtil};
+use crate::db::{DbFieldValue, DbRecord, DbRecords};
+use crate::driver::{Driver, DriverContext, PairStep};
+use crate::otel::Otel;
+use crate::status::DeviceState;
+use crate::timeutil::TimeUtil;
+use super::wpp::{WppComm, WppPkt, WppTlv};
+
+const COMPANY_ID: u16 = 0x03ff; // Withings.
+
+
+const MAIN_SERVICE: &Uuid = &uuid!("00000020-5749-5448-0037-000000000000");
+const MAIN_CHAR: &Uuid = &uuid!("00000024-5749-5448-0037-000000000000");
+
+const CHUNK_SIZE: usize = 20;
+
+const CMD_PROBE: u16 = 0x0101;
+const CMD_SET_TIME: u16 = 0x0501;
+const CMD_GET_MEAS: u16 = 0x0918;
+
+const TLV_TIME: u16 = 0x0501;
+const TLV_MEAS: u16 = 0x0903;
+const TLV_NOTE: u16 = 0x0904;
+
+const MEAS_LEN: usize = 7;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    addr: Address, // TODO: unique check
+    #[serde(default)]
+    keep_connected: bool, // Do not disconnect after pairing/fetching.
+}
+
+pub struct DriverImpl {
+    ctx: DriverContext,
+    config: Config,
+}
+
+impl DriverImpl {
+    pub fn new(ctx: DriverContext, config: Config) -> Self {
+        Self {
+            ctx,
+            config,
+        }
+    }
+
+    async fn pair(&self) -> btutil::Result<()> {
+        // Pair device.
+
+        self.ctx.set_pair_step(PairStep::Discovering);
+        let link = self.ctx.get_link(&self.config.addr, true).await?;
+
+        if link.is_paired().await? {
+            return Err("Device is already paired".into());
+        }
+
+        let result = self.pair_device(&link).await;
+
+        if !self.config.keep_connected {
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn pair_device(&self, link: &BTLinkPtr) -> btutil::Result<()> {
+        self.ctx.set_pair_step(PairStep::Connecting);
+        link.connect().await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_pair_step(PairStep::Bonding);
+        link.pair().await?;
+        self.ctx.set_pair_step(PairStep::Bonded);
+
+        // Synchronize time.
+
+        self.ctx.set_pair_step(PairStep::SyncingTime);
+
+        let mut comm = WppComm::new(&self.ctx, link, MAIN_SERVICE, MAIN_CHAR, CHUNK_SIZE).await?;
+        comm.cmd(CMD_PROBE, &[]).await?;
+
+        self.sync_time(&mut comm).await
+    }
+
+    async fn get_records(&self) -> btutil::Result<DbRecords> {
+        // Connect to device.
+
+        let link = self.ctx.get_link(&self.config.addr, false).await?;
+
+        if !link.is_paired().await? {
+            return Err("Device is not yet paired".into());
+        }
+
+        self.ctx.set_state(DeviceState::WaitingForAdvertisement);
+        self.ctx.wait_for_adv(&self.config.addr, &[AdvPattern::manufacturer(COMPANY_ID, &[], &[])]).await?;
+
+        let result = BTUtil::with_deadline(&link, self.ctx.fetch_timeout, self.fetch(&link)).await;
+
+        if !self.config.keep_connected { // Notification sessions are gone by now, since fetch() has finished.
+            BTUtil::disconnect(&link).await;
+        }
+
+        result
+    }
+
+    async fn fetch(&self, link: &BTLinkPtr) -> btutil::Result<DbRecords> {
+        self.ctx.check_policy(link).await?;
+        self.ctx.set_state(DeviceState::Connecting);
+        Otel::span("connect", link.connect()).await?;
+        self.ctx.check_device(link).await?;
+
+        self.ctx.set_state(DeviceState::Fetching);
+
+        // Exchange data.
+
+        let mut records = DbRecords::new();
+
+        let mut comm = WppComm::new(&self.ctx, link, MAIN_SERVICE, MAIN_CHAR, CHUNK_SIZE).await?;
+        comm.cmd(CMD_PROBE, &[]).await?;
+
+        // Synchronize time.
+
+        self.sync_time(&mut comm).await?;
+
+        // Fetch measurements: the unit sends one packet per measurement, terminated by an empty packet.
+
+        comm.send(CMD_GET_MEAS, &[]).await?;
+
+        loop {
+            let pkt = comm.recv().await?;
+            if pkt.cmd != CMD_GET_MEAS {
+                return Err("Invalid response".into());
+            }
+
+            if pkt.get_tlv(TLV_MEAS).is_none() {
+                break;
+            }
+
+            if let Some(record) = Self::decode_record(&pkt)? {
+                self.ctx.buffer.add(&record);
+                records.push(record);
+            }
+        }
+
+        Ok(records)
+    }
+
+    pub fn decode_record(pkt: &WppPkt) -> btutil::Result<Option<DbRecord>> {
+        // Returns None for measurements to be discarded.
+
+        let data = match pkt.get_tlv(TLV_MEAS) {
+            Some(meas) => &meas.value,
+            None => return Err("Invalid response".into()),
+        };
+
+        if data.len() < MEAS_LEN {
+            return Err("Invalid response".into());
+        }
+
+        let ts = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        let temp = i16::from_be_bytes([data[4], data[5]]);
+        let site = data[6];
+
+        if ts == 0 { // Discard measurements taken before time was set.
+            return Ok(None);
+        }
+
+        let mut record = DbRecord::new(TimeUtil::get_ts_unix(ts.into()));
+        record.add_tag("site", Self::get_site(site));
+        record.add_field("temp", DbFieldValue::Float((temp as f64) / 100.0)); // Unit reports temperature in 0.01 °C.
+
+        if let Some(note) = pkt.get_tlv(TLV_NOTE) {
+            match String::from_utf8(note.value.clone()) {
+                Ok(note) => record.add_field("note", DbFieldValue::String(note)),
+                Err(_) => return Err("Unable to decode note".into()),
+            }
+        }
+
+        Ok(Some(record))
+    }
+
+    async fn sync_time(&self, comm: &mut WppComm) -> btutil::Result<()> {
+        let current = match u32::try_from(TimeUtil::get_current_unix()) {
+            Ok(current) => current,
+            Err(_) => return Err("Host time is out of range".into()),
+        };
+        comm.cmd(CMD_SET_TIME, &[WppTlv::new(TLV_TIME, &current.to_be_bytes())]).await?;
+
+        Ok(())
+    }
+
+    fn get_site(site: u8) -> &'static str {
+        match site {
+            0x00 => "temporal",
+            0x01 => "forehead",
+            _ => "unknown",
+        }
+    }
+}
+
+#[async_trait]
+impl Driver for DriverImpl {
+    async fn pair(&self) -> Result<(), String> {
+        self.pair().await.map_err(|e| format!("{}", e))
+    }
+
+    fn get_addr(&self) -> &Address {
+        &self.config.addr
+    }
+
+    async fn get_records(&self) -> Result<DbRecords, String> {
+        self.get_records().await.map_err(|e| format!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::driver::harness::Harness;
+
+    #[tokio::test]
+    async fn conformance() {
+        Harness::check(
+            "driver: Withings_Thermo\naddr: 00:24:e4:12:34:56",
+            include_str!("../../../tests/fixtures/withings_thermo/pair.txt"),
+            include_str!("../../../tests/fixtures/withings_thermo/fetch.txt"),
+        ).await;
+    }
+}
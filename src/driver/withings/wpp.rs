@@ -0,0 +1,202 @@
+//! # Withings specific RX/TX routines
+//!
+//! Withings devices talk WPP over a single characteristic: each packet is
+//! made of a header (magic, command, payload length) followed by TLV items.
+
+use futures::StreamExt;
+use uuid::Uuid;
+
+use crate::btutil::{self, BTLinkPtr, BTRxStream};
+use crate::driver::{DriverContext, FetchMeterPtr};
+use crate::redact::Redact;
+use crate::tracelog::TraceLog;
+
+const PKT_MAGIC: u8 = 0x01;
+const PKT_HDR_SIZE: usize = 5; // Including magic, cmd and len.
+const TLV_HDR_SIZE: usize = 4; // Including type and len.
+
+pub struct WppComm {
+    link: BTLinkPtr,
+    service_uuid: Uuid,
+    char_uuid: Uuid,
+    rx_stream: BTRxStream,
+    chunk_size: usize,
+    trace: Option<String>, // Device id, if protocol tracing is enabled.
+    meter: FetchMeterPtr,
+}
+
+pub struct WppTlv {
+    pub typ: u16,
+    pub value: Vec<u8>,
+}
+
+pub struct WppPkt {
+    pub cmd: u16,
+    pub tlvs: Vec<WppTlv>,
+}
+
+impl WppComm {
+    pub async fn new(ctx: &DriverContext, link: &BTLinkPtr, service_uuid: &Uuid, char_uuid: &Uuid, chunk_size: usize) -> btutil::Result<Self> {
+        if chunk_size == 0 {
+            return Err("Invalid chunk size".into());
+        }
+
+        let rx_stream = link.notify_char(service_uuid, char_uuid).await?;
+
+        Ok(Self {
+            link: BTLinkPtr::clone(link),
+            service_uuid: *service_uuid,
+            char_uuid: *char_uuid,
+            rx_stream,
+            chunk_size,
+            trace: if ctx.debug_protocol { Some(ctx.id.clone()) } else { None },
+            meter: FetchMeterPtr::clone(&ctx.meter),
+        })
+    }
+
+    pub async fn send(&mut self, cmd: u16, tlvs: &[WppTlv]) -> btutil::Result<()> {
+        // Construct packet.
+
+        let mut payload = Vec::new();
+
+        for tlv in tlvs {
+            payload.extend_from_slice(&tlv.typ.to_be_bytes());
+            payload.extend_from_slice(&Self::get_len(tlv.value.len())?.to_be_bytes());
+            payload.extend_from_slice(&tlv.value);
+        }
+
+        let mut pkt = Vec::new();
+        pkt.push(PKT_MAGIC);
+        pkt.extend_from_slice(&cmd.to_be_bytes());
+        pkt.extend_from_slice(&Self::get_len(payload.len())?.to_be_bytes());
+        pkt.extend_from_slice(&payload);
+
+        // Write command.
+
+        self.trace(|| format!("tx: cmd={:04x} data={}", cmd, hex::encode(&payload)));
+
+        for buf in pkt.chunks(self.chunk_size) {
+            self.link.write_char(&self.service_uuid, &self.char_uuid, buf).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn recv(&mut self) -> btutil::Result<WppPkt> {
+        // Receive packet, it might span over multiple notifications.
+
+        let mut pkt = Vec::new();
+
+        loop {
+            let buf = match self.rx_stream.next().await {
+                Some(buf) => buf,
+                None => return Err("Unable to receive packet".into()),
+            };
+            self.meter.add_bytes(buf.len());
+            pkt.extend_from_slice(&buf);
+
+            if pkt.len() >= PKT_HDR_SIZE {
+                if pkt[0] != PKT_MAGIC {
+                    return Err("Invalid packet magic".into());
+                }
+
+                let pkt_len = PKT_HDR_SIZE + ((pkt[3] as usize) << 8 | (pkt[4] as usize));
+                if pkt.len() >= pkt_len {
+                    if pkt[pkt_len..].iter().any(|b| *b != 0x00) { // Packets start in a new notification, only padding can follow.
+                        return Err("Received packet is too long".into());
+                    }
+
+                    pkt.truncate(pkt_len);
+                    break;
+                }
+            }
+        }
+
+        // Process packet.
+
+        self.trace(|| format!("rx: cmd={:02x}{:02x} data={}", pkt[1], pkt[2], hex::encode(&pkt[PKT_HDR_SIZE..])));
+
+        WppPkt::decode(&pkt)
+    }
+
+
+    pub async fn cmd(&mut self, cmd: u16, tlvs: &[WppTlv]) -> btutil::Result<WppPkt> {
+        self.send(cmd, tlvs).await?;
+
+        let pkt = self.recv().await?;
+        if pkt.cmd != cmd {
+            return Err("Invalid response".into());
+        }
+
+        Ok(pkt)
+    }
+
+    fn get_len(len: usize) -> btutil::Result<u16> {
+        u16::try_from(len).map_err(|_| "Packet is too long".into())
+    }
+
+    fn trace<F>(&self, f: F) where F: FnOnce() -> String {
+        if let Some(id) = &self.trace {
+            TraceLog::write(id, &Redact::apply(&f()));
+        }
+    }
+}
+
+impl WppTlv {
+    pub fn new(typ: u16, value: &[u8]) -> Self {
+        Self {
+            typ,
+            value: Vec::from(value),
+        }
+    }
+}
+
+impl WppPkt {
+    pub fn decode(pkt: &[u8]) -> btutil::Result<Self> {
+        // Whole packet, including header.
+
+        if pkt.len() < PKT_HDR_SIZE {
+            return Err("Received packet is too short".into());
+        }
+
+        if pkt[0] != PKT_MAGIC {
+            return Err("Invalid packet magic".into());
+        }
+
+        let cmd = (pkt[1] as u16) << 8 | (pkt[2] as u16);
+        let mut payload = &pkt[PKT_HDR_SIZE..];
+
+        if payload.len() != ((pkt[3] as usize) << 8 | (pkt[4] as usize)) {
+            return Err("Invalid packet length".into());
+        }
+
+        let mut tlvs = Vec::new();
+
+        while !payload.is_empty() {
+            if payload.len() < TLV_HDR_SIZE {
+                return Err("Received packet is too short".into());
+            }
+
+            let typ = (payload[0] as u16) << 8 | (payload[1] as u16);
+            let len = (payload[2] as usize) << 8 | (payload[3] as usize);
+            if payload.len() < TLV_HDR_SIZE + len {
+                return Err("Received packet is too short".into());
+            }
+
+            tlvs.push(WppTlv {
+                typ,
+                value: Vec::from(&payload[TLV_HDR_SIZE..TLV_HDR_SIZE + len]),
+            });
+            payload = &payload[TLV_HDR_SIZE + len..];
+        }
+
+        Ok(Self {
+            cmd,
+            tlvs,
+        })
+    }
+
+    pub fn get_tlv(&self, typ: u16) -> Option<&WppTlv> {
+        self.tlvs.iter().find(|tlv| tlv.typ == typ)
+    }
+}
@@ -0,0 +1 @@
+pub mod xmtzc05hm;
@@ -0,0 +1,186 @@
+//! # Xiaomi Mi Body Composition Scale 2 (XMTZC05HM) driver
+//!
+//! The scale is broadcast-only: while it is stepped on, it sends its reading
+//! as service data of the Body Composition service (0x181B) in its
+//! advertisements, so the unit is neither paired nor connected. Readings are
+//! interim until the weight is stabilized, the impedance follows a few
+//! seconds later if the measurement is taken barefoot. The layout is as
+//! documented by the openScale project. The unit's clock is set by the vendor
+//! app, timestamps are in its local time.
+
+use async_trait::async_trait;
+use bluer::{Address, UuidExt};
+use serde::Deserialize;
+use std::sync::Mutex;
+use tzfile::Tz;
+use uuid::Uuid;
+
+use crate::btutil::{self, AdvPattern};
+use crate::db::{DbFieldValue, DbRecord, DbRecords};
+use crate::driver::gatt::meas::MeasReader;
+use crate::driver::{Driver, DriverContext};
+use crate::redact::Redact;
+use crate::status::DeviceState;
+use crate::tracelog::TraceLog;
+
+const SERVICE_ID: u16 = 0x181b;
+
+const CTRL0_LB: u8 = 0x01;
+const CTRL1_IMPEDANCE: u8 = 0x02;
+const CTRL1_STABILIZED: u8 = 0x20;
+const CTRL1_CATTY: u8 = 0x40;
+const CTRL1_REMOVED: u8 = 0x80; // Stepped off.
+
+const IMPEDANCE_MAX: u16 = 3000; // [Ω] Above this the measurement failed.
+const CATTY: f64 = 0.5; // [kg]
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    addr: Address, // TODO: unique check
+    #[serde(default = "crate::timeutil::TimeUtil::get_local_tz", deserialize_with = "crate::timeutil::TimeUtil::parse_tz")]
+    tz: Tz, // Host's timezone if unset.
+}
+
+pub struct DriverImpl {
+    ctx: DriverContext,
+    config: Config,
+    last: Mutex<Option<(Vec<u8>, bool)>>, // Timestamp (as sent) and impedance presence of the last reading returned.
+}
+
+impl DriverImpl {
+    pub fn new(ctx: DriverContext, config: Config) -> Self {
+        Self {
+            ctx,
+            config,
+            last: Mutex::new(None),
+        }
+    }
+
+    async fn get_records(&self) -> btutil::Result<DbRecords> {
+        // Returns once a new stabilized reading is received. The unit repeats a reading in several advertisements,
+        // the one completed with impedance is returned again.
+
+        let service_uuid = Uuid::from_u16(SERVICE_ID);
+
+        loop {
+            self.ctx.set_state(DeviceState::WaitingForAdvertisement);
+            let adv_data = self.ctx.recv_adv(&self.config.addr, &[AdvPattern::service_data(SERVICE_ID)]).await?;
+
+            let data = match adv_data.service_data.get(&service_uuid) {
+                Some(data) => data,
+                None => continue, // Advertisement data was not available.
+            };
+
+            self.ctx.meter.add_bytes(data.len());
+
+            if self.ctx.debug_protocol {
+                TraceLog::write(&self.ctx.id, &Redact::apply(&format!("adv: service_data={}", hex::encode(data))));
+            }
+
+            let record = match Self::decode_record(&self.config.tz, data)? {
+                Some(record) => record,
+                None => continue,
+            };
+
+            let reading = (data[2..9].to_vec(), record.get_field("impedance").is_some()); // Length checked by decode_record().
+            let mut last = self.last.lock().unwrap();
+
+            if last.as_ref().is_some_and(|(ts, impedance)| *ts == reading.0 && (*impedance || !reading.1)) {
+                continue;
+            }
+
+            *last = Some(reading);
+            return Ok(vec![record]);
+        }
+    }
+
+    pub fn decode_record(tz: &Tz, data: &[u8]) -> btutil::Result<Option<DbRecord>> {
+        // Returns None for interim readings. Control bytes, timestamp, impedance, then the weight in 5 g (0.01 lb or
+        // 0.01 catty, as displayed).
+
+        let mut reader = MeasReader::new(data);
+        let ctrl0 = reader.get_u8()?;
+        let ctrl1 = reader.get_u8()?;
+        let ts = reader.get_ts(tz)?;
+        let impedance = reader.get_u16()?;
+        let raw_weight = reader.get_u16()?;
+
+        if ctrl1 & CTRL1_STABILIZED == 0 || ctrl1 & CTRL1_REMOVED != 0 {
+            return Ok(None);
+        }
+
+        let weight = if ctrl1 & CTRL1_CATTY != 0 {
+            (raw_weight as f64) / 100.0 * CATTY
+        } else {
+            MeasReader::get_mass(raw_weight, ctrl0 & CTRL0_LB != 0)
+        };
+
+        let mut record = MeasReader::new_record(ts, None);
+        record.add_field("weight", DbFieldValue::Float(weight));
+
+        if ctrl1 & CTRL1_IMPEDANCE != 0 && impedance > 0 && impedance < IMPEDANCE_MAX {
+            record.add_field("impedance", DbFieldValue::Integer(impedance.into()));
+        }
+
+        Ok(Some(record))
+    }
+}
+
+#[async_trait]
+impl Driver for DriverImpl {
+    async fn pair(&self) -> Result<(), String> {
+        Err(String::from("Pairing is not needed, the unit's advertisements are decoded without connecting"))
+    }
+
+    fn get_addr(&self) -> &Address {
+        &self.config.addr
+    }
+
+    async fn get_records(&self) -> Result<DbRecords, String> {
+        self.get_records().await.map_err(|e| format!("{}", e))
+    }
+
+    fn is_passive(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tzfile::Tz;
+
+    use crate::db::DbFieldValue;
+    use crate::driver::harness::Harness;
+    use super::DriverImpl;
+
+    #[test]
+    fn decode_record() {
+        let tz = Tz::named("Europe/Budapest").unwrap();
+        let decode = |data: &str| DriverImpl::decode_record(&tz, &hex::decode(data).unwrap());
+
+        assert!(matches!(decode("0200e80705010830000000a438"), Ok(None))); // Interim.
+        assert!(matches!(decode("02a2e80705010830000000a438"), Ok(None))); // Stepped off.
+
+        let record = decode("0222e8070501081e00f401a438").ok().flatten().unwrap();
+        assert!(matches!(record.get_field("weight"), Some(DbFieldValue::Float(weight)) if *weight == 72.5));
+        assert!(matches!(record.get_field("impedance"), Some(DbFieldValue::Integer(500))));
+
+        let record = decode("0322e8070501081e00ffff8f1b").ok().flatten().unwrap(); // lb, failed impedance.
+        assert!(matches!(record.get_field("weight"), Some(DbFieldValue::Float(weight)) if (*weight - 32.0).abs() < 0.01));
+        assert!(record.get_field("impedance").is_none());
+
+        let record = decode("0260e8070501081e0000004038").ok().flatten().unwrap(); // Catty.
+        assert!(matches!(record.get_field("weight"), Some(DbFieldValue::Float(weight)) if *weight == 72.0));
+
+        assert!(decode("0222e8070501081e00f401a4").is_err());
+    }
+
+    #[tokio::test]
+    async fn conformance() {
+        Harness::check_passive(
+            "driver: Xiaomi_XMTZC05HM\naddr: 00:11:22:33:44:cc\ntz: Europe/Budapest",
+            include_str!("../../../tests/fixtures/xiaomi_xmtzc05hm/fetch.txt"),
+        ).await;
+    }
+}
@@ -0,0 +1,207 @@
+//! # Record export
+//!
+//! Formats the recent records of a device (see `recent` in the device config)
+//! for the export endpoint of the HTTP API: CSV (a column per tag and field),
+//! JSON (as kept) and a FHIR R4 Bundle of Observations, one per field. Fields
+//! with a well-known meaning get their LOINC code and UCUM unit, the others
+//! only their name.
+
+use serde_json::{json, Map, Value};
+use std::collections::BTreeSet;
+
+use crate::store::RecentRecord;
+use crate::timeutil::TimeUtil;
+
+const LOINC: &str = "http://loinc.org";
+const UCUM: &str = "http://unitsofmeasure.org";
+
+const CODES: &[(&str, &str, &str, &str)] = &[ // Field, LOINC code, display, UCUM unit.
+    ("bmi", "39156-5", "Body mass index (BMI) [Ratio]", "kg/m2"),
+    ("bpm", "8867-4", "Heart rate", "/min"),
+    ("dia", "8462-4", "Diastolic blood pressure", "mm[Hg]"),
+    ("fat", "41982-0", "Percentage of body fat Measured", "%"),
+    ("glucose", "2339-0", "Glucose [Mass/volume] in Blood", "mg/dL"),
+    ("height", "8302-2", "Body height", "m"),
+    ("sys", "8480-6", "Systolic blood pressure", "mm[Hg]"),
+    ("temp", "8310-5", "Body temperature", "Cel"),
+    ("weight", "29463-7", "Body weight", "kg"),
+];
+
+#[derive(Clone, Copy)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+    Fhir,
+}
+
+pub struct Export;
+
+impl ExportFormat {
+    pub fn from_file_name(fname: &str) -> Option<Self> {
+        match fname {
+            "records.csv" => Some(Self::Csv),
+            "records.json" => Some(Self::Json),
+            "records.fhir" => Some(Self::Fhir),
+            _ => None,
+        }
+    }
+
+    pub fn get_content_type(&self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Json => "application/json",
+            Self::Fhir => "application/fhir+json",
+        }
+    }
+}
+
+impl Export {
+    pub fn format(format: ExportFormat, id: &str, records: &[RecentRecord]) -> String {
+        match format {
+            ExportFormat::Csv => Self::get_csv(records),
+            ExportFormat::Json => json!({"device_id": id, "records": records}).to_string(),
+            ExportFormat::Fhir => Self::get_fhir(id, records).to_string(),
+        }
+    }
+
+    fn get_csv(records: &[RecentRecord]) -> String {
+        // Time, measurement, id, then the tags and fields of all the records (sorted by name), empty if a record lacks one.
+
+        let tags: BTreeSet<&str> = records.iter().flat_map(|record| record.tags.keys().map(String::as_str)).collect();
+        let fields: BTreeSet<&str> = records.iter().flat_map(|record| record.fields.keys().map(String::as_str)).collect();
+
+        let mut body = String::new();
+        let header: Vec<&str> = ["time", "meas", "id"].into_iter().chain(tags.iter().copied()).chain(fields.iter().copied()).collect();
+        Self::push_csv_row(&mut body, header.into_iter().map(String::from));
+
+        for record in records {
+            let row = [TimeUtil::format_rfc3339(record.ts), record.meas.clone(), record.id.clone().unwrap_or_default()].into_iter()
+                .chain(tags.iter().map(|tag| record.tags.get(*tag).cloned().unwrap_or_default()))
+                .chain(fields.iter().map(|field| match record.fields.get(*field) {
+                    Some(Value::String(value)) => value.clone(),
+                    Some(Value::Null) | None => String::new(),
+                    Some(value) => value.to_string(),
+                }));
+            Self::push_csv_row(&mut body, row);
+        }
+
+        body
+    }
+
+    fn push_csv_row(body: &mut String, values: impl Iterator<Item = String>) {
+        // RFC 4180: values with separators, quotes or line breaks are quoted.
+
+        let values: Vec<String> = values.map(|value| {
+            if value.contains([',', '"', '\r', '\n']) {
+                format!("\"{}\"", value.replace('"', "\"\""))
+            } else {
+                value
+            }
+        }).collect();
+
+        body.push_str(&values.join(","));
+        body.push_str("\r\n");
+    }
+
+    fn get_fhir(id: &str, records: &[RecentRecord]) -> Value {
+        let entries: Vec<Value> = records.iter()
+            .flat_map(|record| record.fields.iter().filter_map(move |(field, value)| Self::get_observation(id, record, field, value)))
+            .map(|observation| json!({"resource": observation}))
+            .collect();
+
+        json!({
+            "resourceType": "Bundle",
+            "type": "collection",
+            "timestamp": TimeUtil::format_rfc3339(TimeUtil::get_ts_unix(TimeUtil::get_current_unix())),
+            "entry": entries,
+        })
+    }
+
+    fn get_observation(id: &str, record: &RecentRecord, field: &str, value: &Value) -> Option<Value> {
+        // None for null (non-finite) values.
+
+        let code = CODES.iter().find(|(name, ..)| *name == field);
+
+        let mut observation = Map::new();
+        observation.insert(String::from("resourceType"), json!("Observation"));
+        observation.insert(String::from("status"), json!("final"));
+
+        if let Some(record_id) = &record.id { // An observation per field.
+            observation.insert(String::from("identifier"), json!([{"value": format!("{}-{}", record_id, field)}]));
+        }
+
+        observation.insert(String::from("code"), match code {
+            Some((_, code, display, _)) => json!({"coding": [{"system": LOINC, "code": code, "display": display}], "text": field}),
+            None => json!({"text": field}),
+        });
+        observation.insert(String::from("effectiveDateTime"), json!(TimeUtil::format_rfc3339(record.ts)));
+        observation.insert(String::from("device"), json!({"display": id}));
+
+        if let Some(person) = record.tags.get("person") {
+            observation.insert(String::from("subject"), json!({"display": person}));
+        }
+
+        let (key, value) = match value {
+            Value::Number(number) => ("valueQuantity", match code {
+                Some((.., unit)) => json!({"value": number, "unit": unit, "system": UCUM, "code": unit}),
+                None => json!({"value": number}),
+            }),
+            Value::Bool(value) => ("valueBoolean", json!(value)),
+            Value::String(value) => ("valueString", json!(value)),
+            _ => return None,
+        };
+        observation.insert(String::from(key), value);
+
+        let tags: Vec<String> = record.tags.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        if !tags.is_empty() {
+            observation.insert(String::from("note"), json!([{"text": format!("{} ({})", record.meas, tags.join(", "))}]));
+        }
+
+        Some(Value::Object(observation))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use std::collections::BTreeMap;
+
+    use crate::store::RecentRecord;
+    use super::{Export, ExportFormat};
+
+    #[test]
+    fn format() {
+        let records = vec![
+            RecentRecord {
+                id: Some(String::from("a1")),
+                ts: 1_714_545_000_000_000_000,
+                meas: String::from("weight"),
+                tags: BTreeMap::from([(String::from("person"), String::from("alice"))]),
+                fields: BTreeMap::from([(String::from("weight"), Value::from(70.5)), (String::from("note"), Value::from("after \"lunch\", late"))]),
+            },
+            RecentRecord {
+                id: None, // Kept before ids were written.
+                ts: 1_714_631_400_000_000_000,
+                meas: String::from("weight"),
+                tags: BTreeMap::new(),
+                fields: BTreeMap::from([(String::from("weight"), Value::from(70.0)), (String::from("bad"), Value::Null)]),
+            },
+        ];
+
+        assert_eq!(Export::format(ExportFormat::Csv, "my_scale", &records),
+            "time,meas,id,person,bad,note,weight\r\n\
+             2024-05-01T06:30:00Z,weight,a1,alice,,\"after \"\"lunch\"\", late\",70.5\r\n\
+             2024-05-02T06:30:00Z,weight,,,,,70.0\r\n");
+
+        let bundle: Value = serde_json::from_str(&Export::format(ExportFormat::Fhir, "my_scale", &records)).unwrap();
+        let entries = bundle["entry"].as_array().unwrap();
+        assert_eq!(entries.len(), 3); // Null field is left out.
+
+        let observation = &entries[1]["resource"];
+        assert_eq!(observation["code"]["coding"][0]["code"], "29463-7");
+        assert_eq!(observation["valueQuantity"]["value"], 70.5);
+        assert_eq!(observation["subject"]["display"], "alice");
+        assert_eq!(observation["effectiveDateTime"], "2024-05-01T06:30:00Z");
+        assert_eq!(observation["identifier"][0]["value"], "a1-weight");
+    }
+}
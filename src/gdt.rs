@@ -0,0 +1,159 @@
+//! # GDT export
+//!
+//! Writes blood pressure, pulse, weight and BMI readings of persons into GDT
+//! files (as used by German practice management systems), one file per
+//! reading. Files are named `<receiver_id><sender_id>.<NNN>`, the first free
+//! number is used, so the practice software should be set up to import
+//! numbered files (and delete them afterwards). Values are taken before
+//! transforms, i.e. in the units reported by the drivers.
+
+use chrono::DateTime;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use crate::db::{DbFieldValue, DbRecord};
+
+const FILE_NUMBERS: u32 = 999;
+const RECLEN_LINE: usize = 3 + 4 + 5 + 2;
+
+const TESTS: [(&str, &str, &str, &str); 5] = [ // Field, test id, test name, unit.
+    ("sys", "RRS", "Blutdruck systolisch", "mmHg"),
+    ("dia", "RRD", "Blutdruck diastolisch", "mmHg"),
+    ("bpm", "PULS", "Puls", "/min"),
+    ("weight", "GEW", "Gewicht", "kg"),
+    ("bmi", "BMI", "Body-Mass-Index", "kg/m2"),
+];
+
+#[derive(Clone, Copy, Default, Deserialize)]
+pub enum GdtVersion {
+    #[serde(rename = "2.1")]
+    #[default]
+    V21,
+    #[serde(rename = "3.0")]
+    V30,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GdtConfig {
+    dir: PathBuf,
+    #[serde(default)]
+    version: GdtVersion,
+    receiver_id: String, // GDT id of the practice software.
+    sender_id: String, // GDT id of phd.
+    patients: HashMap<String, String>, // Person name -> patient number in the practice software.
+}
+
+pub struct Gdt {
+    config: GdtConfig,
+    write_lock: Mutex<()>, // Device tasks share the temporary file.
+}
+
+pub type GdtPtr = Arc<Gdt>;
+
+impl Gdt {
+    pub fn new(config: GdtConfig) -> Self {
+        Self {
+            config,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn export(&self, id: &str, record: &DbRecord) {
+        // Best effort, a failed export doesn't hold back the upload.
+
+        let patient_id = match record.get_tag("person").and_then(|person| self.config.patients.get(person)) {
+            Some(patient_id) => patient_id,
+            None => return,
+        };
+
+        let tests: Vec<_> = TESTS.iter().filter_map(|(field, test_id, test_name, unit)| match record.get_field(field)? {
+            DbFieldValue::Float(value) => Some((test_id, test_name, format!("{:.1}", value), unit)),
+            DbFieldValue::Integer(value) => Some((test_id, test_name, format!("{}", value), unit)),
+            _ => None,
+        }).collect();
+
+        if tests.is_empty() {
+            return;
+        }
+
+        let datetime = DateTime::from_timestamp_nanos(record.get_ts()).naive_local(); // GDT has no timezone, use UTC consistently.
+        let date = datetime.format("%d%m%Y").to_string();
+        let time = datetime.format("%H%M%S").to_string();
+
+        let mut lines = vec![
+            ("8000", String::from("6310")), // Record type: transmit examination data.
+            ("8315", self.config.receiver_id.clone()),
+            ("8316", self.config.sender_id.clone()),
+            ("9206", String::from("3")), // Charset: ISO 8859-1.
+            ("9218", String::from(match self.config.version { GdtVersion::V21 => "02.10", GdtVersion::V30 => "03.00" })),
+            ("3000", patient_id.clone()),
+            ("6200", date.clone()),
+            ("6201", time.clone()),
+            ("8402", String::from("ALLG00")),
+        ];
+
+        for (test_id, test_name, value, unit) in tests {
+            lines.push(("8410", String::from(*test_id)));
+            lines.push(("8411", String::from(*test_name)));
+            lines.push(("8420", value));
+            lines.push(("8421", String::from(*unit)));
+            lines.push(("8432", date.clone()));
+            lines.push(("8439", time.clone()));
+        }
+
+        if let Err(e) = self.write(&Self::encode(&lines)) {
+            eprintln!("{}: unable to write GDT file: {}", id, e);
+        }
+    }
+
+    fn encode(lines: &[(&str, String)]) -> Vec<u8> {
+        // Record length (8100) goes after record type, it includes its own line of fixed length.
+
+        let mut head = Vec::new();
+        let mut tail = Vec::new();
+
+        for (index, (field, content)) in lines.iter().enumerate() {
+            let content = content.chars().map(|c| u8::try_from(u32::from(c)).unwrap_or(b'?')).collect(); // ISO 8859-1
+            Self::encode_line(if index == 0 { &mut head } else { &mut tail }, field, content);
+        }
+
+        let len = head.len() + RECLEN_LINE + tail.len();
+        Self::encode_line(&mut head, "8100", format!("{:05}", len).into_bytes());
+
+        head.extend(tail);
+        head
+    }
+
+    fn encode_line(buf: &mut Vec<u8>, field: &str, content: Vec<u8>) {
+        // <length:3><field:4><content>CRLF
+
+        buf.extend_from_slice(format!("{:03}{}", 3 + 4 + content.len() + 2, field).as_bytes());
+        buf.extend_from_slice(&content);
+        buf.extend_from_slice(b"\r\n");
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<(), String> {
+        // Write into a temporary file first, so the practice software never sees a partial file.
+
+        let _guard = self.write_lock.lock().unwrap();
+        let prefix = format!("{}{}", self.config.receiver_id, self.config.sender_id);
+        let tmp_path = self.config.dir.join(format!(".{}.{}.tmp", prefix, process::id()));
+
+        fs::write(&tmp_path, buf).map_err(|e| format!("{}: {}", tmp_path.display(), e))?;
+
+        let result = (1..=FILE_NUMBERS).map(|number| self.config.dir.join(format!("{}.{:03}", prefix, number))).find_map(|path| match fs::hard_link(&tmp_path, &path) {
+            Ok(_) => Some(Ok(())),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => None,
+            Err(e) => Some(Err(format!("{}: {}", path.display(), e))),
+        }).unwrap_or_else(|| Err(format!("all {} file numbers are taken in {}, is the practice software importing them?", FILE_NUMBERS, self.config.dir.display())));
+
+        let _ = fs::remove_file(&tmp_path);
+        result
+    }
+}
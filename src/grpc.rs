@@ -0,0 +1,185 @@
+//! # gRPC API
+//!
+//! Typed, streaming access for integrators (see proto/phd.proto): records
+//! as they are written to the DB (through the record consumers), triggering
+//! a fetch and the device status of the HTTP status API. Built with the grpc
+//! feature. Authentication and TLS are configured like for the HTTP API.
+
+#![allow(clippy::result_large_err)] // tonic::Status is the error type of the generated service.
+
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use tokio_stream::{Stream, StreamExt};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status as GrpcStatus};
+use tonic::transport::{Identity, Server, ServerTlsConfig};
+
+use crate::auth::{Auth, AuthConfig, AuthPtr, Denied, Scope};
+use crate::consumer::{ConsumersPtr, RecordBatch};
+use crate::control::ControlPtr;
+use crate::db::{DbFieldValue, DbRecord};
+use crate::status::{DeviceState, DeviceStatus, StatusPtr};
+use crate::tls::TlsConfig;
+
+mod proto {
+    tonic::include_proto!("phd.v1");
+}
+
+use proto::phd_server::{Phd, PhdServer};
+use proto::field_value::Value;
+
+const SUBSCRIBE_BUF: usize = 64; // Batches queued per subscriber, dropped (and logged) beyond.
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    listen: SocketAddr,
+    auth: Option<AuthConfig>,
+    tls: Option<TlsConfig>,
+}
+
+struct Service {
+    auth: Option<AuthPtr>,
+    status: StatusPtr,
+    control: ControlPtr,
+    consumers: ConsumersPtr,
+}
+
+pub struct Grpc;
+
+type RecordStream = Pin<Box<dyn Stream<Item = Result<proto::RecordBatch, GrpcStatus>> + Send>>;
+
+impl Grpc {
+    pub async fn start(config: GrpcConfig, status: StatusPtr, control: ControlPtr, consumers: ConsumersPtr) -> Result<(), String> {
+        let mut builder = Server::builder();
+
+        if let Some(tls) = &config.tls {
+            let pem = tls.load("gRPC API")?;
+            builder = builder.tls_config(ServerTlsConfig::new().identity(Identity::from_pem(pem.cert, pem.key))).map_err(|e| format!("Invalid gRPC API TLS configuration: {}", e))?;
+        }
+
+        let auth = match config.auth {
+            Some(auth_config) => Some(AuthPtr::new(Auth::new(auth_config)?)),
+            None => None,
+        };
+
+        let listener = tokio::net::TcpListener::bind(config.listen).await.map_err(|e| format!("Unable to listen on {}: {}", config.listen, e))?;
+        let service = Service {
+            auth,
+            status,
+            control,
+            consumers,
+        };
+
+        tokio::spawn(async move {
+            let incoming = tokio_stream::wrappers::TcpListenerStream::new(listener);
+            if let Err(e) = builder.add_service(PhdServer::new(service)).serve_with_incoming(incoming).await {
+                eprintln!("gRPC API error: {}", e);
+            }
+        });
+
+        Ok(())
+    }
+
+    fn get_batch(batch: RecordBatch) -> proto::RecordBatch {
+        proto::RecordBatch {
+            device_id: batch.id,
+            meas: batch.meas,
+            records: batch.records.iter().map(Self::get_record).collect(),
+        }
+    }
+
+    fn get_record(record: &DbRecord) -> proto::Record {
+        proto::Record {
+            id: record.get_id().map(String::from),
+            ts: record.get_ts(),
+            tags: record.get_tags().map(|(key, value)| (String::from(key), String::from(value))).collect(),
+            fields: record.get_fields().map(|(key, value)| (String::from(key), proto::FieldValue {
+                value: Some(match value {
+                    DbFieldValue::Float(value) => Value::Float(*value),
+                    DbFieldValue::Integer(value) => Value::Integer(*value),
+                    DbFieldValue::Bool(value) => Value::Bool(*value),
+                    DbFieldValue::String(value) => Value::String(value.clone()),
+                }),
+            })).collect(),
+        }
+    }
+
+    fn get_status(status: DeviceStatus) -> proto::DeviceStatus {
+        proto::DeviceStatus {
+            state: String::from(status.state.get_name()),
+            reason: match status.state {
+                DeviceState::Error { reason } => Some(reason),
+                _ => None,
+            },
+            since: status.since,
+            last_adv: status.last_adv,
+            records: status.stats.records,
+            bytes_read: status.stats.bytes_read,
+            failures: status.stats.failures,
+            last_error: status.stats.last_error,
+            paired: status.pairing.paired,
+        }
+    }
+}
+
+impl Service {
+    fn check_auth<T>(&self, request: &Request<T>, scope: Scope) -> Result<(), GrpcStatus> {
+        let auth = match &self.auth {
+            Some(auth) => auth,
+            None => return Ok(()),
+        };
+
+        let header = request.metadata().get("authorization").and_then(|header| header.to_str().ok());
+
+        match auth.check(header, scope) {
+            Ok(()) => Ok(()),
+            Err(Denied::Unauthenticated) => Err(GrpcStatus::unauthenticated("Invalid or missing credentials")),
+            Err(Denied::Forbidden) => Err(GrpcStatus::permission_denied("Credentials lack the needed scope")),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Phd for Service {
+    type SubscribeRecordsStream = RecordStream;
+
+    async fn subscribe_records(&self, request: Request<proto::SubscribeRecordsRequest>) -> Result<Response<Self::SubscribeRecordsStream>, GrpcStatus> {
+        // The subscription is dropped by the consumers once the client goes away.
+
+        self.check_auth(&request, Scope::ReadRecords)?;
+
+        let device_ids: HashSet<String> = request.into_inner().device_ids.into_iter().collect();
+        let rx = self.consumers.subscribe(SUBSCRIBE_BUF);
+
+        let stream = ReceiverStream::new(rx)
+            .filter(move |batch| device_ids.is_empty() || device_ids.contains(&batch.id))
+            .map(|batch| Ok(Grpc::get_batch(batch)));
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn trigger_fetch(&self, request: Request<proto::TriggerFetchRequest>) -> Result<Response<proto::TriggerFetchResponse>, GrpcStatus> {
+        self.check_auth(&request, Scope::TriggerFetch)?;
+
+        let device_id = request.into_inner().device_id;
+
+        match self.control.trigger_fetch(&device_id) {
+            Ok(()) => {
+                println!("{}: fetch triggered through gRPC API", device_id);
+                Ok(Response::new(proto::TriggerFetchResponse {}))
+            },
+            Err(e) => Err(GrpcStatus::not_found(e)),
+        }
+    }
+
+    async fn get_status(&self, request: Request<proto::GetStatusRequest>) -> Result<Response<proto::GetStatusResponse>, GrpcStatus> {
+        self.check_auth(&request, Scope::ReadStatus)?;
+
+        Ok(Response::new(proto::GetStatusResponse {
+            devices: self.status.get_devices().into_iter().map(|(id, status)| (id, Grpc::get_status(status))).collect(),
+        }))
+    }
+}
@@ -0,0 +1,146 @@
+//! # Hooks
+//!
+//! Runs external commands on events, so users can script their own
+//! integrations (e.g. announce the weight on a speaker). Commands are run
+//! directly (no shell) in the background, details are passed in PHD_*
+//! environment variables, records as JSON on stdin. A message template can be
+//! given for notifications: it is expanded per record from its fields, tags,
+//! meas and time (host's local time).
+
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use tokio::task;
+
+use crate::db::{DbFieldValue, DbRecord};
+use crate::redact::Redact;
+use crate::template::Template;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HooksConfig {
+    on_records: Option<Vec<String>>, // Command and its arguments, run after records are written to the DB.
+    on_records_message: Option<Template>, // Expanded per record, lines are passed in PHD_MESSAGE.
+    on_error: Option<Vec<String>>, // Run after a failed data retrieval.
+    on_error_message: Option<Template>, // Expanded from device_id and error, passed in PHD_MESSAGE.
+    on_pair: Option<Vec<String>>, // Run after successful pairing.
+}
+
+pub struct Hooks {
+    config: HooksConfig,
+}
+
+pub type HooksPtr = Arc<Hooks>;
+
+impl Hooks {
+    pub fn new(config: HooksConfig) -> Self {
+        Self {
+            config,
+        }
+    }
+
+    pub fn on_records(&self, id: &str, meas: &str, records: &[DbRecord]) {
+        if let Some(cmd) = &self.config.on_records {
+            let stdin = Value::Array(records.iter().map(|record| Self::get_json(meas, record)).collect());
+            let mut env = vec![("PHD_MEAS", String::from(meas))];
+
+            if let Some(message) = &self.config.on_records_message {
+                env.push(("PHD_MESSAGE", records.iter().map(|record| Self::get_message(message, meas, record)).collect::<Vec<String>>().join("\n")));
+            }
+
+            Self::run(cmd, id, "records", env, Some(stdin.to_string()));
+        }
+    }
+
+    pub fn on_error(&self, id: &str, error: &str) {
+        if let Some(cmd) = &self.config.on_error {
+            let error = Redact::apply(error);
+            let mut env = vec![("PHD_ERROR", error.clone())];
+
+            if let Some(message) = &self.config.on_error_message {
+                env.push(("PHD_MESSAGE", message.expand(|name| match name {
+                    "device_id" => Some(String::from(id)),
+                    "error" => Some(error.clone()),
+                    _ => None,
+                })));
+            }
+
+            Self::run(cmd, id, "error", env, None);
+        }
+    }
+
+    pub fn on_pair(&self, id: &str) {
+        if let Some(cmd) = &self.config.on_pair {
+            Self::run(cmd, id, "pair", Vec::new(), None);
+        }
+    }
+
+    fn run(cmd: &[String], id: &str, event: &str, env: Vec<(&str, String)>, stdin: Option<String>) {
+        // Best effort, failures are only logged. The runtime waits for running hooks on exit.
+
+        let (program, args) = match cmd.split_first() {
+            Some(split) => split,
+            None => return,
+        };
+
+        let mut command = Command::new(program);
+        command.args(args)
+            .env("PHD_DEVICE_ID", id)
+            .env("PHD_EVENT", event)
+            .envs(env)
+            .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() });
+
+        let id = String::from(id);
+        let event = String::from(event);
+
+        task::spawn_blocking(move || {
+            let result = command.spawn().and_then(|mut child| {
+                if let (Some(mut child_stdin), Some(stdin)) = (child.stdin.take(), stdin) {
+                    child_stdin.write_all(stdin.as_bytes())?;
+                }
+
+                child.wait()
+            });
+
+            match result {
+                Ok(exit_status) if exit_status.success() => (),
+                Ok(exit_status) => eprintln!("{}: {} hook failed: {}", id, event, exit_status),
+                Err(e) => eprintln!("{}: unable to run {} hook: {}", id, event, e),
+            }
+        });
+    }
+
+    fn get_message(message: &Template, meas: &str, record: &DbRecord) -> String {
+        message.expand(|name| match (record.get_field(name), record.get_tag(name)) {
+            (Some(DbFieldValue::Float(value)), _) => Some(format!("{}", value)), // Use a round transform for fewer decimals.
+            (Some(DbFieldValue::Integer(value)), _) => Some(format!("{}", value)),
+            (Some(DbFieldValue::Bool(value)), _) => Some(format!("{}", value)),
+            (Some(DbFieldValue::String(value)), _) => Some(value.clone()),
+            (None, Some(value)) => Some(String::from(value)),
+            (None, None) if name == "meas" => Some(String::from(meas)),
+            (None, None) if name == "time" => Some(DateTime::from_timestamp_nanos(record.get_ts()).with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string()),
+            (None, None) => None,
+        })
+    }
+
+    pub fn get_json(meas: &str, record: &DbRecord) -> Value { // Also the MQTT payload.
+        let tags: Map<String, Value> = record.get_tags().map(|(key, value)| (String::from(key), Value::from(value))).collect();
+        let fields: Map<String, Value> = record.get_fields().map(|(key, value)| (String::from(key), match value {
+            DbFieldValue::Float(value) => Value::from(*value), // Non-finite values become null.
+            DbFieldValue::Integer(value) => Value::from(*value),
+            DbFieldValue::Bool(value) => Value::from(*value),
+            DbFieldValue::String(value) => Value::from(value.as_str()),
+        })).collect();
+
+        json!({
+            "id": record.get_id(), // Idempotency key, see DbRecord::set_ids().
+            "ts": record.get_ts(), // [ns]
+            "meas": meas,
+            "tags": tags,
+            "fields": fields,
+        })
+    }
+}
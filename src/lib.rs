@@ -0,0 +1,40 @@
+//! # phd: Personal Health Daemon
+//!
+//! The daemon itself is in main.rs, the modules are exposed for tests, fuzzing
+//! and embedding (see consumer.rs for receiving records in-process).
+
+pub mod api;
+pub mod auth;
+pub mod bluez;
+pub mod btutil;
+pub mod consumer;
+pub mod control;
+pub mod db;
+pub mod device;
+pub mod driver;
+pub mod export;
+pub mod gdt;
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+pub mod hooks;
+pub mod mdns;
+pub mod mqtt;
+pub mod naming;
+pub mod otel;
+pub mod persons;
+pub mod redact;
+pub mod scanner;
+pub mod secrets;
+pub mod selftest;
+pub mod status;
+pub mod store;
+pub mod supervisor;
+pub mod telemetry;
+pub mod template;
+pub mod timeutil;
+pub mod tls;
+pub mod tracelog;
+pub mod transform;
+pub mod trend;
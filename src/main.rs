@@ -1,21 +1,38 @@
+use chrono::DateTime;
 use clap::Parser;
-use config::{Config, File, FileFormat};
+use config::{Config, ConfigError, File, FileFormat, Value};
 use serde::Deserialize;
 use std::collections::HashSet;
+use std::mem;
+use std::path::PathBuf;
 use std::process;
+use std::sync::Arc;
 use tokio::signal;
+use tokio::signal::unix::{self, SignalKind};
+use tokio::time::Duration;
 
-mod btutil;
-
-mod db;
-use db::{Db, DbConfig, DbPtr};
-
-mod device;
-use device::{Device, DeviceConfig};
-
-mod driver;
-
-mod timeutil;
+use phd::api::{Api, ApiConfig};
+use phd::bluez::BluezBackend;
+use phd::consumer::ConsumersPtr;
+use phd::control::ControlPtr;
+use phd::db::{Db, DbConfig, DbFieldValue, DbPtr, DbRecord};
+use phd::device::{Device, DeviceConfig, DeviceEnv};
+use phd::gdt::{Gdt, GdtConfig, GdtPtr};
+#[cfg(feature = "grpc")]
+use phd::grpc::{Grpc, GrpcConfig};
+use phd::hooks::{Hooks, HooksConfig, HooksPtr};
+use phd::mqtt::{Mqtt, MqttConfig, MqttPtr};
+use phd::otel::{Otel, OtelConfig};
+use phd::persons::{PersonConfig, Persons, PersonsPtr};
+use phd::redact::Redact;
+use phd::secrets::Secrets;
+use phd::selftest::{Outcome, SelfTest};
+use phd::status::{Status, StatusPtr};
+use phd::store::{HeldRecord, HeldState, Store, StoreConfig, StorePtr};
+use phd::supervisor::Supervisor;
+use phd::telemetry::{Telemetry, TelemetryConfig, TelemetryPtr};
+use phd::timeutil::{ShiftedClock, TimeUtil};
+use phd::tracelog::{TraceLog, TraceLogConfig};
 
 #[derive(Parser)]
 #[command(name = clap::crate_name!(), version = clap::crate_version!(), about = clap::crate_description!(), author = clap::crate_authors!())]
@@ -25,6 +42,51 @@ struct Args {
 
     #[arg(short = 'p', long = "pair", value_name = "DEVICE_ID", help = "Pair with device")]
     pair_device_id: Option<String>,
+
+    #[arg(long = "repair", help = "Keep the existing bond and only rewrite the key on the unit (e.g. after losing the configured secret)", requires = "pair_device_id")]
+    repair: bool,
+
+    #[arg(short = 'm', long = "measure", value_name = "DEVICE_ID", help = "Take a measurement with device", conflicts_with = "pair_device_id")]
+    measure_device_id: Option<String>,
+
+    #[arg(short = 'a', long = "annotate", value_name = "DEVICE_ID", help = "Write an annotation record for device", conflicts_with_all = ["pair_device_id", "measure_device_id"])]
+    annotate_device_id: Option<String>,
+
+    #[arg(long = "ts", value_name = "TS", help = "Timestamp of annotation (RFC 3339, e.g. 2024-10-16T08:30:00+02:00), default is now", value_parser = TimeUtil::parse_rfc3339, requires = "annotate_device_id")]
+    annotate_ts: Option<i64>,
+
+    #[arg(long = "meas", value_name = "MEAS", help = "Measurement of annotation, default is the device's", requires = "annotate_device_id")]
+    annotate_meas: Option<String>,
+
+    #[arg(long = "tag", value_name = "KEY=VALUE", help = "Tag of annotation", value_parser = parse_key_value, requires = "annotate_device_id")]
+    annotate_tags: Vec<(String, String)>,
+
+    #[arg(value_name = "FIELD=VALUE", help = "String fields of annotation, e.g. note=\"after coffee\"", value_parser = parse_key_value, requires = "annotate_device_id")]
+    annotate_fields: Vec<(String, String)>,
+
+    #[arg(long = "backfill", value_name = "DEVICE_ID", help = "Fetch records from device once and upload them, without time limit (e.g. after losing the DB)", conflicts_with_all = ["pair_device_id", "measure_device_id", "annotate_device_id"])]
+    backfill_device_id: Option<String>,
+
+    #[arg(long = "full", help = "Read the whole memory of the unit, ignoring its unread record counts and the backfill cutoff", requires = "backfill_device_id")]
+    backfill_full: bool,
+
+    #[arg(long = "held", help = "List records of unknown users held for assignment, and records parked after being refused", conflicts_with_all = ["pair_device_id", "measure_device_id", "annotate_device_id", "backfill_device_id"])]
+    held: bool,
+
+    #[arg(long = "assign", value_name = "HELD_ID", help = "Assign a held record to a person, the running daemon uploads it", requires = "assign_person", conflicts_with_all = ["pair_device_id", "measure_device_id", "annotate_device_id", "backfill_device_id", "held"])]
+    assign_held_id: Option<i64>,
+
+    #[arg(long = "person", value_name = "NAME", help = "Person to assign the held record to", requires = "assign_held_id")]
+    assign_person: Option<String>,
+
+    #[arg(long = "selftest", help = "Check configuration, Bluetooth, state and DB, then print a diagnostic summary", conflicts_with_all = ["pair_device_id", "measure_device_id", "annotate_device_id", "backfill_device_id", "held", "assign_held_id"])]
+    selftest: bool,
+
+    #[arg(long = "fake-now", value_name = "TS", help = "Debug: pretend the current time is TS (RFC 3339), the clock runs on from there", value_parser = TimeUtil::parse_rfc3339)]
+    fake_now: Option<i64>,
+
+    #[arg(long = "wait-for-bluetooth", value_name = "SECS", help = "Wait up to SECS for BlueZ and the adapter at startup, then fail (default: the daemon waits forever, other commands don't wait)")]
+    wait_for_bluetooth: Option<u64>,
 }
 
 #[derive(Deserialize)]
@@ -32,6 +94,26 @@ struct Args {
 struct MainConfig {
     devices: Vec<DeviceConfig>,
     db: DbConfig,
+    api: Option<ApiConfig>,
+    #[cfg(feature = "grpc")]
+    grpc: Option<GrpcConfig>,
+    state: Option<StoreConfig>,
+    #[serde(default)]
+    persons: Vec<PersonConfig>,
+    telemetry: Option<TelemetryConfig>,
+    #[serde(default)]
+    startup_spread: u32, // [s]
+    otel: Option<OtelConfig>,
+    gdt: Option<GdtConfig>,
+    hooks: Option<HooksConfig>,
+    mqtt: Option<MqttConfig>,
+    trace_log: Option<TraceLogConfig>,
+    #[allow(dead_code)] // Already consumed by load_config().
+    secrets: Option<PathBuf>,
+    #[allow(dead_code)] // Already consumed by load_config().
+    age_identity: Option<PathBuf>,
+    #[allow(dead_code)] // Already consumed by load_config().
+    redact: Option<bool>,
 }
 
 // TODO: Use proper logging class.
@@ -41,74 +123,418 @@ async fn main() {
 
     let args = Args::parse();
 
+    if let Some(fake_now) = args.fake_now {
+        TimeUtil::set_clock(Arc::new(ShiftedClock::new(DateTime::from_timestamp_nanos(fake_now))));
+    }
+
+    if args.selftest {
+        let ok = selftest(&args.config_fname).await;
+        process::exit(if ok { 0 } else { 1 });
+    }
+
     // Parse configuration file.
 
-    let config_builder = match Config::builder()
-        .add_source(File::new(&args.config_fname, FileFormat::Yaml))
-        .build() {
-        Ok(config_builder) => config_builder,
+    let config_value = match load_config(&args.config_fname) {
+        Ok(config_value) => config_value,
         Err(e) => {
-            eprintln!("Unable to open configuration: {}", e);
+            eprintln!("{}", Redact::apply(&e));
             process::exit(1);
         }
     };
 
-    let main_config: MainConfig = match config_builder.try_deserialize() {
-        Ok(main_config) => main_config,
+    let (main_config, persons) = match parse_config(config_value.clone()) {
+        Ok(parsed) => parsed,
         Err(e) => {
-            eprintln!("Unable to parse configuration: {}", e);
+            eprintln!("{}", Redact::apply(&e));
             process::exit(1);
         }
     };
 
-    // Check for unique device ids.
+    if let Err(e) = TraceLog::init(main_config.trace_log) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 
-    let mut device_ids = HashSet::new();
+    // Open state store.
 
-    for device_id in main_config.devices.iter().map(|device| device.get_id()) {
-        if !device_ids.insert(device_id) {
-            eprintln!("Device id is duplicated: {}", device_id);
+    let store = match Store::open(main_config.state) {
+        Ok(store) => StorePtr::new(store),
+        Err(e) => {
+            eprintln!("{}", e);
             process::exit(1);
         }
-    }
+    };
+
+    // Initialize DB.
+
+    let db = match Db::new(main_config.db) {
+        Ok(db) => DbPtr::new(db),
+        Err(e) => {
+            eprintln!("Unable to parse configuration: {}", Redact::apply(&e.to_string()));
+            process::exit(1);
+        }
+    };
+
+    let gdt = main_config.gdt.map(|gdt_config| GdtPtr::new(Gdt::new(gdt_config)));
+    let hooks = main_config.hooks.map(|hooks_config| HooksPtr::new(Hooks::new(hooks_config)));
 
     // Main logic starts here.
     
-    match args.pair_device_id {
-        Some(device_id) => {
-            // Do pairing.
-
-            match main_config.devices.into_iter().find(|device_config| device_config.get_id() == device_id) {
-                Some(device_config) => {
-                    let ok = Device::pair(device_config).await;
-                    if !ok {
-                        process::exit(1);
+    if let Some(device_id) = args.pair_device_id {
+        // Do pairing.
+
+        let device_config = find_device(main_config.devices, &device_id);
+
+        if let Some(secs) = args.wait_for_bluetooth {
+            wait_for_bluetooth(Some(Duration::from_secs(secs))).await;
+        }
+
+        let ok = Device::pair(store, hooks, device_config, args.repair).await;
+        if !ok {
+            process::exit(1);
+        }
+    } else if let Some(device_id) = args.measure_device_id {
+        // Do triggered measurement.
+
+        let device_config = find_device(main_config.devices, &device_id);
+
+        if let Some(secs) = args.wait_for_bluetooth {
+            wait_for_bluetooth(Some(Duration::from_secs(secs))).await;
+        }
+
+        let ok = Device::measure(db, store, persons, gdt, hooks, device_config).await;
+        if !ok {
+            process::exit(1);
+        }
+    } else if let Some(device_id) = args.backfill_device_id {
+        // Do backfill.
+
+        let device_config = find_device(main_config.devices, &device_id);
+
+        if let Some(secs) = args.wait_for_bluetooth {
+            wait_for_bluetooth(Some(Duration::from_secs(secs))).await;
+        }
+
+        let ok = Device::backfill(db, store, persons, device_config, args.backfill_full).await;
+        if !ok {
+            process::exit(1);
+        }
+    } else if let Some(device_id) = args.annotate_device_id {
+        // Write annotation.
+
+        if args.annotate_fields.is_empty() {
+            eprintln!("At least one FIELD=VALUE is needed for annotation");
+            process::exit(1);
+        }
+
+        let mut record = DbRecord::new(args.annotate_ts.unwrap_or_else(|| TimeUtil::get_ts_unix(TimeUtil::get_current_unix())));
+
+        if let Some(meas) = args.annotate_meas {
+            record.set_meas(&meas);
+        }
+
+        for (key, value) in args.annotate_tags {
+            record.add_tag(&key, &value);
+        }
+
+        for (key, value) in args.annotate_fields {
+            record.add_field(&key, DbFieldValue::String(value));
+        }
+
+        let device_config = find_device(main_config.devices, &device_id);
+        let ok = Device::annotate(db, store, persons, device_config, record).await;
+        if !ok {
+            process::exit(1);
+        }
+    } else if args.held {
+        // List held records, then the parked ones (not assignable, retried by the daemon at its next start).
+
+        for state in [HeldState::Pending, HeldState::Refused] {
+            match store.get_held(None, state) {
+                Ok(held) => {
+                    for (held_id, record) in held {
+                        match &record.reason {
+                            Some(reason) if state == HeldState::Refused => println!("{} (parked: {})", format_held(held_id, &record), reason),
+                            _ => println!("{}", format_held(held_id, &record)),
+                        }
                     }
                 },
-                None => {
-                    eprintln!("No such device: {}", device_id);
-                    process::exit(1);        
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
                 }
             }
-        },
+        }
+    } else if let Some(held_id) = args.assign_held_id {
+        // Assign held record, it is uploaded by the daemon.
+
+        let person = args.assign_person.unwrap_or_default();
+
+        if !persons.has_person(&person) {
+            eprintln!("No such person: {}", person);
+            process::exit(1);
+        }
+
+        let result = store.update_held(held_id, |record| {
+            if record.state != HeldState::Pending {
+                return Err(format!("Held record {} is already {}", held_id, record.state.get_name()));
+            }
+
+            record.state = HeldState::Assigned;
+            record.person = Some(person.clone());
+            Ok(())
+        });
+
+        match result {
+            Ok(record) => println!("{}: assigned to {}", format_held(held_id, &record), person),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        // Do main loop.
+
+        println!("daemon starting");
+
+        // Start trace export.
+
+        if let Some(otel_config) = main_config.otel {
+            if let Err(e) = Otel::start(otel_config) {
+                eprintln!("{}", Redact::apply(&e));
+                process::exit(1);
+            }
+        }
+
+        // Start APIs.
+
+        let status = StatusPtr::new(Status::default());
+        let control = ControlPtr::default();
+
+        if let Some(api_config) = main_config.api {
+            if let Err(e) = Api::start(api_config, StatusPtr::clone(&status), ControlPtr::clone(&control), StorePtr::clone(&store), PersonsPtr::clone(&persons)).await {
+                eprintln!("{}", Redact::apply(&e));
+                process::exit(1);
+            }
+        }
+
+        #[cfg(feature = "grpc")]
+        let consumers = match main_config.grpc {
+            Some(grpc_config) => {
+                let consumers = ConsumersPtr::default();
+
+                if let Err(e) = Grpc::start(grpc_config, StatusPtr::clone(&status), ControlPtr::clone(&control), ConsumersPtr::clone(&consumers)).await {
+                    eprintln!("{}", Redact::apply(&e));
+                    process::exit(1);
+                }
+
+                Some(consumers)
+            },
+            None => None,
+        };
+
+        #[cfg(not(feature = "grpc"))]
+        let consumers: Option<ConsumersPtr> = None;
+    
+        // Start devices, once Bluetooth is up.
+
+        wait_for_bluetooth(args.wait_for_bluetooth.map(Duration::from_secs)).await;
+
+        let telemetry = main_config.telemetry.map(|telemetry_config| TelemetryPtr::new(Telemetry::new(DbPtr::clone(&db), telemetry_config)));
+
+        let mut supervisor = Supervisor::new(DeviceEnv {
+            db,
+            status,
+            control,
+            backend: BluezBackend::start(),
+            store,
+            persons,
+            gdt,
+            hooks,
+            consumers,
+            mqtt: main_config.mqtt.map(|mqtt_config| MqttPtr::new(Mqtt::new(mqtt_config))),
+            telemetry,
+        }, Duration::from_secs(main_config.startup_spread.into()));
+        supervisor.apply(get_raw_devices(config_value).unwrap()); // Already validated.
+    
+        // Reload device definitions on HUP, other sections need a restart.
+        // TODO: Do proper signal handling, e.g. TERM->graceful shutdown.
+
+        let mut hangup = unix::signal(SignalKind::hangup()).unwrap();
+
+        loop {
+            tokio::select! {
+                _ = signal::ctrl_c() => break,
+                _ = hangup.recv() => {
+                    println!("reloading configuration");
+
+                    match load_config(&args.config_fname).and_then(get_raw_devices) {
+                        Ok(raw_devices) => supervisor.apply(raw_devices),
+                        Err(e) => eprintln!("{}, keeping previous one", Redact::apply(&e)),
+                    }
+                }
+            }
+        }
+
+        Otel::stop();
+    }
+}
+
+async fn wait_for_bluetooth(timeout: Option<Duration>) {
+    if let Err(e) = BluezBackend::wait_ready(timeout).await {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn load_config(config_fname: &str) -> Result<Value, String> {
+    // Returns the configuration with secret references resolved, also sets up log redaction.
+
+    let config_builder = Config::builder()
+        .add_source(File::new(config_fname, FileFormat::Yaml))
+        .build()
+        .map_err(|e| format!("Unable to open configuration: {}", e))?;
+
+    let secrets_fname = get_optional(&config_builder, "secrets")?;
+    let identity_fname = get_optional(&config_builder, "age_identity")?;
+    let redact = get_optional(&config_builder, "redact")?.unwrap_or(true);
+    let secrets = Secrets::load(secrets_fname, identity_fname)?;
+
+    let mut config_value: Value = config_builder.try_deserialize().map_err(|e| format!("Unable to parse configuration: {}", e))?;
+    secrets.resolve(&mut config_value).map_err(|e| format!("Unable to parse configuration: {}", e))?;
+
+    Redact::init(redact, &config_value);
+
+    Ok(config_value)
+}
+
+fn parse_config(config_value: Value) -> Result<(MainConfig, PersonsPtr), String> {
+    // Also checks for unique device ids and persons referring to known devices.
+
+    let mut main_config: MainConfig = config_value.try_deserialize().map_err(|e| format!("Unable to parse configuration: {}", e))?;
+
+    let mut device_ids = HashSet::new();
+
+    for device_id in main_config.devices.iter().map(|device| device.get_id()) {
+        if !device_ids.insert(device_id) {
+            return Err(format!("Device id is duplicated: {}", device_id));
+        }
+    }
+
+    let persons = PersonsPtr::new(Persons::new(mem::take(&mut main_config.persons))?);
+
+    if let Some(device_id) = persons.get_device_ids().find(|device_id| !device_ids.contains(device_id)) {
+        return Err(format!("Person refers to unknown device: {}", device_id));
+    }
+
+    Ok((main_config, persons))
+}
+
+async fn selftest(config_fname: &str) -> bool {
+    // Runs all the checks even if some fail, returns whether all passed.
+
+    println!("{} {} self-test", clap::crate_name!(), clap::crate_version!());
+
+    let mut selftest = SelfTest::default();
+
+    let main_config = selftest.check("config", load_config(config_fname).and_then(parse_config).map(|(main_config, _)| {
+        let detail = format!("{}, {} device(s)", config_fname, main_config.devices.len());
+        (main_config, detail)
+    }));
+
+    // Bluetooth.
+
+    let bluez = selftest.check("bluez", BluezBackend::get_adapters().await
+        .map(|adapters| ((), format!("reachable over D-Bus, adapters: {}", if adapters.is_empty() { String::from("none") } else { adapters.join(", ") })))
+        .map_err(|e| e.to_string()));
+
+    match BluezBackend::get_version() {
+        Some(version) => selftest.report("bluez version", Outcome::Ok(version)),
+        None => selftest.report("bluez version", Outcome::Warn(String::from("unknown, bluetoothd binary not found"))),
+    }
+
+    let adapter = match bluez {
+        Some(()) => selftest.check("adapter", BluezBackend::get_default_adapter().await.map(|adapter| ((), format!("{}, powered", adapter))).map_err(|e| e.to_string())),
         None => {
-            // Do main loop.
-
-            println!("daemon starting");
-
-            // Initialize DB.
-        
-            let db = DbPtr::new(Db::new(main_config.db));
-        
-            // Start devices.
-        
-            for device_config in main_config.devices {
-                Device::start(DbPtr::clone(&db), device_config);
+            selftest.report("adapter", Outcome::Skipped(String::from("BlueZ is not reachable")));
+            None
+        },
+    };
+
+    match adapter {
+        Some(()) => {
+            selftest.check("monitor api", BluezBackend::check_monitor().await
+                .map(|_| ((), String::from("advertisement monitor can be registered")))
+                .map_err(|e| format!("{} (is the experimental bluetoothd feature enabled?)", e)));
+        },
+        None => selftest.report("monitor api", Outcome::Skipped(String::from("no usable adapter"))),
+    }
+
+    // State and DB.
+
+    match main_config {
+        Some(main_config) => {
+            selftest.check("state", Store::open(main_config.state).and_then(|store| store.check_writable().map(|path| ((), match path {
+                Some(path) => format!("{} is writable (schema version {})", path.display(), store.get_version()),
+                None => String::from("not configured, kept in memory"),
+            }))));
+
+            match Db::new(main_config.db) {
+                Ok(db) => {
+                    for (name, result) in db.check().await {
+                        selftest.check("db", result.map(|_| ((), format!("{} accepts writes", name))).map_err(|e| format!("{}: {}", name, e)));
+                    }
+                },
+                Err(e) => selftest.report("db", Outcome::Fail(e)),
             }
-        
-            // TODO: Do proper signal handling, e.g. HUP->reload, TERM->graceful shutdown.
-        
-            signal::ctrl_c().await.unwrap();        
+        },
+        None => {
+            selftest.report("state", Outcome::Skipped(String::from("invalid configuration")));
+            selftest.report("db", Outcome::Skipped(String::from("invalid configuration")));
+        },
+    }
+
+    selftest.finish()
+}
+
+fn get_optional<'de, T>(config_builder: &Config, key: &str) -> Result<Option<T>, String> where T: Deserialize<'de> {
+    match config_builder.get(key) {
+        Ok(path) => Ok(Some(path)),
+        Err(ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(format!("Unable to parse configuration: {}", e)),
+    }
+}
+
+fn get_raw_devices(config_value: Value) -> Result<Vec<Value>, String> {
+    // Device blocks are validated one by one, see Supervisor.
+
+    let mut table = config_value.into_table().map_err(|e| format!("Unable to parse configuration: {}", e))?;
+
+    match table.remove("devices") {
+        Some(devices) => devices.into_array().map_err(|e| format!("Unable to parse configuration: {}", e)),
+        None => Err(String::from("Unable to parse configuration: missing field `devices`")),
+    }
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((String::from(key), String::from(value))),
+        _ => Err(String::from("expected KEY=VALUE")),
+    }
+}
+
+fn format_held(held_id: i64, record: &HeldRecord) -> String {
+    let fields: Vec<_> = record.fields.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+
+    format!("{} {} {} user={} {}", held_id, record.device, TimeUtil::format_rfc3339(record.ts), record.tags.get("user").map_or("?", String::as_str), fields.join(" "))
+}
+
+fn find_device(device_configs: Vec<DeviceConfig>, device_id: &str) -> DeviceConfig {
+    match device_configs.into_iter().find(|device_config| device_config.get_id() == device_id) {
+        Some(device_config) => device_config,
+        None => {
+            eprintln!("No such device: {}", device_id);
+            process::exit(1);
         }
     }
 }
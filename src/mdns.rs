@@ -0,0 +1,67 @@
+//! # mDNS announcement
+//!
+//! Announces the HTTP API as _phd._tcp on the LAN, through avahi-daemon
+//! (over D-Bus, like BlueZ), so companion tools can find the daemon. The
+//! announcement lasts as long as the D-Bus connection, i.e. until phd exits.
+
+use dbus::Path;
+use dbus::nonblock::Proxy;
+use serde::Deserialize;
+use std::time::Duration;
+
+const AVAHI_SERVICE: &str = "org.freedesktop.Avahi";
+const AVAHI_SERVER: &str = "org.freedesktop.Avahi.Server";
+const AVAHI_ENTRY_GROUP: &str = "org.freedesktop.Avahi.EntryGroup";
+const AVAHI_IF_UNSPEC: i32 = -1;
+const AVAHI_PROTO_UNSPEC: i32 = -1;
+
+const SERVICE_TYPE: &str = "_phd._tcp";
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnnounceConfig {
+    name: Option<String>, // "phd on <host name>" if unset.
+}
+
+pub struct Mdns;
+
+impl Mdns {
+    pub async fn announce(config: &AnnounceConfig, port: u16, tls: bool) -> Result<(), String> {
+        let (resource, conn) = dbus_tokio::connection::new_system_sync().map_err(|e| format!("Unable to connect to D-Bus: {}", e))?;
+
+        tokio::spawn(async move {
+            let e = resource.await;
+            eprintln!("mDNS: lost connection to D-Bus, announcement is gone: {}", e);
+        });
+
+        let error = |e: dbus::Error| format!("Unable to announce API via avahi-daemon: {}", e);
+
+        let server = Proxy::new(AVAHI_SERVICE, "/", TIMEOUT, conn.clone());
+
+        let name = match &config.name {
+            Some(name) => name.clone(),
+            None => {
+                let (host_name,): (String,) = server.method_call(AVAHI_SERVER, "GetHostName", ()).await.map_err(error)?;
+                format!("phd on {}", host_name)
+            }
+        };
+
+        let (group,): (Path<'static>,) = server.method_call(AVAHI_SERVER, "EntryGroupNew", ()).await.map_err(error)?;
+        let group = Proxy::new(AVAHI_SERVICE, group, TIMEOUT, conn);
+
+        let txt = vec![
+            format!("version={}", env!("CARGO_PKG_VERSION")).into_bytes(),
+            format!("scheme={}", if tls { "https" } else { "http" }).into_bytes(),
+            b"status=/status".to_vec(),
+            b"metrics=/metrics".to_vec(),
+        ];
+
+        group.method_call::<(), _, _, _>(AVAHI_ENTRY_GROUP, "AddService", (AVAHI_IF_UNSPEC, AVAHI_PROTO_UNSPEC, 0u32, name.as_str(), SERVICE_TYPE, "", "", port, txt)).await.map_err(error)?;
+        group.method_call::<(), _, _, _>(AVAHI_ENTRY_GROUP, "Commit", ()).await.map_err(error)?;
+
+        println!("API: announced as \"{}\" ({}) on port {}", name, SERVICE_TYPE, port);
+
+        Ok(())
+    }
+}
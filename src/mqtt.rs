@@ -0,0 +1,275 @@
+//! # MQTT live mirror
+//!
+//! The newest record of each fetch is published (as JSON, retained) to a
+//! per-device topic right away, before it is written to the DB, so a wall
+//! display or home automation can show a reading seconds after it was taken.
+//! This is not a second sink: only the latest reading is kept by the broker,
+//! and messages are dropped while the broker is unreachable. Readings are
+//! rare, so a connection is made per publish (MQTT 3.1.1, QoS 0, plain TCP).
+//! Publishes can overlap (several devices, several measurements of a fetch),
+//! so each connection gets a client id of its own: a broker closes the
+//! existing session of a client id on a new connection with the same one.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{self, Duration};
+
+use crate::db::DbRecord;
+use crate::hooks::Hooks;
+use crate::redact::Redact;
+use crate::template::Template;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const KEEP_ALIVE: u16 = 30; // [s] Only matters for a slow broker, the connection is closed after publishing.
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const DISCONNECT: u8 = 0xe0;
+
+const FLAG_USER: u8 = 0x80;
+const FLAG_PASSWORD: u8 = 0x40;
+const FLAG_CLEAN_SESSION: u8 = 0x02;
+const FLAG_RETAIN: u8 = 0x01;
+
+const PROTOCOL_LEVEL: u8 = 4; // 3.1.1
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    server: String, // host:port
+    #[serde(default = "MqttConfig::get_default_client_id")]
+    client_id: String, // Prefix, suffixed with the process id and a counter per connection.
+    user: Option<String>,
+    secret: Option<String>, // Password.
+    #[serde(default = "MqttConfig::get_default_topic")]
+    topic: Template, // Expanded per record from its tags and meas.
+    #[serde(default = "MqttConfig::get_default_retain")]
+    retain: bool, // Subscribers get the latest reading on connecting.
+}
+
+pub struct Mqtt {
+    config: Arc<MqttConfig>, // Shared with the publishing tasks.
+    published: Mutex<HashMap<String, i64>>, // Timestamp of the last record published per topic.
+    connections: AtomicU64, // Number of connections made, for unique client ids.
+}
+
+pub type MqttPtr = Arc<Mqtt>;
+
+impl MqttConfig {
+    fn get_default_client_id() -> String {
+        String::from("phd")
+    }
+
+    fn get_default_topic() -> Template {
+        Template::try_from(String::from("phd/{device_id}/{meas}")).unwrap()
+    }
+
+    fn get_default_retain() -> bool {
+        true
+    }
+}
+
+impl Mqtt {
+    pub fn new(config: MqttConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            published: Mutex::new(HashMap::new()),
+            connections: AtomicU64::new(0),
+        }
+    }
+
+    pub fn publish(&self, id: &str, meas: &str, records: &[DbRecord]) {
+        // Newest record of a batch, in the background. A record older than the one last published to its topic (e.g.
+        // re-read from the unit's memory) is not published.
+
+        let record = match records.iter().max_by_key(|record| record.get_ts()) {
+            Some(record) => record,
+            None => return,
+        };
+
+        let topic = self.config.topic.expand(|name| match (record.get_tag(name), name) {
+            (Some(value), _) => Some(String::from(value)),
+            (None, "meas") => Some(String::from(meas)),
+            (None, _) => Some(String::from("unknown")),
+        });
+
+        {
+            let mut published = self.published.lock().unwrap();
+
+            if published.get(&topic).is_some_and(|ts| *ts > record.get_ts()) {
+                return;
+            }
+
+            published.insert(topic.clone(), record.get_ts());
+        }
+
+        let payload = Hooks::get_json(meas, record).to_string();
+        let config = Arc::clone(&self.config);
+        let client_id = self.get_client_id();
+        let id = String::from(id);
+
+        tokio::spawn(async move {
+            let result = match time::timeout(TIMEOUT, Self::send(&config, &client_id, &topic, payload.as_bytes())).await {
+                Ok(result) => result,
+                Err(_) => Err(String::from("timed out")),
+            };
+
+            if let Err(e) = result {
+                eprintln!("{}: unable to publish to MQTT {}: {}", id, config.server, Redact::apply(&e));
+            }
+        });
+    }
+
+    fn get_client_id(&self) -> String {
+        format!("{}-{}-{}", self.config.client_id, process::id(), self.connections.fetch_add(1, Ordering::Relaxed))
+    }
+
+    async fn send(config: &MqttConfig, client_id: &str, topic: &str, payload: &[u8]) -> Result<(), String> {
+        let mut stream = TcpStream::connect(&config.server).await.map_err(|e| e.to_string())?;
+
+        stream.write_all(&Self::get_connect(config, client_id)).await.map_err(|e| e.to_string())?;
+
+        let mut connack = [0u8; 4];
+        stream.read_exact(&mut connack).await.map_err(|e| e.to_string())?;
+
+        match connack {
+            [CONNACK, 2, _, 0] => (),
+            [CONNACK, 2, _, 4 | 5] => return Err(String::from("not authorized")),
+            [CONNACK, 2, _, code] => return Err(format!("connection refused ({})", code)),
+            _ => return Err(String::from("invalid response")),
+        }
+
+        stream.write_all(&Self::get_publish(topic, payload, config.retain)).await.map_err(|e| e.to_string())?;
+        stream.write_all(&[DISCONNECT, 0]).await.map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+
+    fn get_connect(config: &MqttConfig, client_id: &str) -> Vec<u8> {
+        let mut flags = FLAG_CLEAN_SESSION;
+        let mut body = Vec::new();
+
+        Self::put_string(&mut body, "MQTT");
+        body.push(PROTOCOL_LEVEL);
+        body.push(0); // Flags, set below.
+        body.extend_from_slice(&KEEP_ALIVE.to_be_bytes());
+        Self::put_string(&mut body, client_id);
+
+        if let Some(user) = &config.user {
+            flags |= FLAG_USER;
+            Self::put_string(&mut body, user);
+
+            if let Some(secret) = &config.secret { // A password is only allowed with a user name.
+                flags |= FLAG_PASSWORD;
+                Self::put_string(&mut body, secret);
+            }
+        }
+
+        body[7] = flags;
+        Self::get_packet(CONNECT, &body)
+    }
+
+    fn get_publish(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+        let mut body = Vec::new();
+
+        Self::put_string(&mut body, topic);
+        body.extend_from_slice(payload);
+
+        Self::get_packet(if retain { PUBLISH | FLAG_RETAIN } else { PUBLISH }, &body)
+    }
+
+    fn get_packet(header: u8, body: &[u8]) -> Vec<u8> {
+        // Remaining length is 7 bits per byte, least significant first.
+
+        let mut packet = vec![header];
+        let mut len = body.len();
+
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+
+            if len > 0 {
+                packet.push(byte | 0x80);
+            } else {
+                packet.push(byte);
+                break;
+            }
+        }
+
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    fn put_string(buf: &mut Vec<u8>, s: &str) {
+        buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+        buf.extend_from_slice(s.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::{Mqtt, MqttConfig};
+
+    fn get_config(server: &str) -> MqttConfig {
+        MqttConfig {
+            server: String::from(server),
+            client_id: String::from("phd"),
+            user: Some(String::from("u")),
+            secret: Some(String::from("pw")),
+            topic: MqttConfig::get_default_topic(),
+            retain: true,
+        }
+    }
+
+    #[test]
+    fn packets() {
+        let config = get_config("localhost:1883");
+
+        assert_eq!(Mqtt::get_connect(&config, "phd"), b"\x10\x16\x00\x04MQTT\x04\xc2\x00\x1e\x00\x03phd\x00\x01u\x00\x02pw");
+        assert_eq!(Mqtt::get_publish("a/b", b"{}", true), b"\x31\x07\x00\x03a/b{}");
+        assert_eq!(Mqtt::get_publish("a/b", b"{}", false)[0], 0x30);
+
+        let packet = Mqtt::get_publish("t", &[0; 200], false);
+        assert_eq!(packet[1..3], [0xcb, 0x01]); // 203 bytes.
+    }
+
+    #[test]
+    fn client_id() {
+        let mqtt = Mqtt::new(get_config("localhost:1883"));
+        let client_id = mqtt.get_client_id();
+
+        assert!(client_id.starts_with("phd-"));
+        assert_ne!(client_id, mqtt.get_client_id()); // Overlapping connections don't take over each other's session.
+    }
+
+    #[tokio::test]
+    async fn send() {
+        // Fake broker, accepts one client and keeps what it sent.
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = get_config(&listener.local_addr().unwrap().to_string());
+
+        let broker = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut connect = [0; 24];
+            stream.read_exact(&mut connect).await.unwrap();
+            stream.write_all(&[0x20, 2, 0, 0]).await.unwrap();
+
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).await.unwrap();
+            rest
+        });
+
+        Mqtt::send(&config, "phd", "a/b", b"{}").await.unwrap();
+        assert_eq!(broker.await.unwrap(), b"\x31\x07\x00\x03a/b{}\xe0\x00");
+    }
+}
@@ -0,0 +1,67 @@
+//! # Field naming profiles
+//!
+//! Drivers name fields the same way (sys, dia, bpm, ...). A profile renames
+//! them when they are written to the DB, to the convention expected by the
+//! downstream system. Fields a profile doesn't know keep their name, so do
+//! the fields of phd's own measurements (stats, telemetry).
+
+use serde::Deserialize;
+
+const NAMES: &[(&str, &str, &str, &str)] = &[ // phd, openmhealth, omron, short ("" keeps the name).
+    ("bmi", "body_mass_index", "bmi", ""),
+    ("bpm", "heart_rate", "pulse", "hr"),
+    ("dia", "diastolic_blood_pressure", "diastolic", ""),
+    ("fat", "body_fat_percentage", "body_fat", "bf"),
+    ("glucose", "blood_glucose", "", "glu"),
+    ("height", "body_height", "", "ht"),
+    ("ihb", "", "irregular_heartbeat", ""),
+    ("mov", "", "body_movement", ""),
+    ("muscle", "", "skeletal_muscle", "sm"),
+    ("sys", "systolic_blood_pressure", "systolic", ""),
+    ("temp", "body_temperature", "temperature", "t"),
+    ("visceral_fat", "", "", "vf"),
+    ("weight", "body_weight", "", "wt"),
+];
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NamingProfile {
+    #[default]
+    Phd, // As written by the drivers.
+    Openmhealth, // Open mHealth schemas (body_weight, systolic_blood_pressure, ...).
+    Omron, // As in Omron's exports (systolic, pulse, body_fat, ...).
+    Short, // Abbreviations (hr, wt, bf, ...).
+}
+
+impl NamingProfile {
+    pub fn get_name<'a>(&self, field: &'a str) -> &'a str {
+        let names = match NAMES.iter().find(|(name, ..)| *name == field) {
+            Some(names) => names,
+            None => return field,
+        };
+
+        let name = match self {
+            NamingProfile::Phd => "",
+            NamingProfile::Openmhealth => names.1,
+            NamingProfile::Omron => names.2,
+            NamingProfile::Short => names.3,
+        };
+
+        if name.is_empty() { field } else { name }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NamingProfile;
+
+    #[test]
+    fn get_name() {
+        assert_eq!(NamingProfile::Phd.get_name("sys"), "sys");
+        assert_eq!(NamingProfile::Openmhealth.get_name("sys"), "systolic_blood_pressure");
+        assert_eq!(NamingProfile::Omron.get_name("bpm"), "pulse");
+        assert_eq!(NamingProfile::Short.get_name("weight"), "wt");
+        assert_eq!(NamingProfile::Short.get_name("sys"), "sys");
+        assert_eq!(NamingProfile::Openmhealth.get_name("bytes_read"), "bytes_read");
+    }
+}
@@ -0,0 +1,80 @@
+//! # OpenTelemetry trace export
+//!
+//! Fetch and upload flows are wrapped into spans, which are exported via
+//! OTLP/HTTP if configured. Without configuration, spans are no-ops.
+
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry::trace::{FutureExt, Status, TraceContextExt, Tracer};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::runtime::Tokio;
+use opentelemetry_sdk::trace::Config;
+use serde::Deserialize;
+use std::fmt::Display;
+use std::future::Future;
+
+const TRACER: &str = "phd";
+const SERVICE_NAME: &str = "phd";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OtelConfig {
+    endpoint: String, // OTLP/HTTP traces endpoint, e.g. http://localhost:4318/v1/traces
+    service_name: Option<String>,
+}
+
+pub struct Otel;
+
+impl Otel {
+    pub fn start(config: OtelConfig) -> Result<(), String> {
+        let service_name = config.service_name.unwrap_or_else(|| String::from(SERVICE_NAME));
+
+        let provider = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().http().with_endpoint(&config.endpoint))
+            .with_trace_config(Config::default().with_resource(Resource::new(vec![KeyValue::new("service.name", service_name)])))
+            .install_batch(Tokio)
+            .map_err(|e| format!("Unable to set up trace export to {}: {}", config.endpoint, e))?;
+
+        global::set_tracer_provider(provider);
+        Ok(())
+    }
+
+    pub fn stop() {
+        // Flush pending spans.
+
+        global::shutdown_tracer_provider();
+    }
+
+    pub async fn span<T, E, F>(name: &'static str, fut: F) -> Result<T, E> where E: Display, F: Future<Output = Result<T, E>> {
+        // Child of the span the caller is running in, if any.
+
+        let span = global::tracer(TRACER).start(name);
+        Self::run(Context::current_with_span(span), fut).await
+    }
+
+    pub async fn device_span<T, E, F>(name: &'static str, id: &str, fut: F) -> Result<T, E> where E: Display, F: Future<Output = Result<T, E>> {
+        // Root span of a flow, tagged with the device id.
+
+        let tracer = global::tracer(TRACER);
+        let span = tracer
+            .span_builder(name)
+            .with_attributes(vec![KeyValue::new("device_id", String::from(id))])
+            .start_with_context(&tracer, &Context::new());
+        Self::run(Context::new().with_span(span), fut).await
+    }
+
+    pub fn sync_span<T, F>(name: &'static str, f: F) -> T where F: FnOnce() -> T {
+        global::tracer(TRACER).in_span(name, |_| f())
+    }
+
+    async fn run<T, E, F>(cx: Context, fut: F) -> Result<T, E> where E: Display, F: Future<Output = Result<T, E>> {
+        let result = fut.with_context(cx.clone()).await;
+
+        if let Err(e) = &result {
+            cx.span().set_status(Status::error(e.to_string()));
+        }
+
+        result
+    }
+}
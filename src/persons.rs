@@ -0,0 +1,166 @@
+//! # Persons
+//!
+//! Persons are defined once, with the devices (and user slots on multi-user
+//! devices) they use. Records of a person get a person tag and derived
+//! fields: BMI if height is known and the record has a weight (in kg, before
+//! transforms), age at the time of the record if birth date is known.
+
+use chrono::{DateTime, NaiveDate};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use crate::db::{DbFieldValue, DbRecord};
+use crate::driver::STATUS_MEAS;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PersonConfig {
+    name: String,
+    height: Option<f64>, // [cm]
+    #[serde(default, deserialize_with = "crate::timeutil::TimeUtil::parse_optional_date")]
+    birth_date: Option<NaiveDate>,
+    devices: Vec<PersonDeviceConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PersonDeviceConfig {
+    device: String, // Device id.
+    user: Option<String>, // Value of user tag, unset if the device is not multi-user.
+}
+
+#[derive(Default)]
+pub struct Persons {
+    persons: Vec<PersonConfig>,
+}
+
+pub type PersonsPtr = Arc<Persons>;
+
+pub const GUEST: &str = "guest"; // Person tag of records of unknown users, if so configured.
+
+impl Persons {
+    pub fn new(persons: Vec<PersonConfig>) -> Result<Self, String> {
+        // Each device/user slot can belong to one person only.
+
+        let slots: Vec<_> = persons.iter().flat_map(|person| person.devices.iter().map(move |device| (person, device))).collect();
+
+        for (index, (person, device)) in slots.iter().enumerate() {
+            for (other, other_device) in &slots[..index] {
+                if other_device.device == device.device && (other_device.user.is_none() || device.user.is_none() || other_device.user == device.user) {
+                    return Err(format!("Device {} is assigned to both {} and {}", device.device, other.name, person.name));
+                }
+            }
+        }
+
+        Ok(Self {
+            persons,
+        })
+    }
+
+    pub fn get_device_ids(&self) -> impl Iterator<Item = &str> {
+        self.persons.iter().flat_map(|person| person.devices.iter().map(|device| device.device.as_str()))
+    }
+
+    pub fn apply(&self, id: &str, record: &mut DbRecord) {
+        if let Some(person) = self.find(id, record.get_tag("user")) {
+            Self::apply_person(person, record);
+        }
+    }
+
+    pub fn assign(&self, name: &str, record: &mut DbRecord) -> Result<(), String> {
+        // Held record assigned to a person by hand.
+
+        let person = self.persons.iter().find(|person| person.name == name).ok_or_else(|| format!("Unknown person {}", name))?;
+        Self::apply_person(person, record);
+
+        Ok(())
+    }
+
+    pub fn has_person(&self, name: &str) -> bool {
+        self.persons.iter().any(|person| person.name == name)
+    }
+
+    fn apply_person(person: &PersonConfig, record: &mut DbRecord) {
+        record.add_tag("person", &person.name);
+
+        if let Some(height) = person.height {
+            let weight = match record.get_field("weight") {
+                Some(DbFieldValue::Float(weight)) => Some(*weight),
+                Some(DbFieldValue::Integer(weight)) => Some(*weight as f64),
+                _ => None,
+            };
+
+            if let Some(weight) = weight {
+                let height = height / 100.0;
+                record.add_field("bmi", DbFieldValue::Float(weight / (height * height)));
+            }
+        }
+
+        if let Some(birth_date) = person.birth_date {
+            if let Some(age) = DateTime::from_timestamp_nanos(record.get_ts()).date_naive().years_since(birth_date) {
+                record.add_field("age", DbFieldValue::Integer(age.into()));
+            }
+        }
+    }
+
+    pub fn is_unassigned(&self, id: &str, record: &DbRecord) -> bool {
+        // Record of a device shared by user slot, taken by none of the persons (e.g. a visitor, or not recognized by the unit).
+        // Device status records are not taken by anybody.
+
+        if record.get_meas() == Some(STATUS_MEAS) {
+            return false;
+        }
+
+        let mut slots = self.persons.iter().flat_map(|person| person.devices.iter()).filter(|device| device.device == id).peekable();
+        slots.peek().is_some() && slots.all(|device| device.user.is_some()) && self.find(id, record.get_tag("user")).is_none()
+    }
+
+    fn find(&self, id: &str, user: Option<&str>) -> Option<&PersonConfig> {
+        self.persons.iter().find(|person| person.devices.iter().any(|device| device.device == id && (device.user.is_none() || device.user.as_deref() == user)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::DbRecord;
+    use crate::driver::STATUS_MEAS;
+    use super::{PersonConfig, PersonDeviceConfig, Persons};
+
+    fn person(name: &str, devices: &[(&str, Option<&str>)]) -> PersonConfig {
+        PersonConfig {
+            name: String::from(name),
+            height: None,
+            birth_date: None,
+            devices: devices.iter().map(|(device, user)| PersonDeviceConfig {
+                device: String::from(*device),
+                user: user.map(String::from),
+            }).collect(),
+        }
+    }
+
+    #[test]
+    fn is_unassigned() {
+        let persons = Persons::new(vec![
+            person("alice", &[("scale", Some("1")), ("bpm", None)]),
+            person("bob", &[("scale", Some("2"))]),
+        ]).unwrap();
+
+        let record = |user: Option<&str>| {
+            let mut record = DbRecord::new(0);
+            if let Some(user) = user {
+                record.add_tag("user", user);
+            }
+            record
+        };
+
+        assert!(!persons.is_unassigned("scale", &record(Some("2"))));
+        assert!(persons.is_unassigned("scale", &record(Some("3")))); // Slot of nobody.
+        assert!(persons.is_unassigned("scale", &record(None))); // Not recognized by the unit.
+        assert!(!persons.is_unassigned("bpm", &record(None))); // Not shared.
+        assert!(!persons.is_unassigned("thermo", &record(None))); // Nobody's.
+
+        let mut status = record(None);
+        status.set_meas(STATUS_MEAS);
+        assert!(!persons.is_unassigned("scale", &status)); // Battery level, not a user slot measurement.
+    }
+}
@@ -0,0 +1,176 @@
+//! # Shared passive scanner
+//!
+//! BlueZ has a limited number of advertisement monitor slots, so instead of
+//! registering a monitor per device, a single monitor is registered with the
+//! patterns of all devices and matched advertisements are dispatched to the
+//! waiting device tasks by address. Waiters give one or more patterns, the
+//! parts BlueZ can't match (masked bits, services not listed first) are
+//! checked against the device's advertisement data before dispatching. The
+//! advertisement data is handed to the waiters, for units which broadcast
+//! their readings.
+
+use bluer::{Adapter, Address, Session};
+use bluer::monitor::{Monitor, MonitorEvent, Pattern, RssiSamplingPeriod, Type};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{oneshot, watch};
+use tokio::time::{self, Duration};
+
+use crate::btutil::{self, AdvData, AdvPattern};
+use crate::redact::Redact;
+
+const WAIT: u64 = 3; // [s]
+
+pub struct Scanner {
+    waiters: Mutex<HashMap<Address, Vec<Waiter>>>,
+    patterns: watch::Sender<Vec<Pattern>>,
+}
+
+pub type ScannerPtr = Arc<Scanner>;
+
+struct Waiter {
+    patterns: Vec<AdvPattern>,
+    tx: oneshot::Sender<AdvData>,
+}
+
+impl Scanner {
+    pub fn start() -> ScannerPtr {
+        // The monitor is only registered once the first device asks for it.
+
+        let scanner = ScannerPtr::new(Self {
+            waiters: Mutex::new(HashMap::new()),
+            patterns: watch::Sender::new(Vec::new()),
+        });
+
+        tokio::spawn(Self::run(ScannerPtr::clone(&scanner)));
+        scanner
+    }
+
+    pub async fn wait_for_adv(&self, addr: Address, patterns: &[AdvPattern]) -> btutil::Result<AdvData> {
+        let (tx, rx) = oneshot::channel();
+
+        {
+            let mut waiters = self.waiters.lock().unwrap();
+            let list = waiters.entry(addr).or_default();
+
+            list.retain(|waiter| !waiter.tx.is_closed()); // Drop the ones given up on (e.g. timed out).
+            list.push(Waiter {
+                patterns: patterns.to_vec(),
+                tx,
+            });
+        }
+
+        self.patterns.send_if_modified(|monitor_patterns| {
+            let mut modified = false;
+
+            for pattern in patterns.iter().flat_map(AdvPattern::get_monitor_patterns) {
+                if !monitor_patterns.contains(&pattern) {
+                    monitor_patterns.push(pattern);
+                    modified = true; // Monitor needs to be re-registered.
+                }
+            }
+
+            modified
+        });
+
+        rx.await.map_err(|_| "Failed to receive advertisements".into())
+    }
+
+    pub fn rearm(&self) {
+        // Re-register the monitor with the same patterns, in case its handle died silently.
+
+        self.patterns.send_modify(|_| ());
+    }
+
+    async fn run(scanner: ScannerPtr) {
+        let mut patterns_rx = scanner.patterns.subscribe();
+
+        loop {
+            let patterns = patterns_rx.borrow_and_update().clone();
+
+            let result = if patterns.is_empty() {
+                Ok(())
+            } else {
+                scanner.monitor(patterns, &mut patterns_rx).await
+            };
+
+            match result {
+                Ok(()) => {
+                    if patterns_rx.changed().await.is_err() { // Scanner is gone.
+                        return;
+                    }
+                },
+                Err(e) => {
+                    eprintln!("scanner: {}", Redact::apply(&e.to_string()));
+                    time::sleep(Duration::from_secs(WAIT)).await;
+                }
+            }
+        }
+    }
+
+    async fn monitor(&self, patterns: Vec<Pattern>, patterns_rx: &mut watch::Receiver<Vec<Pattern>>) -> btutil::Result<()> {
+        // Returns Ok(()) when the set of patterns has changed.
+
+        let session = Session::new().await?;
+        let adapter = session.default_adapter().await?;
+        let mon_mgr = adapter.monitor().await?;
+
+        let mon = Monitor {
+            monitor_type: Type::OrPatterns,
+            rssi_low_threshold: None,
+            rssi_high_threshold: None,
+            rssi_low_timeout: None,
+            rssi_high_timeout: None,
+            rssi_sampling_period: Some(RssiSamplingPeriod::All),
+            patterns: Some(patterns),
+            ..Default::default()
+        };
+        let mut mon_handle = mon_mgr.register(mon).await?;
+
+        loop {
+            tokio::select! {
+                ev = mon_handle.next() => match ev {
+                    Some(MonitorEvent::DeviceFound(device_id)) => self.dispatch(&adapter, device_id.device).await,
+                    Some(_) => (),
+                    None => return Err("Failed to receive advertisements".into()),
+                },
+                _ = patterns_rx.changed() => return Ok(()),
+            }
+        }
+    }
+
+    async fn dispatch(&self, adapter: &Adapter, addr: Address) {
+        if !self.waiters.lock().unwrap().contains_key(&addr) {
+            return;
+        }
+
+        // Without the device's advertisement data, BlueZ's match on the monitor pattern has to do.
+
+        let adv_data = match adapter.device(addr) {
+            Ok(device) => Some(AdvData {
+                manufacturer_data: device.manufacturer_data().await.ok().flatten().unwrap_or_default(),
+                services: device.uuids().await.ok().flatten().unwrap_or_default(),
+                service_data: device.service_data().await.ok().flatten().unwrap_or_default(),
+            }),
+            Err(_) => None,
+        };
+
+        let mut waiters = self.waiters.lock().unwrap();
+
+        if let Some(list) = waiters.remove(&addr) {
+            let (matched, rest): (Vec<Waiter>, Vec<Waiter>) = list.into_iter().partition(|waiter| match &adv_data {
+                Some(adv_data) => waiter.patterns.iter().any(|pattern| pattern.is_match(adv_data)),
+                None => true,
+            });
+
+            for waiter in matched {
+                let _ = waiter.tx.send(adv_data.clone().unwrap_or_default()); // Waiter might have given up already.
+            }
+
+            if !rest.is_empty() {
+                waiters.insert(addr, rest);
+            }
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! # Secrets
+//!
+//! Sensitive values (device secrets, DB token) can be kept in a separate file,
+//! which is a flat map of names to values. The main config refers to them as
+//! `secret:<name>`. Like SSH does with private keys, the secrets file is
+//! refused if it is accessible by group or others.
+//!
+//! Values (both in the main config and in the secrets file) can also be
+//! encrypted with age, in ASCII armored form. They are decrypted at load time
+//! with the identities from the age identity file.
+
+use age::{Decryptor, Identity, IdentityFile};
+use age::armor::ArmoredReader;
+use config::{Config, File, FileFormat, Map, Value, ValueKind};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+const REF_PREFIX: &str = "secret:";
+const AGE_PREFIX: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+const AGE_IDENTITY_ENV: &str = "PHD_AGE_IDENTITY"; // Used if age_identity is not configured.
+
+pub struct Secrets {
+    values: Map<String, Value>,
+    identities: Vec<Box<dyn Identity>>,
+}
+
+impl Secrets {
+    pub fn load(secrets_fname: Option<PathBuf>, identity_fname: Option<PathBuf>) -> Result<Self, String> {
+        let values = match secrets_fname {
+            Some(secrets_fname) => {
+                Self::check_permissions(&secrets_fname)?;
+
+                Config::builder()
+                    .add_source(File::from(secrets_fname.as_path()).format(FileFormat::Yaml))
+                    .build()
+                    .and_then(|config| config.try_deserialize())
+                    .map_err(|e| format!("Unable to parse secrets {}: {}", secrets_fname.display(), e))?
+            },
+            None => Map::new(),
+        };
+
+        let identities = match identity_fname.or_else(|| env::var_os(AGE_IDENTITY_ENV).map(PathBuf::from)) {
+            Some(identity_fname) => {
+                Self::check_permissions(&identity_fname)?;
+
+                IdentityFile::from_file(identity_fname.to_string_lossy().into_owned())
+                    .map_err(|e| format!("Unable to read age identity {}: {}", identity_fname.display(), e))?
+                    .into_identities()
+                    .map_err(|e| format!("Unable to parse age identity {}: {}", identity_fname.display(), e))?
+            },
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            values,
+            identities,
+        })
+    }
+
+    pub fn get_fingerprint(secret: &[u8]) -> String {
+        // Identifies a secret without revealing it, e.g. to tell if it was changed since pairing.
+
+        hex::encode(&Sha256::digest(secret)[..8])
+    }
+
+    pub fn resolve(&self, value: &mut Value) -> Result<(), String> {
+        // Replace references and decrypt values recursively.
+
+        match &mut value.kind {
+            ValueKind::String(s) => {
+                if let Some(name) = s.strip_prefix(REF_PREFIX) {
+                    *value = self.values.get(name).ok_or(format!("Unknown secret: {}", name))?.clone();
+                }
+
+                if let ValueKind::String(s) = &value.kind {
+                    if s.starts_with(AGE_PREFIX) {
+                        *value = Value::new(None, self.decrypt(s)?);
+                    }
+                }
+            },
+            ValueKind::Table(table) => {
+                for value in table.values_mut() {
+                    self.resolve(value)?;
+                }
+            },
+            ValueKind::Array(array) => {
+                for value in array.iter_mut() {
+                    self.resolve(value)?;
+                }
+            },
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    fn decrypt(&self, ciphertext: &str) -> Result<String, String> {
+        if self.identities.is_empty() {
+            return Err(String::from("Encrypted value found, but no age identity is configured"));
+        }
+
+        let decryptor = Decryptor::new_buffered(ArmoredReader::new(ciphertext.as_bytes())).map_err(|e| format!("Unable to decrypt value: {}", e))?;
+        let mut reader = decryptor.decrypt(self.identities.iter().map(|identity| identity.as_ref())).map_err(|e| format!("Unable to decrypt value: {}", e))?;
+
+        let mut plaintext = String::new();
+        reader.read_to_string(&mut plaintext).map_err(|e| format!("Unable to decrypt value: {}", e))?;
+
+        Ok(String::from(plaintext.trim_end_matches(['\r', '\n']))) // E.g. "echo secret | age ..." adds a newline.
+    }
+
+    fn check_permissions(path: &Path) -> Result<(), String> {
+        let metadata = fs::metadata(path).map_err(|e| format!("Unable to open {}: {}", path.display(), e))?;
+
+        if metadata.permissions().mode() & 0o077 != 0 {
+            return Err(format!("{} is accessible by group/others, please restrict permissions (e.g. chmod 600)", path.display()));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+//! # Self-test
+//!
+//! `phd --selftest` checks what phd depends on (configuration, BlueZ and its
+//! advertisement monitor API, the adapter, the state file and the DB) and
+//! prints a line per check, so it's the first thing to attach to a support
+//! issue. Nothing is written to the DB, the units are not contacted.
+
+use crate::redact::Redact;
+
+pub enum Outcome {
+    Ok(String),
+    Warn(String), // Works, but might cause trouble.
+    Fail(String),
+    Skipped(String), // Depends on a failed check.
+}
+
+#[derive(Default)]
+pub struct SelfTest {
+    failures: usize,
+}
+
+impl SelfTest {
+    pub fn report(&mut self, check: &str, outcome: Outcome) {
+        let (label, detail) = match outcome {
+            Outcome::Ok(detail) => ("ok", detail),
+            Outcome::Warn(detail) => ("warn", detail),
+            Outcome::Fail(detail) => {
+                self.failures += 1;
+                ("FAIL", detail)
+            },
+            Outcome::Skipped(detail) => ("skip", detail),
+        };
+
+        println!("{:<4} {}: {}", label, check, Redact::apply(&detail));
+    }
+
+    pub fn check<T>(&mut self, check: &str, result: Result<(T, String), String>) -> Option<T> {
+        // Reports the result, returns the value for dependent checks.
+
+        match result {
+            Ok((value, detail)) => {
+                self.report(check, Outcome::Ok(detail));
+                Some(value)
+            },
+            Err(e) => {
+                self.report(check, Outcome::Fail(e));
+                None
+            },
+        }
+    }
+
+    pub fn finish(&self) -> bool {
+        if self.failures == 0 {
+            println!("all checks passed");
+        } else {
+            println!("{} check(s) failed", self.failures);
+        }
+
+        self.failures == 0
+    }
+}
@@ -0,0 +1,149 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::redact::Redact;
+use crate::store::{DeviceStats, RecentRecord};
+use crate::timeutil::TimeUtil;
+
+#[derive(Clone, Default, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum DeviceState {
+    #[default]
+    Starting,
+    WaitingForAdvertisement,
+    Connecting,
+    Fetching,
+    Streaming, // Connected, records are uploaded as they arrive.
+    Uploading,
+    Sleeping,
+    Error { reason: String },
+}
+
+impl DeviceState {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            DeviceState::Starting => "starting",
+            DeviceState::WaitingForAdvertisement => "waiting_for_advertisement",
+            DeviceState::Connecting => "connecting",
+            DeviceState::Fetching => "fetching",
+            DeviceState::Streaming => "streaming",
+            DeviceState::Uploading => "uploading",
+            DeviceState::Sleeping => "sleeping",
+            DeviceState::Error { .. } => "error",
+        }
+    }
+}
+
+impl fmt::Display for DeviceState {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            DeviceState::Starting => String::from("starting"),
+            DeviceState::WaitingForAdvertisement => String::from("waiting for advertisement"),
+            DeviceState::Connecting => String::from("connecting"),
+            DeviceState::Fetching => String::from("fetching"),
+            DeviceState::Streaming => String::from("streaming"),
+            DeviceState::Uploading => String::from("uploading"),
+            DeviceState::Sleeping => String::from("sleeping"),
+            DeviceState::Error { reason } => format!("error: {}", reason),
+        };
+        formatter.write_str(&s)
+    }
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct PairingStatus {
+    pub paired: Option<bool>, // Whether the unit is bonded with this adapter, None if unknown.
+    pub paired_at: Option<i64>, // Timestamp of last successful pairing by phd [s]
+    pub adapter: Option<String>, // Address of the adapter the unit was paired on by phd.
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct DeviceStatus {
+    #[serde(flatten)]
+    pub state: DeviceState,
+    pub since: i64, // Timestamp of last state change [s]
+    pub last_adv: Option<i64>, // Timestamp of last advertisement seen [s]
+    pub stats: DeviceStats,
+    pub pairing: PairingStatus,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub recent: Vec<RecentRecord>, // Last records written, oldest first.
+}
+
+#[derive(Default)]
+pub struct Status {
+    devices: Mutex<HashMap<String, DeviceStatus>>,
+}
+
+pub type StatusPtr = Arc<Status>;
+
+impl Status {
+    pub fn set_state(&self, id: &str, state: DeviceState) {
+        let state = match state {
+            DeviceState::Error { reason } => DeviceState::Error { reason: Redact::apply(&reason) }, // Also exposed via API.
+            state => state,
+        };
+
+        match state {
+            DeviceState::Error { .. } => eprintln!("{}: {}", id, state),
+            _ => println!("{}: {}", id, state),
+        }
+
+        let mut devices = self.devices.lock().unwrap();
+        let status = devices.entry(String::from(id)).or_default();
+        status.state = state;
+        status.since = TimeUtil::get_current_unix();
+    }
+
+    pub fn set_last_adv(&self, id: &str, last_adv: Option<i64>) {
+        let mut devices = self.devices.lock().unwrap();
+        devices.entry(String::from(id)).or_default().last_adv = last_adv;
+    }
+
+    pub fn set_stats(&self, id: &str, stats: DeviceStats) {
+        let mut devices = self.devices.lock().unwrap();
+        devices.entry(String::from(id)).or_default().stats = stats;
+    }
+
+    pub fn set_pairing(&self, id: &str, pairing: PairingStatus) {
+        let pairing = PairingStatus {
+            adapter: pairing.adapter.map(|adapter| Redact::apply(&adapter)), // Also exposed via API.
+            ..pairing
+        };
+
+        let mut devices = self.devices.lock().unwrap();
+        devices.entry(String::from(id)).or_default().pairing = pairing;
+    }
+
+    pub fn set_recent(&self, id: &str, recent: Vec<RecentRecord>) {
+        let mut devices = self.devices.lock().unwrap();
+        devices.entry(String::from(id)).or_default().recent = recent;
+    }
+
+    pub fn add_recent(&self, id: &str, records: Vec<RecentRecord>, count: usize) -> Vec<RecentRecord> {
+        // Keeps the newest records (by timestamp), returns them.
+
+        let mut devices = self.devices.lock().unwrap();
+        let recent = &mut devices.entry(String::from(id)).or_default().recent;
+
+        recent.retain(|old| !records.iter().any(|record| record.is_same(old)));
+        recent.extend(records);
+        recent.sort_by_key(|record| record.ts);
+        recent.drain(..recent.len().saturating_sub(count));
+
+        recent.clone()
+    }
+
+    pub fn get_recent(&self, id: &str) -> Option<Vec<RecentRecord>> { // None for unknown devices.
+        self.devices.lock().unwrap().get(id).map(|status| status.recent.clone())
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.devices.lock().unwrap().remove(id);
+    }
+
+    pub fn get_devices(&self) -> BTreeMap<String, DeviceStatus> { // Sorted by device id.
+        self.devices.lock().unwrap().iter().map(|(id, status)| (id.clone(), status.clone())).collect()
+    }
+}
@@ -0,0 +1,899 @@
+//! # Persistent state store
+//!
+//! Keeps per-device state (statistics, pairing metadata, backfill cutoffs,
+//! recent records), the field types sent to the DB and the records held for
+//! manual assignment (or parked after being refused) in an SQLite database,
+//! so it survives restarts. Without configuration the state is kept in memory
+//! only. Held records are not cached, so the CLI can assign them while the
+//! daemon is running. Each change is a transaction, so a crash never leaves a half-written
+//! state behind. The schema is versioned (user_version): migrations are
+//! applied in order at startup, a state written by a newer phd is refused
+//! instead of being reset. A JSON state of earlier versions is converted
+//! once, the original is kept next to it. Held records are purged when new
+//! ones are added and at startup: resolved ones after a year, parked ones
+//! after 90 days (by default). The table is capped (see HeldConfig): by
+//! default the oldest resolved and parked rows go, with the block policy
+//! only resolved ones and new rows are refused once full, so they stay on
+//! the unit. Crossing the high-water mark is reported once, by the caller of
+//! hold(). The database file is compacted at startup if much of it is free,
+//! the write-ahead log is truncated after checkpoints.
+
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::db::{DbFieldType, DbFieldValue, DbRecord};
+use crate::timeutil::TimeUtil;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StoreConfig {
+    path: PathBuf,
+    #[serde(default)]
+    held: HeldConfig,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeldConfig { // Rows of the held table, which grows with parked records e.g. while the DB refuses them.
+    #[serde(default = "HeldConfig::get_default_max")]
+    max: usize,
+    high_water: Option<usize>, // Alert at this many rows, 3/4 of max if unset.
+    #[serde(default)]
+    overflow: HeldOverflow,
+    #[serde(default = "HeldConfig::get_default_keep_resolved")]
+    keep_resolved: u32, // [days] Uploaded and expired rows, so they aren't held again when re-read from the unit.
+    #[serde(default = "HeldConfig::get_default_keep_parked")]
+    keep_parked: u32, // [days] Parked records not written by then are given up on.
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum HeldOverflow { // When the table is full.
+    #[default]
+    DropOldest, // Oldest resolved and parked rows are purged.
+    Block, // Only resolved rows are purged, new rows are refused.
+}
+
+#[derive(Default)]
+pub struct HoldResult {
+    pub refused: usize, // Not stored, the table is full (block policy) or couldn't be written.
+    pub high_water: Option<usize>, // Number of rows, if the high-water mark was crossed.
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceEntry {
+    pub last_adv: Option<i64>, // Timestamp of last advertisement seen [s]
+    pub stats: DeviceStats,
+    pub paired_at: Option<i64>, // Timestamp of last successful pairing [s]
+    pub paired_adapter: Option<String>, // Address of the adapter the unit was paired on.
+    pub paired_secret: Option<String>, // Fingerprint of the secret written during pairing.
+    pub firmware: Option<String>, // Firmware version last reported by the unit.
+    pub backfill_cutoff: Option<i64>, // Records older than this are ignored [ns]
+    pub trends: BTreeMap<String, TrendState>, // Last smoothed value per series.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub recent: Vec<RecentRecord>, // Last records written, only if persisting them is configured.
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TrendState {
+    pub ts: i64, // Timestamp of last record smoothed [ns]
+    pub value: f64,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecentRecord {
+    #[serde(default)]
+    pub id: Option<String>, // See DbRecord::set_ids().
+    pub ts: i64, // [ns]
+    pub meas: String,
+    pub tags: BTreeMap<String, String>,
+    pub fields: BTreeMap<String, Value>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HeldRecord { // Record of an unknown user, not uploaded until assigned to a person.
+    pub device: String,
+    #[serde(default)]
+    pub state: HeldState,
+    #[serde(default)]
+    pub person: Option<String>, // Assigned to.
+    #[serde(default)]
+    pub reason: Option<String>, // Why it was refused.
+    pub held_at: i64, // [s]
+    pub ts: i64, // [ns]
+    pub meas: Option<String>, // Set by the driver.
+    pub tags: BTreeMap<String, String>,
+    pub fields: BTreeMap<String, Value>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeldState { // Resolved records are kept for a while, so they aren't held again when re-read from the unit.
+    #[default]
+    Pending,
+    Assigned, // Waiting to be uploaded by the device task.
+    Uploaded,
+    Expired, // Not assigned in time.
+    Refused, // Refused by the schema check or the DB (not necessarily of an unknown user), retried at the next start.
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceStats {
+    pub records: u64, // Total number of records fetched.
+    pub bytes_read: u64, // Total number of bytes received from the unit.
+    pub failures: u32, // Number of consecutive failed fetches.
+    pub last_error: Option<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct StoreData {
+    devices: BTreeMap<String, DeviceEntry>,
+    schemas: BTreeMap<String, BTreeMap<String, DbFieldType>>, // Field types per measurement, as sent to the DB.
+}
+
+pub struct Store {
+    path: Option<PathBuf>,
+    held_config: HeldConfig,
+    held_alerted: AtomicBool, // Above the high-water mark, reported once per crossing.
+    inner: Mutex<StoreInner>,
+}
+
+struct StoreInner {
+    data: StoreData, // Cached, the database is only read at startup.
+    conn: Connection, // In memory, if the state is not configured.
+}
+
+pub type StorePtr = Arc<Store>;
+
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+const MEMORY: &str = ":memory:";
+const WAL_SIZE_LIMIT: i64 = 1024 * 1024; // [bytes] The write-ahead log is truncated to this after checkpoints.
+const VACUUM_FREE: u64 = 4; // Compacted at startup if more than 1/VACUUM_FREE of the pages is free, e.g. after purging.
+
+// Schema migrations, the version of a state is the number of migrations applied. Only ever append.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE devices (id TEXT PRIMARY KEY, entry TEXT NOT NULL); -- DeviceEntry as JSON.
+     CREATE TABLE schemas (meas TEXT NOT NULL, field TEXT NOT NULL, type TEXT NOT NULL, PRIMARY KEY (meas, field));",
+    "CREATE TABLE held (id INTEGER PRIMARY KEY AUTOINCREMENT, record TEXT NOT NULL); -- HeldRecord as JSON.",
+];
+
+impl RecentRecord {
+    pub fn new(meas: &str, record: &DbRecord) -> Self {
+        Self {
+            id: record.get_id().map(String::from),
+            ts: record.get_ts(),
+            meas: String::from(meas),
+            tags: record.get_tags().map(|(key, value)| (String::from(key), String::from(value))).collect(),
+            fields: record.get_fields().map(|(key, value)| (String::from(key), Self::to_json(value))).collect(),
+        }
+    }
+
+    fn to_json(value: &DbFieldValue) -> Value {
+        match value {
+            DbFieldValue::Float(value) => Value::from(*value), // Non-finite values become null.
+            DbFieldValue::Integer(value) => Value::from(*value),
+            DbFieldValue::Bool(value) => Value::from(*value),
+            DbFieldValue::String(value) => Value::from(value.as_str()),
+        }
+    }
+
+    pub fn is_same(&self, other: &RecentRecord) -> bool { // Same point, e.g. re-read from the unit.
+        self.ts == other.ts && self.meas == other.meas && self.tags == other.tags
+    }
+}
+
+impl HeldState {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            HeldState::Pending => "pending",
+            HeldState::Assigned => "assigned",
+            HeldState::Uploaded => "uploaded",
+            HeldState::Expired => "expired",
+            HeldState::Refused => "refused",
+        }
+    }
+}
+
+impl HeldRecord {
+    pub fn new(device: &str, record: &DbRecord) -> Self {
+        Self {
+            device: String::from(device),
+            state: HeldState::Pending,
+            person: None,
+            reason: None,
+            held_at: TimeUtil::get_current_unix(),
+            ts: record.get_ts(),
+            meas: record.get_meas().map(String::from),
+            tags: record.get_tags().map(|(key, value)| (String::from(key), String::from(value))).collect(),
+            fields: record.get_fields().map(|(key, value)| (String::from(key), RecentRecord::to_json(value))).collect(),
+        }
+    }
+
+    pub fn is_same(&self, other: &HeldRecord) -> bool {
+        self.device == other.device && self.ts == other.ts && self.meas == other.meas && self.tags == other.tags
+    }
+
+    pub fn to_record(&self) -> DbRecord {
+        // Integers are told apart from floats by their JSON representation, nulls are dropped.
+
+        let mut record = DbRecord::new(self.ts);
+
+        if let Some(meas) = &self.meas {
+            record.set_meas(meas);
+        }
+
+        for (key, value) in &self.tags {
+            record.add_tag(key, value);
+        }
+
+        for (key, value) in &self.fields {
+            let value = match value {
+                Value::Number(value) => match value.as_i64() {
+                    Some(value) => DbFieldValue::Integer(value),
+                    None => DbFieldValue::Float(value.as_f64().unwrap_or_default()),
+                },
+                Value::Bool(value) => DbFieldValue::Bool(*value),
+                Value::String(value) => DbFieldValue::String(value.clone()),
+                _ => continue,
+            };
+
+            record.add_field(key, value);
+        }
+
+        record
+    }
+}
+
+impl HeldConfig {
+    fn get_default_max() -> usize {
+        10000
+    }
+
+    fn get_default_keep_resolved() -> u32 {
+        365
+    }
+
+    fn get_default_keep_parked() -> u32 {
+        90
+    }
+
+    fn get_high_water(&self) -> usize {
+        self.high_water.unwrap_or(self.max * 3 / 4)
+    }
+}
+
+impl Default for HeldConfig {
+    fn default() -> Self {
+        Self {
+            max: Self::get_default_max(),
+            high_water: None,
+            overflow: HeldOverflow::default(),
+            keep_resolved: Self::get_default_keep_resolved(),
+            keep_parked: Self::get_default_keep_parked(),
+        }
+    }
+}
+
+impl Store {
+    pub fn open(config: Option<StoreConfig>) -> Result<Self, String> {
+        let (path, held_config) = match config {
+            Some(config) => (config.path, config.held),
+            None => {
+                let mut conn = Connection::open_in_memory().map_err(|e| format!("Unable to open state: {}", e))?;
+                Self::migrate(Path::new(MEMORY), &mut conn)?;
+
+                return Ok(Self {
+                    path: None,
+                    held_config: HeldConfig::default(),
+                    held_alerted: AtomicBool::new(false),
+                    inner: Mutex::new(StoreInner {
+                        data: StoreData::default(),
+                        conn,
+                    }),
+                });
+            },
+        };
+
+        let err = |e: rusqlite::Error| format!("Unable to open state {}: {}", path.display(), e);
+
+        match fs::read(&path) {
+            Ok(buf) if !buf.is_empty() && !buf.starts_with(SQLITE_MAGIC) => Self::convert_json(&path, &buf)?,
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::NotFound => (), // First start.
+            Err(e) => return Err(format!("Unable to read state {}: {}", path.display(), e)),
+        }
+
+        let mut conn = Connection::open(&path).map_err(err)?;
+        conn.pragma_update(None, "journal_mode", "WAL").map_err(err)?;
+        conn.pragma_update(None, "synchronous", "FULL").map_err(err)?; // Commits survive a power loss too.
+        conn.pragma_update(None, "journal_size_limit", WAL_SIZE_LIMIT).map_err(err)?;
+        Self::migrate(&path, &mut conn)?;
+        let data = Self::load(&conn).map_err(|e| format!("Unable to read state {}: {}", path.display(), e))?;
+
+        let store = Self {
+            path: Some(path),
+            held_config,
+            held_alerted: AtomicBool::new(false),
+            inner: Mutex::new(StoreInner {
+                data,
+                conn,
+            }),
+        };
+
+        store.hold(Vec::new()); // Purge only, e.g. devices which don't hold records anymore or a lowered maximum.
+        store.held_alerted.store(false, Ordering::Relaxed); // Reported by the first device adding rows.
+        store.compact();
+
+        Ok(store)
+    }
+
+    fn compact(&self) {
+        // SQLite keeps the pages of deleted rows, give them back once many are free (e.g. after a long DB outage).
+
+        let inner = self.inner.lock().unwrap();
+        let conn = &inner.conn;
+
+        let pages: rusqlite::Result<(u64, u64)> = conn.pragma_query_value(None, "page_count", |row| row.get(0))
+            .and_then(|count| Ok((count, conn.pragma_query_value(None, "freelist_count", |row| row.get(0))?)));
+
+        let result = match pages {
+            Ok((count, free)) if free > 0 && free * VACUUM_FREE > count => conn.execute_batch("VACUUM"),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            eprintln!("{}", self.get_write_error(e)); // Not fatal, only takes more space.
+        }
+    }
+
+    fn migrate(path: &Path, conn: &mut Connection) -> Result<(), String> {
+        // Each migration is applied in a transaction along with the version bump, an interrupted one is retried at the
+        // next start.
+
+        let err = |e: rusqlite::Error| format!("Unable to migrate state {}: {}", path.display(), e);
+
+        let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0)).map_err(err)?;
+
+        if version > MIGRATIONS.len() {
+            return Err(format!("State {} has schema version {}, this phd only knows up to {} (was it downgraded?)", path.display(), version, MIGRATIONS.len()));
+        }
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction().map_err(err)?;
+            tx.execute_batch(migration).map_err(err)?;
+            tx.pragma_update(None, "user_version", i + 1).map_err(err)?;
+            tx.commit().map_err(err)?;
+        }
+
+        Ok(())
+    }
+
+    fn load(conn: &Connection) -> rusqlite::Result<StoreData> {
+        let mut data = StoreData::default();
+        let json_err = |e: serde_json::Error| rusqlite::Error::ToSqlConversionFailure(Box::new(e));
+
+        let mut stmt = conn.prepare("SELECT id, entry FROM devices")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let entry: String = row.get(1)?;
+            data.devices.insert(row.get(0)?, serde_json::from_str(&entry).map_err(json_err)?);
+        }
+
+        let mut stmt = conn.prepare("SELECT meas, field, type FROM schemas")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let field_type: String = row.get(2)?;
+            data.schemas.entry(row.get(0)?).or_default().insert(row.get(1)?, serde_json::from_value(Value::from(field_type)).map_err(json_err)?);
+        }
+
+        Ok(data)
+    }
+
+    fn convert_json(path: &Path, buf: &[u8]) -> Result<(), String> {
+        // State of earlier versions: build the database next to it, keep the original and swap them (a crash before the
+        // rename leaves the JSON state in place, it is converted again at the next start).
+
+        let data: StoreData = serde_json::from_slice(buf).map_err(|e| format!("Unable to parse state {}: {}", path.display(), e))?;
+
+        let tmp_path = path.with_extension("tmp");
+        let backup_path = path.with_extension("json.bak");
+        let err = |e: rusqlite::Error| format!("Unable to convert state {}: {}", path.display(), e);
+
+        let _ = fs::remove_file(&tmp_path); // Leftover of an interrupted conversion.
+
+        {
+            let mut conn = Connection::open(&tmp_path).map_err(err)?;
+            Self::migrate(path, &mut conn)?;
+
+            let tx = conn.transaction().map_err(err)?;
+            for (id, entry) in &data.devices {
+                Self::save_device(&tx, id, entry).map_err(err)?;
+            }
+            for (meas, schema) in &data.schemas {
+                Self::save_schema(&tx, meas, schema.iter()).map_err(err)?;
+            }
+            tx.commit().map_err(err)?;
+        } // Closed, so the rollback journal is gone.
+
+        fs::copy(path, &backup_path)
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map_err(|e| format!("Unable to convert state {}: {}", path.display(), e))?;
+
+        println!("Converted state {} to SQLite, the original is kept as {}", path.display(), backup_path.display());
+
+        Ok(())
+    }
+
+    fn save_device(conn: &Connection, id: &str, entry: &DeviceEntry) -> rusqlite::Result<()> {
+        conn.execute("INSERT OR REPLACE INTO devices (id, entry) VALUES (?1, ?2)", params![id, serde_json::to_string(entry).unwrap()])?;
+        Ok(())
+    }
+
+    fn save_schema<'a, I>(conn: &Connection, meas: &str, fields: I) -> rusqlite::Result<()> where I: Iterator<Item = (&'a String, &'a DbFieldType)> {
+        for (field, field_type) in fields {
+            conn.execute("INSERT OR REPLACE INTO schemas (meas, field, type) VALUES (?1, ?2, ?3)", params![meas, field, field_type.to_string()])?;
+        }
+        Ok(())
+    }
+
+    pub fn get_device(&self, id: &str) -> DeviceEntry {
+        self.inner.lock().unwrap().data.devices.get(id).cloned().unwrap_or_default()
+    }
+
+    pub fn update_device<F>(&self, id: &str, f: F) where F: FnOnce(&mut DeviceEntry) {
+        let mut inner = self.inner.lock().unwrap();
+        let StoreInner { data, conn } = &mut *inner;
+        let entry = data.devices.entry(String::from(id)).or_default();
+        f(entry);
+
+        if let Err(e) = Self::save_device(conn, id, entry) {
+            eprintln!("{}", self.get_write_error(e));
+        }
+    }
+
+    pub fn check_schema(&self, meas: &str, records: &[DbRecord]) -> Result<(), String> {
+        // InfluxDB rejects a field forever once it has been written with a different type,
+        // so refuse conflicting records. New fields are only recorded once written, see add_schema().
+
+        let inner = self.inner.lock().unwrap();
+        Self::get_new_fields(inner.data.schemas.get(meas), meas, records).map(|_| ())
+    }
+
+    pub fn add_schema(&self, meas: &str, records: &[DbRecord]) {
+        // Record the field types of records written to the DB.
+
+        let mut inner = self.inner.lock().unwrap();
+        let StoreInner { data, conn } = &mut *inner;
+        let schema = data.schemas.entry(String::from(meas)).or_default();
+
+        let new_fields = match Self::get_new_fields(Some(schema), meas, records) {
+            Ok(new_fields) => new_fields,
+            Err(_) => return, // Checked before writing, another device was faster.
+        };
+
+        if !new_fields.is_empty() {
+            let result = conn.transaction().and_then(|tx| {
+                Self::save_schema(&tx, meas, new_fields.iter())?;
+                tx.commit()
+            });
+
+            if let Err(e) = result {
+                eprintln!("{}", self.get_write_error(e));
+            }
+
+            schema.extend(new_fields);
+        }
+    }
+
+    fn get_new_fields(schema: Option<&BTreeMap<String, DbFieldType>>, meas: &str, records: &[DbRecord]) -> Result<BTreeMap<String, DbFieldType>, String> {
+        let mut new_fields = BTreeMap::new();
+
+        for record in records {
+            for (field, field_type) in record.get_field_types() {
+                match schema.and_then(|schema| schema.get(field)).or(new_fields.get(field)) {
+                    Some(stored_type) if *stored_type != field_type => {
+                        return Err(format!("Type of field {}.{} would change from {} to {}, use a cast transform (or remove it from the state)", meas, field, stored_type, field_type));
+                    },
+                    Some(_) => (),
+                    None => {
+                        new_fields.insert(String::from(field), field_type);
+                    }
+                }
+            }
+        }
+
+        Ok(new_fields)
+    }
+
+    pub fn hold(&self, records: Vec<HeldRecord>) -> HoldResult {
+        // Rows are only added here (by any device, whatever its unknown user policy), so old ones are purged here too.
+
+        let mut inner = self.inner.lock().unwrap();
+        let config = &self.held_config;
+        let count = records.len();
+
+        let result = inner.conn.transaction_with_behavior(TransactionBehavior::Immediate).and_then(|tx| {
+            let held = Self::load_held(&tx)?;
+            let records: Vec<&HeldRecord> = records.iter().filter(|record| !held.iter().any(|(_, held)| held.is_same(record))).collect(); // Not re-read from the unit.
+            let mut len = held.len();
+            let mut refused = 0;
+            let mut dropped = 0;
+
+            if config.overflow == HeldOverflow::Block { // Make room first, only resolved rows can go.
+                let (parked, purged) = Self::purge_held(&tx, config, config.max.saturating_sub(records.len()))?;
+                dropped += parked;
+                len -= purged;
+            }
+
+            for record in records {
+                if config.overflow == HeldOverflow::Block && len >= config.max {
+                    refused += 1;
+                    continue;
+                }
+
+                tx.execute("INSERT INTO held (record) VALUES (?1)", params![serde_json::to_string(record).unwrap()])?;
+                len += 1;
+            }
+
+            let (parked, purged) = Self::purge_held(&tx, config, config.max)?;
+            tx.commit()?;
+            Ok((dropped + parked, refused, len - purged))
+        });
+
+        let (dropped, refused, len) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                eprintln!("{}", self.get_write_error(e));
+                return HoldResult { refused: count, high_water: None };
+            },
+        };
+
+        if dropped > 0 {
+            eprintln!("Dropped {} parked records from the state, they were not written in time or the held records are full", dropped);
+        }
+
+        let high_water = len >= config.get_high_water();
+        let alerted = self.held_alerted.swap(high_water, Ordering::Relaxed);
+
+        HoldResult {
+            refused,
+            high_water: if high_water && !alerted { Some(len) } else { None },
+        }
+    }
+
+    fn purge_held(conn: &Connection, config: &HeldConfig, max: usize) -> rusqlite::Result<(usize, usize)> {
+        // Forget old resolved and parked records, then the oldest ones beyond max (only resolved ones with the block
+        // policy). Returns the number of parked records dropped and the number of rows deleted.
+
+        let now = TimeUtil::get_current_unix();
+        let held = Self::load_held(conn)?;
+        let mut purgeable = Vec::new(); // Oldest first.
+        let mut dropped = 0;
+        let mut len = held.len();
+        let total = len;
+
+        for (id, record) in held {
+            let keep_before = match record.state {
+                HeldState::Uploaded | HeldState::Expired => now - i64::from(config.keep_resolved) * 86400,
+                HeldState::Refused => now - i64::from(config.keep_parked) * 86400,
+                HeldState::Pending | HeldState::Assigned => continue, // Bounded by hold_expiry, resp. uploaded soon.
+            };
+
+            if record.held_at < keep_before {
+                conn.execute("DELETE FROM held WHERE id = ?1", params![id])?;
+                dropped += usize::from(record.state == HeldState::Refused);
+                len -= 1;
+            } else if record.state != HeldState::Refused || config.overflow == HeldOverflow::DropOldest {
+                purgeable.push((id, record.state));
+            }
+        }
+
+        for (id, state) in purgeable.into_iter().take(len.saturating_sub(max)) {
+            conn.execute("DELETE FROM held WHERE id = ?1", params![id])?;
+            dropped += usize::from(state == HeldState::Refused);
+            len -= 1;
+        }
+
+        Ok((dropped, total - len))
+    }
+
+    pub fn get_held(&self, device: Option<&str>, state: HeldState) -> Result<Vec<(i64, HeldRecord)>, String> {
+        let inner = self.inner.lock().unwrap();
+
+        Self::load_held(&inner.conn)
+            .map(|held| held.into_iter().filter(|(_, record)| record.state == state && device.is_none_or(|device| record.device == device)).collect())
+            .map_err(|e| self.get_read_error(e))
+    }
+
+    pub fn get_held_record(&self, id: i64) -> Result<Option<HeldRecord>, String> {
+        let inner = self.inner.lock().unwrap();
+
+        Self::load_held_record(&inner.conn, id).map_err(|e| self.get_read_error(e))
+    }
+
+    pub fn update_held<F>(&self, id: i64, f: F) -> Result<HeldRecord, String> where F: FnOnce(&mut HeldRecord) -> Result<(), String> {
+        // Read and written in one transaction, the daemon and the CLI might be at it at the same time.
+
+        let mut inner = self.inner.lock().unwrap();
+        let tx = inner.conn.transaction_with_behavior(TransactionBehavior::Immediate).map_err(|e| self.get_write_error(e))?;
+
+        let mut record = match Self::load_held_record(&tx, id).map_err(|e| self.get_read_error(e))? {
+            Some(record) => record,
+            None => return Err(format!("Held record {} not found", id)),
+        };
+
+        f(&mut record)?;
+
+        tx.execute("UPDATE held SET record = ?2 WHERE id = ?1", params![id, serde_json::to_string(&record).unwrap()])
+            .and_then(|_| tx.commit())
+            .map_err(|e| self.get_write_error(e))?;
+
+        Ok(record)
+    }
+
+    pub fn expire_held(&self, device: &str, before: i64) -> usize {
+        // Pending records held before the given time [s] expire (they are forgotten by purge_held() later). Returns the
+        // number of records expired.
+
+        let mut inner = self.inner.lock().unwrap();
+
+        let result = inner.conn.transaction_with_behavior(TransactionBehavior::Immediate).and_then(|tx| {
+            let mut expired = 0;
+
+            for (id, mut record) in Self::load_held(&tx)?.into_iter().filter(|(_, record)| record.device == device) {
+                if record.state == HeldState::Pending && record.held_at < before {
+                    record.state = HeldState::Expired;
+                    tx.execute("UPDATE held SET record = ?2 WHERE id = ?1", params![id, serde_json::to_string(&record).unwrap()])?;
+                    expired += 1;
+                }
+            }
+
+            tx.commit()?;
+            Ok(expired)
+        });
+
+        result.unwrap_or_else(|e| {
+            eprintln!("{}", self.get_write_error(e));
+            0
+        })
+    }
+
+    fn load_held(conn: &Connection) -> rusqlite::Result<Vec<(i64, HeldRecord)>> {
+        let mut stmt = conn.prepare("SELECT id, record FROM held ORDER BY id")?;
+        let mut rows = stmt.query([])?;
+        let mut held = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            let record: String = row.get(1)?;
+            held.push((row.get(0)?, serde_json::from_str(&record).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?));
+        }
+
+        Ok(held)
+    }
+
+    fn load_held_record(conn: &Connection, id: i64) -> rusqlite::Result<Option<HeldRecord>> {
+        let record: Option<String> = conn.query_row("SELECT record FROM held WHERE id = ?1", params![id], |row| row.get(0)).optional()?;
+
+        record.map(|record| serde_json::from_str(&record).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))).transpose()
+    }
+
+    pub fn get_version(&self) -> usize {
+        MIGRATIONS.len() // Once opened, the state is migrated to the latest schema.
+    }
+
+    pub fn check_writable(&self) -> Result<Option<&Path>, String> {
+        // Without touching the state (the daemon might be running), SQLite needs to create its journal next to it.
+
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let probe_path = path.with_extension("selftest");
+
+        fs::write(&probe_path, b"").and_then(|_| fs::remove_file(&probe_path)).map_err(|e| format!("Unable to write state {}: {}", path.display(), e))?;
+
+        Ok(Some(path))
+    }
+
+    fn get_read_error(&self, e: rusqlite::Error) -> String {
+        let path = self.path.as_deref().unwrap_or(Path::new(MEMORY));
+        format!("Unable to read state {}: {}", path.display(), e)
+    }
+
+    fn get_write_error(&self, e: rusqlite::Error) -> String {
+        let path = self.path.as_deref().unwrap_or(Path::new(MEMORY));
+        format!("Unable to write state {}: {}", path.display(), e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+    use std::fs;
+    use crate::db::{DbFieldValue, DbRecord};
+    use super::{HeldConfig, HeldOverflow, HeldRecord, HeldState, Store, StoreConfig};
+
+    #[test]
+    fn migrate() {
+        let dir = std::env::temp_dir().join(format!("phd-store-{}", std::process::id()));
+        let path = dir.join("state.json");
+        let open = || Store::open(Some(StoreConfig { path: path.clone(), held: HeldConfig::default() }));
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, r#"{"devices": {"my_bpm": {"firmware": "1.2", "stats": {"records": 42}}}, "schemas": {"bp": {"sys": "integer"}}}"#).unwrap();
+
+        // JSON state of earlier versions is converted, nothing is lost.
+
+        let store = open().unwrap();
+        assert_eq!(store.get_device("my_bpm").firmware.as_deref(), Some("1.2"));
+        store.update_device("my_bpm", |entry| entry.stats.records += 1);
+        drop(store);
+
+        assert!(fs::read(&path).unwrap().starts_with(super::SQLITE_MAGIC));
+        assert!(fs::read(path.with_extension("json.bak")).is_ok());
+
+        let store = open().unwrap();
+        assert_eq!(store.get_device("my_bpm").stats.records, 43);
+        let mut record = DbRecord::new(0);
+        record.add_field("sys", DbFieldValue::Float(120.0));
+        assert!(store.check_schema("bp", &[record]).is_err()); // Stored as integer.
+        drop(store);
+
+        // A state written by a newer version is refused, not reset.
+
+        Connection::open(&path).unwrap().pragma_update(None, "user_version", 99).unwrap();
+        assert!(open().is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn schema() {
+        let store = Store::open(None).unwrap();
+        let mut record = DbRecord::new(0);
+        record.add_field("sys", DbFieldValue::Integer(120));
+        let mut other = DbRecord::new(0);
+        other.add_field("sys", DbFieldValue::Float(120.0));
+
+        // Not recorded until written.
+
+        assert!(store.check_schema("bp", &[record.clone()]).is_ok());
+        assert!(store.check_schema("bp", &[other.clone()]).is_ok());
+        assert!(store.check_schema("bp", &[record.clone(), other.clone()]).is_err());
+
+        store.add_schema("bp", &[record]);
+        assert!(store.check_schema("bp", &[other]).is_err());
+    }
+
+    #[test]
+    fn held() {
+        let store = Store::open(None).unwrap();
+        let mut record = DbRecord::new(1_000_000_000);
+        record.add_tag("user", "3");
+        record.add_field("weight", DbFieldValue::Float(71.5));
+
+        store.hold(vec![HeldRecord::new("my_scale", &record)]);
+        store.hold(vec![HeldRecord::new("my_scale", &record)]); // Re-read from the unit.
+
+        let held = store.get_held(Some("my_scale"), HeldState::Pending).unwrap();
+        assert_eq!(held.len(), 1);
+        let (id, _) = held[0];
+
+        let assign = |record: &mut HeldRecord| {
+            if record.state != HeldState::Pending {
+                return Err(String::from("not pending"));
+            }
+
+            record.state = HeldState::Assigned;
+            Ok(())
+        };
+
+        assert!(store.update_held(id, assign).is_ok());
+        assert!(store.update_held(id, assign).is_err());
+        assert!(store.update_held(id + 1, assign).is_err());
+        assert!(store.get_held(None, HeldState::Pending).unwrap().is_empty());
+
+        let record = store.get_held_record(id).unwrap().unwrap().to_record();
+        assert_eq!(record.get_tag("user"), Some("3"));
+        assert!(matches!(record.get_field("weight"), Some(DbFieldValue::Float(weight)) if *weight == 71.5));
+
+        // Only pending records expire.
+
+        let mut other = DbRecord::new(2_000_000_000);
+        other.add_field("weight", DbFieldValue::Float(80.0));
+        store.hold(vec![HeldRecord::new("my_scale", &other)]);
+        assert_eq!(store.expire_held("my_scale", i64::MAX), 1);
+        assert_eq!(store.get_held(None, HeldState::Expired).unwrap().len(), 1);
+        assert_eq!(store.get_held(None, HeldState::Assigned).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn purge() {
+        let store = Store::open(None).unwrap();
+        let now = crate::timeutil::TimeUtil::get_current_unix();
+        let held = |ts: i64, state: HeldState, age: i64| {
+            let mut held = HeldRecord::new("my_scale", &DbRecord::new(ts));
+            held.state = state;
+            held.held_at = now - age * 86400;
+            held
+        };
+
+        // Old resolved and parked records are forgotten, pending ones only expire.
+
+        store.hold(vec![
+            held(1, HeldState::Uploaded, 400),
+            held(2, HeldState::Uploaded, 10),
+            held(3, HeldState::Refused, 100),
+            held(4, HeldState::Refused, 10),
+            held(5, HeldState::Pending, 400),
+        ]);
+
+        assert_eq!(store.get_held(None, HeldState::Uploaded).unwrap().len(), 1);
+        assert_eq!(store.get_held(None, HeldState::Refused).unwrap().len(), 1);
+        assert_eq!(store.get_held(None, HeldState::Pending).unwrap().len(), 1);
+
+        // The table is capped, the oldest resolved and parked records go first.
+
+        let max = HeldConfig::get_default_max();
+        store.hold((10..10 + max as i64).map(|ts| held(ts, HeldState::Expired, 0)).collect());
+
+        let expired = store.get_held(None, HeldState::Expired).unwrap();
+        assert_eq!(expired.len(), max - 1);
+        assert_eq!(expired[0].1.ts, 11);
+        assert!(store.get_held(None, HeldState::Uploaded).unwrap().is_empty());
+        assert!(store.get_held(None, HeldState::Refused).unwrap().is_empty());
+        assert_eq!(store.get_held(None, HeldState::Pending).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn overflow() {
+        let dir = std::env::temp_dir().join(format!("phd-store-overflow-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let held = |ts: i64, state: HeldState| {
+            let mut held = HeldRecord::new("my_scale", &DbRecord::new(ts));
+            held.state = state;
+            held
+        };
+
+        // Parked records are kept once full, new ones are refused (left on the unit), resolved ones still make room.
+
+        let config = HeldConfig { max: 4, high_water: Some(3), overflow: HeldOverflow::Block, ..HeldConfig::default() };
+        let store = Store::open(Some(StoreConfig { path: dir.join("state.db"), held: config })).unwrap();
+
+        let result = store.hold(vec![held(1, HeldState::Uploaded), held(2, HeldState::Refused)]);
+        assert_eq!((result.refused, result.high_water), (0, None));
+
+        let result = store.hold(vec![held(3, HeldState::Refused), held(4, HeldState::Refused), held(5, HeldState::Refused)]);
+        assert_eq!((result.refused, result.high_water), (0, Some(4))); // Uploaded one purged.
+        assert!(store.get_held(None, HeldState::Uploaded).unwrap().is_empty());
+
+        let result = store.hold(vec![held(6, HeldState::Refused)]);
+        assert_eq!((result.refused, result.high_water), (1, None)); // Reported once.
+        assert_eq!(store.get_held(None, HeldState::Refused).unwrap().iter().map(|(_, held)| held.ts).collect::<Vec<_>>(), [2, 3, 4, 5]);
+
+        // Dropping the oldest instead.
+
+        let config = HeldConfig { overflow: HeldOverflow::DropOldest, ..config };
+        drop(store);
+        let store = Store::open(Some(StoreConfig { path: dir.join("state.db"), held: config })).unwrap();
+
+        let result = store.hold(vec![held(6, HeldState::Refused)]);
+        assert_eq!((result.refused, result.high_water), (0, Some(4)));
+        assert_eq!(store.get_held(None, HeldState::Refused).unwrap().iter().map(|(_, held)| held.ts).collect::<Vec<_>>(), [3, 4, 5, 6]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
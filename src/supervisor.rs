@@ -0,0 +1,113 @@
+//! # Device supervisor
+//!
+//! Keeps track of the running device tasks and applies device definitions
+//! from (re)loaded configuration one by one: a device whose new definition is
+//! invalid keeps running with its old one. Devices started together are
+//! spread over the configured time, so BlueZ and the adapter aren't hit by all
+//! of them at once.
+
+use config::Value;
+use std::collections::{HashMap, HashSet};
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+use crate::device::{Device, DeviceConfig, DeviceEnv};
+use crate::redact::Redact;
+
+struct RunningDevice {
+    raw: Value, // Definition the device task was started with, to detect changes.
+    handle: JoinHandle<()>,
+}
+
+pub struct Supervisor {
+    env: DeviceEnv,
+    startup_spread: Duration,
+    devices: HashMap<String, RunningDevice>,
+}
+
+impl Supervisor {
+    pub fn new(env: DeviceEnv, startup_spread: Duration) -> Self {
+        Self {
+            env,
+            startup_spread,
+            devices: HashMap::new(),
+        }
+    }
+
+    pub fn apply(&mut self, raw_devices: Vec<Value>) {
+        // Validate and apply each device block on its own.
+
+        let mut ids = HashSet::new();
+        let mut starting = Vec::new();
+
+        for raw in raw_devices {
+            let id = match Self::get_id(&raw) {
+                Some(id) => id,
+                None => {
+                    eprintln!("Ignoring device without id");
+                    continue;
+                }
+            };
+
+            if !ids.insert(id.clone()) {
+                eprintln!("{}: device id is duplicated, ignoring", id);
+                continue;
+            }
+
+            if let Some(running) = self.devices.get(&id) {
+                if running.raw == raw { // Unchanged.
+                    continue;
+                }
+            }
+
+            let config: DeviceConfig = match raw.clone().try_deserialize() {
+                Ok(config) => config,
+                Err(e) => {
+                    if self.devices.contains_key(&id) {
+                        eprintln!("{}: invalid configuration, keeping previous one: {}", id, Redact::apply(&e.to_string()));
+                    } else {
+                        eprintln!("{}: invalid configuration, not starting: {}", id, Redact::apply(&e.to_string()));
+                    }
+                    continue;
+                }
+            };
+
+            if let Some(running) = self.devices.remove(&id) {
+                println!("{}: configuration changed, restarting", id);
+                running.handle.abort();
+            }
+
+            starting.push((id, raw, config));
+        }
+
+        let count = starting.len() as u32;
+
+        for (i, (id, raw, config)) in starting.into_iter().enumerate() {
+            let delay = self.startup_spread * i as u32 / count;
+            let handle = Device::start(self.env.clone(), config, delay);
+
+            self.devices.insert(id, RunningDevice {
+                raw,
+                handle,
+            });
+        }
+
+        // Stop devices which are gone from configuration.
+
+        self.devices.retain(|id, running| {
+            if ids.contains(id) {
+                true
+            } else {
+                println!("{}: removed from configuration, stopping", id);
+                running.handle.abort();
+                self.env.status.remove(id);
+                self.env.control.remove(id);
+                false
+            }
+        });
+    }
+
+    fn get_id(raw: &Value) -> Option<String> {
+        raw.clone().into_table().ok()?.remove("id")?.into_string().ok()
+    }
+}
@@ -0,0 +1,70 @@
+//! # Self-telemetry
+//!
+//! Writes an internal measurement into the DB after each fetch cycle, so the
+//! daemon's operational history can be graphed next to the health data.
+
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::time::Duration;
+
+use crate::db::{DbFieldValue, DbPtr, DbRecord};
+use crate::redact::Redact;
+use crate::timeutil::TimeUtil;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TelemetryConfig {
+    meas: String,
+}
+
+#[derive(Default)]
+pub struct TelemetryCycle {
+    pub ok: bool,
+    pub fetch_duration: Option<Duration>, // Counted from receiving the advertisement.
+    pub records: usize,
+    pub retries: u32, // Failed DB writes before the records got through.
+    pub queue_depth: Option<usize>, // Record batches waiting for upload (streaming drivers only).
+    pub dropped_batches: Option<usize>, // Record batches dropped since the previous upload, the queue being full (streaming drivers only).
+}
+
+pub struct Telemetry {
+    db: DbPtr,
+    meas: String,
+}
+
+pub type TelemetryPtr = Arc<Telemetry>;
+
+impl Telemetry {
+    pub fn new(db: DbPtr, config: TelemetryConfig) -> Self {
+        Self {
+            db,
+            meas: config.meas,
+        }
+    }
+
+    pub async fn write(&self, id: &str, cycle: &TelemetryCycle) {
+        // Best effort, telemetry is not worth retrying for.
+
+        let mut record = DbRecord::new(TimeUtil::get_ts_unix(TimeUtil::get_current_unix()));
+        record.add_tag("device_id", id);
+        record.add_field("ok", DbFieldValue::Bool(cycle.ok));
+        record.add_field("records", DbFieldValue::Integer(cycle.records as i64));
+        record.add_field("retries", DbFieldValue::Integer(cycle.retries.into()));
+
+        if let Some(fetch_duration) = cycle.fetch_duration {
+            record.add_field("fetch_duration", DbFieldValue::Float(fetch_duration.as_secs_f64()));
+        }
+
+        if let Some(queue_depth) = cycle.queue_depth {
+            record.add_field("queue_depth", DbFieldValue::Integer(queue_depth as i64));
+        }
+
+        if let Some(dropped_batches) = cycle.dropped_batches {
+            record.add_field("dropped_batches", DbFieldValue::Integer(dropped_batches as i64));
+        }
+
+        if let Err(e) = self.db.send(&self.meas, &[record]).await {
+            eprintln!("{}: unable to write telemetry: {}", id, Redact::apply(&e.to_string()));
+        }
+    }
+}
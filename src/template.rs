@@ -0,0 +1,82 @@
+//! # String templates
+//!
+//! Templates contain `{name}` placeholders, which are expanded by a lookup
+//! function. Use `{{` and `}}` for literal braces.
+
+use serde::Deserialize;
+
+#[derive(Clone)]
+enum TemplatePart {
+    Literal(String),
+    Var(String),
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Template {
+    parts: Vec<TemplatePart>,
+}
+
+impl TryFrom<String> for Template {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                },
+                '{' => {
+                    let mut name = String::new();
+
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) if c.is_alphanumeric() || c == '_' => name.push(c),
+                            _ => return Err(format!("invalid placeholder in template: {}", s)),
+                        }
+                    }
+
+                    if name.is_empty() {
+                        return Err(format!("empty placeholder in template: {}", s));
+                    }
+
+                    if !literal.is_empty() {
+                        parts.push(TemplatePart::Literal(literal.split_off(0)));
+                    }
+                    parts.push(TemplatePart::Var(name));
+                },
+                '}' => return Err(format!("unmatched brace in template: {}", s)),
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+
+        Ok(Self {
+            parts,
+        })
+    }
+}
+
+impl Template {
+    pub fn expand<F>(&self, lookup: F) -> String where F: Fn(&str) -> Option<String> {
+        // Unknown placeholders expand to "unknown".
+
+        self.parts.iter().map(|part| match part {
+            TemplatePart::Literal(literal) => literal.clone(),
+            TemplatePart::Var(name) => lookup(name).unwrap_or_else(|| String::from("unknown")),
+        }).collect()
+    }
+}
@@ -1,8 +1,53 @@
-use chrono::{Datelike, MappedLocalTime, Timelike, TimeZone, Utc};
+use chrono::{DateTime, Datelike, Local, MappedLocalTime, NaiveDate, NaiveTime, SecondsFormat, TimeDelta, Timelike, TimeZone, Utc};
 use serde::de::{self, Deserializer, Visitor};
 use std::fmt;
+use std::sync::{Arc, RwLock};
 use tzfile::Tz;
 
+static CLOCK: RwLock<Option<ClockPtr>> = RwLock::new(None); // Process-wide, system clock if unset.
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub type ClockPtr = Arc<dyn Clock>;
+
+pub struct FixedClock { // Frozen time, for tests.
+    now: DateTime<Utc>,
+}
+
+impl FixedClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now,
+        }
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.now
+    }
+}
+
+pub struct ShiftedClock { // Runs from the given time on, e.g. to reproduce timestamp issues.
+    offset: TimeDelta,
+}
+
+impl ShiftedClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            offset: now - Utc::now(),
+        }
+    }
+}
+
+impl Clock for ShiftedClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now() + self.offset
+    }
+}
+
 struct TzVisitor;
 
 impl<'de> Visitor<'de> for TzVisitor {
@@ -17,6 +62,34 @@ impl<'de> Visitor<'de> for TzVisitor {
     }
 }
 
+struct TimeOfDayVisitor;
+
+impl<'de> Visitor<'de> for TimeOfDayVisitor {
+    type Value = NaiveTime;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("time of day in HH:MM format")
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Self::Value, E> where E: de::Error {
+        NaiveTime::parse_from_str(s, "%H:%M").map_err(|e| E::custom(format!("unable to parse time of day: {}", e)))
+    }
+}
+
+struct DateVisitor;
+
+impl<'de> Visitor<'de> for DateVisitor {
+    type Value = NaiveDate;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("date in YYYY-MM-DD format")
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Self::Value, E> where E: de::Error {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|e| E::custom(format!("unable to parse date: {}", e)))
+    }
+}
+
 pub struct Current {
     pub year: u16,
     pub month: u8,
@@ -33,17 +106,67 @@ impl TimeUtil {
         deserializer.deserialize_str(TzVisitor)
     }
 
+    pub fn get_local_tz() -> Tz {
+        // From $TZ or /etc/localtime, UTC if neither is usable.
+
+        Tz::local().unwrap_or_else(|e| {
+            eprintln!("Unable to determine local timezone, using UTC: {}", e);
+            Tz::from(Utc)
+        })
+    }
+
+    pub fn parse_time_of_day<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error> where D: Deserializer<'de> {
+        deserializer.deserialize_str(TimeOfDayVisitor)
+    }
+
+    pub fn parse_date<'de, D>(deserializer: D) -> Result<NaiveDate, D::Error> where D: Deserializer<'de> {
+        deserializer.deserialize_str(DateVisitor)
+    }
+
+    pub fn parse_optional_date<'de, D>(deserializer: D) -> Result<Option<NaiveDate>, D::Error> where D: Deserializer<'de> {
+        deserializer.deserialize_str(DateVisitor).map(Some)
+    }
+
+    pub fn parse_rfc3339(s: &str) -> Result<i64, String> {
+        DateTime::parse_from_rfc3339(s)
+            .map_err(|e| format!("unable to parse timestamp: {}", e))
+            .and_then(|datetime| datetime.timestamp_nanos_opt().ok_or(String::from("timestamp is out of range")))
+    }
+
+    pub fn format_rfc3339(ts: i64) -> String {
+        // [ns], in UTC.
+
+        DateTime::from_timestamp_nanos(ts).to_rfc3339_opts(SecondsFormat::AutoSi, true)
+    }
+
     pub fn get_ts(tz: &Tz, year: u16, month: u8, day: u8, hour: u8, min: u8, sec: u8) -> Option<i64> {
         match tz.with_ymd_and_hms(year.into(), month.into(), day.into(), hour.into(), min.into(), sec.into()) {
-            MappedLocalTime::Single(datetime) => Some(datetime.timestamp_nanos_opt().unwrap()),
+            MappedLocalTime::Single(datetime) => datetime.timestamp_nanos_opt(), // Out of range years are treated as invalid.
             MappedLocalTime::Ambiguous(_, _) => None,
             MappedLocalTime::None => None,
         }
     }
 
+    pub fn set_clock(clock: ClockPtr) {
+        // Replaces the system clock for all the callers below (tests, --fake-now).
+
+        *CLOCK.write().unwrap() = Some(clock);
+    }
+
+    fn now() -> DateTime<Utc> {
+        match &*CLOCK.read().unwrap() {
+            Some(clock) => clock.now(),
+            None => Utc::now(),
+        }
+    }
+
+    pub fn get_ts_unix(secs: i64) -> i64 {
+        secs * 1_000_000_000
+    }
+
     pub fn get_current(tz: &Tz) -> Current {
-        let datetime = Utc::now().with_timezone(&tz);
-    
+        let datetime = Self::now().with_timezone(&tz);
+
         Current {
             year: datetime.year().try_into().unwrap(),
             month: datetime.month().try_into().unwrap(),
@@ -52,5 +175,46 @@ impl TimeUtil {
             min: datetime.minute().try_into().unwrap(),
             sec: datetime.second().try_into().unwrap(),
         }
-    }    
+    }
+
+    pub fn get_current_unix() -> i64 {
+        Self::now().timestamp()
+    }
+
+    pub fn get_current_unix_ns() -> i64 {
+        Self::now().timestamp_nanos_opt().unwrap_or_default()
+    }
+
+    pub fn get_secs_until_window(from: &NaiveTime, to: &NaiveTime) -> u64 {
+        // Returns 0 if host's local time is within [from, to), the window might wrap around midnight.
+
+        let now = Self::now().with_timezone(&Local).time();
+        let inside = if from <= to { *from <= now && now < *to } else { *from <= now || now < *to };
+
+        if inside {
+            0
+        } else {
+            let secs = (*from - now).num_seconds();
+            (if secs < 0 { secs + 86400 } else { secs }) as u64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use std::sync::Arc;
+    use tzfile::Tz;
+
+    use super::{FixedClock, TimeUtil};
+
+    #[test]
+    fn fixed_clock() {
+        let now = TimeUtil::parse_rfc3339("2024-03-31T01:30:00Z").unwrap(); // Right after DST started in Budapest.
+        TimeUtil::set_clock(Arc::new(FixedClock::new(DateTime::from_timestamp_nanos(now))));
+
+        let current = TimeUtil::get_current(&Tz::named("Europe/Budapest").unwrap());
+        assert_eq!((current.year, current.month, current.day, current.hour, current.min), (2024, 3, 31, 3, 30));
+        assert_eq!(TimeUtil::get_ts_unix(TimeUtil::get_current_unix()), now);
+    }
 }
@@ -0,0 +1,99 @@
+//! # API TLS
+//!
+//! Shared by the HTTP and gRPC APIs. The certificate (chain) and key are read
+//! from PEM files. Without them, a self-signed certificate is generated at
+//! each start, its SHA-256 fingerprint is logged so clients can pin it (it
+//! changes with every restart, use your own certificate for long-term
+//! pinning).
+
+use rustls::ServerConfig;
+use rustls::crypto::ring;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const HOST_NAME_FNAME: &str = "/proc/sys/kernel/hostname";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    #[serde(default)]
+    names: Vec<String>, // Of the self-signed certificate, localhost and the host name if empty.
+}
+
+pub struct TlsPem {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+impl TlsConfig {
+    pub fn load(&self, api: &str) -> Result<TlsPem, String> {
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => {
+                let read = |fname: &PathBuf| fs::read(fname).map_err(|e| format!("Unable to read {}: {}", fname.display(), e));
+
+                Ok(TlsPem {
+                    cert: read(cert)?,
+                    key: read(key)?,
+                })
+            },
+            (None, None) => self.generate(api),
+            _ => Err(format!("{} TLS needs both cert and key (or neither for a self-signed certificate)", api)),
+        }
+    }
+
+    fn generate(&self, api: &str) -> Result<TlsPem, String> {
+        let names = if self.names.is_empty() {
+            let mut names = vec![String::from("localhost")];
+            if let Some(host_name) = fs::read_to_string(HOST_NAME_FNAME).ok().map(|host_name| String::from(host_name.trim())).filter(|host_name| !host_name.is_empty()) {
+                names.push(host_name);
+            }
+            names
+        } else {
+            self.names.clone()
+        };
+
+        let certified = rcgen::generate_simple_self_signed(names.clone()).map_err(|e| format!("Unable to generate {} certificate: {}", api, e))?;
+        let fingerprint = Sha256::digest(certified.cert.der());
+
+        println!("{}: using self-signed certificate for {}, SHA-256 fingerprint {}", api, names.join(", "), hex::encode(fingerprint));
+
+        Ok(TlsPem {
+            cert: certified.cert.pem().into_bytes(),
+            key: certified.key_pair.serialize_pem().into_bytes(),
+        })
+    }
+}
+
+impl TlsPem {
+    pub fn get_server_config(&self) -> Result<ServerConfig, String> {
+        let certs = rustls_pemfile::certs(&mut self.cert.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Unable to parse TLS certificate: {}", e))?;
+        let key = rustls_pemfile::private_key(&mut self.key.as_slice())
+            .map_err(|e| format!("Unable to parse TLS key: {}", e))?
+            .ok_or_else(|| String::from("No TLS key found"))?;
+
+        ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(|e| format!("Invalid TLS certificate/key: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TlsConfig;
+
+    #[test]
+    fn self_signed() {
+        let config = TlsConfig { cert: None, key: None, names: vec![String::from("phd.local")] };
+        let pem = config.load("API").unwrap();
+
+        assert!(pem.get_server_config().is_ok());
+    }
+}
@@ -0,0 +1,182 @@
+//! # Protocol trace log
+//!
+//! Protocol traces (debug_protocol) are printed along with the other logs,
+//! unless a trace log is configured: then they are appended to a file of
+//! their own, with a timestamp. The file is rotated once it reaches the
+//! configured size (trace.log.1 is the newest rotated file), rotated files
+//! can be compressed with zstd and only the configured number of them is
+//! kept, so tracing can be left enabled on small flash storage.
+
+use serde::Deserialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::timeutil::TimeUtil;
+
+const ZSTD_LEVEL: i32 = 3;
+const ZSTD_EXT: &str = "zst";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TraceLogConfig {
+    path: PathBuf,
+    #[serde(default = "TraceLogConfig::get_default_max_size")]
+    max_size: u64, // [bytes] Rotated once this large.
+    #[serde(default = "TraceLogConfig::get_default_keep")]
+    keep: usize, // Rotated files kept, 0: the file is truncated instead.
+    #[serde(default)]
+    compress: bool, // Rotated files are compressed with zstd.
+}
+
+struct TraceFile {
+    config: TraceLogConfig,
+    file: File,
+    len: u64,
+}
+
+static FILE: Mutex<Option<TraceFile>> = Mutex::new(None);
+
+pub struct TraceLog;
+
+impl TraceLogConfig {
+    fn get_default_max_size() -> u64 {
+        1024 * 1024
+    }
+
+    fn get_default_keep() -> usize {
+        5
+    }
+}
+
+impl TraceLog {
+    pub fn init(config: Option<TraceLogConfig>) -> Result<(), String> {
+        let file = match config {
+            Some(config) => Some(TraceFile::open(config).map_err(|e| format!("Unable to open trace log: {}", e))?),
+            None => None,
+        };
+
+        *FILE.lock().unwrap() = file;
+        Ok(())
+    }
+
+    pub fn write(id: &str, trace: &str) {
+        // trace is already redacted.
+
+        let mut file = FILE.lock().unwrap();
+
+        match file.as_mut() {
+            Some(file) => {
+                let line = format!("{} {}: {}\n", TimeUtil::format_rfc3339(TimeUtil::get_current_unix_ns()), id, trace);
+
+                if let Err(e) = file.write(&line) {
+                    eprintln!("Unable to write trace log {}: {}", file.config.path.display(), e);
+                }
+            },
+            None => println!("{}: trace: {}", id, trace),
+        }
+    }
+}
+
+impl TraceFile {
+    fn open(config: TraceLogConfig) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let len = file.metadata()?.len();
+
+        Ok(Self {
+            config,
+            file,
+            len,
+        })
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.len > 0 && self.len + line.len() as u64 > self.config.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.len += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        // Shift the rotated files (compressed or not, compress might have been changed since), the oldest is deleted.
+
+        let keep = self.config.keep;
+
+        for i in (1..=keep).rev() {
+            for compressed in [false, true] {
+                let from = self.get_rotated_path(i, compressed);
+
+                let result = if i == keep {
+                    fs::remove_file(&from)
+                } else {
+                    fs::rename(&from, self.get_rotated_path(i + 1, compressed))
+                };
+
+                match result {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                    _ => (),
+                }
+            }
+        }
+
+        if keep > 0 {
+            let rotated = self.get_rotated_path(1, false);
+            fs::rename(&self.config.path, &rotated)?;
+
+            if self.config.compress {
+                let data = zstd::encode_all(File::open(&rotated)?, ZSTD_LEVEL)?;
+                fs::write(self.get_rotated_path(1, true), data)?;
+                fs::remove_file(&rotated)?;
+            }
+        }
+
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.config.path)?;
+        self.len = 0;
+
+        Ok(())
+    }
+
+    fn get_rotated_path(&self, i: usize, compressed: bool) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{}", i));
+
+        if compressed {
+            path.push(format!(".{}", ZSTD_EXT));
+        }
+
+        PathBuf::from(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{TraceFile, TraceLogConfig};
+
+    #[test]
+    fn rotate() {
+        let dir = std::env::temp_dir().join(format!("phd-tracelog-{}", std::process::id()));
+        let path = dir.join("trace.log");
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut file = TraceFile::open(TraceLogConfig { path: path.clone(), max_size: 10, keep: 2, compress: true }).unwrap();
+
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write(line).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(zstd::decode_all(&fs::read(dir.join("trace.log.1.zst")).unwrap()[..]).unwrap(), b"third\n");
+        assert_eq!(zstd::decode_all(&fs::read(dir.join("trace.log.2.zst")).unwrap()[..]).unwrap(), b"second\n");
+        assert!(!dir.join("trace.log.3.zst").exists()); // Only keep are kept.
+        assert!(!dir.join("trace.log.1").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+//! # Record post-processing
+//!
+//! Transforms are configured per device and applied in order to each record,
+//! between driver output and the DB. A transform referring to a field which is
+//! missing (or is not numeric, where a number is needed) leaves the record
+//! as is.
+
+use serde::Deserialize;
+
+use crate::db::{DbFieldType, DbFieldValue, DbRecord};
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeriveOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BoundsAction {
+    #[default]
+    DropRecord,
+    DropField,
+    Mark, // Add <field>_implausible field.
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TransformConfig {
+    Rename { field: String, to: String },
+    Scale { field: String, factor: f64, #[serde(default)] offset: f64 }, // field * factor + offset
+    Round { field: String, digits: u8 },
+    Derive { field: String, from: [String; 2], with: DeriveOp }, // field = from[0] <with> from[1]
+    Drop { field: String },
+    TagFromValue { field: String, tag: String }, // Move field into a tag.
+    Cast { field: String, to: DbFieldType }, // E.g. to keep the type already stored in the DB.
+    Bounds { field: String, min: Option<f64>, max: Option<f64>, #[serde(default)] action: BoundsAction }, // Plausibility check.
+}
+
+pub struct Transform;
+
+impl Transform {
+    pub fn apply(transforms: &[TransformConfig], record: &mut DbRecord) {
+        for transform in transforms {
+            match transform {
+                TransformConfig::Rename { field, to } => {
+                    if let Some(value) = record.remove_field(field) {
+                        record.add_field(to, value);
+                    }
+                },
+                TransformConfig::Scale { field, factor, offset } => {
+                    if let Some(value) = Self::get_number(record, field) {
+                        record.add_field(field, DbFieldValue::Float(value * factor + offset));
+                    }
+                },
+                TransformConfig::Round { field, digits } => {
+                    if let Some(value) = Self::get_number(record, field) {
+                        let mul = 10_f64.powi((*digits).into());
+                        record.add_field(field, DbFieldValue::Float((value * mul).round() / mul));
+                    }
+                },
+                TransformConfig::Derive { field, from, with } => {
+                    if let (Some(left), Some(right)) = (Self::get_number(record, &from[0]), Self::get_number(record, &from[1])) {
+                        let value = match with {
+                            DeriveOp::Add => left + right,
+                            DeriveOp::Sub => left - right,
+                            DeriveOp::Mul => left * right,
+                            DeriveOp::Div => left / right,
+                        };
+
+                        if value.is_finite() { // E.g. division by zero.
+                            record.add_field(field, DbFieldValue::Float(value));
+                        }
+                    }
+                },
+                TransformConfig::Drop { field } => {
+                    record.remove_field(field);
+                },
+                TransformConfig::Cast { field, to } => {
+                    if let Some(value) = record.remove_field(field) {
+                        match value.cast(*to) {
+                            Some(value) => record.add_field(field, value),
+                            None => eprintln!("Unable to cast field {} to {}, dropping it", field, to),
+                        }
+                    }
+                },
+                TransformConfig::Bounds { field, min, max, action } => {
+                    if let Some(value) = Self::get_number(record, field) {
+                        if min.is_some_and(|min| value < min) || max.is_some_and(|max| value > max) {
+                            match action {
+                                BoundsAction::DropRecord => {
+                                    eprintln!("Field {} is out of bounds ({}), dropping record", field, value);
+                                    record.clear_fields();
+                                },
+                                BoundsAction::DropField => {
+                                    eprintln!("Field {} is out of bounds ({}), dropping it", field, value);
+                                    record.remove_field(field);
+                                },
+                                BoundsAction::Mark => record.add_field(&format!("{}_implausible", field), DbFieldValue::Bool(true)),
+                            }
+                        }
+                    }
+                },
+                TransformConfig::TagFromValue { field, tag } => {
+                    if let Some(value) = record.remove_field(field) {
+                        let value = match value {
+                            DbFieldValue::Float(value) => format!("{}", value),
+                            DbFieldValue::Integer(value) => format!("{}", value),
+                            DbFieldValue::Bool(value) => format!("{}", value),
+                            DbFieldValue::String(value) => value,
+                        };
+                        record.add_tag(tag, &value);
+                    }
+                },
+            }
+        }
+    }
+
+    fn get_number(record: &DbRecord, field: &str) -> Option<f64> {
+        match record.get_field(field)? {
+            DbFieldValue::Float(value) => Some(*value),
+            DbFieldValue::Integer(value) => Some(*value as f64),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! # Trend smoothing
+//!
+//! Adds an exponentially smoothed trend of a field (as in The Hacker's Diet)
+//! to each record, so dashboards get a stable line without server-side
+//! processing. A trend is kept per measurement, field and tag set, and its
+//! last value is kept in the store. Records not newer than the last one
+//! smoothed (e.g. re-read from the unit) get no trend.
+
+use serde::Deserialize;
+
+use crate::db::{DbFieldValue, DbRecord};
+use crate::store::{StorePtr, TrendState};
+
+fn default_smoothing() -> f64 {
+    0.1
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrendConfig {
+    field: String,
+    to: Option<String>, // Default is <field>_trend.
+    #[serde(default = "default_smoothing")]
+    smoothing: f64, // trend = trend + smoothing * (value - trend)
+}
+
+pub struct Trend;
+
+impl Trend {
+    pub fn apply(config: &TrendConfig, store: &StorePtr, id: &str, meas: &str, records: &mut [DbRecord]) {
+        let to = config.to.clone().unwrap_or_else(|| format!("{}_trend", config.field));
+
+        let mut indices: Vec<_> = (0..records.len()).filter(|index| Self::get_number(&records[*index], &config.field).is_some()).collect();
+        indices.sort_by_key(|index| records[*index].get_ts());
+
+        if indices.is_empty() {
+            return;
+        }
+
+        store.update_device(id, |entry| {
+            for index in indices {
+                let record = &mut records[index];
+                let value = Self::get_number(record, &config.field).unwrap();
+                let key = Self::get_key(meas, &config.field, record);
+
+                let state = match entry.trends.get(&key) {
+                    Some(state) if state.ts >= record.get_ts() => continue,
+                    Some(state) => TrendState {
+                        ts: record.get_ts(),
+                        value: state.value + config.smoothing * (value - state.value),
+                    },
+                    None => TrendState { // First value starts the trend.
+                        ts: record.get_ts(),
+                        value,
+                    },
+                };
+
+                record.add_field(&to, DbFieldValue::Float(state.value));
+                entry.trends.insert(key, state);
+            }
+        });
+    }
+
+    fn get_key(meas: &str, field: &str, record: &DbRecord) -> String {
+        let mut tags: Vec<_> = record.get_tags().collect();
+        tags.sort();
+
+        format!("{}.{}{}", meas, field, tags.iter().map(|(key, value)| format!(",{}={}", key, value)).collect::<Vec<String>>().join(""))
+    }
+
+    fn get_number(record: &DbRecord, field: &str) -> Option<f64> {
+        match record.get_field(field)? {
+            DbFieldValue::Float(value) => Some(*value),
+            DbFieldValue::Integer(value) => Some(*value as f64),
+            _ => None,
+        }
+    }
+}